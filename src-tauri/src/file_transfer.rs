@@ -1,28 +1,36 @@
 // src/file_transfer.rs
 
-use std::collections::{HashMap, HashSet, VecDeque};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::time::{Duration, Instant};
 
 use aes_gcm::aead::Aead;
 use aes_gcm::{Aes256Gcm, Key, KeyInit, Nonce};
 use base64::{Engine as _, engine::general_purpose};
-use image::{self, ImageFormat};
+use futures_util::{StreamExt, stream};
+use image::{self, DynamicImage, ImageFormat, imageops::FilterType};
 use lazy_static::lazy_static;
-use mime_guess::from_path;
+use md5::Md5;
+use mime_guess::{from_path, mime};
 use rand::Rng;
 use reqwest;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use std::future::Future;
-use std::io::Cursor;
+use std::io::{Cursor, Read};
 use std::pin::Pin;
+use sysinfo::Disks;
 use tauri::{AppHandle, Emitter, Manager, State, command};
+use tauri_plugin_notification::NotificationExt;
 use tokio::fs::File;
-use tokio::io::{AsyncReadExt, AsyncSeekExt};
-use tokio::sync::Mutex;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::{Mutex, Semaphore};
 use xattr;
+use zeroize::Zeroizing;
+
+use crate::proxy;
 
 /// Type definitions for file transfer
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -33,7 +41,56 @@ pub struct QueueItem {
     path: String,
     name: String,
     parent_id: String,
+    #[serde(default)]
+    share_id: String, // Share the item is uploaded into
     depth: usize, // Tracks hierarchy level
+    #[serde(skip)]
+    data: Option<Arc<[u8]>>, // Contents for uploads from memory, read instead of `path`
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    metadata: HashMap<String, String>, // Caller-supplied tags stored with the file
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    conflict_policy: Option<ConflictPolicy>, // None leaves it to the server
+    #[serde(default)]
+    priority: Priority,
+    // Sent with init-file-upload and finalize-transfer so the server can drop
+    // repeats. Made once per item and kept across retries and restarts.
+    #[serde(default = "generate_idempotency_key")]
+    idempotency_key: String,
+}
+
+/// Scheduling class of a queued item. Classes share the queue by weight, so
+/// higher classes start most items but lower ones are never starved; items
+/// start oldest first within a class.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    Low, // Background sync
+    #[default]
+    Normal,
+    High, // Something the user is waiting on
+}
+
+impl Priority {
+    const ALL: [Priority; 3] = [Priority::High, Priority::Normal, Priority::Low];
+
+    /// Share of the items started while every class has work waiting
+    fn weight(self) -> i64 {
+        match self {
+            Priority::High => 6,
+            Priority::Normal => 3,
+            Priority::Low => 1,
+        }
+    }
+}
+
+/// What to do when a file with the same name already exists in the target folder
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConflictPolicy {
+    Skip,
+    Overwrite,
+    Rename, // Upload as "name (2).ext", "name (3).ext", ...
+    Version,
 }
 
 /// Represents a presigned URL for block upload
@@ -43,6 +100,8 @@ pub struct PresignedUrl {
     block_id: String,
     index: usize,
     expires_in: usize,
+    #[serde(default)]
+    headers: HashMap<String, String>, // Extra headers the storage backend requires on the PUT
 }
 
 /// Response containing upload URLs and encryption key
@@ -55,6 +114,72 @@ pub struct UploadUrlsResponse {
     upload_urls: Vec<PresignedUrl>,
     content_key: String,              // Base64-encoded AES key for encryption
     thumbnail: Option<ThumbnailInfo>, // Add optional thumbnail information
+    #[serde(default)]
+    hash_algorithm: HashAlgorithm, // Algorithm the server verifies hashes with
+    #[serde(default)]
+    verify_md5: bool, // Send Content-MD5 with each block and check it against the ETag
+    #[serde(default)]
+    multipart_upload_id: Option<String>, // Set when blocks are parts of one multipart upload
+    #[serde(default)]
+    key_id: Option<String>, // Version of the account key the content key belongs to
+    #[serde(default)]
+    block_content_type: Option<String>, // Content-Type to PUT blocks with, octet-stream if unset
+}
+
+/// The server's answer to `complete-multipart`
+#[derive(Debug)]
+pub struct MultipartCompleteResponse {
+    retry_parts: Vec<usize>, // Part numbers the store rejected, to upload again
+}
+
+/// Hash algorithm used for block and content hashes
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HashAlgorithm {
+    #[default]
+    Sha256,
+    Blake3,
+}
+
+impl HashAlgorithm {
+    fn as_str(&self) -> &'static str {
+        match self {
+            HashAlgorithm::Sha256 => "sha256",
+            HashAlgorithm::Blake3 => "blake3",
+        }
+    }
+}
+
+/// Incremental hasher for whichever algorithm the server asked for
+enum ContentHasher {
+    Sha256(Sha256),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl ContentHasher {
+    fn new(algorithm: HashAlgorithm) -> Self {
+        match algorithm {
+            HashAlgorithm::Sha256 => ContentHasher::Sha256(Sha256::default()),
+            HashAlgorithm::Blake3 => ContentHasher::Blake3(Box::new(blake3::Hasher::new())),
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            ContentHasher::Sha256(hasher) => hasher.update(data),
+            ContentHasher::Blake3(hasher) => {
+                hasher.update(data);
+            }
+        }
+    }
+
+    /// Finish hashing and return the digest as lowercase hex
+    fn finalize_hex(self) -> String {
+        match self {
+            ContentHasher::Sha256(hasher) => format!("{:x}", hasher.finalize()),
+            ContentHasher::Blake3(hasher) => hasher.finalize().to_hex().to_string(),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -63,6 +188,8 @@ pub struct ThumbnailInfo {
     url: String,
     expires_in: usize,
     content_key: String, // Same key as the main file
+    #[serde(default)]
+    headers: HashMap<String, String>, // Extra headers the storage backend requires on the PUT
 }
 
 /// Payload wrapper for upload URLs response
@@ -77,6 +204,8 @@ pub struct UploadUrlsResponsePayload {
 pub struct ErrorResponsePayload {
     transfer_id: String,
     error: String,
+    #[serde(default)]
+    code: Option<String>, // "already_exists" when the name is taken in the target folder
 }
 
 /// Response for folder creation
@@ -98,6 +227,96 @@ pub struct TransferProgress {
     speed: Option<f64>,          // Bytes per second
     remaining_time: Option<u64>, // Seconds
     size: Option<u64>,           // File size in bytes (optional)
+    uploaded_bytes: Option<u64>, // Bytes uploaded so far; for folders, bytes of completed files inside
+    #[serde(default)]
+    error_code: Option<String>, // TransferError code when status is "failed"
+}
+
+/// Why a transfer failed. Events carry its `code` next to the display message
+/// so the UI can tell, say, a network failure from a missing file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "code", content = "message", rename_all = "snake_case")]
+pub enum TransferError {
+    FileNotFound(String),
+    Io(String), // Reading a file that exists
+    Network(String),
+    Timeout(String),
+    EncryptionFailed(String),
+    Cancelled,
+    UrlExpired(String),
+    UrlCountMismatch(String), // The server sent more or fewer upload URLs than blocks
+    BlockTooLarge(String),    // The server's block size is over the configured limit
+    TlsPinFailure(String),    // The endpoint's certificate doesn't match the pinned ones
+    InsufficientSpace(String), // The destination volume is too full
+    FileTimeout(String),      // The whole file took longer than per_file_timeout
+    InvalidTarget(String),    // The share or parent folder to upload into is missing
+    Server(String),           // Rejected by the API or storage backend
+    HashMismatch(String),     // Downloaded content doesn't match its expected hash
+    Internal(String),
+}
+
+impl TransferError {
+    /// Machine-readable category, e.g. `file_not_found`
+    pub fn code(&self) -> &'static str {
+        match self {
+            TransferError::FileNotFound(_) => "file_not_found",
+            TransferError::Io(_) => "io",
+            TransferError::Network(_) => "network",
+            TransferError::Timeout(_) => "timeout",
+            TransferError::EncryptionFailed(_) => "encryption_failed",
+            TransferError::Cancelled => "cancelled",
+            TransferError::UrlExpired(_) => "url_expired",
+            TransferError::UrlCountMismatch(_) => "url_count_mismatch",
+            TransferError::BlockTooLarge(_) => "block_too_large",
+            TransferError::TlsPinFailure(_) => "tls_pin_failure",
+            TransferError::InsufficientSpace(_) => "insufficient_space",
+            TransferError::FileTimeout(_) => "file_timeout",
+            TransferError::InvalidTarget(_) => "invalid_target",
+            TransferError::Server(_) => "server",
+            TransferError::HashMismatch(_) => "hash_mismatch",
+            TransferError::Internal(_) => "internal",
+        }
+    }
+}
+
+impl std::fmt::Display for TransferError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TransferError::Cancelled => write!(f, "Cancelled by user"),
+            TransferError::FileNotFound(message)
+            | TransferError::Io(message)
+            | TransferError::Network(message)
+            | TransferError::Timeout(message)
+            | TransferError::EncryptionFailed(message)
+            | TransferError::UrlExpired(message)
+            | TransferError::UrlCountMismatch(message)
+            | TransferError::BlockTooLarge(message)
+            | TransferError::TlsPinFailure(message)
+            | TransferError::InsufficientSpace(message)
+            | TransferError::FileTimeout(message)
+            | TransferError::InvalidTarget(message)
+            | TransferError::Server(message)
+            | TransferError::HashMismatch(message)
+            | TransferError::Internal(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+// Plain string errors from helpers have no better category
+impl From<String> for TransferError {
+    fn from(message: String) -> Self {
+        TransferError::Internal(message)
+    }
+}
+
+/// Live state of a single transfer, returned by get_transfer_status
+#[derive(Debug, Clone, Serialize)]
+pub struct TransferStatus {
+    id: String,
+    state: String, // "queued", "processing", "completed" or "failed"
+    progress: Option<TransferProgress>, // Last progress event emitted for it
+    error: Option<String>,
+    hashed_bytes: Option<u64>, // Bytes of a file uploading now that are in its content hash
 }
 
 /// Main queue for managing file transfers
@@ -108,6 +327,7 @@ pub struct TransferQueue {
     failed: HashMap<String, String>,        // ID -> error message
     folder_id_map: HashMap<String, String>, // path -> server folder ID
     paused: bool,
+    pause_after_current: bool, // Let the current item finish, then start nothing new
     start_time: Instant,
 
     // Tracking sets to prevent duplicate requests
@@ -116,2310 +336,9236 @@ pub struct TransferQueue {
     completion_notifications_sent: HashSet<String>, // IDs of transfers that have sent completion notifications
 
     // Block tracking to prevent duplicate notifications
-    block_completion_sent: HashSet<String>, // block_id + index combinations that have been sent
+    block_completion_sent: HashSet<String>, // file_id + block_id + index combinations that have been sent
+
+    // Server file IDs of files being uploaded, keyed by transfer ID, so a
+    // transfer can be cancelled by either
+    server_file_ids: HashMap<String, String>,
 
     // Additional tracking for duplicate responses from frontend
     received_url_responses: HashSet<String>, // transfer_id that have received URLs
     received_folder_responses: HashSet<String>, // transfer_id that have received folder creation responses
-    original_share_id: Option<String>,
 
     // Request timestamps to track stuck or hanging requests
     request_timestamps: HashMap<String, Instant>,
 
     // Track pending folders to ensure proper hierarchy processing
     pending_folders: HashSet<String>, // Path strings of folders being processed
-}
 
-lazy_static! {
-    static ref RESPONSE_CHANNELS: Mutex<HashMap<String, tokio::sync::oneshot::Sender<Result<UploadUrlsResponse, String>>>> =
-        Mutex::new(HashMap::new());
-    static ref FOLDER_RESPONSE_CHANNELS: Mutex<HashMap<String, tokio::sync::oneshot::Sender<Result<FolderResponse, String>>>> =
-        Mutex::new(HashMap::new());
+    // OS notification settings
+    notifications_enabled: bool,
+    notify_each_failure: bool, // Also notify for every failed item, not just the summary
+
+    // Batch tracking for the queue-drained notification. A batch starts when
+    // items are added to an idle queue and ends when the queue drains again.
+    batch_active: bool,
+    batch_completed_start: usize, // completed.len() when the batch started
+    batch_failed_start: usize,    // failed.len() when the batch started
+    batch_skipped_start: usize,   // skipped.len() when the batch started
+
+    // Duplicate detection: (absolute path, size, mtime) key -> ID of the item it was queued as
+    dedup_keys: HashMap<String, String>,
+
+    // Backpressure for large trees: once items reaches max_queue_size, created
+    // folders are not scanned yet but remembered here and scanned as items drain
+    max_queue_size: Option<usize>, // None means unbounded
+    deferred_scans: VecDeque<DeferredScan>,
+
+    // Item popped off the queue for processing, kept so it can be saved on shutdown
+    current_item: Option<QueueItem>,
+    // Last time the processing item showed progress, to detect orphaned state
+    last_activity: Option<Instant>,
+    // Bytes hashed in order so far for the file uploading now. Only a counter
+    // for get_transfer_status; the hash state itself isn't saved anywhere.
+    hash_progress: HashMap<String, u64>,
+    // Files being uploaded, keyed by server file ID, so single blocks can be
+    // uploaded again when the server finds them corrupt
+    upload_contexts: HashMap<String, UploadContext>,
+    // Set once the app starts exiting; no further items are started
+    shutting_down: bool,
+
+    // Uploaded byte totals for created folders, keyed by server folder ID
+    folder_progress: HashMap<String, FolderProgress>,
+
+    // Concurrent pre-scanning of selected folder trees: directory path ->
+    // (subfolders, files), consumed as each folder is created on the server
+    prescan_folders: bool,
+    prescanned: HashMap<String, (Vec<PathBuf>, Vec<PathBuf>)>,
+    prescan_generation: u64, // Bumped on cancel so running pre-scans stop storing results
+
+    // Files left out of the upload rather than failed: ID -> why
+    skipped: HashMap<String, SkipReason>,
+    skip_temp_files: bool, // Skip lock, temp and OS metadata files by name
+
+    // Permits in the session's connection semaphore
+    connection_limit: usize,
+    // Send a perceptual hash of images with their init request
+    perceptual_hashes: bool,
+    // Where partial downloads and upload checkpoints are written, or None
+    // for the OS temp directory
+    staging_directory: Option<PathBuf>,
+    // Folders being listed right now: cancel flags keyed by server folder ID,
+    // and the latest counts for get_queue_status
+    active_scans: HashMap<String, Arc<AtomicBool>>,
+    scan_progress: Option<ScanProgress>,
+
+    // Uploads whose server block size is larger than this are refused
+    block_size_limit: u64,
+
+    // Wall-clock budget for one file, after which it fails and the queue
+    // moves on. None lets a file take as long as it needs.
+    per_file_timeout: Option<Duration>,
+
+    // IDs of completed, failed and skipped items, oldest first, so history
+    // can be trimmed to max_history entries
+    history: VecDeque<String>,
+    max_history: Option<usize>, // None keeps everything
+
+    // How upload speed and ETA are smoothed in progress events
+    progress_smoothing: ProgressSmoothing,
+
+    // Uploaded files whose thumbnail failed, keyed by server file ID, so the
+    // thumbnail alone can be retried
+    thumbnail_retries: HashMap<String, ThumbnailRetry>,
+
+    // Name of the BlockUploader new files are uploaded with
+    upload_backend: String,
+    // Uploader used instead of the named backend, for embedders and tests
+    custom_uploader: Option<Arc<dyn BlockUploader>>,
+    // Checkpoint file of each upload in progress: transfer ID -> path
+    checkpoints: HashMap<String, PathBuf>,
+    // Scheduling credit of each priority class, indexed by Priority as usize
+    priority_credits: [i64; 3],
+
+    // Bumped by rekey_pending_transfers; init responses requested under an
+    // older generation are discarded and requested again
+    key_generation: u64,
+    key_id: Option<String>, // Account key version set by the last rekey
 }
 
-pub struct TransferManagerState(pub Arc<Mutex<TransferQueue>>);
-
-impl TransferQueue {
-    /// Creates a new transfer queue with default values
-    pub fn new() -> Self {
-        Self {
-            items: VecDeque::new(),
-            processing: None,
-            completed: HashSet::new(),
-            failed: HashMap::new(),
-            folder_id_map: HashMap::new(),
-            paused: false,
-            start_time: Instant::now(),
-            initialized_files: HashSet::new(),
-            initialized_folders: HashSet::new(),
-            completion_notifications_sent: HashSet::new(),
-            block_completion_sent: HashSet::new(),
-            received_url_responses: HashSet::new(),
-            received_folder_responses: HashSet::new(),
-            original_share_id: None,
-            request_timestamps: HashMap::new(),
-            pending_folders: HashSet::new(),
-        }
-    }
+/// What reupload_block needs to upload one block of an in-flight file again
+#[derive(Clone)]
+struct UploadContext {
+    item: QueueItem, // Original item, for its local path or in-memory data
+    file_size: u64,
+    block_size: u64,
+    revision_id: String,
+    urls: Vec<PresignedUrl>, // Block IDs by index, for requesting fresh URLs
+    content_key: Zeroizing<String>,
+    hash_algorithm: HashAlgorithm,
+    verify_md5: bool,
+    block_content_type: String,
 }
 
-/// Generates a unique ID for transfer items
-fn generate_id() -> String {
-    let mut rng = rand::rng();
-    let random_part: u64 = rng.random();
-    format!(
-        "transfer-{}-{:x}",
-        chrono::Utc::now().timestamp_millis(),
-        random_part
-    )
+/// Blocks of a file upload the store has accepted, saved in the staging
+/// directory so an upload cut short by a restart picks up where it stopped. Saved under
+/// the local file's checkpoint_key rather than the transfer ID, which is new
+/// every time the file is queued. Only used again for the same server file
+/// and revision, and only while the local file has the size and modified
+/// time it had when the blocks were read.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct UploadCheckpoint {
+    source: String, // Canonical path of the local file
+    file_id: String,
+    revision_id: String,
+    block_size: u64,
+    file_size: u64,
+    modified: Option<u64>,                   // Milliseconds since the epoch
+    blocks: BTreeMap<usize, Option<String>>, // Accepted block index -> part ETag
 }
 
-/// Scans a directory and returns lists of folders and files
-async fn scan_folder(path: &Path) -> Result<(Vec<PathBuf>, Vec<PathBuf>), String> {
-    let mut folders = Vec::new();
-    let mut files = Vec::new();
+impl UploadCheckpoint {
+    /// Where the checkpoint of a local file is saved, or None when the file
+    /// can't be read
+    fn path(dir: &Path, local_path: &Path) -> Option<PathBuf> {
+        Some(dir.join(format!("{}.json", checkpoint_key(local_path)?)))
+    }
 
-    match tokio::fs::read_dir(path).await {
-        Ok(mut dir) => {
-            while let Ok(Some(entry)) = dir.next_entry().await {
-                let entry_path = entry.path();
+    /// Whether `saved` was written for this same upload of this same file
+    fn continues(&self, saved: &UploadCheckpoint) -> bool {
+        self.source == saved.source
+            && self.file_id == saved.file_id
+            && self.revision_id == saved.revision_id
+            && self.block_size == saved.block_size
+            && self.file_size == saved.file_size
+            && self.modified.is_some()
+            && self.modified == saved.modified
+    }
 
-                if entry_path.is_dir() {
-                    folders.push(entry_path);
-                } else if entry_path.is_file() {
-                    files.push(entry_path);
-                }
+    /// Takes over the blocks of a saved checkpoint for this upload. A saved
+    /// checkpoint for anything else, like a file changed since, is deleted.
+    async fn resume(&mut self, path: &Path) {
+        let Ok(contents) = tokio::fs::read(path).await else {
+            return;
+        };
+
+        match serde_json::from_slice::<UploadCheckpoint>(&contents) {
+            Ok(saved) if self.continues(&saved) => {
+                println!(
+                    "Resuming {} with {} blocks already uploaded",
+                    self.source,
+                    saved.blocks.len()
+                );
+                self.blocks = saved.blocks;
+            }
+            _ => {
+                println!(
+                    "Discarding upload checkpoint for {}, the file or upload changed",
+                    self.source
+                );
+                let _ = tokio::fs::remove_file(path).await;
             }
+        }
+    }
 
-            Ok((folders, files))
+    async fn save(&self, path: &Path) -> Result<(), String> {
+        if let Some(dir) = path.parent() {
+            tokio::fs::create_dir_all(dir)
+                .await
+                .map_err(|e| format!("Failed to create checkpoint directory: {}", e))?;
         }
-        Err(e) => Err(format!("Failed to read directory: {}", e)),
+        let contents = serde_json::to_vec(self)
+            .map_err(|e| format!("Failed to serialize upload checkpoint: {}", e))?;
+
+        // Written aside and renamed, so a crash mid-write can't leave half a file
+        let temp_path = path.with_extension("json.tmp");
+        tokio::fs::write(&temp_path, contents)
+            .await
+            .map_err(|e| format!("Failed to write upload checkpoint: {}", e))?;
+        tokio::fs::rename(&temp_path, path)
+            .await
+            .map_err(|e| format!("Failed to write upload checkpoint: {}", e))
     }
 }
 
-/// Command to select and upload files
-#[command]
-pub async fn select_files(
-    app: AppHandle,
-    paths: Vec<String>,
-    share_id: String,
-    parent_id: String,
-    state: State<'_, TransferManagerState>,
-) -> Result<(), String> {
-    let mut items = Vec::new();
+/// Names the checkpoint of a local file after its canonical path, size and
+/// modified time, so the same file finds it again after a restart and a
+/// changed file doesn't
+fn checkpoint_key(path: &Path) -> Option<String> {
+    let key = dedup_key(path)?;
+    Some(hex::encode(&Sha256::digest(key.as_bytes())[..16]))
+}
 
-    // Process each file path
-    for path_str in paths {
-        let path = PathBuf::from(&path_str);
+/// What retry_thumbnail needs to rebuild a file's thumbnail after the upload
+struct ThumbnailRetry {
+    item: QueueItem, // Original item, for its local path or in-memory data
+    file_size: u64,
+    content_key: Zeroizing<String>, // Base64 key the file was encrypted with
+}
 
-        if !path.exists() || !path.is_file() {
-            app.emit("transfer-error", format!("Invalid file path: {}", path_str))
-                .map_err(|e| format!("Failed to emit error: {}", e))?;
-            continue;
-        }
+/// Settings for the speed and ETA shown while a file uploads
+#[derive(Debug, Clone, Copy)]
+struct ProgressSmoothing {
+    factor: f64,           // EMA weight of the newest sample, 0 < factor <= 1
+    window: usize,         // Per-block speeds averaged into each sample
+    stall_after: Duration, // Report "stalled" when a block takes longer than this
+}
 
-        let name = path
-            .file_name()
-            .and_then(|name| name.to_str())
-            .unwrap_or("unknown")
-            .to_string();
+impl Default for ProgressSmoothing {
+    fn default() -> Self {
+        Self {
+            factor: 0.3,
+            window: 5,
+            stall_after: Duration::from_secs(15),
+        }
+    }
+}
 
-        let id = generate_id();
+/// Smooths per-block upload speeds into a steady speed and ETA. Each block's
+/// speed joins a short rolling window whose average feeds an exponentially
+/// weighted moving average.
+struct SpeedTracker {
+    settings: ProgressSmoothing,
+    samples: VecDeque<f64>,
+    speed: Option<f64>, // Bytes per second
+    eta: Option<f64>,   // Seconds
+}
 
-        items.push(QueueItem {
-            item_type: "file".to_string(),
-            id,
-            path: path_str,
-            name,
-            parent_id: parent_id.clone(),
-            depth: 0, // Root level
-        });
+impl SpeedTracker {
+    fn new(settings: ProgressSmoothing) -> Self {
+        Self {
+            settings,
+            samples: VecDeque::with_capacity(settings.window),
+            speed: None,
+            eta: None,
+        }
     }
 
-    // Add items to the queue
-    {
-        let mut queue = state.0.lock().await;
-        queue.original_share_id = Some(share_id.clone());
+    /// Adds a block of `bytes` that took `elapsed` to upload
+    fn record(&mut self, bytes: u64, elapsed: Duration) {
+        if elapsed.is_zero() {
+            return;
+        }
 
-        for item in items {
-            queue.items.push_back(item);
+        self.samples.push_back(bytes as f64 / elapsed.as_secs_f64());
+        while self.samples.len() > self.settings.window.max(1) {
+            self.samples.pop_front();
         }
 
-        // Start processing if not already in progress
-        if queue.processing.is_none() && !queue.paused {
-            drop(queue); // Release the lock before starting process
-            process_next_item(app, state, share_id).await?;
+        let sample = self.samples.iter().sum::<f64>() / self.samples.len() as f64;
+        self.speed = Some(self.smooth(self.speed, sample));
+    }
+
+    /// Smoothed seconds left for `remaining_bytes`, if the speed is known
+    fn remaining_time(&mut self, remaining_bytes: u64) -> Option<u64> {
+        let speed = self.speed.filter(|speed| *speed > 0.0)?;
+        let eta = self.smooth(self.eta, remaining_bytes as f64 / speed);
+        self.eta = Some(eta);
+        Some(eta.round() as u64)
+    }
+
+    fn smooth(&self, previous: Option<f64>, sample: f64) -> f64 {
+        match previous {
+            Some(previous) => {
+                self.settings.factor * sample + (1.0 - self.settings.factor) * previous
+            }
+            None => sample,
         }
     }
+}
 
-    Ok(())
+/// Why a file was left out of an upload, sent with `transfer-skipped`
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SkipReason {
+    Empty,         // Zero bytes
+    Locked,        // Editor lock file, or held open exclusively by another program
+    TempPattern,   // Temporary file or partial download
+    Filtered,      // OS metadata file such as .DS_Store
+    AlreadyExists, // Name taken in the target folder under the skip conflict policy
 }
 
-/// Command to select and upload folders
-#[command]
-pub async fn select_folders(
-    app: AppHandle,
-    paths: Vec<String>,
-    share_id: String,
-    parent_id: String,
-    state: State<'_, TransferManagerState>,
-) -> Result<(), String> {
-    let mut items = Vec::new();
+/// Outcome of a batch that has drained, sent with `queue-idle`
+#[derive(Debug, Clone, Copy, Serialize)]
+struct BatchSummary {
+    completed: usize,
+    failed: usize,
+    skipped: usize,
+}
 
-    // Process each folder path
-    for path_str in paths {
-        let path = PathBuf::from(&path_str);
+/// Rolled-up progress of everything inside a created folder, including subfolders
+#[derive(Debug, Clone, Serialize)]
+struct FolderProgress {
+    item_id: String,
+    name: String,
+    parent_id: String,   // Server ID of the folder it was created in
+    uploaded_bytes: u64, // Bytes of completed files
+    total_items: usize,  // Descendant files and folders queued so far
+    completed_items: usize,
+    failed_items: usize,
+}
 
-        if !path.exists() || !path.is_dir() {
-            app.emit(
-                "transfer-error",
-                format!("Invalid folder path: {}", path_str),
-            )
-            .map_err(|e| format!("Failed to emit error: {}", e))?;
-            continue;
+impl FolderProgress {
+    /// Fraction of queued descendants that have finished, failed ones included
+    fn fraction_done(&self) -> f32 {
+        if self.total_items == 0 {
+            1.0
+        } else {
+            (self.completed_items + self.failed_items) as f32 / self.total_items as f32
         }
+    }
+}
 
-        let name = path
-            .file_name()
-            .and_then(|name| name.to_str())
-            .unwrap_or("unknown")
-            .to_string();
+/// Running totals of a created folder being listed, sent as `scan-progress`
+#[derive(Debug, Clone, Serialize)]
+pub struct ScanProgress {
+    folder_id: String,
+    path: String,
+    files: usize,
+    folders: usize,
+    done: bool,
+}
 
-        let id = generate_id();
+/// Queue contents written to disk on shutdown and restored on the next launch
+#[derive(Debug, Serialize, Deserialize)]
+struct QueueSnapshot {
+    #[serde(default)]
+    session_id: String, // Empty in snapshots written before sessions were saved
+    items: Vec<QueueItem>, // Interrupted item first, then everything still queued
+    failed: HashMap<String, String>,
+    folder_id_map: HashMap<String, String>,
+}
 
-        items.push(QueueItem {
-            item_type: "folder".to_string(),
-            id,
-            path: path_str,
-            name,
-            parent_id: parent_id.clone(),
-            depth: 0, // Root level
-        });
-    }
+// File under the store directory the queue snapshot is written to
+const QUEUE_SNAPSHOT_FILE: &str = "transfer_queue.json";
 
-    // Add items to the queue
-    {
-        let mut queue = state.0.lock().await;
-        queue.original_share_id = Some(share_id.clone());
+// Directory under the staging directory upload checkpoints are written to
+const CHECKPOINT_SUBDIR: &str = "cirrussync-upload-checkpoints";
 
-        for item in items {
-            queue.items.push_back(item);
-        }
+// Prefix for create_folder request IDs, which aren't queue items
+const CREATE_FOLDER_ID_PREFIX: &str = "create-folder-";
 
-        // Start processing if not already in progress
-        if queue.processing.is_none() && !queue.paused {
-            drop(queue); // Release the lock before starting process
-            process_next_item(app, state, share_id).await?;
-        }
-    }
+// Most items listed individually in a transfer plan; the counts cover everything
+const PLAN_ITEM_LIMIT: usize = 1000;
 
-    Ok(())
+/// What a dry run would upload, sent with `transfer-plan`
+#[derive(Debug, Default, Serialize)]
+struct TransferPlan {
+    file_count: usize,
+    folder_count: usize,
+    total_bytes: u64,
+    skipped_count: usize, // Selected items already queued or uploaded
+    items: Vec<serde_json::Value>,
+    items_truncated: bool,
 }
 
-/// Cancels a specific transfer by ID
-#[command]
-pub async fn cancel_transfer(
-    id: String,
-    state: State<'_, TransferManagerState>,
-) -> Result<(), String> {
-    let mut queue = state.0.lock().await;
+impl TransferPlan {
+    fn add_file(&mut self, path: &Path) {
+        let size = std::fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+        self.file_count += 1;
+        self.total_bytes += size;
+        self.add_item("file", path, Some(size));
+    }
 
-    // Check if this is the current processing item
-    if let Some(processing_id) = &queue.processing {
-        if processing_id == &id {
-            queue.processing = None;
-            queue
-                .failed
-                .insert(id.clone(), "Cancelled by user".to_string());
-            // Clean up all tracking for this ID
-            queue.initialized_files.remove(&id);
-            queue.initialized_folders.remove(&id);
-            queue.completion_notifications_sent.remove(&id);
-            queue.received_url_responses.remove(&id);
-            queue.received_folder_responses.remove(&id);
-            queue.request_timestamps.remove(&id);
-            return Ok(());
+    fn add_folder(&mut self, path: &Path) {
+        self.folder_count += 1;
+        self.add_item("folder", path, None);
+    }
+
+    fn add_item(&mut self, item_type: &str, path: &Path, size: Option<u64>) {
+        if self.items.len() >= PLAN_ITEM_LIMIT {
+            self.items_truncated = true;
+            return;
         }
+        self.items.push(serde_json::json!({
+            "type": item_type,
+            "path": path.to_string_lossy(),
+            "size": size,
+        }));
     }
+}
 
-    // Otherwise, remove it from the queue if found
-    queue.items.retain(|item| item.id != id);
-    queue
-        .failed
-        .insert(id.clone(), "Cancelled by user".to_string());
-    // Clean up all tracking for this ID
-    queue.initialized_files.remove(&id);
-    queue.initialized_folders.remove(&id);
-    queue.completion_notifications_sent.remove(&id);
-    queue.received_url_responses.remove(&id);
-    queue.received_folder_responses.remove(&id);
-    queue.request_timestamps.remove(&id);
+// Lock files left by editors: Office (~$report.docx) and Emacs (.#notes.txt)
+const LOCK_FILE_PREFIXES: [&str; 2] = ["~$", ".#"];
 
-    Ok(())
-}
+// Temporary files and partial browser downloads
+const TEMP_FILE_SUFFIXES: [&str; 2] = [".tmp", ".crdownload"];
 
-/// Cancels all pending transfers
-#[command]
-pub async fn cancel_all_transfers(state: State<'_, TransferManagerState>) -> Result<(), String> {
-    let mut queue = state.0.lock().await;
+// Metadata files the OS drops into folders
+const FILTERED_FILE_NAMES: [&str; 3] = [".DS_Store", "Thumbs.db", "desktop.ini"];
 
-    // Cancel the current processing item
-    if let Some(processing_id) = queue.processing.take() {
-        queue
-            .failed
-            .insert(processing_id.clone(), "Cancelled by user".to_string());
-        queue.initialized_files.remove(&processing_id);
-        queue.initialized_folders.remove(&processing_id);
-        queue.completion_notifications_sent.remove(&processing_id);
-        queue.received_url_responses.remove(&processing_id);
-        queue.received_folder_responses.remove(&processing_id);
-        queue.request_timestamps.remove(&processing_id);
-    }
-
-    // Fix the mutable borrow issue by collecting IDs first
-    let item_ids: Vec<String> = queue.items.iter().map(|item| item.id.clone()).collect();
-    queue.items.clear();
-    queue.pending_folders.clear(); // Clear pending folders too
-
-    // Then insert them into the failed map and clean up all tracking
-    for id in item_ids {
-        queue
-            .failed
-            .insert(id.clone(), "Cancelled by user".to_string());
-        queue.initialized_files.remove(&id);
-        queue.initialized_folders.remove(&id);
-        queue.completion_notifications_sent.remove(&id);
-        queue.received_url_responses.remove(&id);
-        queue.received_folder_responses.remove(&id);
-        queue.request_timestamps.remove(&id);
-    }
+// Simultaneous upload requests allowed across all transfers by default
+const DEFAULT_CONNECTION_LIMIT: usize = 8;
 
-    // Clear block completion tracking
-    queue.block_completion_sent.clear();
+// Largest block size accepted from the server, whatever the configured limit.
+// Each block is held in memory while it is encrypted and uploaded.
+const MAX_BLOCK_SIZE: u64 = 128 * 1024 * 1024;
+const DEFAULT_BLOCK_SIZE_LIMIT: u64 = 32 * 1024 * 1024;
 
-    Ok(())
-}
+// Blocks are read and hashed in pieces of this size, into a buffer of the whole block
+const READ_CHUNK_SIZE: usize = 1024 * 1024;
 
-/// Pauses all ongoing transfers
-#[command]
-pub async fn pause_transfers(state: State<'_, TransferManagerState>) -> Result<(), String> {
-    let mut queue = state.0.lock().await;
-    queue.paused = true;
-    Ok(())
-}
+// Error code the server uses when a file with the same name already exists
+const ALREADY_EXISTS: &str = "already_exists";
 
-/// Resumes previously paused transfers
-#[command]
-pub async fn resume_transfers(
-    app: AppHandle,
-    share_id: String,
-    state: State<'_, TransferManagerState>,
-) -> Result<(), String> {
-    {
-        let mut queue = state.0.lock().await;
-        queue.paused = false;
+// A processing item with no sign of life for this long is treated as orphaned
+const ORPHANED_AFTER: Duration = Duration::from_secs(5 * 60);
 
-        // Only start processing if nothing is currently processing
-        if queue.processing.is_none() && !queue.items.is_empty() {
-            drop(queue); // Release the lock before starting process
-            process_next_item(app, state, share_id).await?;
-        }
-    }
+// Upload passes over a multipart session before giving up on parts that keep failing
+const MAX_MULTIPART_ROUNDS: usize = 3;
 
-    Ok(())
-}
+// Name of the default upload backend
+const PRESIGNED_BACKEND: &str = "presigned";
 
-/// Returns the current status of the transfer queue
-#[command]
-pub async fn get_queue_status(
-    state: State<'_, TransferManagerState>,
-) -> Result<serde_json::Value, String> {
-    let queue = state.0.lock().await;
+// Sent to a waiting init request when the account key changes under it
+const REKEYED: &str = "rekeyed";
 
-    let result = serde_json::json!({
-        "queue_size": queue.items.len(),
-        "processing": queue.processing,
-        "completed": queue.completed.len(),
-        "failed": queue.failed.len(),
-        "paused": queue.paused,
-        "elapsedTime": queue.start_time.elapsed().as_secs(),
-        "pending_folders": queue.pending_folders.len()
-    });
+// Files counted between `upload-estimate-progress` events
+const ESTIMATE_PROGRESS_INTERVAL: usize = 500;
 
-    Ok(result)
-}
+// Bytes read at a time by hash_file
+const HASH_READ_SIZE: usize = 1024 * 1024;
 
-/// Finalizes a transfer after content update is complete
-#[command]
-pub async fn finalize_transfer_complete(
-    transfer_id: String,
-    file_id: String,
-    parent_id: String,
-    success: bool,
-    error: Option<String>,
-    app: AppHandle,
-) -> Result<(), String> {
-    let original_share_id;
-    let item_name;
+// Shortest time between `hash-progress` events
+const HASH_PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
 
-    {
-        let state = app.state::<TransferManagerState>();
-        let mut queue = state.0.lock().await;
+// Most finished transfers resync_transfer_state reports, newest first
+const RESYNC_HISTORY_LIMIT: usize = 500;
 
-        // Check if this transfer is already completed
-        if queue.completed.contains(&transfer_id) {
-            println!(
-                "Transfer {} already completed, skipping finalization",
-                transfer_id
-            );
-            return Ok(());
-        }
+// Shortest time between `scan-progress` events while listing a folder
+const SCAN_PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
 
-        // Get the original share ID from the state
-        original_share_id = queue.original_share_id.clone().unwrap_or_else(|| {
-            if parent_id != "root" && !parent_id.starts_with("folder-") {
-                parent_id.clone()
-            } else {
-                String::from("default-share-id")
-            }
-        });
+// How many directories a folder pre-scan reads at once
+const PRESCAN_CONCURRENCY: usize = 8;
 
-        // Try to get the item name
-        item_name = queue
-            .items
-            .iter()
-            .find(|item| item.id == transfer_id)
-            .map(|item| item.name.clone())
-            .unwrap_or_else(|| "Unknown".to_string());
+// How often the watchdog looks for stuck transfers unless set otherwise
+const DEFAULT_WATCHDOG_INTERVAL: Duration = Duration::from_secs(60);
 
-        // Remove the timestamp tracking for this transfer
-        queue.request_timestamps.remove(&transfer_id);
-    }
+/// Whether the watchdog runs, and how often
+#[derive(Debug, Clone, Copy)]
+struct WatchdogConfig {
+    enabled: bool,
+    interval: Duration,
+}
 
-    // If content update was successful or we're allowing failures
-    if success {
-        println!("Content update successful for transfer ID: {}", transfer_id);
+/// A created folder whose contents haven't been added to the queue yet
+#[derive(Debug, Clone)]
+struct DeferredScan {
+    path: PathBuf,
+    folder_id: String,
+    share_id: String,
+    priority: Priority,
+}
 
-        // Send transfer complete event
-        app.emit(
-            "transfer-complete",
-            serde_json::json!({
-                "id": transfer_id,
-                "name": item_name,
-                "file_id": file_id,
-                "parent_id": parent_id,
-                "status": "completed",
-                "message": "Upload complete and verified"
-            }),
-        )
-        .map_err(|e| format!("Failed to emit completion: {}", e))?;
-    } else {
-        println!(
-            "Content update failed for transfer ID: {}, but continuing",
-            transfer_id
-        );
+lazy_static! {
+    static ref RESPONSE_CHANNELS: ResponseChannels<UploadUrlsResponse> = Mutex::new(HashMap::new());
+    static ref FOLDER_RESPONSE_CHANNELS: ResponseChannels<FolderResponse> = Mutex::new(HashMap::new());
+    static ref THUMBNAIL_URL_CHANNELS: ResponseChannels<(String, HashMap<String, String>)> =
+        Mutex::new(HashMap::new());
+    static ref MULTIPART_RESPONSE_CHANNELS: ResponseChannels<MultipartCompleteResponse> =
+        Mutex::new(HashMap::new());
+    // Cancellation flags for running estimate_upload walks, keyed by estimate ID
+    static ref ESTIMATES: Mutex<HashMap<String, Arc<AtomicBool>>> = Mutex::new(HashMap::new());
+    // Cancellation flags for running hash_file calls, keyed by hash ID
+    static ref HASHES: Mutex<HashMap<String, Arc<AtomicBool>>> = Mutex::new(HashMap::new());
+    // Last progress event emitted for each transfer, for get_transfer_status.
+    // A std Mutex so progress can be recorded from sync code.
+    static ref LAST_PROGRESS: std::sync::Mutex<HashMap<String, TransferProgress>> =
+        std::sync::Mutex::new(HashMap::new());
+    static ref WATCHDOG: std::sync::Mutex<WatchdogConfig> = std::sync::Mutex::new(WatchdogConfig {
+        enabled: true,
+        interval: DEFAULT_WATCHDOG_INTERVAL,
+    });
+    // Wakes the watchdog when its config changes, so a new interval applies now
+    static ref WATCHDOG_CHANGED: tokio::sync::Notify = tokio::sync::Notify::new();
+}
 
-        // Send transfer complete with warning
-        let error_message = error.unwrap_or_else(|| "Content update failed".to_string());
-        app.emit(
-            "transfer-complete",
-            serde_json::json!({
-                "id": transfer_id,
-                "name": item_name,
-                "file_id": file_id,
-                "parent_id": parent_id,
-                "status": "completed",
-                "message": format!("Upload complete, but verification failed: {}", error_message)
-            }),
-        )
-        .map_err(|e| format!("Failed to emit completion: {}", e))?;
-    }
+// Waiting receivers for one kind of frontend response, keyed by request ID
+type ResponseChannels<T> = Mutex<HashMap<String, tokio::sync::oneshot::Sender<Result<T, String>>>>;
 
-    // Mark as completed in state
-    {
-        let state = app.state::<TransferManagerState>();
-        let mut queue = state.0.lock().await;
+/// Registers a receiver for the response to request `id`. Call this before
+/// emitting the request, so a response that comes straight back is kept.
+async fn expect_response<T>(
+    channels: &ResponseChannels<T>,
+    id: &str,
+) -> tokio::sync::oneshot::Receiver<Result<T, String>> {
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    channels.lock().await.insert(id.to_string(), tx);
+    rx
+}
 
-        queue.processing = None;
-        queue.completed.insert(transfer_id.clone());
+/// What happened to a response handed to deliver_response
+#[derive(Debug, PartialEq, Eq)]
+enum Delivery {
+    Delivered,
+    ReceiverDropped,
+    NoReceiver, // It timed out or was cancelled before the response came
+}
 
-        // Clean up any other tracking for this transfer
-        queue.initialized_files.remove(&transfer_id);
-        queue.initialized_folders.remove(&transfer_id);
-        queue.completion_notifications_sent.remove(&transfer_id);
-        queue.received_url_responses.remove(&transfer_id);
-        queue.received_folder_responses.remove(&transfer_id);
+/// Hands a frontend response to whoever is waiting on request `id`
+async fn deliver_response<T>(
+    channels: &ResponseChannels<T>,
+    id: &str,
+    response: Result<T, String>,
+) -> Delivery {
+    match channels.lock().await.remove(id) {
+        Some(sender) => match sender.send(response) {
+            Ok(()) => Delivery::Delivered,
+            Err(_) => Delivery::ReceiverDropped,
+        },
+        None => Delivery::NoReceiver,
+    }
+}
 
-        // Clean up any block tracking related to this file ID
-        let block_keys_to_remove: Vec<String> = queue
-            .block_completion_sent
-            .iter()
-            .filter(|key| key.starts_with(&format!("{}:", file_id)))
-            .cloned()
-            .collect();
+/// Session used by commands that don't pass a session_id, bound to the main window
+pub const DEFAULT_SESSION: &str = "main";
+
+/// Store key the transfer settings are saved under
+pub const TRANSFER_SETTINGS_KEY: &str = "transfer_settings";
+
+/// Transfer tuning that persists across restarts. Fields missing from saved
+/// settings take their defaults, so older saves still load.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TransferSettings {
+    pub connection_limit: usize,
+    pub block_size_limit: u64,
+    pub per_file_timeout_seconds: Option<u64>,
+    pub max_queue_size: Option<usize>,
+    pub max_history: Option<usize>,
+    pub prescan_folders: bool,
+    pub skip_temp_files: bool,
+    pub notifications_enabled: bool,
+    pub notify_each_failure: bool,
+    pub upload_backend: String,
+    pub perceptual_hashes: bool,
+    pub staging_directory: Option<PathBuf>,
+}
 
-        for key in block_keys_to_remove {
-            queue.block_completion_sent.remove(&key);
+impl Default for TransferSettings {
+    fn default() -> Self {
+        Self {
+            connection_limit: DEFAULT_CONNECTION_LIMIT,
+            block_size_limit: DEFAULT_BLOCK_SIZE_LIMIT,
+            per_file_timeout_seconds: None,
+            max_queue_size: None,
+            max_history: None,
+            prescan_folders: false,
+            skip_temp_files: true,
+            notifications_enabled: true,
+            notify_each_failure: false,
+            upload_backend: PRESIGNED_BACKEND.to_string(),
+            perceptual_hashes: false,
+            staging_directory: None,
         }
     }
+}
 
-    // Continue with next item if available - using the original share_id
-    process_next_item(
-        app.clone(),
-        app.state::<TransferManagerState>(),
-        original_share_id,
-    )
-    .await?;
+impl TransferSettings {
+    /// Limits every saved setting is checked against, whichever command set it
+    pub fn validate(&self) -> Result<(), String> {
+        if self.connection_limit == 0 {
+            return Err("Connection limit must be at least 1".to_string());
+        }
+        if self.block_size_limit == 0 || self.block_size_limit > MAX_BLOCK_SIZE {
+            return Err(format!(
+                "Block size limit must be between 1 and {} bytes",
+                MAX_BLOCK_SIZE
+            ));
+        }
+        if self.per_file_timeout_seconds == Some(0) {
+            return Err("Per-file timeout must be at least 1 second".to_string());
+        }
+        if self.max_queue_size == Some(0) {
+            return Err("Queue size limit must be at least 1".to_string());
+        }
+        upload_backend(&self.upload_backend)?;
+        Ok(())
+    }
+}
 
-    Ok(())
+/// Where the queue's events and notifications go. The app sends them to the
+/// session's window and the OS; tests record them and answer in place of the
+/// frontend.
+pub trait EventSink: Send + Sync {
+    /// Sends `event` to the window labelled `label`
+    fn send_event(
+        &self,
+        label: &str,
+        event: &str,
+        payload: serde_json::Value,
+    ) -> Result<(), String>;
+
+    /// Shows an OS notification
+    fn notify(&self, title: &str, body: &str) -> Result<(), String>;
 }
 
-/// Processes the next item in the queue
-fn process_next_item<'a>(
-    app: AppHandle,
-    state: State<'a, TransferManagerState>,
-    share_id: String,
-) -> Pin<Box<dyn Future<Output = Result<(), String>> + Send + 'a>> {
-    Box::pin(async move {
-        // Get the next item from queue
-        let next_item = {
-            let mut queue = state.0.lock().await;
+impl EventSink for AppHandle {
+    fn send_event(
+        &self,
+        label: &str,
+        event: &str,
+        payload: serde_json::Value,
+    ) -> Result<(), String> {
+        self.emit_to(label, event, payload)
+            .map_err(|e| e.to_string())
+    }
 
-            // Check if queue is paused
-            if queue.paused {
-                return Ok(());
-            }
+    fn notify(&self, title: &str, body: &str) -> Result<(), String> {
+        self.notification()
+            .builder()
+            .title(title)
+            .body(body)
+            .show()
+            .map_err(|e| e.to_string())
+    }
+}
 
-            // Check if already processing something
-            if queue.processing.is_some() {
-                return Ok(());
-            }
+impl<T: EventSink + ?Sized> EventSink for Arc<T> {
+    fn send_event(
+        &self,
+        label: &str,
+        event: &str,
+        payload: serde_json::Value,
+    ) -> Result<(), String> {
+        (**self).send_event(label, event, payload)
+    }
 
-            // Check for any hanging requests and clear them
-            let current_time = Instant::now();
+    fn notify(&self, title: &str, body: &str) -> Result<(), String> {
+        (**self).notify(title, body)
+    }
+}
 
-            // First collect IDs to remove to avoid borrowing issues
-            let mut ids_to_remove = Vec::new();
-            for (id, timestamp) in &queue.request_timestamps {
-                if current_time.duration_since(*timestamp) > Duration::from_secs(35) {
-                    println!("Detected hanging request for ID: {}, cleaning up", id);
-                    ids_to_remove.push(id.clone());
-                }
-            }
+/// Owned EventSink the processing pipeline carries in place of the AppHandle
+type Events = Arc<dyn EventSink>;
 
-            // Then process them outside the iteration loop
-            for id in &ids_to_remove {
-                // Clean up any pending channels
-                let mut channels = RESPONSE_CHANNELS.lock().await;
-                if let Some(sender) = channels.remove(id) {
-                    let _ = sender.send(Err("Request timed out".to_string()));
-                }
-                drop(channels);
+/// The app's own EventSink, for commands starting the pipeline
+fn events(app: &AppHandle) -> Events {
+    Arc::new(app.clone())
+}
 
-                let mut folder_channels = FOLDER_RESPONSE_CHANNELS.lock().await;
-                if let Some(sender) = folder_channels.remove(id) {
-                    let _ = sender.send(Err("Request timed out".to_string()));
-                }
-                drop(folder_channels);
+/// One isolated transfer session: its queue, the connection permits every
+/// block and thumbnail PUT must hold while it is in flight, its counters, and
+/// the label of the window its events are sent to
+#[derive(Clone)]
+pub struct TransferSession(
+    pub Arc<Mutex<TransferQueue>>,
+    pub Arc<Semaphore>,
+    pub Arc<TransferMetrics>,
+    pub String,
+);
+
+impl TransferSession {
+    pub fn new(window_label: &str, settings: &TransferSettings) -> Self {
+        let mut queue = TransferQueue::new();
+        queue.apply_settings(settings);
+
+        Self(
+            Arc::new(Mutex::new(queue)),
+            Arc::new(Semaphore::new(settings.connection_limit)),
+            Arc::new(TransferMetrics::default()),
+            window_label.to_string(),
+        )
+    }
 
-                // Remove tracking for this ID
-                queue.request_timestamps.remove(id);
-                queue.received_url_responses.remove(id);
-                queue.received_folder_responses.remove(id);
-            }
+    /// Applies settings to the live queue, resizing the connection semaphore
+    pub async fn apply_settings(&self, settings: &TransferSettings) {
+        let mut queue = self.0.lock().await;
+        let current = queue.connection_limit;
+        queue.apply_settings(settings);
+        self.resize_connections(current, settings.connection_limit);
+    }
 
-            // SIMPLIFIED SEQUENTIAL PROCESSING LOGIC:
-            // 1. If there's a folder at the front of the queue, process it
-            // 2. Only process items whose parent folders have been created
+    /// Grows or shrinks the connection semaphore from `current` permits to `limit`
+    fn resize_connections(&self, current: usize, limit: usize) {
+        if limit > current {
+            self.1.add_permits(limit - current);
+        } else if limit < current {
+            // Permits held by in-flight requests are retired once they come back
+            let connections = self.1.clone();
+            let excess = (current - limit) as u32;
+            tauri::async_runtime::spawn(async move {
+                if let Ok(permits) = connections.acquire_many_owned(excess).await {
+                    permits.forget();
+                }
+            });
+        }
+    }
 
-            if queue.items.is_empty() {
-                return Ok(()); // Nothing to process
-            }
+    /// Sends an event to this session's window only
+    fn emit<S: Serialize + Clone>(
+        &self,
+        app: &dyn EventSink,
+        event: &str,
+        payload: S,
+    ) -> Result<(), String> {
+        let payload = serde_json::to_value(payload).map_err(|e| e.to_string())?;
+        app.send_event(self.3.as_str(), event, payload)
+    }
+}
 
-            // Look at the first item
-            let first_item = queue.items.front().unwrap().clone();
+/// Transfer sessions keyed by session id. A session is created on first use,
+/// starting from the saved settings, and its events go to the window labelled
+/// with the session id, so each window gets its own queue.
+#[derive(Default)]
+pub struct TransferManagerState(
+    std::sync::Mutex<HashMap<String, TransferSession>>,
+    std::sync::Mutex<TransferSettings>,
+);
+
+impl TransferManagerState {
+    pub fn with_settings(settings: TransferSettings) -> Self {
+        Self(
+            std::sync::Mutex::new(HashMap::new()),
+            std::sync::Mutex::new(settings),
+        )
+    }
 
-            // If it's a folder, we'll process it
-            if first_item.item_type == "folder" {
-                queue.items.pop_front().unwrap()
-            }
-            // If it's a file, we need to make sure its parent folder exists
-            else {
-                let parent_path = Path::new(&first_item.path)
-                    .parent()
-                    .map(|p| p.to_string_lossy().to_string())
-                    .unwrap_or_default();
+    /// The session for the given id, or the main window's session for None
+    pub fn session(&self, session_id: Option<&str>) -> TransferSession {
+        let session_id = session_id.unwrap_or(DEFAULT_SESSION);
+        let settings = self.1.lock().unwrap().clone();
+        self.0
+            .lock()
+            .unwrap()
+            .entry(session_id.to_string())
+            .or_insert_with(|| TransferSession::new(session_id, &settings))
+            .clone()
+    }
 
-                // If the parent is in pending_folders, we can't process this item yet
-                if queue.pending_folders.contains(&parent_path) {
-                    println!(
-                        "Skipping file because parent folder is still pending: {}",
-                        parent_path
-                    );
+    /// Every session created so far, with its id
+    pub fn sessions(&self) -> Vec<(String, TransferSession)> {
+        self.0
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, session)| (id.clone(), session.clone()))
+            .collect()
+    }
+}
 
-                    // Try to find another item we can process
-                    let mut found_processable = false;
-                    let mut processable_index = 0;
+/// Counters across all transfers since launch or the last reset. They are
+/// relaxed atomics, so the upload loop never waits on the queue lock for them.
+pub struct TransferMetrics {
+    bytes_uploaded: AtomicU64,
+    upload_micros: AtomicU64, // Time spent sending blocks, for average throughput
+    files_completed: AtomicU64,
+    files_failed: AtomicU64,
+    retries: AtomicU64,
+    timeouts: AtomicU64,
+    since: std::sync::Mutex<Instant>,
+}
 
-                    for (index, item) in queue.items.iter().enumerate() {
-                        // Skip files whose parents are pending
-                        if item.item_type == "file" {
-                            let item_parent = Path::new(&item.path)
-                                .parent()
-                                .map(|p| p.to_string_lossy().to_string())
-                                .unwrap_or_default();
+/// Point-in-time copy of TransferMetrics, returned by get_transfer_metrics
+#[derive(Debug, Clone, Serialize)]
+pub struct TransferMetricsSnapshot {
+    bytes_uploaded: u64,
+    files_completed: u64,
+    files_failed: u64,
+    retries: u64,
+    timeouts: u64,
+    average_throughput: Option<f64>, // Bytes per second while blocks were uploading
+    elapsed_seconds: u64,            // Since launch or the last reset
+}
 
-                            if queue.pending_folders.contains(&item_parent) {
-                                continue;
-                            }
-                        }
+impl Default for TransferMetrics {
+    fn default() -> Self {
+        Self {
+            bytes_uploaded: AtomicU64::new(0),
+            upload_micros: AtomicU64::new(0),
+            files_completed: AtomicU64::new(0),
+            files_failed: AtomicU64::new(0),
+            retries: AtomicU64::new(0),
+            timeouts: AtomicU64::new(0),
+            since: std::sync::Mutex::new(Instant::now()),
+        }
+    }
+}
 
-                        // Found a processable item
-                        found_processable = true;
-                        processable_index = index;
-                        break;
-                    }
+impl TransferMetrics {
+    fn record_block(&self, bytes: u64, elapsed: Duration) {
+        self.bytes_uploaded.fetch_add(bytes, Ordering::Relaxed);
+        self.upload_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
 
-                    if found_processable {
-                        // Remove and return this item
-                        let mut items: Vec<QueueItem> = queue.items.drain(..).collect();
-                        let item = items.remove(processable_index);
+    fn count(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
 
-                        // Put the rest back
-                        queue.items = VecDeque::from(items);
+    fn snapshot(&self) -> TransferMetricsSnapshot {
+        let bytes_uploaded = self.bytes_uploaded.load(Ordering::Relaxed);
+        let upload_micros = self.upload_micros.load(Ordering::Relaxed);
+        let since = *self
+            .since
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        TransferMetricsSnapshot {
+            bytes_uploaded,
+            files_completed: self.files_completed.load(Ordering::Relaxed),
+            files_failed: self.files_failed.load(Ordering::Relaxed),
+            retries: self.retries.load(Ordering::Relaxed),
+            timeouts: self.timeouts.load(Ordering::Relaxed),
+            average_throughput: (upload_micros > 0)
+                .then(|| bytes_uploaded as f64 / (upload_micros as f64 / 1_000_000.0)),
+            elapsed_seconds: since.elapsed().as_secs(),
+        }
+    }
 
-                        // If it's a folder, mark it as pending
-                        if item.item_type == "folder" {
-                            queue.pending_folders.insert(item.path.clone());
-                        }
+    fn reset(&self) {
+        for counter in [
+            &self.bytes_uploaded,
+            &self.upload_micros,
+            &self.files_completed,
+            &self.files_failed,
+            &self.retries,
+            &self.timeouts,
+        ] {
+            counter.store(0, Ordering::Relaxed);
+        }
+        *self
+            .since
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = Instant::now();
+    }
+}
 
-                        item
-                    } else {
-                        // Nothing we can process right now
-                        return Ok(());
-                    }
-                } else {
-                    // Parent isn't pending, so we can process this file
-                    queue.items.pop_front().unwrap()
-                }
-            }
-        };
+impl TransferQueue {
+    /// Creates a new transfer queue with default values
+    pub fn new() -> Self {
+        Self {
+            items: VecDeque::new(),
+            processing: None,
+            completed: HashSet::new(),
+            failed: HashMap::new(),
+            folder_id_map: HashMap::new(),
+            paused: false,
+            pause_after_current: false,
+            start_time: Instant::now(),
+            initialized_files: HashSet::new(),
+            initialized_folders: HashSet::new(),
+            completion_notifications_sent: HashSet::new(),
+            block_completion_sent: HashSet::new(),
+            server_file_ids: HashMap::new(),
+            received_url_responses: HashSet::new(),
+            received_folder_responses: HashSet::new(),
+            request_timestamps: HashMap::new(),
+            pending_folders: HashSet::new(),
+            notifications_enabled: true,
+            notify_each_failure: false,
+            batch_active: false,
+            batch_completed_start: 0,
+            batch_failed_start: 0,
+            batch_skipped_start: 0,
+            dedup_keys: HashMap::new(),
+            max_queue_size: None,
+            deferred_scans: VecDeque::new(),
+            current_item: None,
+            last_activity: None,
+            hash_progress: HashMap::new(),
+            upload_contexts: HashMap::new(),
+            shutting_down: false,
+            folder_progress: HashMap::new(),
+            prescan_folders: false,
+            prescanned: HashMap::new(),
+            prescan_generation: 0,
+            skipped: HashMap::new(),
+            skip_temp_files: true,
+            connection_limit: DEFAULT_CONNECTION_LIMIT,
+            perceptual_hashes: false,
+            staging_directory: None,
+            active_scans: HashMap::new(),
+            scan_progress: None,
+            block_size_limit: DEFAULT_BLOCK_SIZE_LIMIT,
+            per_file_timeout: None,
+            history: VecDeque::new(),
+            max_history: None,
+            progress_smoothing: ProgressSmoothing::default(),
+            thumbnail_retries: HashMap::new(),
+            upload_backend: PRESIGNED_BACKEND.to_string(),
+            custom_uploader: None,
+            checkpoints: HashMap::new(),
+            priority_credits: [0; 3],
+            key_generation: 0,
+            key_id: None,
+        }
+    }
 
-        // Process the item if we got one
-        match next_item.item_type.as_str() {
-            "file" => {
-                if let Err(err) = process_file(
-                    app.clone(),
-                    state.clone(),
-                    next_item.clone(),
-                    share_id.clone(),
-                )
-                .await
-                {
-                    println!("Error processing file: {}", err);
-                    // Handle the error, update state, but don't return the error - continue processing
-                    let _ = handle_file_error(
-                        &app,
-                        &state,
-                        &next_item.id,
-                        &next_item.name,
-                        &None::<u64>,
-                        &err,
-                    )
-                    .await;
-                }
-            }
-            "folder" => {
-                if let Err(err) = process_folder(
-                    app.clone(),
-                    state.clone(),
-                    next_item.clone(),
-                    share_id.clone(),
-                )
-                .await
-                {
-                    println!("Error processing folder: {}", err);
-                    // Handle the error, update state, but don't return the error - continue processing
-                    let _ = handle_folder_error(&app, &state, &next_item.id, &next_item.name, &err)
-                        .await;
+    /// The persistable settings this queue currently runs with
+    fn settings(&self) -> TransferSettings {
+        TransferSettings {
+            connection_limit: self.connection_limit,
+            block_size_limit: self.block_size_limit,
+            per_file_timeout_seconds: self.per_file_timeout.map(|timeout| timeout.as_secs()),
+            max_queue_size: self.max_queue_size,
+            max_history: self.max_history,
+            prescan_folders: self.prescan_folders,
+            skip_temp_files: self.skip_temp_files,
+            notifications_enabled: self.notifications_enabled,
+            notify_each_failure: self.notify_each_failure,
+            upload_backend: self.upload_backend.clone(),
+            perceptual_hashes: self.perceptual_hashes,
+            staging_directory: self.staging_directory.clone(),
+        }
+    }
 
-                    // Also remove the folder from pending
-                    let mut queue = state.0.lock().await;
-                    queue.pending_folders.remove(&next_item.path);
-                }
-            }
-            _ => {
-                println!("Unknown item type: {}", next_item.item_type);
+    /// Takes on validated settings. The connection semaphore is the session's
+    /// to resize.
+    fn apply_settings(&mut self, settings: &TransferSettings) {
+        self.connection_limit = settings.connection_limit;
+        self.block_size_limit = settings.block_size_limit;
+        self.per_file_timeout = settings.per_file_timeout_seconds.map(Duration::from_secs);
+        self.max_queue_size = settings.max_queue_size;
+        self.max_history = settings.max_history;
+        self.trim_history();
+        if self.prescan_folders && !settings.prescan_folders {
+            self.prescanned.clear();
+            self.prescan_generation += 1;
+        }
+        self.prescan_folders = settings.prescan_folders;
+        self.skip_temp_files = settings.skip_temp_files;
+        self.notifications_enabled = settings.notifications_enabled;
+        self.notify_each_failure = settings.notify_each_failure;
+        self.upload_backend = settings.upload_backend.clone();
+        self.perceptual_hashes = settings.perceptual_hashes;
+        self.staging_directory = settings.staging_directory.clone();
+    }
+
+    /// Where partial downloads and upload checkpoints go
+    fn staging_dir(&self) -> PathBuf {
+        self.staging_directory
+            .clone()
+            .unwrap_or_else(std::env::temp_dir)
+    }
+
+    fn checkpoint_dir(&self) -> PathBuf {
+        self.staging_dir().join(CHECKPOINT_SUBDIR)
+    }
+
+    /// Applies an update to a folder and every ancestor folder created by this
+    /// queue, returning the updated folders
+    fn update_folder_ancestors(
+        &mut self,
+        parent_id: &str,
+        update: impl Fn(&mut FolderProgress),
+    ) -> Vec<FolderProgress> {
+        let mut updated = Vec::new();
+        let mut folder_id = parent_id.to_string();
+
+        while let Some(folder) = self.folder_progress.get_mut(&folder_id) {
+            update(folder);
+            updated.push(folder.clone());
+            folder_id = folder.parent_id.clone();
+        }
+
+        updated
+    }
+
+    /// Parent folder ID of the item being processed, if it is `id`
+    fn current_parent_id(&self, id: &str) -> Option<String> {
+        self.current_item
+            .as_ref()
+            .filter(|item| item.id == id)
+            .map(|item| item.parent_id.clone())
+    }
+
+    /// Counts a failed item against its ancestor folders, once per failure
+    fn record_failed_child(&mut self, id: &str) -> Vec<FolderProgress> {
+        if self.failed.contains_key(id) {
+            return Vec::new();
+        }
+        match self.current_parent_id(id) {
+            Some(parent_id) => {
+                self.update_folder_ancestors(&parent_id, |folder| folder.failed_items += 1)
             }
+            None => Vec::new(),
         }
+    }
 
-        // Continue with next item regardless of errors
-        process_next_item(app, state, share_id).await?;
+    /// Clears `processing` when it points at an item nothing is working on any
+    /// more: no request waiting on the frontend and no recent activity. The
+    /// item goes back to the front of the queue, or is failed if it can't be
+    /// found. Returns the ID that was reset.
+    fn reset_orphaned_processing(&mut self, waiting_on_frontend: bool) -> Option<String> {
+        let id = self.processing.clone()?;
+        let is_recent = |time: Option<&Instant>| time.is_some_and(|t| t.elapsed() < ORPHANED_AFTER);
+        if waiting_on_frontend
+            || is_recent(self.last_activity.as_ref())
+            || is_recent(self.request_timestamps.get(&id))
+        {
+            return None;
+        }
 
-        Ok(())
-    })
-}
+        self.processing = None;
+        self.last_activity = None;
+        // Let the item request its upload or folder again from scratch
+        self.initialized_files.remove(&id);
+        self.initialized_folders.remove(&id);
+        self.received_url_responses.remove(&id);
+        self.received_folder_responses.remove(&id);
+        self.request_timestamps.remove(&id);
+        if let Some(server_file_id) = self.server_file_ids.remove(&id) {
+            self.clear_block_tracking(&server_file_id);
+        }
 
-// Add this function to check if a file is an image and get its MIME type
-fn get_file_info(path: &Path) -> (String, bool) {
-    let mime = from_path(path).first_or_octet_stream().to_string();
-    let is_image = mime.starts_with("image/");
-    (mime, is_image)
-}
+        match self.current_item.take().filter(|item| item.id == id) {
+            Some(item) => {
+                self.pending_folders.remove(&item.path);
+                self.items.push_front(item);
+            }
+            None => self.mark_failed(&id, "Interrupted while processing".to_string()),
+        }
 
-// Fixed thumbnail generation function
-async fn generate_thumbnail(file_path: &Path) -> Result<Vec<u8>, String> {
-    // Read the file
-    let img_data = match tokio::fs::read(file_path).await {
-        Ok(data) => data,
-        Err(e) => return Err(format!("Failed to read image file: {}", e)),
-    };
+        Some(id)
+    }
 
-    // Process the image in a blocking task since image operations are CPU-intensive
-    let thumbnail_data = tokio::task::spawn_blocking(move || -> Result<Vec<u8>, String> {
-        // Load the image
-        let img = match image::load_from_memory(&img_data) {
-            Ok(img) => img,
-            Err(e) => return Err(format!("Failed to load image: {}", e)),
+    /// Records a skipped file, counting it as done in its ancestor folders
+    fn record_skipped(&mut self, id: &str, reason: SkipReason) -> Vec<FolderProgress> {
+        let updated = match self.current_parent_id(id) {
+            Some(parent_id) => {
+                self.update_folder_ancestors(&parent_id, |folder| folder.completed_items += 1)
+            }
+            None => Vec::new(),
         };
 
-        // Resize the image to max 300x300 while preserving aspect ratio
-        let thumbnail = img.thumbnail(300, 300);
+        self.processing = None;
+        if self.skipped.insert(id.to_string(), reason).is_none() {
+            self.push_history(id);
+        }
+        // Let the file be queued again once it has content or is unlocked
+        self.initialized_files.remove(id);
+        self.dedup_keys.retain(|_, item_id| item_id != id);
 
-        // Create a buffer to write the image data to
-        let mut buffer = Cursor::new(Vec::new());
+        updated
+    }
 
-        // Write the image to the buffer with JPEG format and reduced quality
-        if let Err(e) = thumbnail.write_to(&mut buffer, ImageFormat::Jpeg) {
-            return Err(format!("Failed to create thumbnail: {}", e));
-        }
+    /// Forgets the blocks already reported for a server file, and what was
+    /// kept to upload them again
+    fn clear_block_tracking(&mut self, file_id: &str) {
+        self.upload_contexts.remove(file_id);
+        let prefix = format!("{}:", file_id);
+        self.block_completion_sent
+            .retain(|key| !key.starts_with(&prefix));
+    }
 
-        Ok(buffer.into_inner())
-    })
-    .await
-    .map_err(|e| format!("Task error: {}", e))??;
+    /// Transfer ID of the in-flight upload of a server file
+    fn transfer_id_for_file(&self, file_id: &str) -> Option<String> {
+        self.server_file_ids
+            .iter()
+            .find(|(_, server_file_id)| server_file_id.as_str() == file_id)
+            .map(|(transfer_id, _)| transfer_id.clone())
+    }
 
-    Ok(thumbnail_data)
-}
+    /// Records a completed item
+    fn mark_completed(&mut self, id: &str) {
+        if self.completed.insert(id.to_string()) {
+            self.push_history(id);
+        }
+    }
 
-/// Lists extended attributes for a file
-fn list_xattrs(file_path: &str) -> Option<String> {
-    let path = Path::new(file_path);
+    /// Records a failed item, keeping the latest error
+    fn mark_failed(&mut self, id: &str, error: String) {
+        if self.failed.insert(id.to_string(), error).is_none() {
+            self.push_history(id);
+        }
+    }
 
-    match xattr::list(path) {
-        Ok(attrs) => {
-            let attr_list: Vec<String> = attrs
-                .map(|attr| attr.to_string_lossy().to_string())
-                .collect();
+    /// Adds a finished item to the history, evicting the oldest past max_history
+    fn push_history(&mut self, id: &str) {
+        self.history.push_back(id.to_string());
+        self.trim_history();
+    }
 
-            if attr_list.is_empty() {
-                return None; // No xattrs, return None
+    fn trim_history(&mut self) {
+        let Some(max_history) = self.max_history else {
+            return;
+        };
+        while self.history.len() > max_history {
+            if let Some(oldest) = self.history.pop_front() {
+                self.forget_finished(&oldest);
             }
-
-            Some(attr_list.join(", ")) // Return comma-separated attributes
         }
-        Err(_) => None, // Error reading xattrs, return None
     }
-}
 
-/// Processes a file for upload
-async fn process_file(
-    app: AppHandle,
-    state: State<'_, TransferManagerState>,
-    item: QueueItem,
-    share_id: String,
-) -> Result<(), String> {
-    let path = Path::new(&item.path);
-    println!("Processing file: {} at depth {}", item.path, item.depth);
+    /// Drops everything remembered about a finished item. Items that are
+    /// queued or in progress again are left alone.
+    fn forget_finished(&mut self, id: &str) {
+        if self.processing.as_deref() == Some(id) || self.items.iter().any(|item| item.id == id) {
+            return;
+        }
+
+        // Keep batch counts right; evicted items normally predate the batch
+        if self.completed.remove(id) {
+            self.batch_completed_start = self.batch_completed_start.saturating_sub(1);
+        }
+        if self.failed.remove(id).is_some() {
+            self.batch_failed_start = self.batch_failed_start.saturating_sub(1);
+        }
+        if self.skipped.remove(id).is_some() {
+            self.batch_skipped_start = self.batch_skipped_start.saturating_sub(1);
+        }
+
+        self.initialized_files.remove(id);
+        self.initialized_folders.remove(id);
+        self.completion_notifications_sent.remove(id);
+        self.received_url_responses.remove(id);
+        self.received_folder_responses.remove(id);
+        self.request_timestamps.remove(id);
+        self.dedup_keys.retain(|_, item_id| item_id != id);
+        self.thumbnail_retries
+            .retain(|_, retry| retry.item.id != id);
+        self.hash_progress.remove(id);
+        if let Some(server_file_id) = self.server_file_ids.remove(id) {
+            self.clear_block_tracking(&server_file_id);
+        }
 
-    if !path.exists() || !path.is_file() {
-        return Err(format!("File not found or is not a file: {}", item.path));
+        LAST_PROGRESS
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .remove(id);
     }
 
-    // Get file metadata
-    let file_meta = match tokio::fs::metadata(path).await {
-        Ok(m) => m,
-        Err(e) => {
-            return Err(format!("Failed to read file metadata: {}", e));
+    /// Progress events that rebuild a view of this queue: a final one for each
+    /// of the latest `history_limit` finished items, then the last one sent
+    /// for the processing item and each queued item. Items that never sent
+    /// progress are reported as "processing" or "queued" from what the queue
+    /// knows about them.
+    fn resync_events(
+        &self,
+        last_progress: &HashMap<String, TransferProgress>,
+        history_limit: usize,
+    ) -> Vec<TransferProgress> {
+        let mut events = Vec::new();
+
+        let skip = self.history.len().saturating_sub(history_limit);
+        for id in self.history.iter().skip(skip) {
+            // Without a recorded event there's no name to show it under
+            let Some(progress) = last_progress.get(id) else {
+                continue;
+            };
+            let mut progress = progress.clone();
+            if let Some(error) = self.failed.get(id) {
+                progress.status = "failed".to_string();
+                progress.message = Some(error.clone());
+            } else if self.completed.contains(id) {
+                progress.status = "completed".to_string();
+                progress.progress = 1.0;
+            } else if self.skipped.contains_key(id) {
+                progress.status = "skipped".to_string();
+            } else {
+                continue;
+            }
+            progress.speed = None;
+            progress.remaining_time = None;
+            events.push(progress);
+        }
+
+        let processing = self
+            .current_item
+            .iter()
+            .filter(|item| self.processing.as_ref() == Some(&item.id))
+            .map(|item| (item, "processing"));
+        let queued = self.items.iter().map(|item| (item, "queued"));
+        for (item, status) in processing.chain(queued) {
+            let progress =
+                last_progress
+                    .get(&item.id)
+                    .cloned()
+                    .unwrap_or_else(|| TransferProgress {
+                        id: item.id.clone(),
+                        name: item.name.clone(),
+                        item_type: item.item_type.clone(),
+                        progress: 0.0,
+                        status: status.to_string(),
+                        message: None,
+                        speed: None,
+                        remaining_time: None,
+                        size: None,
+                        uploaded_bytes: None,
+                        error_code: None,
+                    });
+            events.push(progress);
         }
-    };
 
-    let file_size = file_meta.len();
+        events
+    }
 
-    // Get modified date
-    let modified_date = match file_meta.modified() {
-        Ok(time) => match time.duration_since(std::time::UNIX_EPOCH) {
-            Ok(duration) => Some(duration.as_secs()),
-            Err(_) => None,
-        },
-        Err(_) => None,
-    };
+    /// Whether the queue has reached its configured capacity
+    fn is_full(&self) -> bool {
+        self.max_queue_size
+            .is_some_and(|max_queue_size| self.items.len() >= max_queue_size)
+    }
 
-    // Check if file is empty (0 bytes) and return error if so
-    if file_size == 0 {
-        let error = format!("File is empty (0 bytes): {}", item.path);
-        handle_file_error(&app, &state, &item.id, &item.name, &Some(file_size), &error).await?;
-        return Err(error);
+    /// Adds a folder's direct contents to the front of the queue, files first,
+    /// so folders are uploaded depth-first in natural file system order
+    fn insert_folder_contents(
+        &mut self,
+        folder_id: &str,
+        share_id: &str,
+        priority: Priority,
+        subfolders: Vec<PathBuf>,
+        files: Vec<PathBuf>,
+    ) {
+        let count = subfolders.len() + files.len();
+        self.update_folder_ancestors(folder_id, |folder| folder.total_items += count);
+
+        let mut new_items = VecDeque::new();
+
+        for (item_type, paths) in [("file", files), ("folder", subfolders)] {
+            for item_path in paths {
+                let name = item_path
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .unwrap_or("unknown")
+                    .to_string();
+
+                new_items.push_back(QueueItem {
+                    item_type: item_type.to_string(),
+                    id: generate_id(),
+                    path: item_path.to_string_lossy().to_string(),
+                    name,
+                    parent_id: folder_id.to_string(),
+                    share_id: share_id.to_string(),
+                    depth: 0, // Depth not used with this algorithm
+                    data: None,
+                    metadata: HashMap::new(),
+                    conflict_policy: None,
+                    priority,
+                    idempotency_key: generate_idempotency_key(),
+                });
+            }
+        }
+
+        // Now put all existing items after the folder contents
+        new_items.extend(self.items.drain(..));
+        self.items = new_items;
     }
 
-    // Get MIME type and check if it's an image
-    let (mime_type, is_image) = get_file_info(path);
+    /// Takes the next item whose parent folder already exists off the queue,
+    /// in queue order within a priority. Files waiting on a pending folder are
+    /// skipped over. Classes are picked by smooth weighted round robin: each
+    /// class with an item ready gains its weight in credit, the richest class
+    /// goes and pays back the total, so Low still gets a turn under a steady
+    /// stream of High items.
+    fn take_next_item(&mut self) -> Option<QueueItem> {
+        // Oldest ready item of each class
+        let mut oldest: [Option<usize>; 3] = [None; 3];
+        for (index, item) in self.items.iter().enumerate() {
+            if item.item_type == "file" {
+                let parent_path = Path::new(&item.path)
+                    .parent()
+                    .map(|p| p.to_string_lossy().to_string())
+                    .unwrap_or_default();
+                if self.pending_folders.contains(&parent_path) {
+                    continue;
+                }
+            }
+            oldest[item.priority as usize].get_or_insert(index);
+        }
 
-    // Determine if thumbnail should be generated
-    let needs_thumbnail = is_image && file_size < 5 * 1024 * 1024; // less than 5MB
+        let mut total = 0;
+        let mut chosen: Option<Priority> = None;
+        for priority in Priority::ALL {
+            let class = priority as usize;
+            if oldest[class].is_none() {
+                // Credit isn't saved up while a class has nothing to start
+                self.priority_credits[class] = 0;
+                continue;
+            }
+            total += priority.weight();
+            self.priority_credits[class] += priority.weight();
+            if chosen.is_none_or(|best| {
+                self.priority_credits[class] > self.priority_credits[best as usize]
+            }) {
+                chosen = Some(priority);
+            }
+        }
+        let chosen = chosen?;
+        self.priority_credits[chosen as usize] -= total;
+        let index = oldest[chosen as usize]?;
 
-    // Check if this file has already been initialized
-    let already_initialized = {
-        let queue = state.0.lock().await;
-        queue.initialized_files.contains(&item.id)
-    };
+        let item = self.items.remove(index)?;
+        if index > 0 {
+            println!("Taking {} ahead of {} earlier items", item.name, index);
+        }
 
-    if already_initialized {
-        println!(
-            "File {} already initialized, skipping initialization request",
-            item.id
-        );
+        // Files inside this folder wait until it exists on the server
+        if item.item_type == "folder" {
+            self.pending_folders.insert(item.path.clone());
+        }
 
-        // If already initialized but not completed, mark as processing again
-        let mut queue = state.0.lock().await;
-        if !queue.completed.contains(&item.id) {
-            queue.processing = Some(item.id.clone());
-        } else {
-            // If already completed, skip processing
-            return Ok(());
+        Some(item)
+    }
+
+    /// Changes the priority of a queued or in-progress item, and of everything
+    /// queued inside it when it is a folder. Returns how many queued items
+    /// changed, or None when the item isn't in the queue.
+    fn set_priority(&mut self, id: &str, priority: Priority) -> Option<usize> {
+        let item = self
+            .items
+            .iter()
+            .chain(self.current_item.iter())
+            .find(|item| item.id == id)?;
+        let root = (item.item_type == "folder").then(|| PathBuf::from(&item.path));
+
+        let mut changed = 0;
+        for item in self.items.iter_mut() {
+            let inside = root
+                .as_ref()
+                .is_some_and(|root| Path::new(&item.path).starts_with(root));
+            if (item.id == id || inside) && item.priority != priority {
+                item.priority = priority;
+                changed += 1;
+            }
         }
-    } else {
-        // Update state to mark as processing and track that we've initialized
-        {
-            let mut queue = state.0.lock().await;
-            queue.processing = Some(item.id.clone());
-            queue.initialized_files.insert(item.id.clone());
+        if let Some(current) = self.current_item.as_mut().filter(|item| item.id == id) {
+            current.priority = priority;
         }
+        Some(changed)
+    }
 
-        // Emit event to notify progress start
-        app.emit(
-            "transfer-progress",
-            TransferProgress {
-                id: item.id.clone(),
+    /// Records a folder created on the server so its contents can be uploaded into it
+    fn record_created_folder(&mut self, item: &QueueItem, folder_id: &str, parent_id: &str) {
+        self.folder_id_map
+            .insert(item.path.clone(), folder_id.to_string());
+        self.folder_progress.insert(
+            folder_id.to_string(),
+            FolderProgress {
+                item_id: item.id.clone(),
                 name: item.name.clone(),
-                item_type: "file".to_string(),
-                progress: 0.0,
-                status: "preparing".to_string(),
-                message: Some("Preparing upload...".to_string()),
-                speed: None,
-                remaining_time: None,
-                size: Some(file_size),
+                parent_id: parent_id.to_string(),
+                uploaded_bytes: 0,
+                total_items: 0,
+                completed_items: 0,
+                failed_items: 0,
             },
-        )
-        .map_err(|e| format!("Failed to emit progress: {}", e))?;
+        );
+    }
 
-        // Get parent_id from item or folder_id_map
-        let parent_id = {
-            let queue = state.0.lock().await;
-            if let Some(mapped_id) = queue.folder_id_map.get(&item.parent_id) {
-                mapped_id.clone()
-            } else {
-                item.parent_id.clone()
+    /// Drops the server folder IDs cached for a local folder and everything
+    /// below it, so later uploads into those paths create fresh folders.
+    /// Returns how many mappings were removed.
+    fn forget_folder_path(&mut self, path: &str) -> usize {
+        let root = Path::new(path);
+        let before = self.folder_id_map.len();
+        self.folder_id_map
+            .retain(|mapped, _| !Path::new(mapped).starts_with(root));
+        before - self.folder_id_map.len()
+    }
+
+    /// Drops the cached mappings of a folder transfer that was cancelled or
+    /// failed after its folder was created on the server
+    fn forget_folder_item(&mut self, item_id: &str) -> usize {
+        let folder_ids: HashSet<String> = self
+            .folder_progress
+            .iter()
+            .filter(|(_, folder)| folder.item_id == item_id)
+            .map(|(folder_id, _)| folder_id.clone())
+            .collect();
+        let paths: Vec<String> = self
+            .folder_id_map
+            .iter()
+            .filter(|(_, folder_id)| folder_ids.contains(*folder_id))
+            .map(|(path, _)| path.clone())
+            .collect();
+
+        paths.iter().map(|path| self.forget_folder_path(path)).sum()
+    }
+
+    /// Cancels every item still waiting in the queue, leaving the one being
+    /// processed alone. Returns how many were cancelled.
+    fn cancel_queued(&mut self) -> usize {
+        let items: Vec<QueueItem> = self.items.drain(..).collect();
+        self.deferred_scans.clear();
+        self.prescanned.clear();
+        self.prescan_generation += 1;
+
+        for item in &items {
+            if item.item_type == "folder" {
+                self.pending_folders.remove(&item.path);
             }
-        };
+            self.forget_folder_item(&item.id);
+            self.mark_failed(&item.id, TransferError::Cancelled.to_string());
+            self.initialized_files.remove(&item.id);
+            self.initialized_folders.remove(&item.id);
+            self.completion_notifications_sent.remove(&item.id);
+            self.received_url_responses.remove(&item.id);
+            self.received_folder_responses.remove(&item.id);
+            self.request_timestamps.remove(&item.id);
+        }
 
-        let file_extended_attributes = list_xattrs(&item.path);
+        // Cancelled items may be queued again
+        let cancelled: HashSet<&str> = items.iter().map(|item| item.id.as_str()).collect();
+        self.dedup_keys
+            .retain(|_, item_id| !cancelled.contains(item_id.as_str()));
 
-        // Check if we've already received a URL response for this file
-        let already_received_response = {
-            let queue = state.0.lock().await;
-            queue.received_url_responses.contains(&item.id)
-        };
+        items.len()
+    }
 
-        if !already_received_response {
-            // Create a channel to wait for frontend response BEFORE emitting the event
-            let (tx, rx) = tokio::sync::oneshot::channel::<Result<UploadUrlsResponse, String>>();
+    /// Marks a folder as done, releasing any files that were waiting on it.
+    /// Returns the ancestor folders whose counts changed.
+    fn finish_folder(&mut self, item: &QueueItem) -> Vec<FolderProgress> {
+        self.processing = None;
+        self.mark_completed(&item.id);
+        self.request_timestamps.remove(&item.id);
+        self.pending_folders.remove(&item.path);
 
-            // Set up timeout tracking for this request
-            {
-                let mut queue = state.0.lock().await;
-                queue
-                    .request_timestamps
-                    .insert(item.id.clone(), Instant::now());
-            }
+        self.update_folder_ancestors(&item.parent_id, |folder| folder.completed_items += 1)
+    }
 
-            // Insert the channel BEFORE emitting the event
-            {
-                let mut channels = RESPONSE_CHANNELS.lock().await;
-                channels.insert(item.id.clone(), tx);
+    /// Returns the ID of a queued, in-progress or completed item with the same key
+    fn find_duplicate(&self, dedup_key: &str) -> Option<String> {
+        let id = self.dedup_keys.get(dedup_key)?;
+        let active = self.processing.as_ref() == Some(id)
+            || self.completed.contains(id)
+            || self.items.iter().any(|item| &item.id == id);
+
+        active.then(|| id.clone())
+    }
+
+    /// Adds new items to the back of the queue, skipping duplicates of items
+    /// already queued, in progress or completed. Returns how many were added.
+    fn push_unique_items(
+        &mut self,
+        app: &dyn EventSink,
+        state: &TransferSession,
+        items: Vec<(QueueItem, Option<String>)>,
+    ) -> usize {
+        let mut added = 0;
+
+        for (item, dedup_key) in items {
+            if let Some(dedup_key) = dedup_key {
+                if let Some(existing_id) = self.find_duplicate(&dedup_key) {
+                    println!("Skipping duplicate transfer: {}", item.path);
+                    let _ = state.emit(
+                        app,
+                        "transfer-skipped-duplicate",
+                        serde_json::json!({
+                            "name": item.name,
+                            "path": item.path,
+                            "type": item.item_type,
+                            "existing_id": existing_id
+                        }),
+                    );
+                    continue;
+                }
+                self.dedup_keys.insert(dedup_key, item.id.clone());
             }
 
-            // Mark that we're expecting a response
-            {
-                let mut queue = state.0.lock().await;
-                queue.received_url_responses.insert(item.id.clone());
+            if added == 0 {
+                self.begin_batch();
             }
+            self.items.push_back(item);
+            added += 1;
+        }
 
-            // Add a small delay to ensure the receiver is properly set up
-            tokio::time::sleep(Duration::from_millis(50)).await;
+        added
+    }
 
-            // Print the content of init-file-upload for debugging
-            println!(
-                "Sending init-file-upload for file: {} with ID: {}",
-                item.name, item.id
-            );
+    /// What shutdown saves of the queue: the item being processed, then
+    /// everything still queued
+    fn snapshot(&self, session_id: &str) -> QueueSnapshot {
+        let interrupted = self
+            .current_item
+            .clone()
+            .filter(|item| self.processing.as_ref() == Some(&item.id));
+
+        QueueSnapshot {
+            session_id: session_id.to_string(),
+            // Uploads from memory can't be resumed, since their data isn't saved
+            items: interrupted
+                .into_iter()
+                .chain(self.items.iter().cloned())
+                .filter(|item| item.data.is_none())
+                .collect(),
+            failed: self.failed.clone(),
+            folder_id_map: self.folder_id_map.clone(),
+        }
+    }
 
-            // THEN send file info to frontend for initialization with additional parameters
-            app.emit(
-                "init-file-upload",
-                serde_json::json!({
-                    "id": item.id,
-                    "name": item.name,
-                    "path": item.path,
-                    "parent_id": parent_id,
-                    "share_id": share_id,
-                    "size": file_size,
-                    "xattrs": file_extended_attributes,
-                    "mime_type": mime_type,
-                    "modified_date": modified_date,
-                    "needs_thumbnail": needs_thumbnail
-                }),
-            )
-            .map_err(|e| format!("Failed to request file initialization: {}", e))?;
+    /// Queues the items of a saved snapshot, paused. The item that was
+    /// processing at shutdown is first in the snapshot and is simply queued
+    /// again. Returns how many items were queued.
+    fn restore(&mut self, snapshot: QueueSnapshot) -> usize {
+        self.failed.extend(snapshot.failed);
+        self.folder_id_map.extend(snapshot.folder_id_map);
+
+        let restored = snapshot.items.len();
+        for item in snapshot.items {
+            if let Some(key) = dedup_key(Path::new(&item.path)) {
+                self.dedup_keys.insert(key, item.id.clone());
+            }
+            self.items.push_back(item);
+        }
 
-            println!("Waiting for response from frontend for file: {}", item.id);
+        if restored > 0 {
+            self.paused = true;
+            self.begin_batch();
+        }
+        restored
+    }
 
-            // Wait for the response with timeout
-            let response = match tokio::time::timeout(Duration::from_secs(30), rx).await {
-                Ok(Ok(Ok(response))) => {
-                    // Clear the request timestamp since we got a response
-                    let mut queue = state.0.lock().await;
-                    queue.request_timestamps.remove(&item.id);
+    /// Starts tracking a new batch unless one is already running
+    fn begin_batch(&mut self) {
+        if !self.batch_active {
+            self.batch_active = true;
+            self.batch_completed_start = self.completed.len();
+            self.batch_failed_start = self.failed.len();
+            self.batch_skipped_start = self.skipped.len();
+        }
+    }
 
-                    response
-                }
-                Ok(Ok(Err(e))) => {
-                    let error = format!("{}", e);
-                    handle_file_error(&app, &state, &item.id, &item.name, &Some(file_size), &error)
-                        .await?;
-                    return Err(error);
-                }
-                Ok(Err(_)) => {
-                    let error = "Channel closed before receiving response".to_string();
-                    handle_file_error(&app, &state, &item.id, &item.name, &Some(file_size), &error)
-                        .await?;
-                    return Err(error);
-                }
-                Err(_) => {
-                    let error = "Timeout waiting for presigned URLs".to_string();
+    /// Ends the batch once nothing is left, returning what happened in it
+    fn finish_batch(&mut self) -> Option<BatchSummary> {
+        if !self.batch_active
+            || !self.items.is_empty()
+            || self.processing.is_some()
+            || !self.deferred_scans.is_empty()
+        {
+            return None;
+        }
 
-                    // We still need to clean up the request from timestamps
-                    let mut queue = state.0.lock().await;
-                    queue.request_timestamps.remove(&item.id);
+        self.batch_active = false;
+        Some(BatchSummary {
+            completed: self
+                .completed
+                .len()
+                .saturating_sub(self.batch_completed_start),
+            failed: self.failed.len().saturating_sub(self.batch_failed_start),
+            skipped: self.skipped.len().saturating_sub(self.batch_skipped_start),
+        })
+    }
 
-                    handle_file_error(&app, &state, &item.id, &item.name, &Some(file_size), &error)
-                        .await?;
-                    return Err(error);
-                }
-            };
+    /// The BlockUploader new files go through
+    fn uploader(&self) -> Result<Arc<dyn BlockUploader>, String> {
+        match &self.custom_uploader {
+            Some(uploader) => Ok(uploader.clone()),
+            None => upload_backend(&self.upload_backend),
+        }
+    }
 
-            // Handle thumbnail upload if needed and if the response contains a thumbnail_url
-            if needs_thumbnail && response.upload_urls.len() > 0 {
-                if let Some(thumbnail_url) = response
-                    .upload_urls
-                    .iter()
-                    .find(|url| url.url.contains("thumbnail"))
-                {
-                    app.emit(
-                        "transfer-progress",
-                        TransferProgress {
-                            id: item.id.clone(),
-                            name: item.name.clone(),
-                            item_type: "file".to_string(),
-                            progress: 0.02,
-                            status: "preparing".to_string(),
-                            message: Some("Generating thumbnail...".to_string()),
-                            speed: None,
-                            remaining_time: None,
-                            size: Some(file_size),
-                        },
-                    )
-                    .map_err(|e| format!("Failed to emit progress: {}", e))?;
+    /// Number of items waiting or in progress
+    pub fn pending_count(&self) -> usize {
+        self.items.len() + usize::from(self.processing.is_some())
+    }
 
-                    // Generate thumbnail
-                    match generate_thumbnail(path).await {
-                        Ok(thumbnail_data) => {
-                            // Upload thumbnail
-                            let client = reqwest::Client::builder()
-                                .timeout(Duration::from_secs(30))
-                                .build()
-                                .unwrap_or_default();
-
-                            // Try to upload the thumbnail
-                            match client
-                                .put(&thumbnail_url.url)
-                                .body(thumbnail_data)
-                                .header("Content-Type", "image/jpeg")
-                                .send()
-                                .await
-                            {
-                                Ok(resp) => {
-                                    if !resp.status().is_success() {
-                                        println!(
-                                            "Thumbnail upload failed with status: {}",
-                                            resp.status()
-                                        );
-                                    } else {
-                                        println!("Thumbnail uploaded successfully");
-                                    }
-                                }
-                                Err(e) => {
-                                    println!("Thumbnail upload error: {}", e);
-                                    // We'll continue with the main file upload even if thumbnail fails
-                                }
-                            }
-                        }
-                        Err(e) => {
-                            println!("Failed to generate thumbnail: {}", e);
-                            // Continue with main file upload even if thumbnail generation fails
-                        }
-                    }
-                }
-            }
+    /// Whether new items are held back, by either kind of pause
+    pub fn is_paused(&self) -> bool {
+        self.paused || self.pause_after_current
+    }
 
-            println!("Response summary:");
-            println!("  file_id: {}", &response.file_id);
-            println!("  revision_id: {}", &response.revision_id);
-            println!("  total_blocks: {}", &response.total_blocks);
-            println!("  block_size: {}", &response.block_size);
+    /// Whether anything is being processed or waiting on the frontend, which
+    /// is all the watchdog has to look at
+    fn has_in_flight(&self) -> bool {
+        self.processing.is_some()
+            || !self.request_timestamps.is_empty()
+            || !self.pending_folders.is_empty()
+    }
 
-            // Extract information from response
-            let server_file_id = response.file_id;
-            let revision_id = response.revision_id;
-            let presigned_urls = response.upload_urls;
-            let block_size = response.block_size;
-            let total_blocks = presigned_urls.len();
+    /// "immediate" for a hard pause, "after_current" for a soft one
+    fn pause_mode(&self) -> Option<&'static str> {
+        if self.paused {
+            Some("immediate")
+        } else if self.pause_after_current {
+            Some("after_current")
+        } else {
+            None
+        }
+    }
 
-            // Set up encryption with content key (required)
-            let cipher = match general_purpose::STANDARD.decode(&response.content_key) {
-                Ok(key_bytes) if key_bytes.len() == 32 => {
-                    let key = Key::<Aes256Gcm>::from_slice(&key_bytes);
-                    Aes256Gcm::new(key)
-                }
-                Ok(_) => {
-                    let error = "Invalid encryption key length, must be 32 bytes".to_string();
-                    handle_file_error(&app, &state, &item.id, &item.name, &Some(file_size), &error)
-                        .await?;
-                    return Err(error);
-                }
-                Err(e) => {
-                    let error = format!("Failed to decode encryption key: {}", e);
-                    handle_file_error(&app, &state, &item.id, &item.name, &Some(file_size), &error)
-                        .await?;
-                    return Err(error);
-                }
-            };
+    /// Whether a local path is already waiting in the queue
+    pub fn is_path_queued(&self, path: &str) -> bool {
+        self.items.iter().any(|item| item.path == path)
+    }
 
-            println!("Cipher initialized successfully");
+    /// Server ID of the folder an item goes into. Items queued from a folder
+    /// upload name their parent by local path until that folder is created;
+    /// anything else is already a server ID.
+    fn resolve_parent_id(&self, parent_id: &str) -> String {
+        self.folder_id_map
+            .get(parent_id)
+            .cloned()
+            .unwrap_or_else(|| parent_id.to_string())
+    }
 
-            if let Some(thumbnail_info) = response.thumbnail {
-                // Emit progress update for thumbnail generation
-                app.emit(
-                    "transfer-progress",
-                    TransferProgress {
-                        id: item.id.clone(),
-                        name: item.name.clone(),
-                        item_type: "file".to_string(),
-                        progress: 0.02,
-                        status: "preparing".to_string(),
-                        message: Some("Generating thumbnail...".to_string()),
-                        speed: None,
-                        remaining_time: None,
-                        size: Some(file_size),
-                    },
-                )
-                .map_err(|e| format!("Failed to emit progress: {}", e))?;
+    /// Server folder ID for a local folder this queue has already created
+    pub fn folder_id_for_path(&self, path: &str) -> Option<String> {
+        self.folder_id_map.get(path).cloned()
+    }
+}
 
-                // Generate thumbnail from the original file
-                match generate_thumbnail(path).await {
-                    Ok(thumbnail_data) => {
-                        println!("  file_id: {}", &thumbnail_info.url);
-
-                        // Encrypt the thumbnail with the same content key
-                        // Create a fixed nonce for thumbnail encryption
-                        let thumbnail_nonce_bytes = [0u8; 12];
-                        let thumbnail_nonce = Nonce::from_slice(&thumbnail_nonce_bytes);
-
-                        // Encrypt the thumbnail data
-                        let encrypted_thumbnail =
-                            match cipher.encrypt(thumbnail_nonce, thumbnail_data.as_ref()) {
-                                Ok(encrypted) => encrypted,
-                                Err(e) => {
-                                    println!("Failed to encrypt thumbnail: {}", e);
-                                    // Continue with main file upload even if thumbnail encryption fails
-                                    Vec::new()
-                                }
-                            };
+/// Generates a unique ID for transfer items
+fn generate_id() -> String {
+    let mut rng = rand::rng();
+    let random_part: u64 = rng.random();
+    format!(
+        "transfer-{}-{:x}",
+        chrono::Utc::now().timestamp_millis(),
+        random_part
+    )
+}
 
-                        // Only proceed with upload if encryption was successful
-                        if !encrypted_thumbnail.is_empty() {
-                            // Create HTTP client
-                            let client = reqwest::Client::builder()
-                                .timeout(Duration::from_secs(60))
-                                .build()
-                                .unwrap_or_default();
-
-                            // Upload the encrypted thumbnail
-                            match client
-                                .put(&thumbnail_info.url)
-                                .body(encrypted_thumbnail.clone())
-                                .header("Content-Type", "application/octet-stream")
-                                .send()
-                                .await
-                            {
-                                Ok(response) => {
-                                    if response.status().is_success() {
-                                        println!("Thumbnail uploaded successfully");
-
-                                        // Calculate thumbnail hash
-                                        let mut thumbnail_hasher = Sha256::default();
-                                        thumbnail_hasher.update(&encrypted_thumbnail);
-                                        let thumbnail_hash =
-                                            format!("{:x}", thumbnail_hasher.finalize());
-
-                                        // Notify backend about thumbnail completion
-                                        app.emit(
-                                            "thumbnail-complete",
-                                            serde_json::json!({
-                                                "thumbnail_id": thumbnail_info.id,
-                                                "hash": thumbnail_hash,
-                                                "size": encrypted_thumbnail.len(),
-                                            }),
-                                        )
-                                        .map_err(|e| {
-                                            format!("Failed to emit thumbnail completion: {}", e)
-                                        })?;
-                                    } else {
-                                        println!(
-                                            "Thumbnail upload failed with status: {}",
-                                            response.status()
-                                        );
-                                    }
-                                }
-                                Err(e) => {
-                                    println!("Thumbnail upload error: {}", e);
-                                }
-                            }
-                        }
-                    }
-                    Err(e) => {
-                        println!("Failed to generate thumbnail: {}", e);
-                        // Continue with main file upload even if thumbnail generation fails
-                    }
-                }
-            }
+/// A random UUID (version 4) for QueueItem::idempotency_key
+fn generate_idempotency_key() -> String {
+    let mut bytes: [u8; 16] = rand::rng().random();
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex = hex::encode(bytes);
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    )
+}
 
-            // Update progress
-            app.emit(
-                "transfer-progress",
-                TransferProgress {
-                    id: item.id.clone(),
-                    name: item.name.clone(),
-                    item_type: "file".to_string(),
-                    progress: 0.05,
-                    status: "uploading".to_string(),
-                    message: Some(format!("Starting upload of {} blocks...", total_blocks)),
-                    speed: None,
-                    remaining_time: None,
-                    size: Some(file_size), // Add this line
-                },
-            )
-            .map_err(|e| format!("Failed to emit progress: {}", e))?;
+/// Builds the duplicate detection key for a path from its absolute path, size and mtime
+fn dedup_key(path: &Path) -> Option<String> {
+    let absolute = std::fs::canonicalize(path).ok()?;
+    let metadata = std::fs::metadata(&absolute).ok()?;
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+        .map(|duration| duration.as_nanos())
+        .unwrap_or(0);
+
+    Some(format!(
+        "{}|{}|{}",
+        absolute.to_string_lossy(),
+        metadata.len(),
+        modified
+    ))
+}
 
-            // Open the file
-            let mut file = match File::open(path).await {
-                Ok(f) => f,
-                Err(e) => {
-                    let error = format!("Failed to open file: {}", e);
-                    handle_file_error(&app, &state, &item.id, &item.name, &Some(file_size), &error)
-                        .await?;
-                    return Err(error);
-                }
-            };
+// Refresh presigned URLs this long before they expire, so a block upload
+// started just before expiry still completes
+const URL_EXPIRY_MARGIN: Duration = Duration::from_secs(30);
 
-            // Use _start_time to avoid unused variable warning
-            let _start_time = Instant::now();
-            let mut uploaded_bytes = 0u64;
-            let mut completed_blocks = 0;
+/// Whether URLs issued at `issued_at` are expired or about to expire
+fn urls_near_expiry(issued_at: Instant, expires_in: usize) -> bool {
+    issued_at.elapsed() + URL_EXPIRY_MARGIN >= Duration::from_secs(expires_in as u64)
+}
 
-            // Add this for improved speed calculation
-            const SPEED_SAMPLES: usize = 5;
-            let mut speeds = Vec::with_capacity(SPEED_SAMPLES);
-            let mut last_block_time = Instant::now();
+/// Asks the frontend for fresh presigned URLs for the remaining blocks of a file,
+/// using the same response channel as the initial upload URLs request
+async fn refresh_presigned_urls(
+    app: &dyn EventSink,
+    state: &TransferSession,
+    item_id: &str,
+    file_id: &str,
+    revision_id: &str,
+    remaining: &[PresignedUrl],
+) -> Result<Vec<PresignedUrl>, String> {
+    let rx = expect_response(&RESPONSE_CHANNELS, item_id).await;
 
-            // Create SHA-256 hasher for content verification
-            let mut hasher = Sha256::default();
+    println!(
+        "Presigned URLs expiring for {}, requesting {} fresh URLs",
+        item_id,
+        remaining.len()
+    );
 
-            // Create HTTP client with retry capability
-            let client = reqwest::Client::builder()
-                .timeout(Duration::from_secs(300))
-                .build()
-                .unwrap_or_default();
+    state
+        .emit(
+            app,
+            "urls-expired",
+            serde_json::json!({
+                "id": item_id,
+                "file_id": file_id,
+                "revision_id": revision_id,
+                "block_ids": remaining.iter().map(|url| &url.block_id).collect::<Vec<_>>(),
+                "indices": remaining.iter().map(|url| url.index).collect::<Vec<_>>()
+            }),
+        )
+        .map_err(|e| format!("Failed to request fresh upload URLs: {}", e))?;
+
+    match tokio::time::timeout(Duration::from_secs(30), rx).await {
+        Ok(Ok(Ok(response))) => Ok(response.upload_urls),
+        Ok(Ok(Err(e))) => Err(format!("Upload URLs expired and refresh failed: {}", e)),
+        Ok(Err(_)) => Err("Upload URLs expired and refresh channel closed".to_string()),
+        Err(_) => {
+            RESPONSE_CHANNELS.lock().await.remove(item_id);
+            Err("Upload URLs expired and timed out waiting for fresh URLs".to_string())
+        }
+    }
+}
 
-            // Upload each block with retries
-            for presigned_url in &presigned_urls {
-                // Check if transfer was cancelled
-                {
-                    let queue = state.0.lock().await;
-                    if queue.processing.is_none() || queue.paused {
-                        return Ok(());
-                    }
-                }
+/// Replaces URLs from `position` onwards with refreshed ones, matched by block index
+fn apply_refreshed_urls(
+    presigned_urls: &mut [PresignedUrl],
+    position: usize,
+    refreshed: Vec<PresignedUrl>,
+) {
+    for url in presigned_urls[position..].iter_mut() {
+        if let Some(fresh) = refreshed.iter().find(|fresh| fresh.index == url.index) {
+            *url = fresh.clone();
+        }
+    }
+}
 
-                // Calculate block offset and size
-                let offset = presigned_url.index as u64 * block_size;
-                let current_block_size = if offset + block_size > file_size {
-                    file_size - offset
-                } else {
-                    block_size
-                };
+// Content-Type blocks are sent with unless the server names another
+const DEFAULT_BLOCK_CONTENT_TYPE: &str = "application/octet-stream";
+
+/// Builds a presigned PUT request with the given content type plus any headers
+/// the backend asked for, which take precedence over the defaults
+fn presigned_put(
+    client: &reqwest::Client,
+    url: &str,
+    body: Vec<u8>,
+    content_type: &str,
+    headers: &HashMap<String, String>,
+) -> reqwest::RequestBuilder {
+    let mut header_map = reqwest::header::HeaderMap::new();
+    header_map.insert(
+        reqwest::header::CONTENT_TYPE,
+        reqwest::header::HeaderValue::from_str(content_type).unwrap_or(
+            reqwest::header::HeaderValue::from_static(DEFAULT_BLOCK_CONTENT_TYPE),
+        ),
+    );
 
-                // Create buffer for this block only
-                let mut buffer = vec![0u8; current_block_size as usize];
+    for (name, value) in headers {
+        match (
+            reqwest::header::HeaderName::from_bytes(name.as_bytes()),
+            reqwest::header::HeaderValue::from_str(value),
+        ) {
+            (Ok(name), Ok(value)) => {
+                header_map.insert(name, value);
+            }
+            _ => println!("Skipping invalid upload header: {}", name),
+        }
+    }
 
-                // Seek to position and read block
-                match file.seek(std::io::SeekFrom::Start(offset)).await {
-                    Ok(_) => {}
-                    Err(e) => {
-                        let error = format!("Failed to seek in file: {}", e);
-                        handle_file_error(
-                            &app,
-                            &state,
-                            &item.id,
-                            &item.name,
-                            &Some(file_size),
-                            &error,
-                        )
-                        .await?;
-                        return Err(error);
-                    }
-                };
+    client.put(url).headers(header_map).body(body)
+}
 
-                match file.read_exact(&mut buffer).await {
-                    Ok(_) => {}
-                    Err(e) => {
-                        let error = format!("Failed to read file block: {}", e);
-                        handle_file_error(
-                            &app,
-                            &state,
-                            &item.id,
-                            &item.name,
-                            &Some(file_size),
-                            &error,
-                        )
-                        .await?;
-                        return Err(error);
-                    }
-                };
+/// Returns the ETag of a successful upload if it doesn't match the block's MD5.
+/// A missing or multipart-style ETag can't be compared and counts as a match.
+fn etag_mismatch(response: &reqwest::Response, block_md5: &[u8]) -> Option<String> {
+    if !response.status().is_success() {
+        return None;
+    }
 
-                // Update hash with original content before encryption
-                hasher.update(&buffer);
+    let etag = response
+        .headers()
+        .get(reqwest::header::ETAG)?
+        .to_str()
+        .ok()?
+        .trim_start_matches("W/")
+        .trim_matches('"')
+        .to_lowercase();
+
+    if etag.contains('-') || etag == hex::encode(block_md5) {
+        None
+    } else {
+        Some(etag)
+    }
+}
 
-                // Encrypt the buffer with AES-GCM
-                // Create a nonce from the block index
-                let mut nonce_bytes = [0u8; 12]; // AES-GCM requires a 12-byte nonce
-                let index_bytes = presigned_url.index.to_be_bytes();
-                for i in 0..std::cmp::min(index_bytes.len(), nonce_bytes.len()) {
-                    nonce_bytes[i] = index_bytes[i];
-                }
-                let nonce = Nonce::from_slice(&nonce_bytes);
+/// ETag the store returned for an uploaded part, quotes included as S3 expects
+fn response_etag(response: &reqwest::Response) -> Option<String> {
+    response
+        .headers()
+        .get(reqwest::header::ETAG)?
+        .to_str()
+        .ok()
+        .map(|etag| etag.to_string())
+}
 
-                // Encrypt the buffer
-                let upload_buffer = match cipher.encrypt(nonce, buffer.as_ref()) {
-                    Ok(encrypted) => encrypted,
-                    Err(e) => {
-                        let error = format!("Failed to encrypt block: {}", e);
-                        handle_file_error(
-                            &app,
-                            &state,
-                            &item.id,
-                            &item.name,
-                            &Some(file_size),
-                            &error,
-                        )
-                        .await?;
-                        return Err(error);
-                    }
-                };
+/// Where to send one block: the location the server handed out for it and,
+/// when the backend verifies uploads, the MD5 of the data
+pub struct BlockMetadata<'a> {
+    pub url: &'a PresignedUrl,
+    pub content_md5: Option<&'a [u8]>,
+    pub content_type: &'a str,
+}
 
-                // Upload block with retries
-                let max_retries = 3;
-                let mut retry_count = 0;
-                let mut upload_success = false;
-
-                while retry_count < max_retries && !upload_success {
-                    match client
-                        .put(&presigned_url.url)
-                        .body(upload_buffer.clone())
-                        .header("Content-Type", "application/octet-stream")
-                        .send()
-                        .await
-                    {
-                        Ok(response) => {
-                            if response.status().is_success() {
-                                upload_success = true;
-                            } else {
-                                println!(
-                                    "Block upload attempt {} failed with status: {}, retrying...",
-                                    retry_count + 1,
-                                    response.status()
-                                );
-                                retry_count += 1;
-                                tokio::time::sleep(Duration::from_millis(
-                                    1000 * (retry_count as u64),
-                                ))
-                                .await;
-                            }
-                        }
-                        Err(e) => {
-                            println!(
-                                "Block upload attempt {} failed with error: {}, retrying...",
-                                retry_count + 1,
-                                e
-                            );
-                            retry_count += 1;
-                            tokio::time::sleep(Duration::from_millis(1000 * (retry_count as u64)))
-                                .await;
-                        }
-                    }
-                }
+/// What a backend reports for a stored block
+#[derive(Debug, Clone, Default)]
+pub struct BlockReceipt {
+    pub etag: Option<String>, // Needed to complete a multipart upload
+}
 
-                if !upload_success {
-                    let error = format!("Upload failed after {} retries", max_retries);
-                    handle_file_error(&app, &state, &item.id, &item.name, &Some(file_size), &error)
-                        .await?;
-                    return Err(error);
-                }
+/// Why one attempt at uploading a block failed, so the queue can decide
+/// whether to retry, refresh the block's URL or give up
+#[derive(Debug)]
+pub enum BlockUploadError {
+    Retry(String),
+    TimedOut(String), // Retried like Retry, but counted as a timeout
+    Expired(String),  // The block's URL or credentials expired; fresh ones may work
+    Fatal(TransferError),
+}
 
-                // Calculate block hash (of the encrypted data being uploaded) using SHA-256
-                let mut block_hasher = Sha256::default();
-                block_hasher.update(&upload_buffer);
-                let block_hash = format!("{:x}", block_hasher.finalize());
+impl std::fmt::Display for BlockUploadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BlockUploadError::Retry(message)
+            | BlockUploadError::TimedOut(message)
+            | BlockUploadError::Expired(message) => write!(f, "{}", message),
+            BlockUploadError::Fatal(error) => write!(f, "{}", error),
+        }
+    }
+}
 
-                // Create a unique key for this block to prevent duplicates
-                let block_key = format!("{}:{}", presigned_url.block_id, presigned_url.index);
+/// The file a backend is finishing an upload for
+pub struct UploadTarget<'a> {
+    pub app: &'a dyn EventSink,
+    pub session: &'a TransferSession,
+    pub item_id: &'a str,
+    pub file_id: &'a str,
+    pub revision_id: &'a str,
+    pub multipart_upload_id: Option<&'a str>,
+}
 
-                // Check if we've already sent this block completion
-                let already_sent_block = {
-                    let mut queue = state.0.lock().await;
-                    let exists = queue.block_completion_sent.contains(&block_key);
-                    if !exists {
-                        queue.block_completion_sent.insert(block_key);
-                    }
-                    exists
-                };
+type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Transport that puts encrypted blocks into storage. Reading, encryption,
+/// hashing, retries, connection limits and progress stay with the queue; a
+/// backend only moves bytes. New backends are added to upload_backend and
+/// picked per session with set_upload_backend.
+pub trait BlockUploader: Send + Sync {
+    /// Makes one attempt at storing block `index`
+    fn upload_block<'a>(
+        &'a self,
+        index: usize,
+        data: Vec<u8>,
+        metadata: &'a BlockMetadata<'a>,
+    ) -> BoxFuture<'a, Result<BlockReceipt, BlockUploadError>>;
+
+    /// Called once every block is stored, with the receipts' ETags by part
+    /// number. Returns the part numbers the store wants uploaded again.
+    fn finalize<'a>(
+        &'a self,
+        target: &'a UploadTarget<'a>,
+        parts: &'a BTreeMap<usize, String>,
+    ) -> BoxFuture<'a, Result<Vec<usize>, String>>;
+}
 
-                if !already_sent_block {
-                    // Tell frontend to notify backend about block completion
-                    app.emit(
-                        "block-complete",
-                        serde_json::json!({
-                            "block_id": presigned_url.block_id,
-                            "hash": block_hash,
-                            "index": presigned_url.index,
-                            "file_id": server_file_id
-                        }),
-                    )
-                    .map_err(|e| format!("Failed to emit block completion: {}", e))?;
+/// The default backend: a PUT of each block to its presigned URL, with
+/// multipart uploads completed through the frontend
+pub struct PresignedUploader;
+
+impl BlockUploader for PresignedUploader {
+    fn upload_block<'a>(
+        &'a self,
+        _index: usize,
+        data: Vec<u8>,
+        metadata: &'a BlockMetadata<'a>,
+    ) -> BoxFuture<'a, Result<BlockReceipt, BlockUploadError>> {
+        Box::pin(async move {
+            // Shared HTTP client, honoring any configured proxy
+            let client = proxy::upload_client().await;
+            let mut request = presigned_put(
+                &client,
+                &metadata.url.url,
+                data,
+                metadata.content_type,
+                &metadata.url.headers,
+            );
+            if let Some(block_md5) = metadata.content_md5 {
+                request =
+                    request.header("Content-MD5", general_purpose::STANDARD.encode(block_md5));
+            }
+
+            let response = match request.timeout(Duration::from_secs(300)).send().await {
+                Ok(response) => response,
+                // Retrying won't change the certificate
+                Err(e) if proxy::is_pin_failure(&e) => {
+                    return Err(BlockUploadError::Fatal(TransferError::TlsPinFailure(
+                        format!(
+                            "Storage certificate doesn't match the pinned certificates: {}",
+                            e
+                        ),
+                    )));
                 }
+                Err(e) if e.is_timeout() => return Err(BlockUploadError::TimedOut(e.to_string())),
+                Err(e) => return Err(BlockUploadError::Retry(e.to_string())),
+            };
 
-                // Calculate block elapsed time and speed
-                let block_elapsed = last_block_time.elapsed();
-                last_block_time = Instant::now();
+            // The store saved something other than what we sent
+            if let Some(etag) = metadata
+                .content_md5
+                .and_then(|block_md5| etag_mismatch(&response, block_md5))
+            {
+                return Err(BlockUploadError::Retry(format!(
+                    "stored ETag {} which doesn't match its MD5",
+                    etag
+                )));
+            }
 
-                if block_elapsed.as_secs_f64() > 0.0 {
-                    let current_speed = current_block_size as f64 / block_elapsed.as_secs_f64();
-                    speeds.push(current_speed);
-                    if speeds.len() > SPEED_SAMPLES {
-                        speeds.remove(0);
-                    }
+            // An expired presigned URL is rejected with 403
+            if response.status() == reqwest::StatusCode::FORBIDDEN {
+                return Err(BlockUploadError::Expired(format!(
+                    "status: {}",
+                    response.status()
+                )));
+            }
+            if !response.status().is_success() {
+                return Err(BlockUploadError::Retry(format!(
+                    "status: {}",
+                    response.status()
+                )));
+            }
+
+            Ok(BlockReceipt {
+                etag: response_etag(&response),
+            })
+        })
+    }
+
+    fn finalize<'a>(
+        &'a self,
+        target: &'a UploadTarget<'a>,
+        parts: &'a BTreeMap<usize, String>,
+    ) -> BoxFuture<'a, Result<Vec<usize>, String>> {
+        Box::pin(async move {
+            let Some(upload_id) = target.multipart_upload_id else {
+                return Ok(Vec::new());
+            };
+            complete_multipart(
+                target.app,
+                target.session,
+                target.item_id,
+                target.file_id,
+                target.revision_id,
+                upload_id,
+                parts,
+            )
+            .await
+        })
+    }
+}
+
+/// The uploader registered under `name`
+fn upload_backend(name: &str) -> Result<Arc<dyn BlockUploader>, String> {
+    match name {
+        PRESIGNED_BACKEND => Ok(Arc::new(PresignedUploader)),
+        _ => Err(format!("Unknown upload backend: {}", name)),
+    }
+}
+
+/// Asks the server to complete a multipart upload from its collected part
+/// ETags, returning the part numbers it wants uploaded again
+async fn complete_multipart(
+    app: &dyn EventSink,
+    state: &TransferSession,
+    item_id: &str,
+    file_id: &str,
+    revision_id: &str,
+    upload_id: &str,
+    parts: &BTreeMap<usize, String>,
+) -> Result<Vec<usize>, String> {
+    let rx = expect_response(&MULTIPART_RESPONSE_CHANNELS, item_id).await;
+
+    state
+        .emit(
+            app,
+            "complete-multipart",
+            serde_json::json!({
+                "id": item_id,
+                "file_id": file_id,
+                "revision_id": revision_id,
+                "upload_id": upload_id,
+                "parts": parts
+                    .iter()
+                    .map(|(part_number, etag)| serde_json::json!({
+                        "part_number": part_number,
+                        "etag": etag,
+                    }))
+                    .collect::<Vec<_>>(),
+            }),
+        )
+        .map_err(|e| format!("Failed to request multipart completion: {}", e))?;
+
+    match tokio::time::timeout(Duration::from_secs(60), rx).await {
+        Ok(Ok(Ok(response))) => Ok(response.retry_parts),
+        Ok(Ok(Err(e))) => Err(format!("Failed to complete multipart upload: {}", e)),
+        Ok(Err(_)) => Err("Multipart completion channel closed".to_string()),
+        Err(_) => {
+            MULTIPART_RESPONSE_CHANNELS.lock().await.remove(item_id);
+            Err("Timeout waiting for multipart completion".to_string())
+        }
+    }
+}
+
+/// Scans a directory and returns lists of folders and files
+async fn scan_folder(path: &Path) -> Result<(Vec<PathBuf>, Vec<PathBuf>), String> {
+    let mut folders = Vec::new();
+    let mut files = Vec::new();
+
+    match tokio::fs::read_dir(path).await {
+        Ok(mut dir) => {
+            while let Ok(Some(entry)) = dir.next_entry().await {
+                let entry_path = entry.path();
+
+                if entry_path.is_dir() {
+                    folders.push(entry_path);
+                } else if entry_path.is_file() {
+                    files.push(entry_path);
                 }
+            }
 
-                // Update progress tracking
-                uploaded_bytes += current_block_size;
-                completed_blocks += 1;
-                let progress = uploaded_bytes as f32 / file_size as f32;
+            Ok((folders, files))
+        }
+        Err(e) => Err(format!("Failed to read directory: {}", e)),
+    }
+}
 
-                // Use the average speed for calculations
-                let avg_speed = if !speeds.is_empty() {
-                    speeds.iter().sum::<f64>() / speeds.len() as f64
-                } else {
-                    0.0
-                };
+/// Totals returned by estimate_upload
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct UploadEstimate {
+    file_count: usize,
+    folder_count: usize,
+    total_bytes: u64,
+    skipped_count: usize, // Files the upload would skip: empty, lock or temp files
+    cancelled: bool,
+}
 
-                // Calculate remaining time
-                let remaining_bytes = file_size - uploaded_bytes;
-                let remaining_time = if avg_speed > 0.1 {
-                    // Threshold to avoid very large numbers
-                    (remaining_bytes as f64 / avg_speed) as u64
-                } else {
-                    3600 // Default to 1 hour when speed is too low
-                };
+impl UploadEstimate {
+    fn add_file(&mut self, path: &Path, skip_temp_files: bool) {
+        let size = std::fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy())
+            .unwrap_or_default();
 
-                // Update progress notification
-                app.emit(
-                    "transfer-progress",
-                    TransferProgress {
-                        id: item.id.clone(),
-                        name: item.name.clone(),
-                        item_type: "file".to_string(),
-                        progress,
-                        status: "uploading".to_string(),
-                        message: Some(format!(
-                            "Uploading block {}/{}",
-                            completed_blocks, total_blocks
-                        )),
-                        speed: Some(avg_speed),
-                        remaining_time: Some(remaining_time),
-                        size: Some(file_size), // Add this line
-                    },
-                )
-                .map_err(|e| format!("Failed to emit progress: {}", e))?;
+        if size == 0 || (skip_temp_files && skip_reason_for_name(&name).is_some()) {
+            self.skipped_count += 1;
+        } else {
+            self.file_count += 1;
+            self.total_bytes += size;
+        }
+    }
+}
+
+/// Counts the files and folders under the given paths and their total size,
+/// without queueing anything. Partial totals are sent as
+/// `upload-estimate-progress`; pass an `estimate_id` to be able to stop the
+/// walk with cancel_estimate.
+#[command]
+pub async fn estimate_upload(
+    app: AppHandle,
+    paths: Vec<String>,
+    estimate_id: Option<String>,
+    state: State<'_, TransferManagerState>,
+    session_id: Option<String>,
+) -> Result<UploadEstimate, String> {
+    let state = state.session(session_id.as_deref());
+    let skip_temp_files = state.0.lock().await.skip_temp_files;
+    let estimate_id = estimate_id.unwrap_or_else(generate_id);
+    let cancelled = Arc::new(AtomicBool::new(false));
+    ESTIMATES
+        .lock()
+        .await
+        .insert(estimate_id.clone(), cancelled.clone());
+
+    let mut estimate = UploadEstimate::default();
+    let mut pending_dirs = Vec::new();
+    let mut next_report = ESTIMATE_PROGRESS_INTERVAL;
+
+    for path_str in paths {
+        let path = PathBuf::from(&path_str);
+        if path.is_dir() {
+            estimate.folder_count += 1;
+            pending_dirs.push(path);
+        } else if path.is_file() {
+            estimate.add_file(&path, skip_temp_files);
+        }
+    }
+
+    while let Some(dir) = pending_dirs.pop() {
+        if cancelled.load(Ordering::Relaxed) {
+            estimate.cancelled = true;
+            break;
+        }
+
+        // Unreadable folders are left out rather than failing the estimate
+        let (subfolders, files) = match scan_folder(&dir).await {
+            Ok(contents) => contents,
+            Err(e) => {
+                println!("Skipping {} in estimate: {}", dir.display(), e);
+                continue;
             }
+        };
 
-            // Calculate final content hash
-            let content_hash = format!("{:x}", hasher.finalize());
+        estimate.folder_count += subfolders.len();
+        pending_dirs.extend(subfolders);
+        for file in files {
+            estimate.add_file(&file, skip_temp_files);
+        }
 
-            // Check if we've already sent finalization request for this file
-            let finalization_already_sent = {
-                let mut queue = state.0.lock().await;
-                let exists = queue.completion_notifications_sent.contains(&item.id);
-                if !exists {
-                    queue.completion_notifications_sent.insert(item.id.clone());
+        if estimate.file_count >= next_report {
+            next_report = estimate.file_count + ESTIMATE_PROGRESS_INTERVAL;
+            let _ = state.emit(
+                &app,
+                "upload-estimate-progress",
+                serde_json::json!({
+                    "estimate_id": estimate_id,
+                    "estimate": estimate,
+                }),
+            );
+        }
+    }
+
+    ESTIMATES.lock().await.remove(&estimate_id);
+
+    Ok(estimate)
+}
+
+/// Stops a running estimate_upload, which then returns its partial totals
+#[command]
+pub async fn cancel_estimate(estimate_id: String) -> Result<(), String> {
+    match ESTIMATES.lock().await.get(&estimate_id) {
+        Some(cancelled) => {
+            cancelled.store(true, Ordering::Relaxed);
+            Ok(())
+        }
+        None => Err(format!("No estimate running with ID: {}", estimate_id)),
+    }
+}
+
+/// Hashes a file without uploading it, so the frontend can ask the server
+/// whether it already has the content. Returns the lowercase hex digest.
+/// Files that take a while send `hash-progress`; pass a `hash_id` to be able
+/// to stop hashing with cancel_hash.
+#[command]
+pub async fn hash_file(
+    app: AppHandle,
+    path: String,
+    algorithm: Option<HashAlgorithm>,
+    hash_id: Option<String>,
+    state: State<'_, TransferManagerState>,
+    session_id: Option<String>,
+) -> Result<String, String> {
+    let state = state.session(session_id.as_deref());
+    let hash_id = hash_id.unwrap_or_else(generate_id);
+    let cancelled = Arc::new(AtomicBool::new(false));
+    HASHES
+        .lock()
+        .await
+        .insert(hash_id.clone(), cancelled.clone());
+
+    let result = hash_local_file(
+        &app,
+        &state,
+        Path::new(&path),
+        algorithm.unwrap_or_default(),
+        &hash_id,
+        &cancelled,
+    )
+    .await;
+
+    HASHES.lock().await.remove(&hash_id);
+
+    result.map_err(|e| e.to_string())
+}
+
+/// Stops a running hash_file, which then fails as cancelled
+#[command]
+pub async fn cancel_hash(hash_id: String) -> Result<(), String> {
+    match HASHES.lock().await.get(&hash_id) {
+        Some(cancelled) => {
+            cancelled.store(true, Ordering::Relaxed);
+            Ok(())
+        }
+        None => Err(format!("No hash running with ID: {}", hash_id)),
+    }
+}
+
+/// Reads a file through the content hasher the way process_file does, sending
+/// `hash-progress` at most every HASH_PROGRESS_INTERVAL
+async fn hash_local_file(
+    app: &dyn EventSink,
+    state: &TransferSession,
+    path: &Path,
+    algorithm: HashAlgorithm,
+    hash_id: &str,
+    cancelled: &AtomicBool,
+) -> Result<String, TransferError> {
+    let mut file = File::open(path).await.map_err(|e| match e.kind() {
+        std::io::ErrorKind::NotFound => TransferError::FileNotFound(path.display().to_string()),
+        _ => TransferError::Io(format!("Failed to open file: {}", e)),
+    })?;
+    let total_bytes = file
+        .metadata()
+        .await
+        .map_err(|e| TransferError::Io(format!("Failed to read file metadata: {}", e)))?
+        .len();
+
+    let mut hasher = ContentHasher::new(algorithm);
+    let mut buffer = vec![0u8; HASH_READ_SIZE];
+    let mut hashed_bytes = 0u64;
+    let mut last_report = Instant::now();
+
+    loop {
+        if cancelled.load(Ordering::Relaxed) {
+            return Err(TransferError::Cancelled);
+        }
+
+        let read = file
+            .read(&mut buffer)
+            .await
+            .map_err(|e| TransferError::Io(format!("Failed to read file: {}", e)))?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+        hashed_bytes += read as u64;
+
+        if last_report.elapsed() >= HASH_PROGRESS_INTERVAL {
+            last_report = Instant::now();
+            let _ = state.emit(
+                app,
+                "hash-progress",
+                serde_json::json!({
+                    "hash_id": hash_id,
+                    "path": path.to_string_lossy(),
+                    "hashed_bytes": hashed_bytes,
+                    "total_bytes": total_bytes,
+                }),
+            );
+        }
+    }
+
+    Ok(hasher.finalize_hex())
+}
+
+/// The first of "name", "name (2)", "name (3)", ... that isn't taken,
+/// numbering before the extension
+fn unique_name(name: &str, taken: &HashSet<String>) -> String {
+    if !taken.contains(name) {
+        return name.to_string();
+    }
+
+    let path = Path::new(name);
+    let stem = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_else(|| name.to_string());
+    let extension = path
+        .extension()
+        .map(|extension| format!(".{}", extension.to_string_lossy()))
+        .unwrap_or_default();
+
+    (2..)
+        .map(|n| format!("{} ({}){}", stem, n, extension))
+        .find(|candidate| !taken.contains(candidate))
+        .unwrap_or_else(|| name.to_string())
+}
+
+/// Checks the share and parent folder an upload was asked to go into,
+/// returning them with surrounding whitespace removed
+pub fn validate_target(share_id: &str, parent_id: &str) -> Result<(String, String), TransferError> {
+    let share_id = share_id.trim();
+    let parent_id = parent_id.trim();
+    if share_id.is_empty() {
+        return Err(TransferError::InvalidTarget(
+            "No share to upload into".to_string(),
+        ));
+    }
+    if parent_id.is_empty() {
+        return Err(TransferError::InvalidTarget(
+            "No folder to upload into".to_string(),
+        ));
+    }
+    Ok((share_id.to_string(), parent_id.to_string()))
+}
+
+/// Command to select and upload files
+#[command]
+pub async fn select_files(
+    app: AppHandle,
+    paths: Vec<String>,
+    share_id: String,
+    parent_id: String,
+    metadata: Option<HashMap<String, String>>,
+    conflict_policy: Option<ConflictPolicy>,
+    priority: Option<Priority>,
+    dry_run: Option<bool>,
+    state: State<'_, TransferManagerState>,
+    session_id: Option<String>,
+) -> Result<(), TransferError> {
+    let (share_id, parent_id) = validate_target(&share_id, &parent_id)?;
+    let state = state.session(session_id.as_deref());
+    let mut items = Vec::new();
+
+    // Names already headed for this folder, so renamed uploads don't collide
+    // with each other
+    let mut taken_names: HashSet<String> = if conflict_policy == Some(ConflictPolicy::Rename) {
+        let queue = state.0.lock().await;
+        queue
+            .items
+            .iter()
+            .chain(queue.current_item.iter())
+            .filter(|item| item.parent_id == parent_id)
+            .map(|item| item.name.clone())
+            .collect()
+    } else {
+        HashSet::new()
+    };
+
+    // Process each file path
+    for path_str in paths {
+        let path = PathBuf::from(&path_str);
+
+        if !path.exists() || !path.is_file() {
+            state
+                .emit(
+                    &app,
+                    "transfer-error",
+                    format!("Invalid file path: {}", path_str),
+                )
+                .map_err(|e| format!("Failed to emit error: {}", e))?;
+            continue;
+        }
+
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or("unknown")
+            .to_string();
+
+        let name = if conflict_policy == Some(ConflictPolicy::Rename) {
+            let name = unique_name(&name, &taken_names);
+            taken_names.insert(name.clone());
+            name
+        } else {
+            name
+        };
+
+        let id = generate_id();
+
+        items.push((
+            QueueItem {
+                item_type: "file".to_string(),
+                id,
+                path: path_str,
+                name,
+                parent_id: parent_id.clone(),
+                share_id: share_id.clone(),
+                depth: 0, // Root level
+                data: None,
+                metadata: metadata.clone().unwrap_or_default(),
+                conflict_policy,
+                priority: priority.unwrap_or_default(),
+                idempotency_key: generate_idempotency_key(),
+            },
+            dedup_key(&path),
+        ));
+    }
+
+    // Only report what would be uploaded
+    if dry_run.unwrap_or(false) {
+        return Ok(emit_transfer_plan(&app, &state, items).await?);
+    }
+
+    // Add items to the queue
+    {
+        let mut queue = state.0.lock().await;
+        queue.push_unique_items(&app, &state, items);
+
+        // Start processing if not already in progress
+        if queue.processing.is_none() && !queue.is_paused() {
+            drop(queue); // Release the lock before starting process
+            process_next_item(events(&app), state).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Command to select and upload folders
+#[command]
+pub async fn select_folders(
+    app: AppHandle,
+    paths: Vec<String>,
+    share_id: String,
+    parent_id: String,
+    priority: Option<Priority>,
+    dry_run: Option<bool>,
+    state: State<'_, TransferManagerState>,
+    session_id: Option<String>,
+) -> Result<(), TransferError> {
+    let (share_id, parent_id) = validate_target(&share_id, &parent_id)?;
+    let state = state.session(session_id.as_deref());
+    enqueue_folders(
+        events(&app),
+        state,
+        paths,
+        share_id,
+        parent_id,
+        priority.unwrap_or_default(),
+        dry_run.unwrap_or(false),
+    )
+    .await
+}
+
+/// Queues folders into a session, or only reports the plan on a dry run, and
+/// starts processing. The target must already be validated. Everything found
+/// inside the folders inherits `priority`.
+async fn enqueue_folders(
+    app: Events,
+    state: TransferSession,
+    paths: Vec<String>,
+    share_id: String,
+    parent_id: String,
+    priority: Priority,
+    dry_run: bool,
+) -> Result<(), TransferError> {
+    let mut items = Vec::new();
+
+    // Process each folder path
+    for path_str in paths {
+        let path = PathBuf::from(&path_str);
+
+        if !path.exists() || !path.is_dir() {
+            state
+                .emit(
+                    &app,
+                    "transfer-error",
+                    format!("Invalid folder path: {}", path_str),
+                )
+                .map_err(|e| format!("Failed to emit error: {}", e))?;
+            continue;
+        }
+
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or("unknown")
+            .to_string();
+
+        let id = generate_id();
+
+        items.push((
+            QueueItem {
+                item_type: "folder".to_string(),
+                id,
+                path: path_str,
+                name,
+                parent_id: parent_id.clone(),
+                share_id: share_id.clone(),
+                depth: 0, // Root level
+                data: None,
+                metadata: HashMap::new(),
+                conflict_policy: None,
+                priority,
+                idempotency_key: generate_idempotency_key(),
+            },
+            dedup_key(&path),
+        ));
+    }
+
+    // Only report what would be uploaded
+    if dry_run {
+        return Ok(emit_transfer_plan(&app, &state, items).await?);
+    }
+
+    // Add items to the queue
+    {
+        let mut queue = state.0.lock().await;
+
+        let ids: Vec<String> = items.iter().map(|(item, _)| item.id.clone()).collect();
+        let roots: Vec<PathBuf> = items
+            .iter()
+            .map(|(item, _)| PathBuf::from(&item.path))
+            .collect();
+        queue.push_unique_items(&app, &state, items);
+
+        // Read the trees in the background so scanning doesn't wait on folder creation.
+        // Duplicates that weren't queued are left out.
+        if queue.prescan_folders {
+            for (id, root) in ids.iter().zip(roots) {
+                if queue.items.iter().any(|item| &item.id == id) {
+                    tauri::async_runtime::spawn(prescan_tree(
+                        state.0.clone(),
+                        root,
+                        queue.prescan_generation,
+                    ));
+                }
+            }
+        }
+
+        // Start processing if not already in progress
+        if queue.processing.is_none() && !queue.is_paused() {
+            drop(queue); // Release the lock before starting process
+            process_next_item(app, state).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Walks the selected items the same way an upload would and emits a single
+/// `transfer-plan` event describing it, without queueing anything
+async fn emit_transfer_plan(
+    app: &dyn EventSink,
+    state: &TransferSession,
+    items: Vec<(QueueItem, Option<String>)>,
+) -> Result<(), String> {
+    let mut plan = TransferPlan::default();
+    let mut folders_to_scan = Vec::new();
+
+    {
+        let queue = state.0.lock().await;
+        for (item, dedup_key) in items {
+            if dedup_key.is_some_and(|key| queue.find_duplicate(&key).is_some()) {
+                plan.skipped_count += 1;
+                continue;
+            }
+
+            if item.item_type == "folder" {
+                folders_to_scan.push(PathBuf::from(&item.path));
+            } else {
+                plan.add_file(Path::new(&item.path));
+            }
+        }
+    }
+
+    while let Some(folder) = folders_to_scan.pop() {
+        plan.add_folder(&folder);
+
+        let (subfolders, files) = scan_folder(&folder).await?;
+        for file in &files {
+            plan.add_file(file);
+        }
+        folders_to_scan.extend(subfolders);
+    }
+
+    println!(
+        "Transfer plan: {} files, {} folders, {} bytes, {} skipped",
+        plan.file_count, plan.folder_count, plan.total_bytes, plan.skipped_count
+    );
+
+    state
+        .emit(app, "transfer-plan", &plan)
+        .map_err(|e| format!("Failed to emit transfer plan: {}", e))
+}
+
+/// Contents for upload_bytes, either base64 or a plain byte array
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum UploadData {
+    Base64(String),
+    Bytes(Vec<u8>),
+}
+
+/// Uploads content the frontend holds in memory as a file named `name`,
+/// through the same encrypt and block upload pipeline as files on disk
+#[command]
+pub async fn upload_bytes(
+    app: AppHandle,
+    name: String,
+    data: UploadData,
+    share_id: String,
+    parent_id: String,
+    priority: Option<Priority>,
+    state: State<'_, TransferManagerState>,
+    session_id: Option<String>,
+) -> Result<String, String> {
+    let state = state.session(session_id.as_deref());
+    let (share_id, parent_id) =
+        validate_target(&share_id, &parent_id).map_err(|e| e.to_string())?;
+    let name = name.trim().to_string();
+    if name.is_empty() {
+        return Err("File name cannot be empty".to_string());
+    }
+    if name.contains('/') || name.contains('\\') {
+        return Err(format!(
+            "File name cannot contain path separators: {}",
+            name
+        ));
+    }
+
+    let data = match data {
+        UploadData::Base64(encoded) => general_purpose::STANDARD
+            .decode(encoded.trim())
+            .map_err(|e| format!("Invalid base64 data: {}", e))?,
+        UploadData::Bytes(bytes) => bytes,
+    };
+
+    let id = generate_id();
+    let item = QueueItem {
+        item_type: "file".to_string(),
+        id: id.clone(),
+        path: name.clone(),
+        name,
+        parent_id,
+        share_id,
+        depth: 0, // Root level
+        data: Some(Arc::from(data)),
+        metadata: HashMap::new(),
+        conflict_policy: None,
+        priority: priority.unwrap_or_default(),
+        idempotency_key: generate_idempotency_key(),
+    };
+
+    {
+        let mut queue = state.0.lock().await;
+        queue.push_unique_items(&app, &state, vec![(item, None)]);
+
+        // Start processing if not already in progress
+        if queue.processing.is_none() && !queue.is_paused() {
+            drop(queue); // Release the lock before starting process
+            process_next_item(events(&app), state).await?;
+        }
+    }
+
+    Ok(id)
+}
+
+/// Reads a whole folder tree level by level, scanning up to PRESCAN_CONCURRENCY
+/// directories at once, and stores each listing for scan_and_enqueue to use.
+/// Contents are still only queued once their folder has a server ID.
+async fn prescan_tree(queue: Arc<Mutex<TransferQueue>>, root: PathBuf, generation: u64) {
+    let started = Instant::now();
+    let mut level = vec![root.clone()];
+    let mut scanned_count = 0;
+
+    while !level.is_empty() {
+        let scanned: Vec<(PathBuf, Result<(Vec<PathBuf>, Vec<PathBuf>), String>)> =
+            stream::iter(level)
+                .map(|dir| async move {
+                    let result = scan_folder(&dir).await;
+                    (dir, result)
+                })
+                .buffer_unordered(PRESCAN_CONCURRENCY)
+                .collect()
+                .await;
+
+        let mut queue = queue.lock().await;
+
+        // Stop if the upload was cancelled or the queue was shut down meanwhile
+        if !queue.prescan_folders || queue.shutting_down || queue.prescan_generation != generation {
+            return;
+        }
+
+        level = Vec::new();
+        for (dir, result) in scanned {
+            match result {
+                Ok((subfolders, files)) => {
+                    level.extend(subfolders.iter().cloned());
+                    scanned_count += 1;
+                    queue
+                        .prescanned
+                        .insert(dir.to_string_lossy().to_string(), (subfolders, files));
+                }
+                // Left out, so the folder is scanned again when it's created
+                Err(e) => println!("Pre-scan skipped {}: {}", dir.display(), e),
+            }
+        }
+    }
+
+    println!(
+        "Pre-scanned {} folders under {} in {:?}",
+        scanned_count,
+        root.display(),
+        started.elapsed()
+    );
+}
+
+/// Looks up the live state of one transfer. Returns None for unknown IDs.
+#[command]
+pub async fn get_transfer_status(
+    id: String,
+    state: State<'_, TransferManagerState>,
+    session_id: Option<String>,
+) -> Result<Option<TransferStatus>, String> {
+    let state = state.session(session_id.as_deref());
+    let queue = state.0.lock().await;
+    let progress = LAST_PROGRESS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .get(&id)
+        .cloned();
+
+    let (transfer_state, error) = if let Some(error) = queue.failed.get(&id) {
+        ("failed", Some(error.clone()))
+    } else if queue.completed.contains(&id) {
+        ("completed", None)
+    } else if queue.processing.as_ref() == Some(&id) {
+        ("processing", None)
+    } else if queue.items.iter().any(|item| item.id == id) {
+        ("queued", None)
+    } else {
+        return Ok(None);
+    };
+
+    let hashed_bytes = queue.hash_progress.get(&id).copied();
+
+    Ok(Some(TransferStatus {
+        id,
+        state: transfer_state.to_string(),
+        progress,
+        error,
+        hashed_bytes,
+    }))
+}
+
+/// Sends `transfer-progress` again for the transfer in progress, everything
+/// queued and the most recently finished transfers, so a frontend that
+/// reloaded can rebuild its view. Returns how many events were sent.
+#[command]
+pub async fn resync_transfer_state(
+    app: AppHandle,
+    state: State<'_, TransferManagerState>,
+    session_id: Option<String>,
+) -> Result<usize, String> {
+    let state = state.session(session_id.as_deref());
+    let events = {
+        let queue = state.0.lock().await;
+        let last_progress = LAST_PROGRESS
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        queue.resync_events(&last_progress, RESYNC_HISTORY_LIMIT)
+    };
+
+    for progress in &events {
+        state
+            .emit(&app, "transfer-progress", progress)
+            .map_err(|e| format!("Failed to emit progress: {}", e))?;
+    }
+
+    println!("Resynced {} transfers", events.len());
+
+    Ok(events.len())
+}
+
+/// Creates an empty folder on the server without queueing any upload, using
+/// the same `create-folder` round trip as folder uploads. Returns the new folder ID.
+#[command]
+pub async fn create_folder(
+    app: AppHandle,
+    share_id: String,
+    parent_id: String,
+    name: String,
+    state: State<'_, TransferManagerState>,
+    session_id: Option<String>,
+) -> Result<String, String> {
+    let state = state.session(session_id.as_deref());
+    let (share_id, parent_id) =
+        validate_target(&share_id, &parent_id).map_err(|e| e.to_string())?;
+    let name = name.trim().to_string();
+    if name.is_empty() {
+        return Err("Folder name cannot be empty".to_string());
+    }
+    if name.contains('/') || name.contains('\\') {
+        return Err(format!(
+            "Folder name cannot contain path separators: {}",
+            name
+        ));
+    }
+    if name == "." || name == ".." {
+        return Err(format!("Invalid folder name: {}", name));
+    }
+
+    let request_id = format!("{}{}", CREATE_FOLDER_ID_PREFIX, generate_id());
+
+    // Register the receiver BEFORE emitting the event so a fast response isn't lost
+    let rx = expect_response(&FOLDER_RESPONSE_CHANNELS, &request_id).await;
+
+    println!("Requesting creation of folder {} in {}", name, parent_id);
+
+    if let Err(e) = state.emit(
+        &app,
+        "create-folder",
+        serde_json::json!({
+            "id": request_id,
+            "name": name,
+            "path": null,
+            "parent_id": parent_id,
+            "share_id": share_id
+        }),
+    ) {
+        FOLDER_RESPONSE_CHANNELS.lock().await.remove(&request_id);
+        return Err(format!("Failed to request folder creation: {}", e));
+    }
+
+    match tokio::time::timeout(Duration::from_secs(30), rx).await {
+        Ok(Ok(Ok(response))) => Ok(response.folder_id),
+        Ok(Ok(Err(e))) => Err(e),
+        Ok(Err(_)) => Err("Channel closed before receiving response".to_string()),
+        Err(_) => {
+            FOLDER_RESPONSE_CHANNELS.lock().await.remove(&request_id);
+            Err("Timeout waiting for folder creation".to_string())
+        }
+    }
+}
+
+/// Cancels a specific transfer by its queue item ID, or by the server file ID
+/// of a file that is being uploaded
+#[command]
+pub async fn cancel_transfer(
+    id: Option<String>,
+    file_id: Option<String>,
+    state: State<'_, TransferManagerState>,
+    session_id: Option<String>,
+) -> Result<(), String> {
+    let state = state.session(session_id.as_deref());
+    let mut queue = state.0.lock().await;
+
+    let id = match (id, file_id) {
+        (Some(id), _) => id,
+        (None, Some(file_id)) => queue
+            .transfer_id_for_file(&file_id)
+            .ok_or_else(|| format!("No transfer in progress for file: {}", file_id))?,
+        (None, None) => return Err("Either id or file_id is required".to_string()),
+    };
+
+    // Stop reporting blocks for the file; its upload loop exits at the next block
+    if let Some(server_file_id) = queue.server_file_ids.remove(&id) {
+        queue.clear_block_tracking(&server_file_id);
+    }
+
+    // Check if this is the current processing item
+    if let Some(processing_id) = &queue.processing {
+        if processing_id == &id {
+            queue.processing = None;
+            queue.forget_folder_item(&id);
+            queue.mark_failed(&id, TransferError::Cancelled.to_string());
+            // Clean up all tracking for this ID
+            queue.initialized_files.remove(&id);
+            queue.initialized_folders.remove(&id);
+            queue.completion_notifications_sent.remove(&id);
+            queue.received_url_responses.remove(&id);
+            queue.received_folder_responses.remove(&id);
+            queue.request_timestamps.remove(&id);
+            return Ok(());
+        }
+    }
+
+    // Otherwise, remove it from the queue if found
+    queue.items.retain(|item| item.id != id);
+    queue.forget_folder_item(&id);
+    queue.mark_failed(&id, TransferError::Cancelled.to_string());
+    // Clean up all tracking for this ID
+    queue.initialized_files.remove(&id);
+    queue.initialized_folders.remove(&id);
+    queue.completion_notifications_sent.remove(&id);
+    queue.received_url_responses.remove(&id);
+    queue.received_folder_responses.remove(&id);
+    queue.request_timestamps.remove(&id);
+
+    Ok(())
+}
+
+/// Cancels all pending transfers
+#[command]
+pub async fn cancel_all_transfers(
+    state: State<'_, TransferManagerState>,
+    session_id: Option<String>,
+) -> Result<(), String> {
+    let state = state.session(session_id.as_deref());
+    let mut queue = state.0.lock().await;
+
+    // Cancel the current processing item
+    if let Some(processing_id) = queue.processing.take() {
+        queue.forget_folder_item(&processing_id);
+        queue.mark_failed(&processing_id, TransferError::Cancelled.to_string());
+        queue.initialized_files.remove(&processing_id);
+        queue.initialized_folders.remove(&processing_id);
+        queue.completion_notifications_sent.remove(&processing_id);
+        queue.received_url_responses.remove(&processing_id);
+        queue.received_folder_responses.remove(&processing_id);
+        queue.request_timestamps.remove(&processing_id);
+    }
+
+    // Fix the mutable borrow issue by collecting IDs first
+    let item_ids: Vec<String> = queue.items.iter().map(|item| item.id.clone()).collect();
+    queue.items.clear();
+    queue.pending_folders.clear(); // Clear pending folders too
+    queue.deferred_scans.clear();
+    for id in &item_ids {
+        queue.forget_folder_item(id);
+    }
+    queue.folder_progress.clear();
+    queue.prescanned.clear();
+    queue.prescan_generation += 1;
+    for cancelled in queue.active_scans.values() {
+        cancelled.store(true, Ordering::Relaxed);
+    }
+
+    // Then insert them into the failed map and clean up all tracking
+    for id in item_ids {
+        queue.mark_failed(&id, TransferError::Cancelled.to_string());
+        queue.initialized_files.remove(&id);
+        queue.initialized_folders.remove(&id);
+        queue.completion_notifications_sent.remove(&id);
+        queue.received_url_responses.remove(&id);
+        queue.received_folder_responses.remove(&id);
+        queue.request_timestamps.remove(&id);
+    }
+
+    // Clear block completion tracking
+    queue.block_completion_sent.clear();
+    queue.server_file_ids.clear();
+    queue.hash_progress.clear();
+    queue.upload_contexts.clear();
+
+    // Cancelled items may be queued again; only completed ones stay deduplicated
+    let queue = &mut *queue;
+    queue
+        .dedup_keys
+        .retain(|_, item_id| queue.completed.contains(item_id));
+
+    Ok(())
+}
+
+/// Cancels everything still queued but lets the transfer in progress finish
+/// and finalize, after which the queue goes idle. A folder being created
+/// still has its contents queued once it exists. Returns how many items were
+/// cancelled.
+#[command]
+pub async fn cancel_queued_transfers(
+    state: State<'_, TransferManagerState>,
+    session_id: Option<String>,
+) -> Result<usize, String> {
+    let state = state.session(session_id.as_deref());
+    let cancelled = state.0.lock().await.cancel_queued();
+
+    println!("Cancelled {} queued transfers", cancelled);
+
+    Ok(cancelled)
+}
+
+/// Moves a queued transfer into another priority class. For a folder, its
+/// queued contents move with it. Returns how many queued items changed.
+#[command]
+pub async fn set_transfer_priority(
+    transfer_id: String,
+    priority: Priority,
+    state: State<'_, TransferManagerState>,
+    session_id: Option<String>,
+) -> Result<usize, String> {
+    let state = state.session(session_id.as_deref());
+    state
+        .0
+        .lock()
+        .await
+        .set_priority(&transfer_id, priority)
+        .ok_or_else(|| format!("Transfer not found in queue: {}", transfer_id))
+}
+
+/// Pauses all ongoing transfers
+#[command]
+pub async fn pause_transfers(
+    state: State<'_, TransferManagerState>,
+    session_id: Option<String>,
+) -> Result<(), String> {
+    let state = state.session(session_id.as_deref());
+    let mut queue = state.0.lock().await;
+    queue.paused = true;
+    Ok(())
+}
+
+/// Lets the transfer in progress finish normally, then stops the queue before
+/// the next item starts
+#[command]
+pub async fn pause_after_current(
+    state: State<'_, TransferManagerState>,
+    session_id: Option<String>,
+) -> Result<(), String> {
+    let state = state.session(session_id.as_deref());
+    let mut queue = state.0.lock().await;
+    queue.pause_after_current = true;
+    Ok(())
+}
+
+/// Resumes transfers after either kind of pause
+#[command]
+pub async fn resume_transfers(
+    app: AppHandle,
+    state: State<'_, TransferManagerState>,
+    session_id: Option<String>,
+) -> Result<(), String> {
+    let state = state.session(session_id.as_deref());
+    {
+        let mut queue = state.0.lock().await;
+
+        // The queue stays stopped once the app has started exiting
+        if queue.shutting_down {
+            return Ok(());
+        }
+
+        queue.paused = false;
+        queue.pause_after_current = false;
+
+        // A processing item left over from an interrupted run would block the queue
+        let waiting = match queue.processing.clone() {
+            Some(id) => is_waiting_on_frontend(&id).await,
+            None => false,
+        };
+        if let Some(id) = queue.reset_orphaned_processing(waiting) {
+            println!("Reset orphaned processing state for {}", id);
+        }
+
+        // Only start processing if nothing is currently processing
+        if queue.processing.is_none() && !queue.items.is_empty() {
+            drop(queue); // Release the lock before starting process
+            process_next_item(events(&app), state).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the current status of the transfer queue
+#[command]
+pub async fn get_queue_status(
+    state: State<'_, TransferManagerState>,
+    session_id: Option<String>,
+) -> Result<serde_json::Value, String> {
+    let state = state.session(session_id.as_deref());
+    let queue = state.0.lock().await;
+
+    let result = serde_json::json!({
+        "queue_size": queue.items.len(),
+        "processing": queue.processing,
+        "completed": queue.completed.len(),
+        "failed": queue.failed.len(),
+        "paused": queue.is_paused(),
+        "pause_mode": queue.pause_mode(),
+        "elapsedTime": queue.start_time.elapsed().as_secs(),
+        "pending_folders": queue.pending_folders.len(),
+        "max_queue_size": queue.max_queue_size,
+        "backpressure": !queue.deferred_scans.is_empty(),
+        "deferred_scans": queue.deferred_scans.len(),
+        "scanning": queue.scan_progress
+    });
+
+    Ok(result)
+}
+
+/// Stops every session's queue for app exit: no new items or blocks are
+/// started, anything waiting on the frontend is cancelled and the remaining
+/// queues are saved to disk
+pub async fn shutdown(app: &AppHandle) {
+    let mut snapshots = Vec::new();
+    for (session_id, state) in app.state::<TransferManagerState>().sessions() {
+        let mut queue = state.0.lock().await;
+
+        if queue.shutting_down {
+            continue;
+        }
+
+        // Block loops check `paused` before every block, so they stop at the next boundary
+        queue.shutting_down = true;
+        queue.paused = true;
+
+        let snapshot = queue.snapshot(&session_id);
+        snapshots.push((session_id, snapshot));
+    }
+
+    if snapshots.is_empty() {
+        return;
+    }
+
+    // Resolve every request still waiting on the frontend
+    let cancelled = "Cancelled: application is shutting down".to_string();
+    for (_, sender) in RESPONSE_CHANNELS.lock().await.drain() {
+        let _ = sender.send(Err(cancelled.clone()));
+    }
+    for (_, sender) in FOLDER_RESPONSE_CHANNELS.lock().await.drain() {
+        let _ = sender.send(Err(cancelled.clone()));
+    }
+
+    let store_path = match app.path().app_data_dir() {
+        Ok(dir) => dir.join("store"),
+        Err(e) => {
+            println!("Failed to get app data directory: {}", e);
+            return;
+        }
+    };
+    for (session_id, snapshot) in snapshots {
+        match save_queue_snapshot(&store_path, &session_id, &snapshot) {
+            Ok(path) => println!(
+                "Saved {} queued transfers to {:?}",
+                snapshot.items.len(),
+                path
+            ),
+            Err(e) => println!("Failed to save transfer queue: {}", e),
+        }
+    }
+}
+
+/// Writes a session's queue snapshot into the store directory. The main
+/// session keeps the original file name.
+fn save_queue_snapshot(
+    store_path: &Path,
+    session_id: &str,
+    snapshot: &QueueSnapshot,
+) -> Result<PathBuf, String> {
+    std::fs::create_dir_all(store_path)
+        .map_err(|e| format!("Failed to create store directory: {}", e))?;
+
+    let file_path = if session_id == DEFAULT_SESSION {
+        store_path.join(QUEUE_SNAPSHOT_FILE)
+    } else {
+        // Window labels may contain characters that aren't safe in file names
+        let safe_id: String = session_id
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect();
+        store_path.join(format!("transfer_queue.{}.json", safe_id))
+    };
+    let contents = serde_json::to_string_pretty(snapshot)
+        .map_err(|e| format!("Failed to serialize transfer queue: {}", e))?;
+    std::fs::write(&file_path, contents)
+        .map_err(|e| format!("Failed to write transfer queue: {}", e))?;
+
+    Ok(file_path)
+}
+
+/// Puts the queues saved at the last shutdown back into their sessions. They
+/// come back paused, since the frontend isn't listening yet; resume_transfers
+/// starts them. Each snapshot file is removed once it has been read.
+pub async fn restore_queue_snapshots(store_path: &Path, state: &TransferManagerState) {
+    let Ok(entries) = std::fs::read_dir(store_path) else {
+        return;
+    };
+
+    for entry in entries.flatten() {
+        let file_name = entry.file_name().to_string_lossy().into_owned();
+        if !(file_name.starts_with("transfer_queue.") && file_name.ends_with(".json")) {
+            continue;
+        }
+
+        let path = entry.path();
+        let snapshot = std::fs::read_to_string(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|contents| {
+                serde_json::from_str::<QueueSnapshot>(&contents).map_err(|e| e.to_string())
+            });
+        // A snapshot that can't be read won't get better on the next launch either
+        let _ = std::fs::remove_file(&path);
+
+        let snapshot = match snapshot {
+            Ok(snapshot) => snapshot,
+            Err(e) => {
+                println!("Failed to load transfer queue {:?}: {}", path, e);
+                continue;
+            }
+        };
+        let session_id = match snapshot.session_id.as_str() {
+            "" => DEFAULT_SESSION.to_string(),
+            id => id.to_string(),
+        };
+
+        let session = state.session(Some(&session_id));
+        let restored = session.0.lock().await.restore(snapshot);
+        println!(
+            "Restored {} queued transfers for session {}",
+            restored, session_id
+        );
+    }
+}
+
+/// Deletes the upload checkpoints no queued item can resume, such as those of
+/// files changed or cancelled since. Run at startup, after the saved queues
+/// are restored.
+pub async fn remove_stale_checkpoints(state: &TransferManagerState) {
+    // Checkpoint directory -> checkpoints still wanted in it
+    let mut wanted: HashMap<PathBuf, HashSet<PathBuf>> = HashMap::new();
+    state.session(None); // The main session's directory is cleaned even with nothing queued
+    for (_, session) in state.sessions() {
+        let queue = session.0.lock().await;
+        let dir = queue.checkpoint_dir();
+        let paths: Vec<PathBuf> = queue
+            .items
+            .iter()
+            .filter_map(|item| UploadCheckpoint::path(&dir, Path::new(&item.path)))
+            .collect();
+        wanted.entry(dir).or_default().extend(paths);
+    }
+
+    for (dir, paths) in wanted {
+        let Ok(entries) = std::fs::read_dir(&dir) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if !paths.contains(&path) {
+                println!("Removing stale upload checkpoint {:?}", path);
+                let _ = std::fs::remove_file(&path);
+            }
+        }
+    }
+}
+
+/// Limits how long one file may take from start to finish before it fails
+/// with `file_timeout`. Pass None to remove the limit.
+#[command]
+pub async fn set_per_file_timeout(
+    app: AppHandle,
+    seconds: Option<u64>,
+    state: State<'_, TransferManagerState>,
+) -> Result<(), String> {
+    update_transfer_settings(&transfer_store_path(&app)?, &state, |settings| {
+        settings.per_file_timeout_seconds = seconds
+    })
+    .await
+}
+
+/// Caps how many items the queue holds before folder scanning pauses.
+/// Pass None to remove the cap.
+#[command]
+pub async fn set_max_queue_size(
+    app: AppHandle,
+    max_queue_size: Option<usize>,
+    state: State<'_, TransferManagerState>,
+) -> Result<(), String> {
+    update_transfer_settings(&transfer_store_path(&app)?, &state, |settings| {
+        settings.max_queue_size = max_queue_size
+    })
+    .await
+}
+
+/// Turns concurrent pre-scanning of selected folder trees on or off
+#[command]
+pub async fn set_folder_prescan(
+    app: AppHandle,
+    enabled: bool,
+    state: State<'_, TransferManagerState>,
+) -> Result<(), String> {
+    update_transfer_settings(&transfer_store_path(&app)?, &state, |settings| {
+        settings.prescan_folders = enabled
+    })
+    .await
+}
+
+/// Turns skipping of lock, temp and OS metadata files on or off. Empty files
+/// are always skipped.
+#[command]
+pub async fn set_skip_temp_files(
+    app: AppHandle,
+    enabled: bool,
+    state: State<'_, TransferManagerState>,
+) -> Result<(), String> {
+    update_transfer_settings(&transfer_store_path(&app)?, &state, |settings| {
+        settings.skip_temp_files = enabled
+    })
+    .await
+}
+
+/// Turns perceptual hashing of images on or off. When on, images small enough
+/// to get a thumbnail carry a `perceptual_hash` in their init request so the
+/// server can flag near-duplicates, at the cost of decoding them earlier.
+#[command]
+pub async fn set_perceptual_hashing(
+    app: AppHandle,
+    enabled: bool,
+    state: State<'_, TransferManagerState>,
+) -> Result<(), String> {
+    update_transfer_settings(&transfer_store_path(&app)?, &state, |settings| {
+        settings.perceptual_hashes = enabled
+    })
+    .await
+}
+
+/// Caps how many block and thumbnail uploads run at once across all transfers
+#[command]
+pub async fn set_connection_limit(
+    app: AppHandle,
+    limit: usize,
+    state: State<'_, TransferManagerState>,
+) -> Result<(), String> {
+    update_transfer_settings(&transfer_store_path(&app)?, &state, |settings| {
+        settings.connection_limit = limit
+    })
+    .await
+}
+
+/// Sets the largest server block size an upload accepts, up to MAX_BLOCK_SIZE
+#[command]
+pub async fn set_block_size_limit(
+    app: AppHandle,
+    bytes: u64,
+    state: State<'_, TransferManagerState>,
+) -> Result<(), String> {
+    update_transfer_settings(&transfer_store_path(&app)?, &state, |settings| {
+        settings.block_size_limit = bytes
+    })
+    .await
+}
+
+/// Counters across all transfers since launch or the last reset_transfer_metrics
+#[command]
+pub async fn get_transfer_metrics(
+    state: State<'_, TransferManagerState>,
+    session_id: Option<String>,
+) -> Result<TransferMetricsSnapshot, String> {
+    let state = state.session(session_id.as_deref());
+    Ok(state.2.snapshot())
+}
+
+/// Zeroes the counters returned by get_transfer_metrics
+#[command]
+pub async fn reset_transfer_metrics(
+    state: State<'_, TransferManagerState>,
+    session_id: Option<String>,
+) -> Result<(), String> {
+    let state = state.session(session_id.as_deref());
+    state.2.reset();
+    Ok(())
+}
+
+/// Removes the cached server folder ID for a local folder and its subfolders,
+/// so the next upload into that path requests a fresh folder. Returns how many
+/// mappings were removed.
+#[command]
+pub async fn invalidate_folder_mapping(
+    path: String,
+    state: State<'_, TransferManagerState>,
+    session_id: Option<String>,
+) -> Result<usize, String> {
+    let state = state.session(session_id.as_deref());
+    let mut queue = state.0.lock().await;
+    let removed = queue.forget_folder_path(&path);
+    println!("Removed {} folder mappings under {}", removed, path);
+    Ok(removed)
+}
+
+/// Forgets completed, failed and skipped transfers, leaving queued and
+/// in-progress ones alone. Returns how many were cleared.
+#[command]
+pub async fn clear_transfer_history(
+    state: State<'_, TransferManagerState>,
+    session_id: Option<String>,
+) -> Result<usize, String> {
+    let state = state.session(session_id.as_deref());
+    let mut queue = state.0.lock().await;
+
+    let finished: HashSet<String> = queue
+        .completed
+        .iter()
+        .chain(queue.failed.keys())
+        .chain(queue.skipped.keys())
+        .cloned()
+        .collect();
+    for id in &finished {
+        queue.forget_finished(id);
+    }
+
+    // Items queued again keep their history entry
+    let queue = &mut *queue;
+    let is_finished = |id: &String| {
+        queue.completed.contains(id)
+            || queue.failed.contains_key(id)
+            || queue.skipped.contains_key(id)
+    };
+    let cleared = finished.iter().filter(|id| !is_finished(id)).count();
+    queue.history.retain(|id| is_finished(id));
+
+    // Block keys of files that are no longer uploading
+    let in_flight: Vec<String> = queue.server_file_ids.values().cloned().collect();
+    queue.block_completion_sent.retain(|key| {
+        in_flight
+            .iter()
+            .any(|file_id| key.starts_with(&format!("{}:", file_id)))
+    });
+
+    println!("Cleared {} finished transfers from history", cleared);
+
+    Ok(cleared)
+}
+
+/// Keeps at most `max_history` finished transfers, evicting the oldest.
+/// Pass None to keep everything.
+#[command]
+pub async fn set_max_history(
+    app: AppHandle,
+    max_history: Option<usize>,
+    state: State<'_, TransferManagerState>,
+) -> Result<(), String> {
+    update_transfer_settings(&transfer_store_path(&app)?, &state, |settings| {
+        settings.max_history = max_history
+    })
+    .await
+}
+
+/// Tunes how upload speed and ETA are smoothed. `smoothing_factor` is the
+/// weight of each new sample (0-1, lower is steadier), `window` the number of
+/// block speeds averaged per sample, and `stall_seconds` how long a block may
+/// take before the transfer is reported as stalled.
+#[command]
+pub async fn set_progress_smoothing(
+    smoothing_factor: Option<f64>,
+    window: Option<usize>,
+    stall_seconds: Option<u64>,
+    state: State<'_, TransferManagerState>,
+    session_id: Option<String>,
+) -> Result<(), String> {
+    let state = state.session(session_id.as_deref());
+    let mut queue = state.0.lock().await;
+    let settings = &mut queue.progress_smoothing;
+
+    if let Some(factor) = smoothing_factor {
+        if !(factor > 0.0 && factor <= 1.0) {
+            return Err("Smoothing factor must be between 0 and 1".to_string());
+        }
+        settings.factor = factor;
+    }
+    if let Some(window) = window {
+        if window == 0 {
+            return Err("Speed window must be at least 1".to_string());
+        }
+        settings.window = window;
+    }
+    if let Some(stall_seconds) = stall_seconds {
+        if stall_seconds == 0 {
+            return Err("Stall timeout must be at least 1 second".to_string());
+        }
+        settings.stall_after = Duration::from_secs(stall_seconds);
+    }
+
+    Ok(())
+}
+
+/// Turns OS notifications for finished transfers on or off
+#[command]
+pub async fn set_notifications_enabled(
+    app: AppHandle,
+    enabled: bool,
+    notify_each_failure: Option<bool>,
+    state: State<'_, TransferManagerState>,
+) -> Result<(), String> {
+    update_transfer_settings(&transfer_store_path(&app)?, &state, |settings| {
+        settings.notifications_enabled = enabled;
+        if let Some(notify_each_failure) = notify_each_failure {
+            settings.notify_each_failure = notify_each_failure;
+        }
+    })
+    .await
+}
+
+/// Shows a native OS notification, logging rather than failing if it can't
+fn show_notification(app: &dyn EventSink, title: &str, body: &str) {
+    if let Err(e) = app.notify(title, body) {
+        println!("Failed to show notification: {}", e);
+    }
+}
+
+/// Summarizes a drained batch in an OS notification
+fn notify_batch_finished(app: &dyn EventSink, completed: usize, failed: usize) {
+    let plural = |count: usize| if count == 1 { "" } else { "s" };
+
+    let (title, body) = match (completed, failed) {
+        (0, 0) => return,
+        (completed, 0) => (
+            "Uploads complete".to_string(),
+            format!("{} item{} uploaded", completed, plural(completed)),
+        ),
+        (0, failed) => (
+            "Uploads failed".to_string(),
+            format!("{} item{} failed to upload", failed, plural(failed)),
+        ),
+        (completed, failed) => (
+            "Uploads finished with errors".to_string(),
+            format!(
+                "{} item{} uploaded, {} failed",
+                completed,
+                plural(completed),
+                failed
+            ),
+        ),
+    };
+
+    show_notification(app, &title, &body);
+}
+
+/// Finalizes a transfer after content update is complete
+#[command]
+pub async fn finalize_transfer_complete(
+    transfer_id: String,
+    file_id: String,
+    parent_id: String,
+    success: bool,
+    error: Option<String>,
+    app: AppHandle,
+    session_id: Option<String>,
+) -> Result<(), String> {
+    let state = app
+        .state::<TransferManagerState>()
+        .session(session_id.as_deref());
+    complete_transfer(
+        events(&app),
+        state,
+        transfer_id,
+        file_id,
+        parent_id,
+        success,
+        error,
+    )
+    .await
+}
+
+/// Records a finalized file as completed and moves on to the next item
+async fn complete_transfer(
+    app: Events,
+    state: TransferSession,
+    transfer_id: String,
+    file_id: String,
+    parent_id: String,
+    success: bool,
+    error: Option<String>,
+) -> Result<(), String> {
+    let item_name;
+    let checkpoint_path;
+
+    {
+        let mut queue = state.0.lock().await;
+
+        // Check if this transfer is already completed
+        if queue.completed.contains(&transfer_id) {
+            println!(
+                "Transfer {} already completed, skipping finalization",
+                transfer_id
+            );
+            return Ok(());
+        }
+
+        // Try to get the item name
+        item_name = queue
+            .items
+            .iter()
+            .find(|item| item.id == transfer_id)
+            .map(|item| item.name.clone())
+            .unwrap_or_else(|| "Unknown".to_string());
+
+        // Remove the timestamp tracking for this transfer
+        queue.request_timestamps.remove(&transfer_id);
+        checkpoint_path = queue.checkpoints.remove(&transfer_id);
+    }
+
+    // The upload is done either way, so there is nothing left to resume
+    if let Some(checkpoint_path) = checkpoint_path {
+        let _ = tokio::fs::remove_file(checkpoint_path).await;
+    }
+
+    // If content update was successful or we're allowing failures
+    if success {
+        println!("Content update successful for transfer ID: {}", transfer_id);
+
+        // Send transfer complete event
+        state
+            .emit(
+                &app,
+                "transfer-complete",
+                serde_json::json!({
+                    "id": transfer_id,
+                    "name": item_name,
+                    "file_id": file_id,
+                    "parent_id": parent_id,
+                    "status": "completed",
+                    "message": "Upload complete and verified"
+                }),
+            )
+            .map_err(|e| format!("Failed to emit completion: {}", e))?;
+    } else {
+        println!(
+            "Content update failed for transfer ID: {}, but continuing",
+            transfer_id
+        );
+
+        // Send transfer complete with warning
+        let error_message = error.unwrap_or_else(|| "Content update failed".to_string());
+        state.emit(&app,
+            "transfer-complete",
+            serde_json::json!({
+                "id": transfer_id,
+                "name": item_name,
+                "file_id": file_id,
+                "parent_id": parent_id,
+                "status": "completed",
+                "message": format!("Upload complete, but verification failed: {}", error_message)
+            }),
+        )
+        .map_err(|e| format!("Failed to emit completion: {}", e))?;
+    }
+
+    // Mark as completed in state
+    let updated_folders = {
+        let mut queue = state.0.lock().await;
+
+        queue.processing = None;
+        queue.mark_completed(&transfer_id);
+        TransferMetrics::count(&state.2.files_completed);
+
+        // Count the file towards the folders it was uploaded into
+        let file_size = queue
+            .current_item
+            .as_ref()
+            .filter(|item| item.id == transfer_id)
+            .and_then(|item| match &item.data {
+                Some(data) => Some(data.len() as u64),
+                None => std::fs::metadata(&item.path)
+                    .ok()
+                    .map(|metadata| metadata.len()),
+            })
+            .unwrap_or(0);
+        let updated_folders = queue.update_folder_ancestors(&parent_id, |folder| {
+            folder.uploaded_bytes += file_size;
+            folder.completed_items += 1;
+        });
+
+        // Clean up any other tracking for this transfer
+        queue.initialized_files.remove(&transfer_id);
+        queue.initialized_folders.remove(&transfer_id);
+        queue.completion_notifications_sent.remove(&transfer_id);
+        queue.received_url_responses.remove(&transfer_id);
+        queue.received_folder_responses.remove(&transfer_id);
+
+        // Clean up any block tracking related to this file ID
+        queue.server_file_ids.remove(&transfer_id);
+        queue.clear_block_tracking(&file_id);
+
+        updated_folders
+    };
+
+    emit_folder_progress(&app, &state, updated_folders)?;
+
+    // Continue with next item if available, each in its own share
+    process_next_item(app.clone(), state).await?;
+
+    Ok(())
+}
+
+/// Processes the next item in the queue
+fn process_next_item(
+    app: Events,
+    state: TransferSession,
+) -> Pin<Box<dyn Future<Output = Result<(), String>> + Send>> {
+    Box::pin(async move {
+        // Refill the queue from folders deferred while it was full
+        resume_deferred_scans(&app, &state).await;
+
+        // Get the next item from queue
+        let next_item = {
+            let mut queue = state.0.lock().await;
+
+            // Check if queue is paused, by either kind of pause, or the app is exiting
+            if queue.is_paused() || queue.shutting_down {
+                return Ok(());
+            }
+
+            // Check if already processing something
+            if queue.processing.is_some() {
+                return Ok(());
+            }
+
+            // Check for any hanging requests and clear them
+            let current_time = Instant::now();
+
+            // First collect IDs to remove to avoid borrowing issues
+            let mut ids_to_remove = Vec::new();
+            for (id, timestamp) in &queue.request_timestamps {
+                if current_time.duration_since(*timestamp) > Duration::from_secs(35) {
+                    println!("Detected hanging request for ID: {}, cleaning up", id);
+                    ids_to_remove.push(id.clone());
+                }
+            }
+
+            // Then process them outside the iteration loop
+            for id in &ids_to_remove {
+                // Clean up any pending channels
+                let timed_out = "Request timed out".to_string();
+                deliver_response(&RESPONSE_CHANNELS, id, Err(timed_out.clone())).await;
+                deliver_response(&FOLDER_RESPONSE_CHANNELS, id, Err(timed_out)).await;
+
+                // Remove tracking for this ID
+                queue.request_timestamps.remove(id);
+                queue.received_url_responses.remove(id);
+                queue.received_folder_responses.remove(id);
+            }
+
+            // SIMPLIFIED SEQUENTIAL PROCESSING LOGIC:
+            // 1. If there's a folder at the front of the queue, process it
+            // 2. Only process items whose parent folders have been created
+
+            if queue.items.is_empty() {
+                // The batch just drained; tell the UI, and the user if they're elsewhere
+                let summary = queue.finish_batch();
+                let notifications_enabled = queue.notifications_enabled;
+                drop(queue);
+
+                if let Some(summary) = summary {
+                    let _ = state.emit(&app, "queue-idle", summary);
+                    if notifications_enabled {
+                        notify_batch_finished(&app, summary.completed, summary.failed);
+                    }
+                }
+
+                return Ok(()); // Nothing to process
+            }
+
+            match queue.take_next_item() {
+                Some(item) => item,
+                None => return Ok(()), // Nothing we can process right now
+            }
+        };
+
+        {
+            let mut queue = state.0.lock().await;
+            queue.current_item = Some(next_item.clone());
+            queue.last_activity = Some(Instant::now());
+        }
+
+        // Process the item if we got one
+        match next_item.item_type.as_str() {
+            "file" => {
+                let per_file_timeout = state.0.lock().await.per_file_timeout;
+                let upload = process_file(app.clone(), state.clone(), next_item.clone());
+                let result = match per_file_timeout {
+                    Some(limit) => tokio::select! {
+                        result = upload => result,
+                        _ = tokio::time::sleep(limit) => {
+                            // The upload was dropped mid-way, so nothing will
+                            // answer or remove its pending requests
+                            RESPONSE_CHANNELS.lock().await.remove(&next_item.id);
+                            MULTIPART_RESPONSE_CHANNELS.lock().await.remove(&next_item.id);
+                            state.0.lock().await.hash_progress.remove(&next_item.id);
+                            Err(TransferError::FileTimeout(format!(
+                                "Upload took longer than {} seconds",
+                                limit.as_secs()
+                            )))
+                        }
+                    },
+                    None => upload.await,
+                };
+
+                if let Err(err) = result {
+                    println!("Error processing file: {}", err);
+                    // Handle the error, update state, but don't return the error - continue processing.
+                    // Most failures were already reported where they happened.
+                    let reported = state.0.lock().await.failed.contains_key(&next_item.id);
+                    if !reported {
+                        let _ = handle_file_error(
+                            &app,
+                            &state,
+                            &next_item.id,
+                            &next_item.name,
+                            &None::<u64>,
+                            &err,
+                        )
+                        .await;
+                    }
+                }
+            }
+            "folder" => {
+                if let Err(err) =
+                    process_folder(app.clone(), state.clone(), next_item.clone()).await
+                {
+                    println!("Error processing folder: {}", err);
+                    // Handle the error, update state, but don't return the error - continue processing.
+                    // Most failures were already reported where they happened.
+                    let reported = state.0.lock().await.failed.contains_key(&next_item.id);
+                    if !reported {
+                        let _ =
+                            handle_folder_error(&app, &state, &next_item.id, &next_item.name, &err)
+                                .await;
+                    }
+
+                    // Also remove the folder from pending
+                    let mut queue = state.0.lock().await;
+                    queue.pending_folders.remove(&next_item.path);
+                }
+            }
+            _ => {
+                println!("Unknown item type: {}", next_item.item_type);
+            }
+        }
+
+        // Continue with next item regardless of errors
+        process_next_item(app, state).await?;
+
+        Ok(())
+    })
+}
+
+/// Why a file should be skipped based on its name alone, if it should be
+fn skip_reason_for_name(name: &str) -> Option<SkipReason> {
+    let lower = name.to_lowercase();
+
+    if LOCK_FILE_PREFIXES
+        .iter()
+        .any(|prefix| name.starts_with(prefix))
+    {
+        Some(SkipReason::Locked)
+    } else if TEMP_FILE_SUFFIXES
+        .iter()
+        .any(|suffix| lower.ends_with(suffix))
+    {
+        Some(SkipReason::TempPattern)
+    } else if FILTERED_FILE_NAMES
+        .iter()
+        .any(|filtered| filtered.eq_ignore_ascii_case(name))
+    {
+        Some(SkipReason::Filtered)
+    } else {
+        None
+    }
+}
+
+/// Why a file about to be uploaded should be skipped, if it should be. `path`
+/// is None for uploads from memory, which can't be locked. process_file and
+/// would_upload both go through this, so they can't disagree.
+fn file_skip_reason(
+    name: &str,
+    path: Option<&Path>,
+    file_size: u64,
+    skip_temp_files: bool,
+) -> Option<SkipReason> {
+    if file_size == 0 {
+        Some(SkipReason::Empty)
+    } else if path.is_some_and(is_locked) {
+        Some(SkipReason::Locked)
+    } else if skip_temp_files {
+        skip_reason_for_name(name)
+    } else {
+        None
+    }
+}
+
+/// What would happen to a path passed to select_files or select_folders,
+/// as reported by would_upload
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum UploadCheck {
+    Accepted,
+    NotFound,  // Missing, or neither a file nor a folder
+    Duplicate, // The same file or folder is already queued, uploading or uploaded
+    #[serde(untagged)]
+    Skipped(SkipReason), // Left out with `transfer-skipped` for this reason
+}
+
+/// Reports whether selecting `path` would upload it, and if not why, using
+/// the same checks and session settings a real upload goes through. Nothing
+/// is queued. Files inside a selected folder are checked the same way as
+/// selected files, except that they are never duplicates.
+#[command]
+pub async fn would_upload(
+    path: String,
+    state: State<'_, TransferManagerState>,
+    session_id: Option<String>,
+) -> Result<UploadCheck, String> {
+    let state = state.session(session_id.as_deref());
+    let path = PathBuf::from(path);
+    if !path.is_dir() && !path.is_file() {
+        return Ok(UploadCheck::NotFound);
+    }
+
+    let skip_temp_files = {
+        let queue = state.0.lock().await;
+        if dedup_key(&path).is_some_and(|key| queue.find_duplicate(&key).is_some()) {
+            return Ok(UploadCheck::Duplicate);
+        }
+        queue.skip_temp_files
+    };
+    if path.is_dir() {
+        return Ok(UploadCheck::Accepted);
+    }
+
+    let file_size = tokio::fs::metadata(&path)
+        .await
+        .map_err(|e| format!("Failed to read file metadata: {}", e))?
+        .len();
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or("unknown");
+
+    Ok(
+        match file_skip_reason(name, Some(&path), file_size, skip_temp_files) {
+            Some(reason) => UploadCheck::Skipped(reason),
+            None => UploadCheck::Accepted,
+        },
+    )
+}
+
+/// Whether another program holds the file open exclusively. Only Windows
+/// enforces this; elsewhere locks are advisory and reads always succeed.
+fn is_locked(path: &Path) -> bool {
+    #[cfg(windows)]
+    {
+        // ERROR_SHARING_VIOLATION and ERROR_LOCK_VIOLATION
+        matches!(
+            std::fs::File::open(path).map_err(|e| e.raw_os_error()),
+            Err(Some(32 | 33))
+        )
+    }
+    #[cfg(not(windows))]
+    {
+        let _ = path;
+        false
+    }
+}
+
+/// Marks a file as skipped and reports it with `transfer-skipped`, which the
+/// UI counts separately from failures
+async fn skip_file(
+    app: &dyn EventSink,
+    state: &TransferSession,
+    item: &QueueItem,
+    file_size: u64,
+    reason: SkipReason,
+) -> Result<(), String> {
+    println!("Skipping {} ({:?})", item.path, reason);
+
+    let updated_folders = state.0.lock().await.record_skipped(&item.id, reason);
+    emit_folder_progress(app, state, updated_folders)?;
+
+    state
+        .emit(
+            app,
+            "transfer-skipped",
+            serde_json::json!({
+                "id": item.id,
+                "name": item.name,
+                "path": item.path,
+                "parent_id": item.parent_id,
+                "size": file_size,
+                "reason": reason,
+            }),
+        )
+        .map_err(|e| format!("Failed to emit skipped event: {}", e))
+}
+
+/// Checks that the server sent one upload URL per block of the file. Too few
+/// would finalize a truncated file; too many would read past the end of it.
+fn check_url_count(file_size: u64, block_size: u64, url_count: usize) -> Result<(), TransferError> {
+    if block_size == 0 {
+        return Err(TransferError::UrlCountMismatch(
+            "Server returned a block size of 0".to_string(),
+        ));
+    }
+
+    let expected = file_size.div_ceil(block_size);
+    if url_count as u64 != expected {
+        return Err(TransferError::UrlCountMismatch(format!(
+            "Server returned {} upload URLs but the file needs {} blocks",
+            url_count, expected
+        )));
+    }
+
+    Ok(())
+}
+
+/// Fails when a server block size is over the limit, or over MAX_BLOCK_SIZE
+/// whatever the limit, before anything that size is allocated
+fn check_block_size(block_size: u64, limit: u64) -> Result<(), TransferError> {
+    let limit = limit.min(MAX_BLOCK_SIZE);
+    if block_size > limit {
+        return Err(TransferError::BlockTooLarge(format!(
+            "Server block size of {} bytes is over the {} byte limit",
+            block_size, limit
+        )));
+    }
+
+    Ok(())
+}
+
+/// Reads `len` bytes at `offset` into one buffer of the whole block, feeding
+/// the hasher in READ_CHUNK_SIZE pieces as they arrive. Each block is sealed
+/// with AES-GCM in one call, so the whole block has to be in memory before it
+/// is encrypted; the block size cap is what bounds this buffer.
+async fn read_block(
+    file: &mut Box<dyn BlockSource>,
+    offset: u64,
+    len: usize,
+    mut hasher: Option<&mut ContentHasher>,
+) -> Result<Vec<u8>, TransferError> {
+    file.seek(std::io::SeekFrom::Start(offset))
+        .await
+        .map_err(|e| TransferError::Io(format!("Failed to seek in file: {}", e)))?;
+
+    let mut buffer = Vec::with_capacity(len);
+    let mut chunk = vec![0u8; READ_CHUNK_SIZE.min(len)];
+    while buffer.len() < len {
+        let piece = &mut chunk[..READ_CHUNK_SIZE.min(len - buffer.len())];
+        file.read_exact(piece)
+            .await
+            .map_err(|e| TransferError::Io(format!("Failed to read file block: {}", e)))?;
+        if let Some(hasher) = hasher.as_deref_mut() {
+            hasher.update(piece);
+        }
+        buffer.extend_from_slice(piece);
+    }
+
+    Ok(buffer)
+}
+
+/// Content hasher that takes blocks strictly in index order, however they are
+/// uploaded. Blocks that go up ahead of the next one in order are hashed by
+/// `finish` reading just the bytes not hashed yet. The state lives only in
+/// memory, since the sha2 and blake3 hashers can't be saved, so after a
+/// resume `finish` reads the file again from the start.
+struct OrderedHasher {
+    hasher: ContentHasher,
+    block_size: u64,
+    next_index: usize, // Blocks before this one have been hashed
+}
+
+impl OrderedHasher {
+    fn new(algorithm: HashAlgorithm, block_size: u64) -> Self {
+        Self {
+            hasher: ContentHasher::new(algorithm),
+            block_size,
+            next_index: 0,
+        }
+    }
+
+    /// The hasher to read block `index` into, if it is the next one in order
+    fn for_block(&mut self, index: usize) -> Option<&mut ContentHasher> {
+        if index != self.next_index {
+            return None;
+        }
+        self.next_index += 1;
+        Some(&mut self.hasher)
+    }
+
+    /// Bytes of the file hashed so far
+    fn hashed_bytes(&self, file_size: u64) -> u64 {
+        (self.next_index as u64 * self.block_size).min(file_size)
+    }
+
+    /// Hashes the rest of the file in order and returns the digest as hex
+    async fn finish(
+        mut self,
+        file: &mut Box<dyn BlockSource>,
+        file_size: u64,
+    ) -> Result<String, TransferError> {
+        let mut offset = self.hashed_bytes(file_size);
+        while offset < file_size {
+            let len = self.block_size.min(file_size - offset);
+            read_block(file, offset, len as usize, Some(&mut self.hasher)).await?;
+            offset += len;
+        }
+
+        Ok(self.hasher.finalize_hex())
+    }
+}
+
+/// Opens an item's file, or reads straight from memory for in-memory uploads
+async fn open_block_source(item: &QueueItem) -> std::io::Result<Box<dyn BlockSource>> {
+    match &item.data {
+        Some(data) => Ok(Box::new(Cursor::new(data.clone()))),
+        None => File::open(&item.path)
+            .await
+            .map(|file| Box::new(file) as Box<dyn BlockSource>),
+    }
+}
+
+/// AES-GCM nonce for a block: its index, big-endian, in the first bytes
+fn block_nonce(index: usize) -> [u8; 12] {
+    let mut nonce_bytes = [0u8; 12]; // AES-GCM requires a 12-byte nonce
+    let index_bytes = index.to_be_bytes();
+    let len = std::cmp::min(index_bytes.len(), nonce_bytes.len());
+    nonce_bytes[..len].copy_from_slice(&index_bytes[..len]);
+    nonce_bytes
+}
+
+/// Anything process_file can read blocks from: a file on disk or an in-memory upload
+trait BlockSource: AsyncRead + AsyncSeek + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncSeek + Unpin + Send> BlockSource for T {}
+
+// Bytes read from the start of a file to recognize its type
+const SNIFF_LEN: usize = 8192;
+
+// Images this size or larger get no thumbnail, to avoid decoding huge files
+const THUMBNAIL_MAX_SOURCE_SIZE: u64 = 5 * 1024 * 1024;
+
+// Edge length of the box upload thumbnails are scaled to fit
+const THUMBNAIL_SIZE: u32 = 300;
+
+// Largest edge generate_local_thumbnail will produce
+const MAX_PREVIEW_SIZE: u32 = 2048;
+
+// Add this function to check if a file is an image and get its MIME type
+fn get_file_info(path: &Path, data: Option<&[u8]>) -> (String, bool) {
+    let guessed = from_path(path).first_or_octet_stream();
+
+    // No or an unknown extension, so look at the content instead
+    let mime = if guessed == mime::APPLICATION_OCTET_STREAM {
+        sniff_mime(path, data).unwrap_or_else(|| guessed.to_string())
+    } else {
+        guessed.to_string()
+    };
+
+    let is_image = mime.starts_with("image/");
+    (mime, is_image)
+}
+
+/// MIME type from the magic bytes at the start of a file, reading only its
+/// first SNIFF_LEN bytes
+fn sniff_mime(path: &Path, data: Option<&[u8]>) -> Option<String> {
+    let header = match data {
+        Some(data) => data[..data.len().min(SNIFF_LEN)].to_vec(),
+        None => {
+            let mut header = Vec::with_capacity(SNIFF_LEN);
+            std::fs::File::open(path)
+                .ok()?
+                .take(SNIFF_LEN as u64)
+                .read_to_end(&mut header)
+                .ok()?;
+            header
+        }
+    };
+
+    infer::get(&header).map(|kind| kind.mime_type().to_string())
+}
+
+/// Generates a thumbnail unless one is passed in, encrypts it when given a
+/// cipher, uploads it and reports its SHA-256 and size with
+/// `thumbnail-complete`. Returns the uploaded size; errors are for the
+/// caller to log, not to fail the file.
+async fn upload_thumbnail(
+    app: &dyn EventSink,
+    state: &TransferSession,
+    item: &QueueItem,
+    file_size: u64,
+    thumbnail_id: &str,
+    url: &str,
+    headers: &HashMap<String, String>,
+    cipher: Option<&Aes256Gcm>,
+    thumbnail: Option<Vec<u8>>,
+) -> Result<usize, String> {
+    emit_progress(
+        app,
+        state,
+        TransferProgress {
+            id: item.id.clone(),
+            name: item.name.clone(),
+            item_type: "file".to_string(),
+            progress: 0.02,
+            status: "preparing".to_string(),
+            message: Some("Generating thumbnail...".to_string()),
+            speed: None,
+            remaining_time: None,
+            size: Some(file_size),
+            uploaded_bytes: Some(0),
+            error_code: None,
+        },
+    )
+    .map_err(|e| format!("Failed to emit progress: {}", e))?;
+
+    put_thumbnail(
+        app,
+        state,
+        item,
+        thumbnail_id,
+        url,
+        headers,
+        cipher,
+        thumbnail,
+    )
+    .await
+}
+
+/// Generates a thumbnail unless one is passed in, optionally encrypts and
+/// uploads it, then emits `thumbnail-complete`
+async fn put_thumbnail(
+    app: &dyn EventSink,
+    state: &TransferSession,
+    item: &QueueItem,
+    thumbnail_id: &str,
+    url: &str,
+    headers: &HashMap<String, String>,
+    cipher: Option<&Aes256Gcm>,
+    thumbnail: Option<Vec<u8>>,
+) -> Result<usize, String> {
+    let thumbnail_data = match thumbnail {
+        Some(data) => data,
+        None => {
+            generate_thumbnail(item, false)
+                .await
+                .map_err(|e| format!("Failed to generate thumbnail: {}", e))?
+                .data
+        }
+    };
+
+    let (body, content_type, nonce) = match cipher {
+        Some(cipher) => {
+            let (encrypted, nonce) = encrypt_thumbnail(cipher, &thumbnail_data)?;
+            (encrypted, "application/octet-stream", Some(nonce))
+        }
+        None => (thumbnail_data, "image/jpeg", None),
+    };
+
+    // Hash exactly what is uploaded
+    let mut thumbnail_hasher = Sha256::default();
+    thumbnail_hasher.update(&body);
+    let thumbnail_hash = format!("{:x}", thumbnail_hasher.finalize());
+    let size = body.len();
+
+    // Shared HTTP client, honoring any configured proxy
+    let client = proxy::upload_client().await;
+    let connections = state.1.clone();
+    let permit = connections
+        .acquire()
+        .await
+        .map_err(|e| format!("Failed to acquire upload connection: {}", e))?;
+    let response = presigned_put(&client, url, body, content_type, headers)
+        .timeout(Duration::from_secs(60))
+        .send()
+        .await
+        .map_err(|e| format!("Thumbnail upload error: {}", e))?;
+    drop(permit);
+
+    if !response.status().is_success() {
+        return Err(format!(
+            "Thumbnail upload failed with status: {}",
+            response.status()
+        ));
+    }
+
+    state
+        .emit(
+            app,
+            "thumbnail-complete",
+            serde_json::json!({
+                "thumbnail_id": thumbnail_id,
+                "hash": thumbnail_hash,
+                "size": size,
+                "nonce": nonce.map(|nonce| general_purpose::STANDARD.encode(nonce)),
+            }),
+        )
+        .map_err(|e| format!("Failed to emit thumbnail completion: {}", e))?;
+
+    Ok(size)
+}
+
+/// Encrypts a thumbnail under the file's content key with a random nonce, so
+/// it never shares one with another thumbnail or with the file's blocks,
+/// whose nonces are their indexes. Returns the nonce followed by the
+/// ciphertext, and the nonce on its own.
+fn encrypt_thumbnail(cipher: &Aes256Gcm, thumbnail: &[u8]) -> Result<(Vec<u8>, [u8; 12]), String> {
+    let mut nonce = [0u8; 12];
+    rand::rng().fill(&mut nonce);
+
+    let encrypted = cipher
+        .encrypt(Nonce::from_slice(&nonce), thumbnail)
+        .map_err(|e| format!("Failed to encrypt thumbnail: {}", e))?;
+
+    let mut body = Vec::with_capacity(nonce.len() + encrypted.len());
+    body.extend_from_slice(&nonce);
+    body.extend_from_slice(&encrypted);
+    Ok((body, nonce))
+}
+
+/// Builds the AES-256-GCM cipher for a base64 content key. The decoded key
+/// bytes are wiped as soon as the cipher has its own copy.
+fn content_cipher(content_key: &str) -> Result<Aes256Gcm, String> {
+    let key_bytes = general_purpose::STANDARD
+        .decode(content_key.as_bytes())
+        .map(Zeroizing::new)
+        .map_err(|e| format!("Failed to decode encryption key: {}", e))?;
+    if key_bytes.len() != 32 {
+        return Err("Invalid encryption key length, must be 32 bytes".to_string());
+    }
+
+    Ok(Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key_bytes)))
+}
+
+/// Asks the frontend for a fresh presigned thumbnail URL with
+/// `request-thumbnail-url`, answered by thumbnail_url_response
+async fn request_thumbnail_url(
+    app: &dyn EventSink,
+    state: &TransferSession,
+    file_id: &str,
+    thumbnail_id: &str,
+) -> Result<(String, HashMap<String, String>), String> {
+    let rx = expect_response(&THUMBNAIL_URL_CHANNELS, thumbnail_id).await;
+
+    state
+        .emit(
+            app,
+            "request-thumbnail-url",
+            serde_json::json!({
+                "file_id": file_id,
+                "thumbnail_id": thumbnail_id,
+            }),
+        )
+        .map_err(|e| format!("Failed to request thumbnail URL: {}", e))?;
+
+    match tokio::time::timeout(Duration::from_secs(30), rx).await {
+        Ok(Ok(response)) => response,
+        Ok(Err(_)) => Err("Thumbnail URL channel closed".to_string()),
+        Err(_) => {
+            THUMBNAIL_URL_CHANNELS.lock().await.remove(thumbnail_id);
+            Err("Timeout waiting for thumbnail URL".to_string())
+        }
+    }
+}
+
+/// Uploads the thumbnail of an already uploaded file again, after it failed
+/// during the upload. The thumbnail is rebuilt from the original local file
+/// and encrypted with the file's content key. Returns the uploaded size.
+#[command]
+pub async fn retry_thumbnail(
+    app: AppHandle,
+    file_id: String,
+    thumbnail_id: String,
+    state: State<'_, TransferManagerState>,
+    session_id: Option<String>,
+) -> Result<usize, String> {
+    let state = state.session(session_id.as_deref());
+    let (item, content_key) = {
+        let queue = state.0.lock().await;
+        let retry = queue
+            .thumbnail_retries
+            .get(&file_id)
+            .ok_or_else(|| format!("No failed thumbnail for file: {}", file_id))?;
+        println!(
+            "Retrying thumbnail for {} ({} bytes)",
+            retry.item.name, retry.file_size
+        );
+        (retry.item.clone(), retry.content_key.clone())
+    };
+
+    let cipher = content_cipher(&content_key)?;
+    let (url, headers) = request_thumbnail_url(&app, &state, &file_id, &thumbnail_id).await?;
+    let size = put_thumbnail(
+        &app,
+        &state,
+        &item,
+        &thumbnail_id,
+        &url,
+        &headers,
+        Some(&cipher),
+        None,
+    )
+    .await?;
+
+    state.0.lock().await.thumbnail_retries.remove(&file_id);
+    println!("Thumbnail retried successfully ({} bytes)", size);
+
+    Ok(size)
+}
+
+/// Uploads one block of an in-flight file again, after the server found it
+/// corrupt. The block is read from the original file, encrypted with the same
+/// key and nonce, and sent to a freshly requested URL. Emits `block-complete`
+/// again once the store has it.
+#[command]
+pub async fn reupload_block(
+    app: AppHandle,
+    file_id: String,
+    block_index: usize,
+    state: State<'_, TransferManagerState>,
+    session_id: Option<String>,
+) -> Result<(), String> {
+    let state = state.session(session_id.as_deref());
+    let context = state
+        .0
+        .lock()
+        .await
+        .upload_contexts
+        .get(&file_id)
+        .cloned()
+        .ok_or_else(|| format!("No upload in progress for file: {}", file_id))?;
+    let block = context
+        .urls
+        .iter()
+        .find(|url| url.index == block_index)
+        .cloned()
+        .ok_or_else(|| format!("File {} has no block {}", file_id, block_index))?;
+
+    println!(
+        "Uploading block {} of {} again",
+        block_index, context.item.name
+    );
+
+    // Same response channel as a mid-upload URL refresh, for just this block
+    let fresh = refresh_presigned_urls(
+        &app,
+        &state,
+        &context.item.id,
+        &file_id,
+        &context.revision_id,
+        std::slice::from_ref(&block),
+    )
+    .await?
+    .into_iter()
+    .find(|url| url.index == block_index)
+    .ok_or_else(|| format!("No upload URL returned for block {}", block_index))?;
+
+    let offset = block_index as u64 * context.block_size;
+    let len = context.block_size.min(context.file_size - offset);
+    let mut file = open_block_source(&context.item)
+        .await
+        .map_err(|e| format!("Failed to open file: {}", e))?;
+    let buffer = read_block(&mut file, offset, len as usize, None)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let cipher = content_cipher(&context.content_key)?;
+    let nonce_bytes = block_nonce(block_index);
+    let upload_buffer = cipher
+        .encrypt(Nonce::from_slice(&nonce_bytes), buffer.as_ref())
+        .map_err(|e| format!("Failed to encrypt block: {}", e))?;
+    drop(buffer);
+
+    let block_md5 = context.verify_md5.then(|| Md5::digest(&upload_buffer));
+    let mut block_hasher = ContentHasher::new(context.hash_algorithm);
+    block_hasher.update(&upload_buffer);
+    let block_hash = block_hasher.finalize_hex();
+
+    let uploader = state.0.lock().await.uploader()?;
+    let metadata = BlockMetadata {
+        url: &fresh,
+        content_md5: block_md5.as_deref(),
+        content_type: &context.block_content_type,
+    };
+
+    let permit = state
+        .1
+        .acquire()
+        .await
+        .map_err(|e| format!("Failed to acquire upload connection: {}", e))?;
+    let result = uploader
+        .upload_block(block_index, upload_buffer, &metadata)
+        .await;
+    drop(permit);
+    result.map_err(|e| format!("Block upload failed: {}", e))?;
+
+    state
+        .emit(
+            &app,
+            "block-complete",
+            serde_json::json!({
+                "block_id": fresh.block_id,
+                "hash": block_hash,
+                "hash_algorithm": context.hash_algorithm.as_str(),
+                "index": block_index,
+                "file_id": file_id
+            }),
+        )
+        .map_err(|e| format!("Failed to emit block completion: {}", e))?;
+
+    Ok(())
+}
+
+/// Downloads a file from the presigned URLs of its blocks, in order, and
+/// decrypts each with `content_key` the way process_file encrypted it. The
+/// file is assembled in the staging directory and only moved into place once
+/// its hash matches `content_hash`, so a failed download leaves nothing
+/// behind. The hash is SHA-256 unless `hash_algorithm` says otherwise.
+/// Progress goes out as `transfer-progress` under `transfer_id`.
+#[command]
+pub async fn download_file(
+    app: AppHandle,
+    transfer_id: String,
+    urls: Vec<String>,
+    content_key: Zeroizing<String>,
+    block_size: u64,
+    dest_path: String,
+    content_hash: String,
+    hash_algorithm: Option<HashAlgorithm>,
+    state: State<'_, TransferManagerState>,
+    session_id: Option<String>,
+) -> Result<(), String> {
+    let state = state.session(session_id.as_deref());
+    let dest_path = PathBuf::from(dest_path);
+    let name = dest_path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let (staging_dir, block_size_limit) = {
+        let queue = state.0.lock().await;
+        (queue.staging_dir(), queue.block_size_limit)
+    };
+    let partial_path = staging_dir.join(format!("{}-{}.download", generate_id(), name));
+
+    let progress =
+        |status: &str, fraction: f32, bytes: u64, error: Option<&TransferError>| TransferProgress {
+            id: transfer_id.clone(),
+            name: name.clone(),
+            item_type: "file".to_string(),
+            progress: fraction,
+            status: status.to_string(),
+            message: error.map(|e| e.to_string()),
+            speed: None,
+            remaining_time: None,
+            size: None,
+            uploaded_bytes: Some(bytes),
+            error_code: error.map(|e| e.code().to_string()),
+        };
+
+    println!(
+        "Downloading {} blocks to {}",
+        urls.len(),
+        dest_path.display()
+    );
+    let started = Instant::now();
+    let mut downloaded = 0u64;
+
+    let result = async {
+        check_block_size(block_size, block_size_limit)?;
+        let cipher = content_cipher(&content_key).map_err(TransferError::EncryptionFailed)?;
+
+        // The whole file is written to the staging directory, then moved to
+        // its destination, which may be another volume
+        let required = (urls.len() as u64).saturating_mul(block_size);
+        let dest_dir = dest_path
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or_default();
+        let volumes = [dest_dir, staging_dir];
+        tauri::async_runtime::spawn_blocking(move || {
+            volumes
+                .iter()
+                .try_for_each(|dir| ensure_free_space(dir, required).map(|_| ()))
+        })
+        .await
+        .map_err(|e| TransferError::Internal(format!("Free space check failed: {}", e)))??;
+
+        let client = proxy::upload_client().await;
+        let mut file = File::create(&partial_path)
+            .await
+            .map_err(|e| TransferError::Io(format!("Failed to create file: {}", e)))?;
+        let mut hasher = ContentHasher::new(hash_algorithm.unwrap_or_default());
+
+        for (index, url) in urls.iter().enumerate() {
+            // AES-GCM adds a 16 byte tag to each block
+            let encrypted = fetch_block(&client, url, index, block_size + 16).await?;
+            let last = index + 1 == urls.len();
+            let block = decrypt_block(&cipher, index, &encrypted, block_size, last)?;
+
+            hasher.update(&block);
+            file.write_all(&block)
+                .await
+                .map_err(|e| TransferError::Io(format!("Failed to write file: {}", e)))?;
+            downloaded += block.len() as u64;
+
+            let mut update = progress(
+                "downloading",
+                (index + 1) as f32 / urls.len() as f32,
+                downloaded,
+                None,
+            );
+            let elapsed = started.elapsed().as_secs_f64();
+            if elapsed > 0.0 {
+                let speed = downloaded as f64 / elapsed;
+                let remaining_blocks = (urls.len() - index - 1) as u64;
+                update.speed = Some(speed);
+                update.remaining_time = Some(
+                    (remaining_blocks.saturating_mul(block_size) as f64 / speed.max(1.0)) as u64,
+                );
+            }
+            let _ = emit_progress(&app, &state, update);
+        }
+
+        file.sync_all()
+            .await
+            .map_err(|e| TransferError::Io(format!("Failed to write file: {}", e)))?;
+
+        let actual_hash = hasher.finalize_hex();
+        if !actual_hash.eq_ignore_ascii_case(content_hash.trim()) {
+            return Err(TransferError::HashMismatch(format!(
+                "Downloaded content hash {} doesn't match the expected {}",
+                actual_hash, content_hash
+            )));
+        }
+
+        move_into_place(&partial_path, &dest_path)
+            .await
+            .map_err(|e| TransferError::Io(format!("Failed to move file into place: {}", e)))
+    }
+    .await;
+
+    match result {
+        Ok(()) => {
+            println!("Downloaded {} ({} bytes)", dest_path.display(), downloaded);
+            let _ = emit_progress(&app, &state, progress("completed", 1.0, downloaded, None));
+            Ok(())
+        }
+        Err(error) => {
+            println!("Download of {} failed: {}", dest_path.display(), error);
+            let _ = tokio::fs::remove_file(&partial_path).await;
+            let fraction = if urls.is_empty() {
+                0.0
+            } else {
+                downloaded as f32 / (urls.len() as u64).saturating_mul(block_size.max(1)) as f32
+            };
+            let _ = emit_progress(
+                &app,
+                &state,
+                progress("failed", fraction, downloaded, Some(&error)),
+            );
+            Err(error.to_string())
+        }
+    }
+}
+
+/// Moves a finished download from the staging directory to `to`. When the
+/// two are on different volumes the rename fails, so the file is copied
+/// beside `to` and renamed from there; `to` only ever appears complete.
+async fn move_into_place(from: &Path, to: &Path) -> std::io::Result<()> {
+    if tokio::fs::rename(from, to).await.is_ok() {
+        return Ok(());
+    }
+
+    let name = to
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let beside = to.with_file_name(format!("{}.download", name));
+    let result = async {
+        tokio::fs::copy(from, &beside).await?;
+        tokio::fs::rename(&beside, to).await
+    }
+    .await;
+    match result {
+        Ok(()) => {
+            let _ = tokio::fs::remove_file(from).await;
+            Ok(())
+        }
+        Err(e) => {
+            let _ = tokio::fs::remove_file(&beside).await;
+            Err(e)
+        }
+    }
+}
+
+/// Fetches one encrypted block from its presigned URL, failing as soon as the
+/// body grows past `max_len`
+async fn fetch_block(
+    client: &reqwest::Client,
+    url: &str,
+    index: usize,
+    max_len: u64,
+) -> Result<Vec<u8>, TransferError> {
+    let mut response = client
+        .get(url)
+        .timeout(Duration::from_secs(300))
+        .send()
+        .await
+        .map_err(|e| {
+            if proxy::is_pin_failure(&e) {
+                TransferError::TlsPinFailure(format!(
+                    "Storage certificate doesn't match the pinned certificates: {}",
+                    e
+                ))
+            } else if e.is_timeout() {
+                TransferError::Timeout(format!("Block {} download timed out", index))
+            } else {
+                TransferError::Network(format!("Failed to download block {}: {}", index, e))
+            }
+        })?;
+
+    // An expired presigned URL is rejected with 403
+    if response.status() == reqwest::StatusCode::FORBIDDEN {
+        return Err(TransferError::UrlExpired(format!(
+            "Download URL for block {} has expired",
+            index
+        )));
+    }
+    if !response.status().is_success() {
+        return Err(TransferError::Server(format!(
+            "Block {} download failed with status: {}",
+            index,
+            response.status()
+        )));
+    }
+
+    let too_large = || {
+        TransferError::BlockTooLarge(format!(
+            "Block {} is larger than the {} bytes an encrypted block can be",
+            index, max_len
+        ))
+    };
+    if response.content_length().is_some_and(|len| len > max_len) {
+        return Err(too_large());
+    }
+
+    let mut body = Vec::new();
+    while let Some(chunk) = response
+        .chunk()
+        .await
+        .map_err(|e| TransferError::Network(format!("Failed to download block {}: {}", index, e)))?
+    {
+        if (body.len() + chunk.len()) as u64 > max_len {
+            return Err(too_large());
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(body)
+}
+
+/// Decrypts block `index` of a download. Every block but the last must come
+/// out at exactly `block_size`, so a truncated or reordered block is caught
+/// even when it decrypts.
+fn decrypt_block(
+    cipher: &Aes256Gcm,
+    index: usize,
+    encrypted: &[u8],
+    block_size: u64,
+    last: bool,
+) -> Result<Vec<u8>, TransferError> {
+    let nonce_bytes = block_nonce(index);
+    let block = cipher
+        .decrypt(Nonce::from_slice(&nonce_bytes), encrypted)
+        .map_err(|_| {
+            TransferError::EncryptionFailed(format!(
+                "Block {} failed to decrypt; the key is wrong or the block is damaged",
+                index
+            ))
+        })?;
+
+    let size = block.len() as u64;
+    let size_ok = if last {
+        size > 0 && size <= block_size
+    } else {
+        size == block_size
+    };
+    if !size_ok {
+        return Err(TransferError::EncryptionFailed(format!(
+            "Block {} is {} bytes, which doesn't fit a {} byte block size",
+            index, size, block_size
+        )));
+    }
+
+    Ok(block)
+}
+
+/// Receives the presigned URL requested by retry_thumbnail
+#[command]
+pub async fn thumbnail_url_response(
+    thumbnail_id: String,
+    url: Option<String>,
+    headers: Option<HashMap<String, String>>,
+    error: Option<String>,
+) -> Result<(), String> {
+    let response = match (url, error) {
+        (_, Some(error)) => Err(error),
+        (Some(url), None) => Ok((url, headers.unwrap_or_default())),
+        (None, None) => Err("Thumbnail URL response had no URL".to_string()),
+    };
+
+    match deliver_response(&THUMBNAIL_URL_CHANNELS, &thumbnail_id, response).await {
+        Delivery::Delivered => {}
+        Delivery::ReceiverDropped => {
+            println!("Failed to send thumbnail URL through channel - receiver dropped")
+        }
+        Delivery::NoReceiver => println!(
+            "No waiting receiver found for thumbnail ID: {}",
+            thumbnail_id
+        ),
+    }
+
+    Ok(())
+}
+
+/// A generated thumbnail, with the perceptual hash of the full image when
+/// one was asked for
+struct Thumbnail {
+    data: Vec<u8>,
+    perceptual_hash: Option<u64>,
+}
+
+/// Difference hash of an image: a 9x8 grayscale copy, with one bit per pixel
+/// set when it is brighter than its right neighbour. Re-encoded or rescaled
+/// copies of a photo land within a few bits of each other.
+fn dhash(img: &DynamicImage) -> u64 {
+    let small = img
+        .grayscale()
+        .resize_exact(9, 8, FilterType::Triangle)
+        .to_luma8();
+    let mut hash = 0u64;
+    for y in 0..8 {
+        for x in 0..8 {
+            hash <<= 1;
+            if small.get_pixel(x, y)[0] > small.get_pixel(x + 1, y)[0] {
+                hash |= 1;
+            }
+        }
+    }
+    hash
+}
+
+// Fixed thumbnail generation function
+async fn generate_thumbnail(item: &QueueItem, perceptual_hash: bool) -> Result<Thumbnail, String> {
+    // Read the file, unless the upload is already in memory
+    let img_data = match &item.data {
+        Some(data) => data.to_vec(),
+        None => match tokio::fs::read(&item.path).await {
+            Ok(data) => data,
+            Err(e) => return Err(format!("Failed to read image file: {}", e)),
+        },
+    };
+
+    // Process the image in a blocking task since image operations are CPU-intensive
+    let thumbnail = tokio::task::spawn_blocking(move || {
+        render_thumbnail(
+            &img_data,
+            THUMBNAIL_SIZE,
+            THUMBNAIL_SIZE,
+            ImageFormat::Jpeg,
+            perceptual_hash,
+        )
+    })
+    .await
+    .map_err(|e| format!("Task error: {}", e))??;
+
+    Ok(thumbnail)
+}
+
+/// Decodes an image and scales it to fit within max_width x max_height,
+/// keeping its aspect ratio. CPU-heavy, so run it off the async runtime.
+fn render_thumbnail(
+    img_data: &[u8],
+    max_width: u32,
+    max_height: u32,
+    format: ImageFormat,
+    perceptual_hash: bool,
+) -> Result<Thumbnail, String> {
+    // Load the image
+    let img = match image::load_from_memory(img_data) {
+        Ok(img) => img,
+        Err(e) => return Err(format!("Failed to load image: {}", e)),
+    };
+
+    // Hashed from the decoded image while it's here, for near-duplicate detection
+    let perceptual_hash = perceptual_hash.then(|| dhash(&img));
+
+    let thumbnail = img.thumbnail(max_width, max_height);
+
+    // Create a buffer to write the image data to
+    let mut buffer = Cursor::new(Vec::new());
+    if let Err(e) = thumbnail.write_to(&mut buffer, format) {
+        return Err(format!("Failed to create thumbnail: {}", e));
+    }
+
+    Ok(Thumbnail {
+        data: buffer.into_inner(),
+        perceptual_hash,
+    })
+}
+
+/// Builds a thumbnail of a local image through the same pipeline uploads use,
+/// without queueing or uploading anything. Sizes default to the upload
+/// thumbnail size and `format` is "jpeg" (the default) or "png". Returns the
+/// thumbnail as base64.
+#[command]
+pub async fn generate_local_thumbnail(
+    path: String,
+    max_width: Option<u32>,
+    max_height: Option<u32>,
+    format: Option<String>,
+) -> Result<String, String> {
+    let max_width = max_width.unwrap_or(THUMBNAIL_SIZE);
+    let max_height = max_height.unwrap_or(THUMBNAIL_SIZE);
+    if max_width == 0
+        || max_height == 0
+        || max_width > MAX_PREVIEW_SIZE
+        || max_height > MAX_PREVIEW_SIZE
+    {
+        return Err(format!(
+            "Thumbnail dimensions must be between 1 and {} pixels",
+            MAX_PREVIEW_SIZE
+        ));
+    }
+
+    let format = match format.as_deref().unwrap_or("jpeg") {
+        "jpeg" | "jpg" => ImageFormat::Jpeg,
+        "png" => ImageFormat::Png,
+        other => return Err(format!("Unsupported thumbnail format: {}", other)),
+    };
+
+    let file_path = Path::new(&path);
+    let (mime_type, is_image) = get_file_info(file_path, None);
+    if !is_image {
+        return Err(format!("Not an image ({}): {}", mime_type, path));
+    }
+
+    // Same limit as uploads, so large images aren't decoded just for a preview
+    let size = tokio::fs::metadata(file_path)
+        .await
+        .map_err(|e| format!("Failed to read image file: {}", e))?
+        .len();
+    if size >= THUMBNAIL_MAX_SOURCE_SIZE {
+        return Err(format!(
+            "Image is too large to preview ({} bytes, limit {})",
+            size, THUMBNAIL_MAX_SOURCE_SIZE
+        ));
+    }
+
+    let img_data = tokio::fs::read(file_path)
+        .await
+        .map_err(|e| format!("Failed to read image file: {}", e))?;
+    let thumbnail = tokio::task::spawn_blocking(move || {
+        render_thumbnail(&img_data, max_width, max_height, format, false)
+    })
+    .await
+    .map_err(|e| format!("Task error: {}", e))??;
+
+    Ok(general_purpose::STANDARD.encode(thumbnail.data))
+}
+
+// Larger attributes, such as resource forks, are left out
+const MAX_XATTR_VALUE_SIZE: usize = 64 * 1024;
+
+// Attributes never restored unless asked for, since they change how the OS treats the file
+const UNSAFE_XATTRS: [&str; 1] = ["com.apple.quarantine"];
+
+/// Reads a file's extended attributes as name -> base64 value, since values
+/// are often binary
+fn list_xattrs(file_path: &str) -> HashMap<String, String> {
+    let path = Path::new(file_path);
+    let mut values = HashMap::new();
+
+    let Ok(attrs) = xattr::list(path) else {
+        return values; // Error reading xattrs, or not supported here
+    };
+
+    for attr in attrs {
+        if let Ok(Some(value)) = xattr::get(path, &attr) {
+            if value.len() <= MAX_XATTR_VALUE_SIZE {
+                values.insert(
+                    attr.to_string_lossy().to_string(),
+                    general_purpose::STANDARD.encode(value),
+                );
+            }
+        }
+    }
+
+    values
+}
+
+/// Free bytes on the volume holding `path`, which doesn't need to exist yet
+fn available_space(path: &Path) -> Result<u64, TransferError> {
+    let existing = path
+        .ancestors()
+        .find(|ancestor| ancestor.exists())
+        .ok_or_else(|| TransferError::FileNotFound(format!("No such path: {}", path.display())))?;
+    let path = existing
+        .canonicalize()
+        .map_err(|e| TransferError::Io(format!("Failed to resolve {}: {}", path.display(), e)))?;
+
+    // The volume is the disk with the longest mount point containing the path
+    let disks = Disks::new_with_refreshed_list();
+    disks
+        .list()
+        .iter()
+        .filter(|disk| path.starts_with(disk.mount_point()))
+        .max_by_key(|disk| disk.mount_point().as_os_str().len())
+        .map(|disk| disk.available_space())
+        .ok_or_else(|| TransferError::Io(format!("No volume found for {}", path.display())))
+}
+
+/// Fails with `insufficient_space`, naming the shortfall, unless the volume
+/// holding `path` has `required_bytes` free. Returns the free bytes.
+fn ensure_free_space(path: &Path, required_bytes: u64) -> Result<u64, TransferError> {
+    let available = available_space(path)?;
+    if available < required_bytes {
+        return Err(TransferError::InsufficientSpace(format!(
+            "{} needs {} bytes but only {} are free ({} bytes short)",
+            path.display(),
+            required_bytes,
+            available,
+            required_bytes - available
+        )));
+    }
+    Ok(available)
+}
+
+/// Checks there is room for `required_bytes` at `path` before a download or
+/// local staging starts. Returns the free bytes on that volume.
+#[command]
+pub async fn check_free_space(path: String, required_bytes: u64) -> Result<u64, TransferError> {
+    // Listing disks reads mount tables, so keep it off the async runtime
+    tauri::async_runtime::spawn_blocking(move || {
+        ensure_free_space(Path::new(&path), required_bytes)
+    })
+    .await
+    .map_err(|e| TransferError::Internal(format!("Free space check failed: {}", e)))?
+}
+
+/// Writes extended attributes captured on upload back onto a downloaded file.
+/// `namespaces` limits restoring to names with those prefixes (e.g. `user.`,
+/// `com.apple.metadata:`); quarantine flags are skipped unless
+/// `restore_unsafe` is set. Returns how many attributes were written, and
+/// does nothing on platforms without xattr support.
+#[command]
+pub async fn restore_xattrs(
+    path: String,
+    xattrs: HashMap<String, String>,
+    namespaces: Option<Vec<String>>,
+    restore_unsafe: Option<bool>,
+) -> Result<usize, String> {
+    if !xattr::SUPPORTED_PLATFORM {
+        return Ok(0);
+    }
+
+    let file_path = Path::new(&path);
+    if !file_path.exists() {
+        return Err(format!("File not found: {}", path));
+    }
+
+    let restore_unsafe = restore_unsafe.unwrap_or(false);
+    let mut restored = 0;
+
+    for (name, value) in xattrs {
+        if !restore_unsafe && UNSAFE_XATTRS.contains(&name.as_str()) {
+            continue;
+        }
+        if let Some(namespaces) = &namespaces {
+            if !namespaces
+                .iter()
+                .any(|namespace| name.starts_with(namespace.as_str()))
+            {
+                continue;
+            }
+        }
+
+        let value = match general_purpose::STANDARD.decode(&value) {
+            Ok(value) => value,
+            Err(e) => {
+                println!("Skipping attribute {} with invalid value: {}", name, e);
+                continue;
+            }
+        };
+
+        // One bad attribute, e.g. a namespace this file system rejects,
+        // shouldn't stop the rest
+        match xattr::set(file_path, &name, &value) {
+            Ok(()) => restored += 1,
+            Err(e) => println!("Failed to restore attribute {} on {}: {}", name, path, e),
+        }
+    }
+
+    Ok(restored)
+}
+
+/// Processes a file for upload
+async fn process_file(
+    app: Events,
+    state: TransferSession,
+    item: QueueItem,
+) -> Result<(), TransferError> {
+    let path = Path::new(&item.path);
+    println!("Processing file: {} at depth {}", item.path, item.depth);
+
+    let (file_size, modified_date) = match &item.data {
+        // Uploads from memory have no file on disk
+        Some(data) => (data.len() as u64, None),
+        None => {
+            if !path.exists() || !path.is_file() {
+                return Err(TransferError::FileNotFound(format!(
+                    "File not found or is not a file: {}",
+                    item.path
+                )));
+            }
+
+            // Get file metadata
+            let file_meta = match tokio::fs::metadata(path).await {
+                Ok(m) => m,
+                Err(e) => {
+                    return Err(TransferError::Io(format!(
+                        "Failed to read file metadata: {}",
+                        e
+                    )));
+                }
+            };
+
+            // Get modified date
+            let modified_date = match file_meta.modified() {
+                Ok(time) => match time.duration_since(std::time::UNIX_EPOCH) {
+                    Ok(duration) => Some(duration.as_secs()),
+                    Err(_) => None,
+                },
+                Err(_) => None,
+            };
+
+            (file_meta.len(), modified_date)
+        }
+    };
+
+    // Leave out empty, lock and temp files instead of failing them
+    let skip_temp_files = state.0.lock().await.skip_temp_files;
+    let skip_reason = file_skip_reason(
+        &item.name,
+        item.data.is_none().then_some(path),
+        file_size,
+        skip_temp_files,
+    );
+
+    if let Some(reason) = skip_reason {
+        skip_file(&app, &state, &item, file_size, reason).await?;
+        return Ok(());
+    }
+
+    // Get MIME type and check if it's an image
+    let (mime_type, is_image) = get_file_info(path, item.data.as_deref());
+
+    // Determine if thumbnail should be generated
+    let needs_thumbnail = is_image && file_size < THUMBNAIL_MAX_SOURCE_SIZE;
+
+    // Check if this file has already been initialized
+    let already_initialized = {
+        let queue = state.0.lock().await;
+        queue.initialized_files.contains(&item.id)
+    };
+
+    if already_initialized {
+        println!(
+            "File {} already initialized, skipping initialization request",
+            item.id
+        );
+
+        // If already initialized but not completed, mark as processing again
+        let mut queue = state.0.lock().await;
+        if !queue.completed.contains(&item.id) {
+            queue.processing = Some(item.id.clone());
+        } else {
+            // If already completed, skip processing
+            return Ok(());
+        }
+    } else {
+        // Update state to mark as processing and track that we've initialized
+        {
+            let mut queue = state.0.lock().await;
+            queue.processing = Some(item.id.clone());
+            queue.initialized_files.insert(item.id.clone());
+        }
+
+        // Emit event to notify progress start
+        emit_progress(
+            &app,
+            &state,
+            TransferProgress {
+                id: item.id.clone(),
+                name: item.name.clone(),
+                item_type: "file".to_string(),
+                progress: 0.0,
+                status: "preparing".to_string(),
+                message: Some("Preparing upload...".to_string()),
+                speed: None,
+                remaining_time: None,
+                size: Some(file_size),
+                uploaded_bytes: Some(0),
+                error_code: None,
+            },
+        )
+        .map_err(|e| format!("Failed to emit progress: {}", e))?;
+
+        let parent_id = state.0.lock().await.resolve_parent_id(&item.parent_id);
+
+        let file_extended_attributes = match item.data {
+            Some(_) => HashMap::new(),
+            None => list_xattrs(&item.path),
+        };
+
+        // Captured fields first, so caller-supplied tags can override them
+        let mut metadata = HashMap::new();
+        if let Some(modified_date) = modified_date {
+            metadata.insert("modified_date".to_string(), modified_date.to_string());
+        }
+        if !file_extended_attributes.is_empty() {
+            let mut names: Vec<&str> = file_extended_attributes
+                .keys()
+                .map(String::as_str)
+                .collect();
+            names.sort_unstable();
+            metadata.insert("xattrs".to_string(), names.join(", "));
+        }
+        metadata.extend(item.metadata.clone());
+
+        // Check if we've already received a URL response for this file
+        let already_received_response = {
+            let queue = state.0.lock().await;
+            queue.received_url_responses.contains(&item.id)
+        };
+
+        if !already_received_response {
+            // With perceptual hashing on, the thumbnail is built before the init
+            // request so its hash can go along, and is reused for the upload
+            let perceptual_hashes = state.0.lock().await.perceptual_hashes;
+            let prepared_thumbnail = if needs_thumbnail && perceptual_hashes {
+                match generate_thumbnail(&item, true).await {
+                    Ok(thumbnail) => Some(thumbnail),
+                    Err(e) => {
+                        println!("Failed to generate thumbnail for {}: {}", item.name, e);
+                        None
+                    }
+                }
+            } else {
+                None
+            };
+
+            // Server file ID of an init response discarded after a rekey
+            let mut stale_file_id: Option<String> = None;
+
+            let (response, key_id) = loop {
+                let (key_generation, current_key_id) = {
+                    let queue = state.0.lock().await;
+                    (queue.key_generation, queue.key_id.clone())
+                };
+
+                // Set up timeout tracking for this request
+                {
+                    let mut queue = state.0.lock().await;
+                    queue
+                        .request_timestamps
+                        .insert(item.id.clone(), Instant::now());
+                }
+
+                // Register the receiver BEFORE emitting the event
+                let rx = expect_response(&RESPONSE_CHANNELS, &item.id).await;
+
+                // Mark that we're expecting a response
+                {
+                    let mut queue = state.0.lock().await;
+                    queue.received_url_responses.insert(item.id.clone());
+                }
+
+                // Print the content of init-file-upload for debugging
+                println!(
+                    "Sending init-file-upload for file: {} with ID: {}",
+                    item.name, item.id
+                );
+
+                // A re-init after a rekey replaces the first file record, so it
+                // must not be dropped as a repeat of the first init
+                let idempotency_key = match &stale_file_id {
+                    Some(stale_file_id) => format!("{}:{}", item.idempotency_key, stale_file_id),
+                    None => item.idempotency_key.clone(),
+                };
+
+                // THEN send file info to frontend for initialization with additional parameters
+                let mut payload = serde_json::json!({
+                    "id": item.id,
+                    "idempotency_key": idempotency_key,
+                    "name": item.name,
+                    "path": item.path,
+                    "parent_id": parent_id,
+                    "share_id": item.share_id,
+                    "size": file_size,
+                    "xattrs": file_extended_attributes,
+                    "mime_type": mime_type,
+                    "modified_date": modified_date,
+                    "metadata": metadata,
+                    "conflict_policy": item.conflict_policy,
+                    "needs_thumbnail": needs_thumbnail,
+                    "replaces_file_id": stale_file_id
+                });
+                if let Some(hash) = prepared_thumbnail
+                    .as_ref()
+                    .and_then(|thumbnail| thumbnail.perceptual_hash)
+                {
+                    payload["perceptual_hash"] = serde_json::json!(format!("{:016x}", hash));
+                }
+                state
+                    .emit(&app, "init-file-upload", payload)
+                    .map_err(|e| format!("Failed to request file initialization: {}", e))?;
+
+                println!("Waiting for response from frontend for file: {}", item.id);
+
+                // Wait for the response with timeout
+                let response = match tokio::time::timeout(Duration::from_secs(30), rx).await {
+                    Ok(Ok(Ok(response))) => {
+                        // Clear the request timestamp since we got a response
+                        let mut queue = state.0.lock().await;
+                        queue.request_timestamps.remove(&item.id);
+
+                        response
+                    }
+                    Ok(Ok(Err(e))) if e == REKEYED => {
+                        println!("Account key changed, initializing {} again", item.name);
+                        continue;
+                    }
+                    Ok(Ok(Err(e))) if e == ALREADY_EXISTS => {
+                        if item.conflict_policy == Some(ConflictPolicy::Skip) {
+                            skip_file(&app, &state, &item, file_size, SkipReason::AlreadyExists)
+                                .await?;
+                            return Ok(());
+                        }
+                        let error = TransferError::Server(format!(
+                            "A file named {} already exists",
+                            item.name
+                        ));
+                        handle_file_error(
+                            &app,
+                            &state,
+                            &item.id,
+                            &item.name,
+                            &Some(file_size),
+                            &error,
+                        )
+                        .await?;
+                        return Err(error);
+                    }
+                    Ok(Ok(Err(e))) => {
+                        let error = TransferError::Server(e);
+                        handle_file_error(
+                            &app,
+                            &state,
+                            &item.id,
+                            &item.name,
+                            &Some(file_size),
+                            &error,
+                        )
+                        .await?;
+                        return Err(error);
+                    }
+                    Ok(Err(_)) => {
+                        let error = TransferError::Internal(
+                            "Channel closed before receiving response".to_string(),
+                        );
+                        handle_file_error(
+                            &app,
+                            &state,
+                            &item.id,
+                            &item.name,
+                            &Some(file_size),
+                            &error,
+                        )
+                        .await?;
+                        return Err(error);
+                    }
+                    Err(_) => {
+                        let error = TransferError::Timeout(
+                            "Timeout waiting for presigned URLs".to_string(),
+                        );
+
+                        // We still need to clean up the request from timestamps
+                        let mut queue = state.0.lock().await;
+                        queue.request_timestamps.remove(&item.id);
+
+                        handle_file_error(
+                            &app,
+                            &state,
+                            &item.id,
+                            &item.name,
+                            &Some(file_size),
+                            &error,
+                        )
+                        .await?;
+                        return Err(error);
+                    }
+                };
+
+                // The key rotated while this request was out, or this is a late
+                // answer to a request interrupted by a rekey, so the response may
+                // carry the old content key. Nothing is uploaded yet; ask again.
+                let stale_key = matches!(
+                    (&response.key_id, &current_key_id),
+                    (Some(used), Some(current)) if used != current
+                );
+                if stale_key || state.0.lock().await.key_generation != key_generation {
+                    println!("Account key changed, initializing {} again", item.name);
+                    stale_file_id = Some(response.file_id);
+                    continue;
+                }
+
+                let key_id = response.key_id.clone().or(current_key_id);
+                break (response, key_id);
+            };
+
+            // Refuse to start unless there is exactly one URL per block, and
+            // each block is small enough to hold in memory
+            let block_size_limit = state.0.lock().await.block_size_limit;
+            if let Err(error) =
+                check_url_count(file_size, response.block_size, response.upload_urls.len())
+                    .and_then(|_| check_block_size(response.block_size, block_size_limit))
+            {
+                handle_file_error(&app, &state, &item.id, &item.name, &Some(file_size), &error)
+                    .await?;
+                return Err(error);
+            }
+
+            // Handle thumbnail upload if needed and if the response contains a thumbnail_url
+            if needs_thumbnail {
+                if let Some(thumbnail_url) = response
+                    .upload_urls
+                    .iter()
+                    .find(|url| url.url.contains("thumbnail"))
+                {
+                    // The cipher isn't set up yet, so this thumbnail goes up as is
+                    match upload_thumbnail(
+                        &app,
+                        &state,
+                        &item,
+                        file_size,
+                        &thumbnail_url.block_id,
+                        &thumbnail_url.url,
+                        &thumbnail_url.headers,
+                        None,
+                        prepared_thumbnail
+                            .as_ref()
+                            .map(|thumbnail| thumbnail.data.clone()),
+                    )
+                    .await
+                    {
+                        Ok(size) => println!("Thumbnail uploaded successfully ({} bytes)", size),
+                        // Continue with main file upload even if the thumbnail fails
+                        Err(e) => println!("{}", e),
+                    }
+                }
+            }
+
+            println!("Response summary:");
+            println!("  file_id: {}", &response.file_id);
+            println!("  revision_id: {}", &response.revision_id);
+            println!("  total_blocks: {}", &response.total_blocks);
+            println!("  block_size: {}", &response.block_size);
+
+            // Extract information from response
+            let server_file_id = response.file_id;
+            let revision_id = response.revision_id;
+            let mut presigned_urls = response.upload_urls;
+            let mut urls_issued_at = Instant::now();
+            let block_size = response.block_size;
+            let total_blocks = presigned_urls.len();
+            let hash_algorithm = response.hash_algorithm;
+            let verify_md5 = response.verify_md5;
+            let block_content_type = response
+                .block_content_type
+                .unwrap_or_else(|| DEFAULT_BLOCK_CONTENT_TYPE.to_string());
+            let multipart_upload_id = response.multipart_upload_id;
+
+            // Remember the server file ID so the upload can be cancelled by it
+            state
+                .0
+                .lock()
+                .await
+                .server_file_ids
+                .insert(item.id.clone(), server_file_id.clone());
+
+            // Set up encryption with content key (required). The decoded key
+            // bytes are wiped as soon as the cipher has its own copy.
+            let content_key = Zeroizing::new(response.content_key);
+            let cipher = match content_cipher(&content_key) {
+                Ok(cipher) => cipher,
+                Err(e) => {
+                    let error = TransferError::EncryptionFailed(e);
+                    handle_file_error(&app, &state, &item.id, &item.name, &Some(file_size), &error)
+                        .await?;
+                    return Err(error);
+                }
+            };
+
+            println!("Cipher initialized successfully");
+
+            // Single parts of a multipart upload can't be replaced once it is
+            // completed, so only plain block uploads can be redone
+            if multipart_upload_id.is_none() {
+                state.0.lock().await.upload_contexts.insert(
+                    server_file_id.clone(),
+                    UploadContext {
+                        item: item.clone(),
+                        file_size,
+                        block_size,
+                        revision_id: revision_id.clone(),
+                        urls: presigned_urls.clone(),
+                        content_key: content_key.clone(),
+                        hash_algorithm,
+                        verify_md5,
+                        block_content_type: block_content_type.clone(),
+                    },
+                );
+            }
+
+            if let Some(thumbnail_info) = response.thumbnail {
+                // Encrypted with the same content key as the file
+                match upload_thumbnail(
+                    &app,
+                    &state,
+                    &item,
+                    file_size,
+                    &thumbnail_info.id,
+                    &thumbnail_info.url,
+                    &thumbnail_info.headers,
+                    Some(&cipher),
+                    prepared_thumbnail
+                        .as_ref()
+                        .map(|thumbnail| thumbnail.data.clone()),
+                )
+                .await
+                {
+                    Ok(size) => println!("Thumbnail uploaded successfully ({} bytes)", size),
+                    // Continue with main file upload even if the thumbnail fails,
+                    // keeping what retry_thumbnail needs to try again later
+                    Err(e) => {
+                        println!("{}", e);
+                        state.0.lock().await.thumbnail_retries.insert(
+                            server_file_id.clone(),
+                            ThumbnailRetry {
+                                item: item.clone(),
+                                file_size,
+                                content_key: content_key.clone(),
+                            },
+                        );
+                    }
+                }
+            }
+
+            // Update progress
+            emit_progress(
+                &app,
+                &state,
+                TransferProgress {
+                    id: item.id.clone(),
+                    name: item.name.clone(),
+                    item_type: "file".to_string(),
+                    progress: 0.05,
+                    status: "uploading".to_string(),
+                    message: Some(format!("Starting upload of {} blocks...", total_blocks)),
+                    speed: None,
+                    remaining_time: None,
+                    size: Some(file_size), // Add this line
+                    uploaded_bytes: Some(0),
+                    error_code: None,
+                },
+            )
+            .map_err(|e| format!("Failed to emit progress: {}", e))?;
+
+            // Open the file, or read straight from memory
+            let mut file = match open_block_source(&item).await {
+                Ok(f) => f,
+                Err(e) => {
+                    let message = format!("Failed to open file: {}", e);
+                    let error = if e.kind() == std::io::ErrorKind::NotFound {
+                        TransferError::FileNotFound(message)
+                    } else {
+                        TransferError::Io(message)
+                    };
+                    handle_file_error(&app, &state, &item.id, &item.name, &Some(file_size), &error)
+                        .await?;
+                    return Err(error);
+                }
+            };
+
+            // Use _start_time to avoid unused variable warning
+            let _start_time = Instant::now();
+            let mut uploaded_bytes = 0u64;
+            let mut completed_blocks = 0;
+
+            // Smoothed speed and ETA for progress events
+            let smoothing = state.0.lock().await.progress_smoothing;
+            let mut speed_tracker = SpeedTracker::new(smoothing);
+            let mut last_block_time = Instant::now();
+
+            // Create hasher for content verification, using the same
+            // algorithm as the per-block hashes
+            let mut hasher = OrderedHasher::new(hash_algorithm, block_size);
+
+            // Backend the blocks go through, chosen with set_upload_backend
+            let uploader = match state.0.lock().await.uploader() {
+                Ok(uploader) => uploader,
+                Err(e) => {
+                    let error = TransferError::Server(e);
+                    handle_file_error(&app, &state, &item.id, &item.name, &Some(file_size), &error)
+                        .await?;
+                    return Err(error);
+                }
+            };
+
+            // Blocks accepted in an earlier run of this same upload are left
+            // out. Uploads from memory can't outlive the app, so they aren't
+            // checkpointed.
+            let checkpoint_path = match item.data {
+                Some(_) => None,
+                None => {
+                    let mut queue = state.0.lock().await;
+                    let checkpoint_path = UploadCheckpoint::path(&queue.checkpoint_dir(), path);
+                    if let Some(checkpoint_path) = &checkpoint_path {
+                        queue
+                            .checkpoints
+                            .insert(item.id.clone(), checkpoint_path.clone());
+                    }
+                    checkpoint_path
+                }
+            };
+            let mut checkpoint = UploadCheckpoint {
+                source: std::fs::canonicalize(path)
+                    .unwrap_or_else(|_| path.to_path_buf())
+                    .to_string_lossy()
+                    .to_string(),
+                file_id: server_file_id.clone(),
+                revision_id: revision_id.clone(),
+                block_size,
+                file_size,
+                modified: std::fs::metadata(path)
+                    .and_then(|metadata| metadata.modified())
+                    .ok()
+                    .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+                    .map(|duration| duration.as_millis() as u64),
+                blocks: BTreeMap::new(),
+            };
+            if let Some(checkpoint_path) = &checkpoint_path {
+                checkpoint.resume(checkpoint_path).await;
+            }
+
+            // Multipart uploads collect each part's ETag by part number (block
+            // index + 1). Parts that fail are uploaded again in a later pass
+            // rather than failing the whole session.
+            let mut parts: BTreeMap<usize, String> = checkpoint
+                .blocks
+                .iter()
+                .filter_map(|(index, etag)| Some((index + 1, etag.clone()?)))
+                .collect();
+            let mut positions: Vec<usize> = (0..presigned_urls.len())
+                .filter(|position| {
+                    !checkpoint
+                        .blocks
+                        .contains_key(&presigned_urls[*position].index)
+                })
+                .collect();
+            let mut round = 0;
+            for index in checkpoint.blocks.keys() {
+                let offset = *index as u64 * block_size;
+                uploaded_bytes += block_size.min(file_size.saturating_sub(offset));
+                completed_blocks += 1;
+            }
+
+            loop {
+                let mut failed_positions = Vec::new();
+
+                // Upload each block with retries
+                for position in positions {
+                    // Check if transfer was cancelled
+                    {
+                        let mut queue = state.0.lock().await;
+                        if queue.processing.as_deref() != Some(item.id.as_str()) || queue.paused {
+                            return Ok(());
+                        }
+                        queue.last_activity = Some(Instant::now());
+                        queue
+                            .hash_progress
+                            .insert(item.id.clone(), hasher.hashed_bytes(file_size));
+                    }
+
+                    // Swap in fresh URLs for the remaining blocks before these expire
+                    if urls_near_expiry(urls_issued_at, presigned_urls[position].expires_in) {
+                        match refresh_presigned_urls(
+                            &app,
+                            &state,
+                            &item.id,
+                            &server_file_id,
+                            &revision_id,
+                            &presigned_urls[position..],
+                        )
+                        .await
+                        {
+                            Ok(refreshed) => {
+                                apply_refreshed_urls(&mut presigned_urls, position, refreshed);
+                                urls_issued_at = Instant::now();
+                            }
+                            Err(error) => {
+                                emit_url_expired(&app, &state, &item, file_size, &error);
+                                let error = TransferError::UrlExpired(error);
+                                handle_file_error(
+                                    &app,
+                                    &state,
+                                    &item.id,
+                                    &item.name,
+                                    &Some(file_size),
+                                    &error,
+                                )
+                                .await?;
+                                return Err(error);
+                            }
+                        }
+                    }
+
+                    let mut presigned_url = presigned_urls[position].clone();
+
+                    // Calculate block offset and size
+                    let offset = presigned_url.index as u64 * block_size;
+                    let current_block_size = if offset + block_size > file_size {
+                        file_size - offset
+                    } else {
+                        block_size
+                    };
+
+                    // Read the block, updating the hash with the original
+                    // content before encryption when it is next in order
+                    let content_hasher = hasher.for_block(presigned_url.index);
+                    let buffer = match read_block(
+                        &mut file,
+                        offset,
+                        current_block_size as usize,
+                        content_hasher,
+                    )
+                    .await
+                    {
+                        Ok(buffer) => buffer,
+                        Err(error) => {
+                            handle_file_error(
+                                &app,
+                                &state,
+                                &item.id,
+                                &item.name,
+                                &Some(file_size),
+                                &error,
+                            )
+                            .await?;
+                            return Err(error);
+                        }
+                    };
+
+                    // Encrypt the buffer with AES-GCM
+                    let nonce_bytes = block_nonce(presigned_url.index);
+                    let nonce = Nonce::from_slice(&nonce_bytes);
+
+                    // Encrypt the buffer
+                    let upload_buffer = match cipher.encrypt(nonce, buffer.as_ref()) {
+                        Ok(encrypted) => encrypted,
+                        Err(e) => {
+                            let error = TransferError::EncryptionFailed(format!(
+                                "Failed to encrypt block: {}",
+                                e
+                            ));
+                            handle_file_error(
+                                &app,
+                                &state,
+                                &item.id,
+                                &item.name,
+                                &Some(file_size),
+                                &error,
+                            )
+                            .await?;
+                            return Err(error);
+                        }
+                    };
+                    // Only the encrypted copy is needed from here on
+                    drop(buffer);
+
+                    // MD5 of the encrypted block, sent as Content-MD5 and checked
+                    // against the returned ETag when the backend wants it
+                    let block_md5 = verify_md5.then(|| Md5::digest(&upload_buffer));
+
+                    // Upload block with retries
+                    let max_retries = 3;
+                    let mut retry_count = 0;
+                    let mut upload_success = false;
+                    let mut urls_refreshed = false;
+                    let mut part_etag = None;
+
+                    while retry_count < max_retries && !upload_success {
+                        if retry_count > 0 {
+                            TransferMetrics::count(&state.2.retries);
+                        }
+
+                        // Hold a connection permit only while the request is in
+                        // flight, not during the retry backoff
+                        let permit =
+                            state.1.acquire().await.map_err(|e| {
+                                format!("Failed to acquire upload connection: {}", e)
+                            })?;
+                        let result = {
+                            let metadata = BlockMetadata {
+                                url: &presigned_url,
+                                content_md5: block_md5.as_deref(),
+                                content_type: &block_content_type,
+                            };
+                            let mut upload = uploader.upload_block(
+                                presigned_url.index,
+                                upload_buffer.clone(),
+                                &metadata,
+                            );
+
+                            // Tell the UI when a block stops making progress rather
+                            // than letting the ETA drift
+                            loop {
+                                tokio::select! {
+                                    result = &mut upload => break result,
+                                    _ = tokio::time::sleep(smoothing.stall_after) => {
+                                        let _ = emit_progress(
+                                            &app,
+                                            &state,
+                                            TransferProgress {
+                                                id: item.id.clone(),
+                                                name: item.name.clone(),
+                                                item_type: "file".to_string(),
+                                                progress: uploaded_bytes as f32 / file_size as f32,
+                                                status: "stalled".to_string(),
+                                                message: Some(format!(
+                                                    "No progress for {} seconds",
+                                                    smoothing.stall_after.as_secs()
+                                                )),
+                                                speed: Some(0.0),
+                                                remaining_time: None,
+                                                size: Some(file_size),
+                                                uploaded_bytes: Some(uploaded_bytes),
+                                                error_code: None,
+                                            },
+                                        );
+                                    }
+                                }
+                            }
+                        };
+                        drop(permit);
+
+                        match result {
+                            Ok(receipt) => {
+                                upload_success = true;
+                                part_etag = receipt.etag;
+                            }
+                            Err(BlockUploadError::Expired(_)) if !urls_refreshed => {
+                                // Retrying an expired URL is pointless, so fetch
+                                // fresh URLs once instead
+                                urls_refreshed = true;
+                                match refresh_presigned_urls(
+                                    &app,
+                                    &state,
+                                    &item.id,
+                                    &server_file_id,
+                                    &revision_id,
+                                    &presigned_urls[position..],
+                                )
+                                .await
+                                {
+                                    Ok(refreshed) => {
+                                        apply_refreshed_urls(
+                                            &mut presigned_urls,
+                                            position,
+                                            refreshed,
+                                        );
+                                        urls_issued_at = Instant::now();
+                                        presigned_url = presigned_urls[position].clone();
+                                    }
+                                    Err(error) => {
+                                        emit_url_expired(&app, &state, &item, file_size, &error);
+                                        let error = TransferError::UrlExpired(error);
+                                        handle_file_error(
+                                            &app,
+                                            &state,
+                                            &item.id,
+                                            &item.name,
+                                            &Some(file_size),
+                                            &error,
+                                        )
+                                        .await?;
+                                        return Err(error);
+                                    }
+                                }
+                            }
+                            Err(BlockUploadError::Fatal(error)) => {
+                                handle_file_error(
+                                    &app,
+                                    &state,
+                                    &item.id,
+                                    &item.name,
+                                    &Some(file_size),
+                                    &error,
+                                )
+                                .await?;
+                                return Err(error);
+                            }
+                            Err(error) => {
+                                if let BlockUploadError::TimedOut(_) = error {
+                                    TransferMetrics::count(&state.2.timeouts);
+                                }
+                                println!(
+                                    "Block upload attempt {} failed: {}, retrying...",
+                                    retry_count + 1,
+                                    error
+                                );
+                                retry_count += 1;
+                                tokio::time::sleep(Duration::from_millis(
+                                    1000 * (retry_count as u64),
+                                ))
+                                .await;
+                            }
+                        }
+                    }
+
+                    if !upload_success && multipart_upload_id.is_some() {
+                        println!(
+                            "Part {} failed after {} retries, will retry it after this pass",
+                            presigned_url.index + 1,
+                            max_retries
+                        );
+                        failed_positions.push(position);
+                        continue;
+                    }
+
+                    if !upload_success {
+                        let error = TransferError::Network(format!(
+                            "Upload failed after {} retries",
+                            max_retries
+                        ));
+                        handle_file_error(
+                            &app,
+                            &state,
+                            &item.id,
+                            &item.name,
+                            &Some(file_size),
+                            &error,
+                        )
+                        .await?;
+                        return Err(error);
+                    }
+
+                    // A part uploaded again replaces its ETag but isn't counted twice
+                    let first_upload = if multipart_upload_id.is_some() {
+                        let Some(etag) = part_etag.clone() else {
+                            let error = TransferError::Server(format!(
+                                "Store returned no ETag for part {}",
+                                presigned_url.index + 1
+                            ));
+                            handle_file_error(
+                                &app,
+                                &state,
+                                &item.id,
+                                &item.name,
+                                &Some(file_size),
+                                &error,
+                            )
+                            .await?;
+                            return Err(error);
+                        };
+                        parts.insert(presigned_url.index + 1, etag).is_none()
+                    } else {
+                        true
+                    };
+
+                    if let Some(checkpoint_path) = &checkpoint_path {
+                        checkpoint.blocks.insert(presigned_url.index, part_etag);
+                        if let Err(e) = checkpoint.save(checkpoint_path).await {
+                            println!("{}", e);
+                        }
+                    }
+
+                    if !first_upload {
+                        continue;
+                    }
+
+                    // Calculate block hash (of the encrypted data being uploaded)
+                    let mut block_hasher = ContentHasher::new(hash_algorithm);
+                    block_hasher.update(&upload_buffer);
+                    let block_hash = block_hasher.finalize_hex();
+
+                    // Create a unique key for this block to prevent duplicates
+                    let block_key = format!(
+                        "{}:{}:{}",
+                        server_file_id, presigned_url.block_id, presigned_url.index
+                    );
+
+                    // Check if we've already sent this block completion
+                    let already_sent_block = {
+                        let mut queue = state.0.lock().await;
+                        let exists = queue.block_completion_sent.contains(&block_key);
+                        if !exists {
+                            queue.block_completion_sent.insert(block_key);
+                        }
+                        exists
+                    };
+
+                    if !already_sent_block {
+                        // Tell frontend to notify backend about block completion
+                        state
+                            .emit(
+                                &app,
+                                "block-complete",
+                                serde_json::json!({
+                                    "block_id": presigned_url.block_id,
+                                    "hash": block_hash,
+                                    "hash_algorithm": hash_algorithm.as_str(),
+                                    "index": presigned_url.index,
+                                    "file_id": server_file_id
+                                }),
+                            )
+                            .map_err(|e| format!("Failed to emit block completion: {}", e))?;
+                    }
+
+                    // Feed this block's speed into the smoothed estimate
+                    speed_tracker.record(current_block_size, last_block_time.elapsed());
+                    state
+                        .2
+                        .record_block(current_block_size, last_block_time.elapsed());
+                    last_block_time = Instant::now();
+
+                    // Update progress tracking
+                    uploaded_bytes += current_block_size;
+                    completed_blocks += 1;
+                    let progress = uploaded_bytes as f32 / file_size as f32;
+
+                    // Unknown until the first block has been timed
+                    let remaining_time = speed_tracker.remaining_time(file_size - uploaded_bytes);
+
+                    // Update progress notification
+                    emit_progress(
+                        &app,
+                        &state,
+                        TransferProgress {
+                            id: item.id.clone(),
+                            name: item.name.clone(),
+                            item_type: "file".to_string(),
+                            progress,
+                            status: "uploading".to_string(),
+                            message: Some(format!(
+                                "Uploading block {}/{}",
+                                completed_blocks, total_blocks
+                            )),
+                            speed: speed_tracker.speed,
+                            remaining_time,
+                            size: Some(file_size), // Add this line
+                            uploaded_bytes: Some(uploaded_bytes),
+                            error_code: None,
+                        },
+                    )
+                    .map_err(|e| format!("Failed to emit progress: {}", e))?;
+                }
+
+                round += 1;
+                if !failed_positions.is_empty() {
+                    if round >= MAX_MULTIPART_ROUNDS {
+                        let error = TransferError::Network(format!(
+                            "{} parts failed to upload after {} attempts",
+                            failed_positions.len(),
+                            round
+                        ));
+                        handle_file_error(
+                            &app,
+                            &state,
+                            &item.id,
+                            &item.name,
+                            &Some(file_size),
+                            &error,
+                        )
+                        .await?;
+                        return Err(error);
+                    }
+                    positions = failed_positions;
+                    continue;
+                }
+
+                let target = UploadTarget {
+                    app: &app,
+                    session: &state,
+                    item_id: &item.id,
+                    file_id: &server_file_id,
+                    revision_id: &revision_id,
+                    multipart_upload_id: multipart_upload_id.as_deref(),
+                };
+                let retry_parts = match uploader.finalize(&target, &parts).await {
+                    Ok(retry_parts) => retry_parts,
+                    Err(error) => {
+                        let error = TransferError::Server(error);
+                        handle_file_error(
+                            &app,
+                            &state,
+                            &item.id,
+                            &item.name,
+                            &Some(file_size),
+                            &error,
+                        )
+                        .await?;
+                        return Err(error);
+                    }
+                };
+
+                if retry_parts.is_empty() {
+                    break;
+                }
+                if round >= MAX_MULTIPART_ROUNDS {
+                    let error = TransferError::Server(format!(
+                        "Multipart upload still rejected parts {:?} after {} attempts",
+                        retry_parts, round
+                    ));
+                    handle_file_error(&app, &state, &item.id, &item.name, &Some(file_size), &error)
+                        .await?;
+                    return Err(error);
+                }
+
+                println!("Server asked to upload parts {:?} again", retry_parts);
+                positions = presigned_urls
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, url)| retry_parts.contains(&(url.index + 1)))
+                    .map(|(position, _)| position)
+                    .collect();
+            }
+
+            // Calculate final content hash
+            let content_hash = match hasher.finish(&mut file, file_size).await {
+                Ok(hash) => {
+                    state.0.lock().await.hash_progress.remove(&item.id);
+                    hash
+                }
+                Err(error) => {
+                    handle_file_error(&app, &state, &item.id, &item.name, &Some(file_size), &error)
+                        .await?;
+                    return Err(error);
+                }
+            };
+
+            // Check if we've already sent finalization request for this file
+            let finalization_already_sent = {
+                let mut queue = state.0.lock().await;
+                let exists = queue.completion_notifications_sent.contains(&item.id);
+                if !exists {
+                    queue.completion_notifications_sent.insert(item.id.clone());
+                }
+                exists
+            };
+
+            if !finalization_already_sent {
+                // Send final progress update
+                emit_progress(
+                    &app,
+                    &state,
+                    TransferProgress {
+                        id: item.id.clone(),
+                        name: item.name.clone(),
+                        item_type: "file".to_string(),
+                        progress: 1.0,
+                        status: "uploading".to_string(),
+                        message: Some("Upload complete, finalizing...".to_string()),
+                        speed: None,
+                        remaining_time: None,
+                        size: Some(file_size), // Add this line
+                        uploaded_bytes: Some(file_size),
+                        error_code: None,
+                    },
+                )
+                .map_err(|e| format!("Failed to emit progress: {}", e))?;
+
+                // Request frontend to finalize the transfer by updating content hash
+                state
+                    .emit(
+                        &app,
+                        "finalize-transfer",
+                        serde_json::json!({
+                            "id": item.id.clone(),
+                            "idempotency_key": item.idempotency_key,
+                            "name": item.name.clone(),
+                            "size": file_size,
+                            "content_hash": content_hash,
+                            "hash_algorithm": hash_algorithm.as_str(),
+                            "file_id": server_file_id,
+                            "parent_id": parent_id,
+                            "revision_id": revision_id,
+                            "key_id": key_id,
+                            "metadata": metadata
+                        }),
+                    )
+                    .map_err(|e| format!("Failed to emit finalization request: {}", e))?;
+
+                // Note: We don't mark as completed here - that happens when finalize_transfer_complete is called
+                // The processing state stays active until finalization completes
+            }
+        }
+    }
+
+    // Don't mark as completed here - wait for finalize_transfer_complete
+
+    Ok(())
+}
+
+/// Scans a created folder and queues its contents into the same share,
+/// returning (files, subfolders) found
+async fn scan_and_enqueue(
+    app: &dyn EventSink,
+    state: &TransferSession,
+    path: &Path,
+    folder_id: &str,
+    share_id: &str,
+    priority: Priority,
+) -> Result<(usize, usize), TransferError> {
+    // Registered until the contents are queued, so cancel_scan and
+    // cancel_all_transfers can stop them at any point before that
+    let cancelled = Arc::new(AtomicBool::new(false));
+
+    // Use the pre-scanned listing when there is one
+    let prescanned = {
+        let mut queue = state.0.lock().await;
+        queue
+            .active_scans
+            .insert(folder_id.to_string(), cancelled.clone());
+        queue.prescanned.remove(path.to_string_lossy().as_ref())
+    };
+    let listing = match prescanned {
+        Some(contents) => Ok(contents),
+        None => scan_folder_with_progress(app, state, path, folder_id, &cancelled).await,
+    };
+
+    // Checked under the lock the contents are queued with, so a cancel that
+    // lands after the listing finished still keeps them out
+    let mut queue = state.0.lock().await;
+    queue.active_scans.remove(folder_id);
+    queue.scan_progress = None;
+    let (subfolders, files) = listing?;
+    if cancelled.load(Ordering::Relaxed) {
+        return Err(TransferError::Cancelled);
+    }
+
+    let counts = (files.len(), subfolders.len());
+    queue.insert_folder_contents(folder_id, share_id, priority, subfolders, files);
+
+    Ok(counts)
+}
+
+/// Lists a folder like scan_folder, sending running counts as `scan-progress`
+/// right away and then at most every SCAN_PROGRESS_INTERVAL. The listing
+/// stops at the next entry once `cancelled` is set.
+async fn scan_folder_with_progress(
+    app: &dyn EventSink,
+    state: &TransferSession,
+    path: &Path,
+    folder_id: &str,
+    cancelled: &AtomicBool,
+) -> Result<(Vec<PathBuf>, Vec<PathBuf>), TransferError> {
+    let mut progress = ScanProgress {
+        folder_id: folder_id.to_string(),
+        path: path.to_string_lossy().to_string(),
+        files: 0,
+        folders: 0,
+        done: false,
+    };
+
+    let result = async {
+        let mut dir = tokio::fs::read_dir(path)
+            .await
+            .map_err(|e| TransferError::Io(format!("Failed to scan folder: {}", e)))?;
+        let mut folders = Vec::new();
+        let mut files = Vec::new();
+        let mut last_report: Option<Instant> = None;
+
+        while let Ok(Some(entry)) = dir.next_entry().await {
+            if cancelled.load(Ordering::Relaxed) {
+                return Err(TransferError::Cancelled);
+            }
+
+            let entry_path = entry.path();
+            if entry_path.is_dir() {
+                folders.push(entry_path);
+            } else if entry_path.is_file() {
+                files.push(entry_path);
+            }
+
+            if last_report.is_none_or(|reported| reported.elapsed() >= SCAN_PROGRESS_INTERVAL) {
+                last_report = Some(Instant::now());
+                progress.files = files.len();
+                progress.folders = folders.len();
+                state.0.lock().await.scan_progress = Some(progress.clone());
+                let _ = state.emit(app, "scan-progress", &progress);
+            }
+        }
+
+        Ok((folders, files))
+    }
+    .await;
+
+    if let Ok((folders, files)) = &result {
+        progress.files = files.len();
+        progress.folders = folders.len();
+        progress.done = true;
+        let _ = state.emit(app, "scan-progress", &progress);
+    }
+
+    result
+}
+
+/// Stops listing a folder that was created for an upload. The folder upload
+/// fails as cancelled and nothing found so far is queued.
+#[command]
+pub async fn cancel_scan(
+    folder_id: String,
+    state: State<'_, TransferManagerState>,
+    session_id: Option<String>,
+) -> Result<(), String> {
+    let state = state.session(session_id.as_deref());
+    match state.0.lock().await.active_scans.get(&folder_id) {
+        Some(cancelled) => {
+            cancelled.store(true, Ordering::Relaxed);
+            Ok(())
+        }
+        None => Err(format!("No scan running for folder: {}", folder_id)),
+    }
+}
+
+/// Scans deferred folders while the queue has room again
+async fn resume_deferred_scans(app: &dyn EventSink, state: &TransferSession) {
+    loop {
+        let scan = {
+            let mut queue = state.0.lock().await;
+            if queue.is_full() {
+                return;
+            }
+            match queue.deferred_scans.pop_front() {
+                Some(scan) => scan,
+                None => return,
+            }
+        };
+
+        println!("Resuming deferred scan of folder: {}", scan.path.display());
+
+        if let Err(e) = scan_and_enqueue(
+            app,
+            state,
+            &scan.path,
+            &scan.folder_id,
+            &scan.share_id,
+            scan.priority,
+        )
+        .await
+        {
+            println!("{}", e);
+            let _ = state.emit(
+                app,
+                "transfer-error",
+                format!("{}: {}", e, scan.path.to_string_lossy()),
+            );
+        }
+    }
+}
+
+/// Processes a folder for upload
+async fn process_folder(
+    app: Events,
+    state: TransferSession,
+    item: QueueItem,
+) -> Result<(), TransferError> {
+    let path = Path::new(&item.path);
+    println!("Processing folder: {}", item.path);
+    if !path.exists() || !path.is_dir() {
+        return Err(TransferError::FileNotFound(format!(
+            "Folder not found or is not a directory: {}",
+            item.path
+        )));
+    }
+
+    // Check if this folder has already been initialized
+    let already_initialized = {
+        let queue = state.0.lock().await;
+        queue.initialized_folders.contains(&item.id)
+    };
+
+    if already_initialized {
+        println!(
+            "Folder {} already initialized, skipping initialization request",
+            item.id
+        );
+
+        // If already initialized but not completed, mark as processing again
+        let mut queue = state.0.lock().await;
+        if !queue.completed.contains(&item.id) {
+            queue.processing = Some(item.id.clone());
+        } else {
+            // If already completed, skip processing
+            // Remove from pending folders if it was there
+            queue.pending_folders.remove(&item.path);
+            return Ok(());
+        }
+    } else {
+        // Update state to mark as processing and track that we've initialized
+        {
+            let mut queue = state.0.lock().await;
+            queue.processing = Some(item.id.clone());
+            queue.initialized_folders.insert(item.id.clone());
+        }
+
+        // Emit event to notify progress start
+        emit_progress(
+            &app,
+            &state,
+            TransferProgress {
+                id: item.id.clone(),
+                name: item.name.clone(),
+                item_type: "folder".to_string(),
+                progress: 0.0,
+                status: "preparing".to_string(),
+                message: Some("Scanning folder contents...".to_string()),
+                speed: None,
+                remaining_time: None,
+                size: None, // Add this line
+                uploaded_bytes: Some(0),
+                error_code: None,
+            },
+        )
+        .map_err(|e| format!("Failed to emit progress: {}", e))?;
+
+        let parent_id = state.0.lock().await.resolve_parent_id(&item.parent_id);
+
+        // Check if we already received a folder response
+        let already_received_response = {
+            let queue = state.0.lock().await;
+            queue.received_folder_responses.contains(&item.id)
+        };
+
+        if !already_received_response {
+            // Set timestamp for this folder request
+            {
+                let mut queue = state.0.lock().await;
+                queue
+                    .request_timestamps
+                    .insert(item.id.clone(), Instant::now());
+            }
+
+            // Register the receiver BEFORE emitting the event
+            let rx = expect_response(&FOLDER_RESPONSE_CHANNELS, &item.id).await;
+
+            // Mark that we're expecting a response
+            {
+                let mut queue = state.0.lock().await;
+                queue.received_folder_responses.insert(item.id.clone());
+            }
+
+            // Send folder info to frontend for creation - once per folder
+            if let Err(e) = state.emit(
+                &app,
+                "create-folder",
+                serde_json::json!({
+                    "id": item.id,
+                    "name": item.name,
+                    "path": item.path,
+                    "parent_id": parent_id,
+                    "share_id": item.share_id
+                }),
+            ) {
+                FOLDER_RESPONSE_CHANNELS.lock().await.remove(&item.id);
+                return Err(format!("Failed to request folder creation: {}", e).into());
+            }
+
+            // Wait for the response with timeout
+            let folder_response = match tokio::time::timeout(Duration::from_secs(30), rx).await {
+                Ok(Ok(Ok(response))) => {
+                    // Clear the timestamp tracking since we got a response
+                    let mut queue = state.0.lock().await;
+                    queue.request_timestamps.remove(&item.id);
+
+                    response
+                }
+                Ok(Ok(Err(e))) => {
+                    let error = TransferError::Server(e);
+                    handle_folder_error(&app, &state, &item.id, &item.name, &error).await?;
+
+                    // Remove from pending folders
+                    let mut queue = state.0.lock().await;
+                    queue.pending_folders.remove(&item.path);
+
+                    return Err(error);
+                }
+                Ok(Err(_)) => {
+                    let error = TransferError::Internal(
+                        "Channel closed before receiving response".to_string(),
+                    );
+                    handle_folder_error(&app, &state, &item.id, &item.name, &error).await?;
+
+                    // Remove from pending folders
+                    let mut queue = state.0.lock().await;
+                    queue.pending_folders.remove(&item.path);
+
+                    return Err(error);
+                }
+                Err(_) => {
+                    let error =
+                        TransferError::Timeout("Timeout waiting for folder creation".to_string());
+
+                    // Clear the timestamp tracking for this request
+                    let mut queue = state.0.lock().await;
+                    queue.request_timestamps.remove(&item.id);
+                    queue.pending_folders.remove(&item.path);
+
+                    handle_folder_error(&app, &state, &item.id, &item.name, &error).await?;
+                    return Err(error);
+                }
+            };
+
+            let folder_id = folder_response.folder_id;
+
+            // Store folder ID mapping
+            {
+                let mut queue = state.0.lock().await;
+                queue.record_created_folder(&item, &folder_id, &parent_id);
+            }
+
+            // Scan now, or leave it for later if the queue is already full
+            let deferred = {
+                let mut queue = state.0.lock().await;
+                let full = queue.is_full();
+                if full {
+                    queue.deferred_scans.push_back(DeferredScan {
+                        path: path.to_path_buf(),
+                        folder_id: folder_id.clone(),
+                        share_id: item.share_id.clone(),
+                        priority: item.priority,
+                    });
+                }
+                full
+            };
+
+            let mut is_empty = false;
+            let message = if deferred {
+                println!("Queue full, deferring scan of folder: {}", item.path);
+                "Queue full, contents will be scanned as uploads finish".to_string()
+            } else {
+                match scan_and_enqueue(
+                    &app,
+                    &state,
+                    path,
+                    &folder_id,
+                    &item.share_id,
+                    item.priority,
+                )
+                .await
+                {
+                    Ok((0, 0)) => {
+                        // Nothing will wait on an empty folder, so release it right away
+                        is_empty = true;
+                        let mut queue = state.0.lock().await;
+                        queue.pending_folders.remove(&item.path);
+                        "Folder is empty".to_string()
+                    }
+                    Ok((file_count, subfolder_count)) => format!(
+                        "Found {} files and {} subfolders",
+                        file_count, subfolder_count
+                    ),
+                    Err(error) => {
+                        handle_folder_error(&app, &state, &item.id, &item.name, &error).await?;
+
+                        // Remove from pending folders
+                        let mut queue = state.0.lock().await;
+                        queue.pending_folders.remove(&item.path);
+
+                        return Err(error);
+                    }
+                }
+            };
+
+            // Update progress
+            emit_progress(
+                &app,
+                &state,
+                TransferProgress {
+                    id: item.id.clone(),
+                    name: item.name.clone(),
+                    item_type: "folder".to_string(),
+                    progress: 0.3,
+                    status: "processing".to_string(),
+                    message: Some(message),
+                    speed: None,
+                    remaining_time: None,
+                    size: None, // Add this line
+                    uploaded_bytes: Some(0),
+                    error_code: None,
+                },
+            )
+            .map_err(|e| format!("Failed to emit progress: {}", e))?;
+
+            // Check if we've already sent completion notification for this folder
+            let notification_already_sent = {
+                let mut queue = state.0.lock().await;
+                let exists = queue.completion_notifications_sent.contains(&item.id);
+                if !exists {
+                    queue.completion_notifications_sent.insert(item.id.clone());
                 }
                 exists
             };
 
-            if !finalization_already_sent {
-                // Send final progress update
-                app.emit(
-                    "transfer-progress",
-                    TransferProgress {
-                        id: item.id.clone(),
-                        name: item.name.clone(),
-                        item_type: "file".to_string(),
-                        progress: 1.0,
-                        status: "uploading".to_string(),
-                        message: Some("Upload complete, finalizing...".to_string()),
-                        speed: None,
-                        remaining_time: None,
-                        size: Some(file_size), // Add this line
-                    },
-                )
-                .map_err(|e| format!("Failed to emit progress: {}", e))?;
+            if !notification_already_sent {
+                // Emit final progress for folder
+                emit_progress(
+                    &app,
+                    &state,
+                    TransferProgress {
+                        id: item.id.clone(),
+                        name: item.name.clone(),
+                        item_type: "folder".to_string(),
+                        progress: 1.0,
+                        status: "completed".to_string(),
+                        message: Some(if is_empty {
+                            "Empty folder created".to_string()
+                        } else {
+                            "Folder processing complete, starting contents...".to_string()
+                        }),
+                        speed: None,
+                        remaining_time: None,
+                        size: None, // Add this line
+                        uploaded_bytes: Some(0),
+                        error_code: None,
+                    },
+                )
+                .map_err(|e| format!("Failed to emit progress: {}", e))?;
+
+                // Emit folder completion event
+                state
+                    .emit(
+                        &app,
+                        "transfer-complete",
+                        serde_json::json!({
+                            "id": item.id.clone(),
+                            "name": item.name,
+                            "folder_id": folder_id,
+                            "parent_id": parent_id,
+                            "empty": is_empty,
+                            "status": "completed",
+                            "message": if is_empty {
+                                "Empty folder created successfully"
+                            } else {
+                                "Folder created successfully"
+                            }
+                        }),
+                    )
+                    .map_err(|e| format!("Failed to emit folder completion: {}", e))?;
+            }
+        }
+    }
+
+    // Mark folder as completed in state
+    let updated_folders = {
+        let mut queue = state.0.lock().await;
+        queue.finish_folder(&item)
+    };
+    emit_folder_progress(&app, &state, updated_folders)?;
+
+    process_next_item(app.clone(), state.clone()).await?;
+
+    Ok(())
+}
+
+/// Emits a `transfer-progress` event, remembering it for get_transfer_status
+fn emit_progress(
+    app: &dyn EventSink,
+    state: &TransferSession,
+    progress: TransferProgress,
+) -> Result<(), String> {
+    LAST_PROGRESS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .insert(progress.id.clone(), progress.clone());
+
+    state.emit(app, "transfer-progress", progress)
+}
+
+/// Emits rolled-up progress for folders whose descendant counts changed
+fn emit_folder_progress(
+    app: &dyn EventSink,
+    state: &TransferSession,
+    folders: Vec<FolderProgress>,
+) -> Result<(), String> {
+    for folder in folders {
+        let done = folder.completed_items + folder.failed_items;
+        let progress = folder.fraction_done();
+        let status = if done < folder.total_items {
+            "uploading"
+        } else {
+            "completed"
+        };
+
+        emit_progress(
+            app,
+            state,
+            TransferProgress {
+                id: folder.item_id,
+                name: folder.name,
+                item_type: "folder".to_string(),
+                progress,
+                status: status.to_string(),
+                message: Some(if folder.failed_items > 0 {
+                    format!(
+                        "{}/{} items done, {} failed",
+                        done, folder.total_items, folder.failed_items
+                    )
+                } else {
+                    format!("{}/{} items done", done, folder.total_items)
+                }),
+                speed: None,
+                remaining_time: None,
+                size: None,
+                uploaded_bytes: Some(folder.uploaded_bytes),
+                error_code: None,
+            },
+        )
+        .map_err(|e| format!("Failed to emit progress: {}", e))?;
+    }
+
+    Ok(())
+}
+
+/// Handles errors that occur during file processing
+async fn handle_file_error(
+    app: &dyn EventSink,
+    state: &TransferSession,
+    id: &str,
+    name: &str,
+    file_size: &Option<u64>,
+    error: &TransferError,
+) -> Result<(), String> {
+    let notify_failure;
+
+    TransferMetrics::count(&state.2.files_failed);
+    if matches!(error, TransferError::Timeout(_)) {
+        TransferMetrics::count(&state.2.timeouts);
+    }
+
+    // Update state
+    let updated_folders = {
+        let mut queue = state.0.lock().await;
+        let updated_folders = queue.record_failed_child(id);
+        queue.processing = None;
+        queue.mark_failed(id, error.to_string());
+        notify_failure = queue.notifications_enabled && queue.notify_each_failure;
+        // Also clean up all tracking, letting the same item be queued again
+        queue.initialized_files.remove(id);
+        queue.dedup_keys.retain(|_, item_id| item_id != id);
+        queue.completion_notifications_sent.remove(id);
+        queue.received_url_responses.remove(id);
+        queue.request_timestamps.remove(id);
+        if let Some(server_file_id) = queue.server_file_ids.remove(id) {
+            queue.clear_block_tracking(&server_file_id);
+        }
+
+        updated_folders
+    };
+    emit_folder_progress(app, state, updated_folders)?;
+
+    // Emit error event
+    emit_progress(
+        app,
+        state,
+        TransferProgress {
+            id: id.to_string(),
+            name: name.to_string(),
+            item_type: "file".to_string(),
+            progress: 0.0,
+            status: "failed".to_string(),
+            message: Some(error.to_string()),
+            speed: None,
+            remaining_time: None,
+            size: *file_size, // Add this line
+            uploaded_bytes: None,
+            error_code: Some(error.code().to_string()),
+        },
+    )
+    .map_err(|e| format!("Failed to emit error: {}", e))?;
+
+    // Also emit a transfer-complete with error for frontend to properly handle it
+    state
+        .emit(
+            app,
+            "transfer-complete",
+            serde_json::json!({
+                "id": id,
+                "name": name,
+                "status": "failed",
+                "code": error.code(),
+                "message": error.to_string()
+            }),
+        )
+        .map_err(|e| format!("Failed to emit completion error: {}", e))?;
+
+    if notify_failure {
+        show_notification(
+            app,
+            &format!("Failed to upload {}", name),
+            &error.to_string(),
+        );
+    }
+
+    Ok(())
+}
+
+/// Reports that a file failed because its upload URLs expired, as opposed to
+/// a network or server error
+fn emit_url_expired(
+    app: &dyn EventSink,
+    state: &TransferSession,
+    item: &QueueItem,
+    file_size: u64,
+    error: &str,
+) {
+    let _ = emit_progress(
+        app,
+        state,
+        TransferProgress {
+            id: item.id.clone(),
+            name: item.name.clone(),
+            item_type: "file".to_string(),
+            progress: 0.0,
+            status: "url_expired".to_string(),
+            message: Some(error.to_string()),
+            speed: None,
+            remaining_time: None,
+            size: Some(file_size),
+            uploaded_bytes: None,
+            error_code: None,
+        },
+    );
+}
+
+/// Handles errors that occur during folder processing
+async fn handle_folder_error(
+    app: &dyn EventSink,
+    state: &TransferSession,
+    id: &str,
+    name: &str,
+    error: &TransferError,
+) -> Result<(), String> {
+    let notify_failure;
+
+    // Update state
+    let updated_folders = {
+        let mut queue = state.0.lock().await;
+        let updated_folders = queue.record_failed_child(id);
+        queue.processing = None;
+        queue.forget_folder_item(id);
+        queue.mark_failed(id, error.to_string());
+        notify_failure = queue.notifications_enabled && queue.notify_each_failure;
+        // Also clean up all tracking, letting the same item be queued again
+        queue.initialized_folders.remove(id);
+        queue.dedup_keys.retain(|_, item_id| item_id != id);
+        queue.completion_notifications_sent.remove(id);
+        queue.received_folder_responses.remove(id);
+        queue.request_timestamps.remove(id);
+
+        updated_folders
+    };
+    emit_folder_progress(app, state, updated_folders)?;
+
+    // Emit error event
+    emit_progress(
+        app,
+        state,
+        TransferProgress {
+            id: id.to_string(),
+            name: name.to_string(),
+            item_type: "folder".to_string(),
+            progress: 0.0,
+            status: "failed".to_string(),
+            message: Some(error.to_string()),
+            speed: None,
+            remaining_time: None,
+            size: None, // Add this line
+            uploaded_bytes: None,
+            error_code: Some(error.code().to_string()),
+        },
+    )
+    .map_err(|e| format!("Failed to emit error: {}", e))?;
+
+    // Also emit a transfer-complete with error for frontend to properly handle it
+    state
+        .emit(
+            app,
+            "transfer-complete",
+            serde_json::json!({
+                "id": id,
+                "name": name,
+                "status": "failed",
+                "code": error.code(),
+                "message": error.to_string()
+            }),
+        )
+        .map_err(|e| format!("Failed to emit completion error: {}", e))?;
+
+    if notify_failure {
+        show_notification(
+            app,
+            &format!("Failed to upload {}", name),
+            &error.to_string(),
+        );
+    }
+
+    Ok(())
+}
+
+// Add a handler function for thumbnail completion
+#[command]
+pub fn handle_thumbnail_complete(payload: Option<&str>) -> Result<(), String> {
+    if let Some(payload_str) = payload {
+        if let Ok(payload_json) = serde_json::from_str::<serde_json::Value>(payload_str) {
+            // Extract the thumbnail info
+            let thumbnail_id = payload_json["thumbnail_id"].as_str().unwrap_or("");
+            let hash = payload_json["hash"].as_str().unwrap_or("");
+            let size = payload_json["size"].as_u64().unwrap_or(0);
+
+            println!(
+                "Thumbnail completed: id={}, hash={}, size={}",
+                thumbnail_id, hash, size
+            );
+
+            // The frontend API will handle updating the server with this information
+            return Ok(());
+        }
+    }
+
+    Err("Invalid thumbnail completion payload".to_string())
+}
+
+/// Handler for URL response from frontend
+#[command]
+pub async fn upload_urls_response(
+    payload: UploadUrlsResponsePayload,
+    app: AppHandle,
+    session_id: Option<String>,
+) -> Result<(), String> {
+    let state = app
+        .state::<TransferManagerState>()
+        .session(session_id.as_deref());
+    println!(
+        "Received upload URLs response for transfer ID: {}",
+        payload.transfer_id
+    );
+
+    match deliver_response(
+        &RESPONSE_CHANNELS,
+        &payload.transfer_id,
+        Ok(payload.response),
+    )
+    .await
+    {
+        Delivery::Delivered => println!("Successfully sent response through channel"),
+        Delivery::ReceiverDropped => {
+            println!("Failed to send response through channel - receiver dropped")
+        }
+        Delivery::NoReceiver => {
+            println!(
+                "No waiting receiver found for transfer ID: {}",
+                payload.transfer_id
+            );
+
+            // The request already timed out or was cancelled, so clear its state
+            let mut queue = state.0.lock().await;
+            queue.received_url_responses.remove(&payload.transfer_id);
+            queue.request_timestamps.remove(&payload.transfer_id);
+        }
+    }
+
+    Ok(())
+}
+
+/// Handler for error response from frontend
+#[command]
+pub async fn upload_error_response(
+    payload: ErrorResponsePayload,
+    app: AppHandle,
+    session_id: Option<String>,
+) -> Result<(), String> {
+    let state = app
+        .state::<TransferManagerState>()
+        .session(session_id.as_deref());
+    println!(
+        "Received error response for transfer ID: {}: {}",
+        payload.transfer_id, payload.error
+    );
+
+    // The waiting upload decides whether an existing file is an error,
+    // based on its conflict policy
+    let already_exists = payload.code.as_deref() == Some(ALREADY_EXISTS);
+    let error = if already_exists {
+        ALREADY_EXISTS.to_string()
+    } else {
+        payload.error.clone()
+    };
+
+    match deliver_response(&RESPONSE_CHANNELS, &payload.transfer_id, Err(error)).await {
+        Delivery::Delivered => {
+            println!("Successfully sent error through channel");
+            if already_exists {
+                return Ok(());
+            }
+        }
+        Delivery::ReceiverDropped => {
+            println!("Failed to send error through channel - receiver dropped")
+        }
+        Delivery::NoReceiver => {
+            println!(
+                "No waiting receiver found for transfer ID: {}",
+                payload.transfer_id
+            );
+
+            // If no receiver was found, we should clear any state related to this ID
+            let mut queue = state.0.lock().await;
+            queue.received_url_responses.remove(&payload.transfer_id);
+            queue.request_timestamps.remove(&payload.transfer_id);
+        }
+    }
+
+    // Get the item name for the error
+    let item_name = {
+        let queue = state.0.lock().await;
+        queue
+            .items
+            .iter()
+            .find(|item| item.id == payload.transfer_id)
+            .map(|item| item.name.clone())
+            .unwrap_or_else(|| "Unknown".to_string())
+    };
+
+    // Update transfer state to failed
+    handle_file_error(
+        &app,
+        &state,
+        &payload.transfer_id,
+        &item_name,
+        &None,
+        &TransferError::Server(payload.error.clone()),
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Handler for the server's answer to a `complete-multipart` request. A list
+/// of rejected part numbers has just those parts uploaded again.
+#[command]
+pub async fn complete_multipart_response(
+    transfer_id: String,
+    retry_parts: Option<Vec<usize>>,
+    error: Option<String>,
+) -> Result<(), String> {
+    println!(
+        "Received multipart completion response for transfer ID: {}",
+        transfer_id
+    );
+
+    let response = match error {
+        Some(error) => Err(error),
+        None => Ok(MultipartCompleteResponse {
+            retry_parts: retry_parts.unwrap_or_default(),
+        }),
+    };
+
+    match deliver_response(&MULTIPART_RESPONSE_CHANNELS, &transfer_id, response).await {
+        Delivery::Delivered => {}
+        Delivery::ReceiverDropped => {
+            println!("Failed to send multipart response through channel - receiver dropped")
+        }
+        Delivery::NoReceiver => println!(
+            "No waiting receiver found for multipart transfer ID: {}",
+            transfer_id
+        ),
+    }
+
+    Ok(())
+}
+
+/// Handler for folder creation response from frontend
+#[command]
+pub async fn folder_created_response(
+    transfer_id: String,
+    response: FolderResponse,
+    app: AppHandle,
+    session_id: Option<String>,
+) -> Result<(), String> {
+    let state = app
+        .state::<TransferManagerState>()
+        .session(session_id.as_deref());
+    println!(
+        "Received folder creation response for transfer ID: {}",
+        transfer_id
+    );
+
+    match deliver_response(&FOLDER_RESPONSE_CHANNELS, &transfer_id, Ok(response)).await {
+        Delivery::Delivered => println!("Successfully sent folder response through channel"),
+        Delivery::ReceiverDropped => {
+            println!("Failed to send folder response through channel - receiver dropped")
+        }
+        Delivery::NoReceiver => {
+            println!(
+                "No waiting receiver found for folder transfer ID: {}",
+                transfer_id
+            );
+
+            // The request already timed out or was cancelled, so clear its state
+            let mut queue = state.0.lock().await;
+            queue.received_folder_responses.remove(&transfer_id);
+            queue.request_timestamps.remove(&transfer_id);
+        }
+    }
+
+    Ok(())
+}
+
+/// Handler for folder error response from frontend
+#[command]
+pub async fn folder_error_response(
+    transfer_id: String,
+    error: String,
+    app: AppHandle,
+    session_id: Option<String>,
+) -> Result<(), String> {
+    let state = app
+        .state::<TransferManagerState>()
+        .session(session_id.as_deref());
+    println!(
+        "Received folder error response for transfer ID: {}: {}",
+        transfer_id, error
+    );
+
+    match deliver_response(&FOLDER_RESPONSE_CHANNELS, &transfer_id, Err(error.clone())).await {
+        Delivery::Delivered => println!("Successfully sent folder error through channel"),
+        Delivery::ReceiverDropped => {
+            println!("Failed to send folder error through channel - receiver dropped")
+        }
+        Delivery::NoReceiver => {
+            println!(
+                "No waiting receiver found for folder transfer ID: {}",
+                transfer_id
+            );
+
+            // If no receiver was found, we should clear any state related to this ID
+            let mut queue = state.0.lock().await;
+            queue.received_folder_responses.remove(&transfer_id);
+            queue.request_timestamps.remove(&transfer_id);
+        }
+    }
+
+    // create_folder reports errors to its caller; there's no queue item to fail
+    if transfer_id.starts_with(CREATE_FOLDER_ID_PREFIX) {
+        return Ok(());
+    }
+
+    // Get the item name for the error
+    let item_name = {
+        let queue = state.0.lock().await;
+        queue
+            .items
+            .iter()
+            .find(|item| item.id == transfer_id)
+            .map(|item| item.name.clone())
+            .unwrap_or_else(|| "Unknown".to_string())
+    };
+
+    // Update transfer state to failed
+    handle_folder_error(
+        &app,
+        &state,
+        &transfer_id,
+        &item_name,
+        &TransferError::Server(error),
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Whether a transfer has a request out to the frontend it is waiting on
+async fn is_waiting_on_frontend(id: &str) -> bool {
+    RESPONSE_CHANNELS.lock().await.contains_key(id)
+        || FOLDER_RESPONSE_CHANNELS.lock().await.contains_key(id)
+        || MULTIPART_RESPONSE_CHANNELS.lock().await.contains_key(id)
+}
+
+/// Clears a `processing` item that nothing is working on, putting it back at
+/// the front of the queue, and starts processing again. Returns the ID that
+/// was reset, if any.
+#[command]
+pub async fn reset_processing_state(
+    app: AppHandle,
+    state: State<'_, TransferManagerState>,
+    session_id: Option<String>,
+) -> Result<Option<String>, String> {
+    let state = state.session(session_id.as_deref());
+    let processing = state.0.lock().await.processing.clone();
+    let Some(id) = processing else {
+        return Ok(None);
+    };
+    let waiting = is_waiting_on_frontend(&id).await;
+
+    let reset = {
+        let mut queue = state.0.lock().await;
+        // Only reset the item that was checked; it may have moved on meanwhile
+        if queue.processing.as_deref() != Some(id.as_str()) {
+            return Ok(None);
+        }
+        queue.reset_orphaned_processing(waiting)
+    };
+
+    if let Some(id) = &reset {
+        println!("Reset orphaned processing state for {}", id);
+        process_next_item(events(&app), state).await?;
+    }
+
+    Ok(reset)
+}
+
+/// Cleans up any stuck or hanging transfers
+#[command]
+pub async fn cleanup_stuck_transfers(
+    app: AppHandle,
+    state: State<'_, TransferManagerState>,
+    session_id: Option<String>,
+) -> Result<serde_json::Value, String> {
+    let state = state.session(session_id.as_deref());
+    let cleaned_count;
+    let mut cleaned_ids: Vec<String> = Vec::new();
+
+    {
+        let mut queue = state.0.lock().await;
+        let current_time = Instant::now();
+        let mut hanging_ids: Vec<String> = Vec::new();
+
+        // Find all hanging requests (older than 35 seconds)
+        let mut hanging_ids = Vec::new();
+        for (id, timestamp) in &queue.request_timestamps {
+            if current_time.duration_since(*timestamp) > Duration::from_secs(35) {
+                hanging_ids.push(id.clone());
+                println!("Found hanging request for ID: {}, will clean up", id);
+            }
+        }
+
+        cleaned_count = hanging_ids.len();
+        cleaned_ids = hanging_ids.clone();
+
+        // Clean up each hanging request
+        for id in &hanging_ids {
+            queue.request_timestamps.remove(id);
+            queue.received_url_responses.remove(id);
+            queue.received_folder_responses.remove(id);
+
+            // If this was the current processing item, clear it
+            if let Some(processing_id) = &queue.processing {
+                if processing_id == id {
+                    queue.processing = None;
+                }
+            }
+
+            queue.mark_failed(&id, "Request timed out".to_string());
+        }
+
+        // Handle channels outside the main lock to avoid deadlocks
+        for id in &hanging_ids {
+            let item_name = queue
+                .items
+                .iter()
+                .find(|item| &item.id == id)
+                .map(|item| item.name.clone())
+                .unwrap_or_else(|| "Unknown".to_string());
+
+            // Also notify frontend of failure
+            state
+                .emit(
+                    &app,
+                    "transfer-complete",
+                    serde_json::json!({
+                        "id": id,
+                        "name": item_name,
+                        "status": "failed",
+                        "message": "Request timed out"
+                    }),
+                )
+                .ok();
+        }
+    }
+
+    // Handle channels
+    let mut channels_to_clean = Vec::new();
+    {
+        let queue = state.0.lock().await;
+        for (id, timestamp) in &queue.request_timestamps {
+            if Instant::now().duration_since(*timestamp) > Duration::from_secs(35) {
+                channels_to_clean.push(id.clone());
+            }
+        }
+    }
+
+    for id in &channels_to_clean {
+        let timed_out = "Request timed out".to_string();
+        deliver_response(&RESPONSE_CHANNELS, id, Err(timed_out.clone())).await;
+        deliver_response(&FOLDER_RESPONSE_CHANNELS, id, Err(timed_out)).await;
+    }
+
+    // Recheck pending folders and clean up any that are stuck
+    {
+        let mut queue = state.0.lock().await;
+        for id in &channels_to_clean {
+            // Find any queue items that matched this ID and get their paths
+            let path_to_remove = queue
+                .items
+                .iter()
+                .find(|item| &item.id == id)
+                .map(|item| item.path.clone());
+
+            if let Some(path) = path_to_remove {
+                queue.pending_folders.remove(&path);
+            }
+        }
+    }
+
+    // If we cleaned up any items, try to process the next one
+    if !channels_to_clean.is_empty() {
+        process_next_item(events(&app), state.clone()).await?;
+    }
+
+    // Return the number of cleaned up transfers
+    Ok(serde_json::json!({
+        "cleaned_count": cleaned_count,
+        "cleaned_ids": channels_to_clean
+    }))
+}
+
+/// Health check to verify frontend-backend communication
+#[command]
+pub async fn check_transfer_health() -> Result<serde_json::Value, String> {
+    Ok(serde_json::json!({
+        "status": "healthy",
+        "timestamp": chrono::Utc::now().timestamp(),
+        "version": env!("CARGO_PKG_VERSION")
+    }))
+}
+
+/// Checks and repairs pending folder state
+#[command]
+pub async fn repair_pending_folders(
+    app: AppHandle,
+    state: State<'_, TransferManagerState>,
+    session_id: Option<String>,
+) -> Result<serde_json::Value, String> {
+    let state = state.session(session_id.as_deref());
+    let mut repaired_count = 0;
+
+    {
+        let mut queue = state.0.lock().await;
+
+        // Check if any pending folders don't have a matching folder in the queue or processing
+        let mut stale_pending_folders = Vec::new();
+
+        for pending_path in queue.pending_folders.iter() {
+            // Check if this folder is currently being processed
+            let is_processing = if let Some(processing_id) = &queue.processing {
+                queue.items.iter().any(|item| {
+                    &item.id == processing_id
+                        && item.item_type == "folder"
+                        && &item.path == pending_path
+                })
+            } else {
+                false
+            };
+
+            // Check if this folder is waiting in the queue
+            let in_queue = queue
+                .items
+                .iter()
+                .any(|item| item.item_type == "folder" && &item.path == pending_path);
+
+            // If neither processing nor in queue, it's stale
+            if !is_processing && !in_queue {
+                stale_pending_folders.push(pending_path.clone());
+            }
+        }
+
+        // Remove stale pending folders
+        for stale_path in stale_pending_folders {
+            queue.pending_folders.remove(&stale_path);
+            repaired_count += 1;
+        }
+    }
+
+    // If we repaired any items and processing is not active, try to process the next one
+    if repaired_count > 0 {
+        let is_processing = {
+            let queue = state.0.lock().await;
+            queue.processing.is_some() || queue.is_paused()
+        };
+
+        if !is_processing {
+            process_next_item(events(&app), state.clone()).await?;
+        }
+    }
+
+    Ok(serde_json::json!({
+        "repaired_count": repaired_count
+    }))
+}
+
+/// Starts the background task that runs cleanup_stuck_transfers and
+/// repair_pending_folders for every session with in-flight items, so a
+/// wedged queue recovers even when the frontend never polls them
+pub fn start_watchdog(app: AppHandle) {
+    tauri::async_runtime::spawn(async move {
+        loop {
+            let config = *WATCHDOG.lock().unwrap();
+            tokio::select! {
+                _ = tokio::time::sleep(config.interval) => {}
+                _ = WATCHDOG_CHANGED.notified() => continue,
+            }
+            if config.enabled {
+                run_watchdog(&app).await;
+            }
+        }
+    });
+}
+
+/// One watchdog pass over every session, through the same commands the
+/// frontend calls, so only requests past their timeout are touched
+async fn run_watchdog(app: &AppHandle) {
+    let sessions = app.state::<TransferManagerState>().sessions();
+
+    for (session_id, session) in sessions {
+        if !session.0.lock().await.has_in_flight() {
+            continue;
+        }
+
+        let cleaned = match cleanup_stuck_transfers(
+            app.clone(),
+            app.state::<TransferManagerState>(),
+            Some(session_id.clone()),
+        )
+        .await
+        {
+            Ok(result) => result["cleaned_count"].as_u64().unwrap_or(0),
+            Err(e) => {
+                println!("Watchdog cleanup failed for session {}: {}", session_id, e);
+                0
+            }
+        };
+
+        let repaired = match repair_pending_folders(
+            app.clone(),
+            app.state::<TransferManagerState>(),
+            Some(session_id.clone()),
+        )
+        .await
+        {
+            Ok(result) => result["repaired_count"].as_u64().unwrap_or(0),
+            Err(e) => {
+                println!("Watchdog repair failed for session {}: {}", session_id, e);
+                0
+            }
+        };
+
+        if cleaned > 0 || repaired > 0 {
+            println!(
+                "Watchdog recovered session {}: {} stuck transfers, {} pending folders",
+                session_id, cleaned, repaired
+            );
+            session
+                .emit(
+                    app,
+                    "watchdog-recovered",
+                    serde_json::json!({
+                        "session_id": session_id,
+                        "cleaned_count": cleaned,
+                        "repaired_count": repaired,
+                    }),
+                )
+                .ok();
+        }
+    }
+}
+
+/// Turns the stuck-transfer watchdog on or off, optionally changing how
+/// often it runs. It starts enabled at every launch.
+#[command]
+pub async fn set_watchdog(enabled: bool, interval_seconds: Option<u64>) -> Result<(), String> {
+    if interval_seconds == Some(0) {
+        return Err("Watchdog interval must be at least 1 second".to_string());
+    }
+
+    {
+        let mut config = WATCHDOG.lock().unwrap();
+        config.enabled = enabled;
+        if let Some(seconds) = interval_seconds {
+            config.interval = Duration::from_secs(seconds);
+        }
+    }
+    WATCHDOG_CHANGED.notify_one();
+    Ok(())
+}
+
+/// Chooses the backend blocks are uploaded with, by name. Files already
+/// uploading keep the backend they started with.
+#[command]
+pub async fn set_upload_backend(
+    app: AppHandle,
+    backend: String,
+    state: State<'_, TransferManagerState>,
+) -> Result<(), String> {
+    update_transfer_settings(&transfer_store_path(&app)?, &state, |settings| {
+        settings.upload_backend = backend
+    })
+    .await
+}
+
+/// Reads the saved transfer settings, falling back to the defaults when none
+/// were saved or they can't be used
+pub async fn load_transfer_settings(store_path: &Path) -> TransferSettings {
+    let saved = match crate::read_store_value(store_path, TRANSFER_SETTINGS_KEY).await {
+        Ok(Some(saved)) => saved,
+        Ok(None) => return TransferSettings::default(),
+        Err(e) => {
+            println!("Failed to read transfer settings: {}", e);
+            return TransferSettings::default();
+        }
+    };
+
+    match serde_json::from_str::<TransferSettings>(&saved)
+        .map_err(|e| e.to_string())
+        .and_then(|settings| settings.validate().map(|_| settings))
+    {
+        Ok(settings) => settings,
+        Err(e) => {
+            println!("Ignoring saved transfer settings: {}", e);
+            TransferSettings::default()
+        }
+    }
+}
+
+/// The settings a session is running with, including changes made through
+/// the individual set_* commands
+#[command]
+pub async fn get_transfer_settings(
+    state: State<'_, TransferManagerState>,
+    session_id: Option<String>,
+) -> Result<TransferSettings, String> {
+    let state = state.session(session_id.as_deref());
+    let settings = state.0.lock().await.settings();
+    Ok(settings)
+}
+
+/// Saves transfer settings for future launches and applies them to every
+/// session. They are saved before being applied, so a failed write leaves
+/// the running sessions unchanged.
+#[command]
+pub async fn set_transfer_settings(
+    app: AppHandle,
+    settings: TransferSettings,
+    state: State<'_, TransferManagerState>,
+) -> Result<(), String> {
+    settings.validate()?;
+    if let Some(path) = &settings.staging_directory {
+        check_staging_directory(path)?;
+    }
+    save_transfer_settings(&transfer_store_path(&app)?, settings, &state).await
+}
+
+/// Store directory transfer settings are saved in
+fn transfer_store_path(app: &AppHandle) -> Result<PathBuf, String> {
+    Ok(app
+        .path()
+        .app_data_dir()
+        .map_err(|e| format!("Failed to get app data directory: {}", e))?
+        .join("store"))
+}
+
+/// Changes the current settings with `update`, then saves and applies them
+/// like set_transfer_settings. Invalid results are rejected unsaved.
+async fn update_transfer_settings(
+    store_path: &Path,
+    state: &TransferManagerState,
+    update: impl FnOnce(&mut TransferSettings),
+) -> Result<(), String> {
+    let mut settings = state.1.lock().unwrap().clone();
+    update(&mut settings);
+    settings.validate()?;
+    save_transfer_settings(store_path, settings, state).await
+}
+
+/// Persists settings and applies them to every session
+async fn save_transfer_settings(
+    store_path: &Path,
+    settings: TransferSettings,
+    state: &TransferManagerState,
+) -> Result<(), String> {
+    tokio::fs::create_dir_all(store_path)
+        .await
+        .map_err(|e| format!("Failed to create store directory: {}", e))?;
+    let contents = serde_json::to_string(&settings)
+        .map_err(|e| format!("Failed to serialize transfer settings: {}", e))?;
+    crate::write_store_value(store_path, TRANSFER_SETTINGS_KEY, &contents).await?;
+
+    *state.1.lock().unwrap() = settings.clone();
+    for (_, session) in state.sessions() {
+        session.apply_settings(&settings).await;
+    }
+
+    Ok(())
+}
+
+/// Checks that `path` is a directory intermediate files can be written to,
+/// by writing and removing a small file in it
+fn check_staging_directory(path: &Path) -> Result<(), String> {
+    if !path.is_dir() {
+        return Err(format!(
+            "Staging directory doesn't exist: {}",
+            path.display()
+        ));
+    }
+
+    let probe = path.join(format!(".cirrussync-staging-{}", generate_id()));
+    std::fs::write(&probe, b"").map_err(|e| format!("Staging directory isn't writable: {}", e))?;
+    let _ = std::fs::remove_file(&probe);
+    Ok(())
+}
+
+/// Chooses where partial downloads and upload checkpoints go, saved for
+/// future launches. Pass None to go back to the OS temp directory.
+#[command]
+pub async fn set_staging_directory(
+    app: AppHandle,
+    path: Option<String>,
+    state: State<'_, TransferManagerState>,
+) -> Result<(), String> {
+    let staging_directory = path.map(PathBuf::from);
+    if let Some(path) = &staging_directory {
+        check_staging_directory(path)?;
+    }
+
+    let settings = TransferSettings {
+        staging_directory,
+        ..state.1.lock().unwrap().clone()
+    };
+    save_transfer_settings(&transfer_store_path(&app)?, settings, &state).await
+}
+
+/// Switches the queue to a new account key. Files that haven't been
+/// initialized yet, and the one waiting on its init response, get a fresh
+/// init with the new key; files already uploading blocks finish under the key
+/// they started with and report its ID when finalized. Returns how many files
+/// will use the new key.
+#[command]
+pub async fn rekey_pending_transfers(
+    key_id: Option<String>,
+    state: State<'_, TransferManagerState>,
+    session_id: Option<String>,
+) -> Result<usize, String> {
+    let state = state.session(session_id.as_deref());
+    let (waiting_id, queued) = {
+        let mut queue = state.0.lock().await;
+        queue.key_generation += 1;
+        queue.key_id = key_id;
+
+        // Init requests are the only ones tracked in request_timestamps; a
+        // URL refresh for a file that is already uploading isn't
+        let waiting_id = queue
+            .processing
+            .clone()
+            .filter(|id| queue.request_timestamps.contains_key(id));
+        let queued = queue
+            .items
+            .iter()
+            .filter(|item| item.item_type == "file")
+            .count();
+        (waiting_id, queued)
+    };
+
+    let mut interrupted = 0;
+    if let Some(id) = waiting_id {
+        if deliver_response(&RESPONSE_CHANNELS, &id, Err(REKEYED.to_string())).await
+            != Delivery::NoReceiver
+        {
+            interrupted = 1;
+        }
+    }
+
+    println!(
+        "Rekeyed transfers: {} queued, {} interrupted",
+        queued, interrupted
+    );
+    Ok(queued + interrupted)
+}
+
+/// Returns detailed queue status for debugging
+#[command]
+pub async fn get_detailed_queue_status(
+    state: State<'_, TransferManagerState>,
+    session_id: Option<String>,
+) -> Result<serde_json::Value, String> {
+    let state = state.session(session_id.as_deref());
+    let queue = state.0.lock().await;
+    Ok(detailed_status(&queue))
+}
+
+/// Queue state for get_detailed_queue_status and diagnostics bundles
+fn detailed_status(queue: &TransferQueue) -> serde_json::Value {
+    // Format queue items for display
+    let queue_items: Vec<serde_json::Value> = queue
+        .items
+        .iter()
+        .map(|item| {
+            serde_json::json!({
+                "id": item.id,
+                "type": item.item_type,
+                "name": item.name,
+                "depth": item.depth,
+                "parent_id": item.parent_id,
+                "priority": item.priority
+            })
+        })
+        .collect();
+
+    // Format pending folders for display
+    let pending_folders: Vec<String> = queue.pending_folders.iter().cloned().collect();
+
+    // Format folder ID mappings
+    let folder_mappings: HashMap<String, String> = queue.folder_id_map.clone();
+
+    // Rolled-up progress per created folder, keyed by server folder ID
+    let folders: HashMap<String, serde_json::Value> = queue
+        .folder_progress
+        .iter()
+        .map(|(folder_id, folder)| {
+            (
+                folder_id.clone(),
+                serde_json::json!({
+                    "id": folder.item_id,
+                    "name": folder.name,
+                    "total_items": folder.total_items,
+                    "completed_items": folder.completed_items,
+                    "failed_items": folder.failed_items,
+                    "uploaded_bytes": folder.uploaded_bytes,
+                    "progress": folder.fraction_done(),
+                }),
+            )
+        })
+        .collect();
+
+    serde_json::json!({
+        "queue_size": queue.items.len(),
+        "processing": queue.processing,
+        "completed_count": queue.completed.len(),
+        "failed_count": queue.failed.len(),
+        "skipped_count": queue.skipped.len(),
+        "paused": queue.is_paused(),
+        "pause_mode": queue.pause_mode(),
+        "elapsed_time": queue.start_time.elapsed().as_secs(),
+        "pending_folders_count": queue.pending_folders.len(),
+        "queue_items": queue_items,
+        "pending_folders": pending_folders,
+        "folder_mappings": folder_mappings,
+        "folders": folders,
+        "initialized_files_count": queue.initialized_files.len(),
+        "initialized_folders_count": queue.initialized_folders.len(),
+        "block_completion_sent_count": queue.block_completion_sent.len()
+    })
+}
+
+/// Gathers everything support needs to look into stuck uploads into one JSON
+/// bundle: the detailed queue status, how long each request has been waiting
+/// on the frontend, the response channels still open and the health check.
+/// With `save`, the bundle is also written under the app data directory.
+#[command]
+pub async fn export_diagnostics(
+    app: AppHandle,
+    save: Option<bool>,
+    state: State<'_, TransferManagerState>,
+    session_id: Option<String>,
+) -> Result<serde_json::Value, String> {
+    let sessions: Vec<String> = state.sessions().into_iter().map(|(id, _)| id).collect();
+    let state = state.session(session_id.as_deref());
+
+    let (queue_status, request_ages, processing, last_activity_age) = {
+        let queue = state.0.lock().await;
+        let request_ages: HashMap<String, u64> = queue
+            .request_timestamps
+            .iter()
+            .map(|(id, timestamp)| (id.clone(), timestamp.elapsed().as_secs()))
+            .collect();
+        (
+            detailed_status(&queue),
+            request_ages,
+            queue.processing.clone(),
+            queue.last_activity.map(|at| at.elapsed().as_secs()),
+        )
+    };
+
+    let waiting_on_frontend = match &processing {
+        Some(id) => is_waiting_on_frontend(id).await,
+        None => false,
+    };
 
-                // Request frontend to finalize the transfer by updating content hash
-                app.emit(
-                    "finalize-transfer",
-                    serde_json::json!({
-                        "id": item.id.clone(),
-                        "name": item.name.clone(),
-                        "size": file_size,
-                        "content_hash": content_hash,
-                        "file_id": server_file_id,
-                        "parent_id": parent_id,
-                        "revision_id": revision_id
-                    }),
-                )
-                .map_err(|e| format!("Failed to emit finalization request: {}", e))?;
+    let mut bundle = serde_json::json!({
+        "generated_at": chrono::Utc::now().to_rfc3339(),
+        "sessions": sessions,
+        "queue": queue_status,
+        "request_ages_seconds": request_ages,
+        "processing": {
+            "id": processing,
+            "waiting_on_frontend": waiting_on_frontend,
+            "last_activity_seconds_ago": last_activity_age,
+        },
+        "open_channels": {
+            "upload_urls": RESPONSE_CHANNELS.lock().await.len(),
+            "folders": FOLDER_RESPONSE_CHANNELS.lock().await.len(),
+            "thumbnail_urls": THUMBNAIL_URL_CHANNELS.lock().await.len(),
+            "multipart": MULTIPART_RESPONSE_CHANNELS.lock().await.len(),
+        },
+        "metrics": state.2.snapshot(),
+        "health": check_transfer_health().await?,
+    });
 
-                // Note: We don't mark as completed here - that happens when finalize_transfer_complete is called
-                // The processing state stays active until finalization completes
-            }
-        }
+    if save.unwrap_or(false) {
+        let diagnostics_dir = app
+            .path()
+            .app_data_dir()
+            .map_err(|e| format!("Failed to get app data directory: {}", e))?
+            .join("diagnostics");
+        tokio::fs::create_dir_all(&diagnostics_dir)
+            .await
+            .map_err(|e| format!("Failed to create diagnostics directory: {}", e))?;
+
+        let file_path = diagnostics_dir.join(format!(
+            "transfers-{}.json",
+            chrono::Utc::now().format("%Y%m%d-%H%M%S")
+        ));
+        let contents = serde_json::to_string_pretty(&bundle)
+            .map_err(|e| format!("Failed to serialize diagnostics: {}", e))?;
+        tokio::fs::write(&file_path, contents)
+            .await
+            .map_err(|e| format!("Failed to write diagnostics: {}", e))?;
+
+        println!("Saved transfer diagnostics to {:?}", file_path);
+        bundle["file"] = serde_json::json!(file_path);
     }
 
-    // Don't mark as completed here - wait for finalize_transfer_complete
+    Ok(bundle)
+}
 
+/// Registers all the file transfer commands with Tauri
+pub fn register_file_transfer_commands() -> Result<(), Box<dyn std::error::Error>> {
+    println!("File transfer commands registered");
     Ok(())
 }
 
-/// Processes a folder for upload
-async fn process_folder(
-    app: AppHandle,
-    state: State<'_, TransferManagerState>,
-    item: QueueItem,
-    share_id: String,
-) -> Result<(), String> {
-    let path = Path::new(&item.path);
-    println!("Processing folder: {}", item.path);
-    if !path.exists() || !path.is_dir() {
-        return Err(format!(
-            "Folder not found or is not a directory: {}",
-            item.path
-        ));
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Queue item fixture: named after the last part of its path, in the
+    /// share root of share-1, at normal priority
+    fn test_item(id: &str, path: &str, item_type: &str) -> QueueItem {
+        let name = Path::new(path)
+            .file_name()
+            .map_or(path.to_string(), |name| name.to_string_lossy().to_string());
+        QueueItem {
+            item_type: item_type.to_string(),
+            id: id.to_string(),
+            path: path.to_string(),
+            name,
+            parent_id: "share-root".to_string(),
+            share_id: "share-1".to_string(),
+            depth: 0,
+            data: None,
+            metadata: HashMap::new(),
+            conflict_policy: None,
+            priority: Priority::default(),
+            idempotency_key: generate_idempotency_key(),
+        }
     }
 
-    // Check if this folder has already been initialized
-    let already_initialized = {
-        let queue = state.0.lock().await;
-        queue.initialized_folders.contains(&item.id)
-    };
+    // 10 MiB in 4 MiB blocks needs 3 upload URLs
+    const FILE_SIZE: u64 = 10 * 1024 * 1024;
+    const BLOCK_SIZE: u64 = 4 * 1024 * 1024;
 
-    if already_initialized {
-        println!(
-            "Folder {} already initialized, skipping initialization request",
-            item.id
-        );
+    #[test]
+    fn url_count_check_accepts_one_url_per_block() {
+        assert!(check_url_count(FILE_SIZE, BLOCK_SIZE, 3).is_ok());
+        // A file that fills its last block exactly needs no extra URL
+        assert!(check_url_count(2 * BLOCK_SIZE, BLOCK_SIZE, 2).is_ok());
+    }
 
-        // If already initialized but not completed, mark as processing again
-        let mut queue = state.0.lock().await;
-        if !queue.completed.contains(&item.id) {
-            queue.processing = Some(item.id.clone());
-        } else {
-            // If already completed, skip processing
-            // Remove from pending folders if it was there
-            queue.pending_folders.remove(&item.path);
-            return Ok(());
-        }
-    } else {
-        // Update state to mark as processing and track that we've initialized
-        {
-            let mut queue = state.0.lock().await;
-            queue.processing = Some(item.id.clone());
-            queue.initialized_folders.insert(item.id.clone());
-        }
+    #[test]
+    fn url_count_check_rejects_too_few_urls() {
+        let error = check_url_count(FILE_SIZE, BLOCK_SIZE, 2).unwrap_err();
+        assert_eq!(error.code(), "url_count_mismatch");
+    }
 
-        // Emit event to notify progress start
-        app.emit(
-            "transfer-progress",
-            TransferProgress {
-                id: item.id.clone(),
-                name: item.name.clone(),
-                item_type: "folder".to_string(),
-                progress: 0.0,
-                status: "preparing".to_string(),
-                message: Some("Scanning folder contents...".to_string()),
-                speed: None,
-                remaining_time: None,
-                size: None, // Add this line
-            },
-        )
-        .map_err(|e| format!("Failed to emit progress: {}", e))?;
+    #[test]
+    fn url_count_check_rejects_too_many_urls() {
+        let error = check_url_count(FILE_SIZE, BLOCK_SIZE, 4).unwrap_err();
+        assert_eq!(error.code(), "url_count_mismatch");
+        assert!(check_url_count(2 * BLOCK_SIZE, BLOCK_SIZE, 3).is_err());
+    }
 
-        // Get parent_id from item or folder_id_map
-        let parent_id = {
-            let queue = state.0.lock().await;
-            if let Some(mapped_id) = queue.folder_id_map.get(&item.parent_id) {
-                mapped_id.clone()
-            } else {
-                item.parent_id.clone()
-            }
-        };
+    #[test]
+    fn missing_space_is_reported_with_the_shortfall() {
+        let dir = std::env::temp_dir();
+        assert!(ensure_free_space(&dir, 0).is_ok());
 
-        // Check if we already received a folder response
-        let already_received_response = {
-            let queue = state.0.lock().await;
-            queue.received_folder_responses.contains(&item.id)
-        };
+        let error = ensure_free_space(&dir.join("not-created-yet"), u64::MAX).unwrap_err();
+        assert_eq!(error.code(), "insufficient_space");
+        assert!(error.to_string().contains("bytes short"));
+    }
 
-        if !already_received_response {
-            // Set timestamp for this folder request
-            {
-                let mut queue = state.0.lock().await;
-                queue
-                    .request_timestamps
-                    .insert(item.id.clone(), Instant::now());
-            }
+    #[test]
+    fn saved_settings_fill_in_missing_fields() {
+        let settings: TransferSettings =
+            serde_json::from_str(r#"{"connection_limit": 2}"#).unwrap();
+        assert_eq!(settings.connection_limit, 2);
+        assert_eq!(settings.block_size_limit, DEFAULT_BLOCK_SIZE_LIMIT);
+        assert!(settings.validate().is_ok());
+
+        let mut queue = TransferQueue::new();
+        queue.apply_settings(&settings);
+        assert_eq!(queue.settings(), settings);
+
+        let unknown_backend = TransferSettings {
+            upload_backend: "carrier-pigeon".to_string(),
+            ..TransferSettings::default()
+        };
+        assert!(unknown_backend.validate().is_err());
+    }
 
-            // Send folder info to frontend for creation - once per folder
-            app.emit(
-                "create-folder",
-                serde_json::json!({
-                    "id": item.id,
-                    "name": item.name,
-                    "path": item.path,
-                    "parent_id": parent_id,
-                    "share_id": share_id
-                }),
-            )
-            .map_err(|e| format!("Failed to request folder creation: {}", e))?;
+    #[test]
+    fn absurd_block_sizes_are_rejected_before_allocating() {
+        assert!(check_block_size(BLOCK_SIZE, DEFAULT_BLOCK_SIZE_LIMIT).is_ok());
 
-            // Create a channel to wait for frontend response
-            let (tx, rx) = tokio::sync::oneshot::channel::<Result<FolderResponse, String>>();
+        let error = check_block_size(u64::MAX, DEFAULT_BLOCK_SIZE_LIMIT).unwrap_err();
+        assert_eq!(error.code(), "block_too_large");
+        // The hard cap applies even when the configured limit is higher
+        assert!(check_block_size(MAX_BLOCK_SIZE + 1, u64::MAX).is_err());
+    }
 
-            {
-                let mut channels = FOLDER_RESPONSE_CHANNELS.lock().await;
-                channels.insert(item.id.clone(), tx);
-            }
+    #[tokio::test]
+    async fn blocks_are_read_and_hashed_in_chunks() {
+        let data: Vec<u8> = (0..3 * READ_CHUNK_SIZE + 7).map(|i| i as u8).collect();
+        let mut file: Box<dyn BlockSource> = Box::new(Cursor::new(data.clone()));
+
+        let mut hasher = ContentHasher::new(HashAlgorithm::Sha256);
+        let len = data.len() - 5;
+        let block = read_block(&mut file, 5, len, Some(&mut hasher))
+            .await
+            .unwrap();
+        assert_eq!(block, data[5..]);
+
+        let mut expected = ContentHasher::new(HashAlgorithm::Sha256);
+        expected.update(&data[5..]);
+        assert_eq!(hasher.finalize_hex(), expected.finalize_hex());
+    }
 
-            // Mark that we're expecting a response
-            {
-                let mut queue = state.0.lock().await;
-                queue.received_folder_responses.insert(item.id.clone());
-            }
+    #[tokio::test]
+    async fn blocks_uploaded_out_of_order_are_hashed_in_order() {
+        let data: Vec<u8> = (0..10_000).map(|i| (i % 251) as u8).collect();
+        let mut file: Box<dyn BlockSource> = Box::new(Cursor::new(data.clone()));
+
+        // Block 1 goes up first, then 0 and 2, as parallel uploads might
+        let mut hasher = OrderedHasher::new(HashAlgorithm::Blake3, 4096);
+        for index in [1, 0, 2] {
+            let offset = index as u64 * 4096;
+            let len = 4096.min(data.len() as u64 - offset) as usize;
+            read_block(&mut file, offset, len, hasher.for_block(index))
+                .await
+                .unwrap();
+        }
+        assert_eq!(hasher.hashed_bytes(data.len() as u64), 4096);
 
-            // Wait for the response with timeout
-            let folder_response = match tokio::time::timeout(Duration::from_secs(30), rx).await {
-                Ok(Ok(Ok(response))) => {
-                    // Clear the timestamp tracking since we got a response
-                    let mut queue = state.0.lock().await;
-                    queue.request_timestamps.remove(&item.id);
+        let mut expected = ContentHasher::new(HashAlgorithm::Blake3);
+        expected.update(&data);
+        assert_eq!(
+            hasher.finish(&mut file, data.len() as u64).await.unwrap(),
+            expected.finalize_hex()
+        );
+    }
 
-                    response
-                }
-                Ok(Ok(Err(e))) => {
-                    let error = format!("{}", e);
-                    handle_folder_error(&app, &state, &item.id, &item.name, &error).await?;
+    #[test]
+    fn speed_tracker_smooths_out_a_slow_block() {
+        let mut tracker = SpeedTracker::new(ProgressSmoothing::default());
+        assert_eq!(tracker.remaining_time(1000), None);
 
-                    // Remove from pending folders
-                    let mut queue = state.0.lock().await;
-                    queue.pending_folders.remove(&item.path);
+        tracker.record(1000, Duration::from_secs(1));
+        assert_eq!(tracker.speed, Some(1000.0));
+        assert_eq!(tracker.remaining_time(10_000), Some(10));
 
-                    return Err(error);
-                }
-                Ok(Err(_)) => {
-                    let error = "Channel closed before receiving response".to_string();
-                    handle_folder_error(&app, &state, &item.id, &item.name, &error).await?;
+        // One block at a tenth of the speed only nudges the estimate
+        tracker.record(100, Duration::from_secs(1));
+        let speed = tracker.speed.unwrap();
+        assert!(speed > 800.0 && speed < 1000.0);
+    }
 
-                    // Remove from pending folders
-                    let mut queue = state.0.lock().await;
-                    queue.pending_folders.remove(&item.path);
+    #[test]
+    fn orphaned_processing_item_goes_back_to_the_queue() {
+        let mut queue = TransferQueue::new();
+        let item = test_item(&generate_id(), "/tmp/photo.jpg", "file");
 
-                    return Err(error);
-                }
-                Err(_) => {
-                    let error = "Timeout waiting for folder creation".to_string();
+        // Left behind by a run that stopped mid-upload
+        queue.processing = Some(item.id.clone());
+        queue.current_item = Some(item.clone());
+        queue.initialized_files.insert(item.id.clone());
+        queue.last_activity = Some(Instant::now() - ORPHANED_AFTER * 2);
 
-                    // Clear the timestamp tracking for this request
-                    let mut queue = state.0.lock().await;
-                    queue.request_timestamps.remove(&item.id);
-                    queue.pending_folders.remove(&item.path);
+        // Still waiting on the frontend, so not orphaned yet
+        assert_eq!(queue.reset_orphaned_processing(true), None);
+        assert!(queue.processing.is_some());
 
-                    handle_folder_error(&app, &state, &item.id, &item.name, &error).await?;
-                    return Err(error);
-                }
-            };
+        assert_eq!(
+            queue.reset_orphaned_processing(false),
+            Some(item.id.clone())
+        );
+        assert!(queue.processing.is_none());
+        assert!(!queue.initialized_files.contains(&item.id));
+        assert_eq!(queue.take_next_item().map(|next| next.id), Some(item.id));
+    }
 
-            let folder_id = folder_response.folder_id;
+    #[test]
+    fn active_processing_item_is_not_reset() {
+        let mut queue = TransferQueue::new();
+        queue.processing = Some(generate_id());
+        queue.last_activity = Some(Instant::now());
+        assert_eq!(queue.reset_orphaned_processing(false), None);
 
-            // Store folder ID mapping
-            {
-                let mut queue = state.0.lock().await;
-                queue
-                    .folder_id_map
-                    .insert(item.path.clone(), folder_id.clone());
-            }
+        // Without its item, an orphaned ID can only be failed
+        let id = generate_id();
+        queue.processing = Some(id.clone());
+        queue.last_activity = None;
+        assert_eq!(queue.reset_orphaned_processing(false), Some(id.clone()));
+        assert!(queue.failed.contains_key(&id));
+    }
 
-            // Scan folder for subfolders and files
-            let (subfolders, files) = match scan_folder(path).await {
-                Ok(result) => result,
-                Err(e) => {
-                    let error = format!("Failed to scan folder: {}", e);
-                    handle_folder_error(&app, &state, &item.id, &item.name, &error).await?;
+    #[test]
+    fn cancelling_queued_items_keeps_the_current_one() {
+        let item = |item_type: &str, path: &str| test_item(&generate_id(), path, item_type);
 
-                    // Remove from pending folders
-                    let mut queue = state.0.lock().await;
-                    queue.pending_folders.remove(&item.path);
+        let mut queue = TransferQueue::new();
+        queue.items.push_back(item("folder", "/photos"));
+        queue.items.push_back(item("file", "/big.iso"));
+        queue.items.push_back(item("file", "/notes.txt"));
 
-                    return Err(error);
-                }
-            };
+        let current = queue.take_next_item().unwrap();
+        queue.processing = Some(current.id.clone());
+        let queued: Vec<String> = queue.items.iter().map(|item| item.id.clone()).collect();
 
-            // Update progress
-            app.emit(
-                "transfer-progress",
-                TransferProgress {
-                    id: item.id.clone(),
-                    name: item.name.clone(),
-                    item_type: "folder".to_string(),
-                    progress: 0.3,
-                    status: "processing".to_string(),
-                    message: Some(format!(
-                        "Found {} files and {} subfolders",
-                        files.len(),
-                        subfolders.len()
-                    )),
-                    speed: None,
-                    remaining_time: None,
-                    size: None, // Add this line
-                },
-            )
-            .map_err(|e| format!("Failed to emit progress: {}", e))?;
+        assert_eq!(queue.cancel_queued(), 2);
+        assert!(queue.items.is_empty());
+        assert_eq!(queue.processing.as_deref(), Some(current.id.as_str()));
+        assert!(queue.pending_folders.contains("/photos"));
+        assert!(queued.iter().all(|id| queue.failed.contains_key(id)));
+    }
 
-            // Add subfolders and files to the queue in strict order
-            {
-                let mut queue = state.0.lock().await;
-                let mut new_items = VecDeque::new();
+    #[test]
+    fn empty_upload_targets_are_rejected() {
+        let error = validate_target("  ", "folder-1").unwrap_err();
+        assert_eq!(error.code(), "invalid_target");
+        assert!(validate_target("share-1", "").is_err());
 
-                // Add the immediate contents of this folder to the front of the queue
-                // Files are added directly after folders to ensure they're processed
-                // in the natural file system order
+        assert_eq!(
+            validate_target(" share-1 ", "folder-1\n").unwrap(),
+            ("share-1".to_string(), "folder-1".to_string())
+        );
+    }
 
-                // First, get all the current items from the queue
-                let existing_items: Vec<QueueItem> = queue.items.drain(..).collect();
+    #[test]
+    fn parents_resolve_through_created_folders() {
+        let mut queue = TransferQueue::new();
+        queue
+            .folder_id_map
+            .insert("/home/me/photos".to_string(), "folder-9".to_string());
+
+        // Local paths of created folders map to their server IDs
+        assert_eq!(queue.resolve_parent_id("/home/me/photos"), "folder-9");
+        // Server IDs, and folders not created yet, pass through unchanged
+        assert_eq!(queue.resolve_parent_id("folder-2"), "folder-2");
+        assert_eq!(queue.resolve_parent_id("/home/me/music"), "/home/me/music");
+    }
 
-                // Add direct files of the current folder - we process files first
-                // within each folder's contents
-                for file_path in files {
-                    let file_name = file_path
-                        .file_name()
-                        .and_then(|name| name.to_str())
-                        .unwrap_or("unknown")
-                        .to_string();
+    #[test]
+    fn resized_copies_share_a_perceptual_hash() {
+        // Brightness falls off left to right, so every pixel beats its neighbour
+        let gradient =
+            image::GrayImage::from_fn(360, 240, |x, _| image::Luma([255 - (x / 2) as u8]));
+        let original = DynamicImage::ImageLuma8(gradient);
+        assert_eq!(dhash(&original), u64::MAX);
 
-                    new_items.push_back(QueueItem {
-                        item_type: "file".to_string(),
-                        id: generate_id(),
-                        path: file_path.to_string_lossy().to_string(),
-                        name: file_name,
-                        parent_id: folder_id.clone(),
-                        depth: 0, // Depth not used with this algorithm
-                    });
-                }
+        let smaller = original.resize_exact(90, 60, FilterType::Nearest);
+        assert_eq!(dhash(&smaller), dhash(&original));
 
-                // Then add subfolders
-                for subfolder_path in subfolders {
-                    let subfolder_name = subfolder_path
-                        .file_name()
-                        .and_then(|name| name.to_str())
-                        .unwrap_or("unknown")
-                        .to_string();
+        let flipped = original.fliph();
+        assert_eq!(dhash(&flipped), 0);
+    }
 
-                    new_items.push_back(QueueItem {
-                        item_type: "folder".to_string(),
-                        id: generate_id(),
-                        path: subfolder_path.to_string_lossy().to_string(),
-                        name: subfolder_name,
-                        parent_id: folder_id.clone(),
-                        depth: 0, // Depth not used with this algorithm
-                    });
-                }
+    #[tokio::test]
+    async fn responses_arriving_right_after_the_request_are_kept() {
+        let id = generate_id();
+        let rx = expect_response(&FOLDER_RESPONSE_CHANNELS, &id).await;
 
-                // Now put all existing items after the folder contents
-                for item in existing_items {
-                    new_items.push_back(item);
-                }
+        // The frontend answers before anything awaits the receiver
+        let response = FolderResponse {
+            folder_id: "folder-7".to_string(),
+        };
+        assert_eq!(
+            deliver_response(&FOLDER_RESPONSE_CHANNELS, &id, Ok(response)).await,
+            Delivery::Delivered
+        );
+        assert_eq!(rx.await.unwrap().unwrap().folder_id, "folder-7");
 
-                // Update the queue with our ordered items
-                queue.items = new_items;
-            }
+        // A second answer, or one after a timeout, has nowhere to go
+        assert_eq!(
+            deliver_response(&FOLDER_RESPONSE_CHANNELS, &id, Err("late".to_string())).await,
+            Delivery::NoReceiver
+        );
+    }
 
-            // Check if we've already sent completion notification for this folder
-            let notification_already_sent = {
-                let mut queue = state.0.lock().await;
-                let exists = queue.completion_notifications_sent.contains(&item.id);
-                if !exists {
-                    queue.completion_notifications_sent.insert(item.id.clone());
+    /// Answers through the real response commands from inside send_event, so
+    /// the answer is in before the request starts waiting on it
+    struct InlineFrontend;
+
+    impl EventSink for InlineFrontend {
+        fn send_event(
+            &self,
+            _label: &str,
+            event: &str,
+            payload: serde_json::Value,
+        ) -> Result<(), String> {
+            let answer = async {
+                match event {
+                    "complete-multipart" => {
+                        let id = payload["id"].as_str().unwrap().to_string();
+                        complete_multipart_response(id, Some(vec![2]), None).await
+                    }
+                    "request-thumbnail-url" => {
+                        let id = payload["thumbnail_id"].as_str().unwrap().to_string();
+                        let url = Some("mock://thumbnail".to_string());
+                        thumbnail_url_response(id, url, None, None).await
+                    }
+                    _ => Ok(()),
                 }
-                exists
-            };
-
-            if !notification_already_sent {
-                // Emit final progress for folder
-                app.emit(
-                    "transfer-progress",
-                    TransferProgress {
-                        id: item.id.clone(),
-                        name: item.name.clone(),
-                        item_type: "folder".to_string(),
-                        progress: 1.0,
-                        status: "completed".to_string(),
-                        message: Some(
-                            "Folder processing complete, starting contents...".to_string(),
-                        ),
-                        speed: None,
-                        remaining_time: None,
-                        size: None, // Add this line
-                    },
-                )
-                .map_err(|e| format!("Failed to emit progress: {}", e))?;
-
-                // Emit folder completion event
-                app.emit(
-                    "transfer-complete",
-                    serde_json::json!({
-                        "id": item.id.clone(),
-                        "name": item.name,
-                        "status": "completed",
-                        "message": "Folder created successfully"
-                    }),
-                )
-                .map_err(|e| format!("Failed to emit folder completion: {}", e))?;
-            }
+            };
+            tokio::task::block_in_place(|| tokio::runtime::Handle::current().block_on(answer))
+        }
+
+        fn notify(&self, _title: &str, _body: &str) -> Result<(), String> {
+            Ok(())
         }
     }
 
-    // Mark folder as completed in state
-    {
-        let mut queue = state.0.lock().await;
-        queue.processing = None;
-        queue.completed.insert(item.id.clone());
-        queue.request_timestamps.remove(&item.id); // Ensure any leftover timestamps are cleared
-        queue.pending_folders.remove(&item.path); // Remove from pending folders
+    #[tokio::test(flavor = "multi_thread")]
+    async fn commands_answering_before_the_request_waits_are_not_lost() {
+        let state = TransferManagerState::default();
+        let session = state.session(None);
+        let item_id = generate_id();
+        let parts = BTreeMap::from([(1, "etag-1".to_string()), (2, "etag-2".to_string())]);
+
+        let retry = complete_multipart(
+            &InlineFrontend,
+            &session,
+            &item_id,
+            "file-1",
+            "revision-1",
+            "upload-1",
+            &parts,
+        )
+        .await;
+        assert_eq!(retry, Ok(vec![2]));
+        assert!(
+            !MULTIPART_RESPONSE_CHANNELS
+                .lock()
+                .await
+                .contains_key(&item_id)
+        );
+
+        let thumbnail_id = generate_id();
+        let (url, _) = request_thumbnail_url(&InlineFrontend, &session, "file-1", &thumbnail_id)
+            .await
+            .unwrap();
+        assert_eq!(url, "mock://thumbnail");
     }
 
-    process_next_item(app.clone(), state.clone(), share_id).await?;
+    #[test]
+    fn extensionless_images_are_recognized_by_content() {
+        let png_header = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+        let path = std::env::temp_dir().join(format!("cirrussync-{}", generate_id()));
+        std::fs::write(&path, png_header).unwrap();
+
+        assert_eq!(get_file_info(&path, None), ("image/png".to_string(), true));
+        // Uploads from memory are sniffed from their data
+        assert_eq!(
+            get_file_info(Path::new("a1b2c3d4"), Some(png_header)),
+            ("image/png".to_string(), true)
+        );
+        // A known extension is trusted without reading anything
+        assert_eq!(
+            get_file_info(Path::new("missing.txt"), None),
+            ("text/plain".to_string(), false)
+        );
 
-    Ok(())
-}
+        std::fs::remove_file(&path).unwrap();
+    }
 
-/// Handles errors that occur during file processing
-async fn handle_file_error(
-    app: &AppHandle,
-    state: &State<'_, TransferManagerState>,
-    id: &str,
-    name: &str,
-    file_size: &Option<u64>,
-    error: &str,
-) -> Result<(), String> {
-    // Update state
-    {
-        let mut queue = state.0.lock().await;
-        queue.processing = None;
-        queue.failed.insert(id.to_string(), error.to_string());
-        // Also clean up all tracking
-        queue.initialized_files.remove(id);
-        queue.completion_notifications_sent.remove(id);
-        queue.received_url_responses.remove(id);
-        queue.request_timestamps.remove(id);
+    #[test]
+    fn thumbnails_fit_the_requested_box() {
+        let mut png = Cursor::new(Vec::new());
+        DynamicImage::new_rgb8(600, 300)
+            .write_to(&mut png, ImageFormat::Png)
+            .unwrap();
+
+        let thumbnail = render_thumbnail(png.get_ref(), 300, 300, ImageFormat::Png, false).unwrap();
+        let decoded = image::load_from_memory(&thumbnail.data).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (300, 150));
+        assert_eq!(thumbnail.perceptual_hash, None);
     }
 
-    // Emit error event
-    app.emit(
-        "transfer-progress",
-        TransferProgress {
+    #[test]
+    fn resync_reports_finished_current_and_queued_items() {
+        let item = |name: &str| test_item(&generate_id(), &format!("/{}", name), "file");
+        let uploading = |id: &str, name: &str| TransferProgress {
             id: id.to_string(),
             name: name.to_string(),
             item_type: "file".to_string(),
-            progress: 0.0,
-            status: "failed".to_string(),
-            message: Some(error.to_string()),
-            speed: None,
-            remaining_time: None,
-            size: *file_size, // Add this line
-        },
-    )
-    .map_err(|e| format!("Failed to emit error: {}", e))?;
+            progress: 0.5,
+            status: "uploading".to_string(),
+            message: None,
+            speed: Some(1024.0),
+            remaining_time: Some(3),
+            size: Some(100),
+            uploaded_bytes: Some(50),
+            error_code: None,
+        };
 
-    // Also emit a transfer-complete with error for frontend to properly handle it
-    app.emit(
-        "transfer-complete",
-        serde_json::json!({
-            "id": id,
-            "name": name,
-            "status": "failed",
-            "message": error
-        }),
-    )
-    .map_err(|e| format!("Failed to emit completion error: {}", e))?;
+        let mut queue = TransferQueue::new();
+        let (done, broken, current, waiting) = (item("a"), item("b"), item("c"), item("d"));
+        queue.mark_completed(&done.id);
+        queue.mark_failed(&broken.id, "Network error".to_string());
+        queue.processing = Some(current.id.clone());
+        queue.current_item = Some(current.clone());
+        queue.items.push_back(waiting.clone());
+
+        let last_progress: HashMap<String, TransferProgress> = [&done, &broken, &current]
+            .into_iter()
+            .map(|item| (item.id.clone(), uploading(&item.id, &item.name)))
+            .collect();
 
-    Ok(())
-}
+        let events = queue.resync_events(&last_progress, RESYNC_HISTORY_LIMIT);
+        let statuses: Vec<(&str, &str)> = events
+            .iter()
+            .map(|event| (event.name.as_str(), event.status.as_str()))
+            .collect();
+        assert_eq!(
+            statuses,
+            [
+                ("a", "completed"),
+                ("b", "failed"),
+                ("c", "uploading"),
+                ("d", "queued")
+            ]
+        );
+        assert_eq!(events[1].message.as_deref(), Some("Network error"));
 
-/// Handles errors that occur during folder processing
-async fn handle_folder_error(
-    app: &AppHandle,
-    state: &State<'_, TransferManagerState>,
-    id: &str,
-    name: &str,
-    error: &str,
-) -> Result<(), String> {
-    // Update state
-    {
-        let mut queue = state.0.lock().await;
-        queue.processing = None;
-        queue.failed.insert(id.to_string(), error.to_string());
-        // Also clean up all tracking
-        queue.initialized_folders.remove(id);
-        queue.completion_notifications_sent.remove(id);
-        queue.received_folder_responses.remove(id);
-        queue.request_timestamps.remove(id);
+        // Only the latest finished items are reported
+        assert_eq!(queue.resync_events(&last_progress, 1).len(), 3);
     }
 
-    // Emit error event
-    app.emit(
-        "transfer-progress",
-        TransferProgress {
-            id: id.to_string(),
-            name: name.to_string(),
-            item_type: "folder".to_string(),
-            progress: 0.0,
-            status: "failed".to_string(),
-            message: Some(error.to_string()),
-            speed: None,
-            remaining_time: None,
-            size: None, // Add this line
-        },
-    )
-    .map_err(|e| format!("Failed to emit error: {}", e))?;
+    #[test]
+    fn renamed_uploads_take_the_next_free_number() {
+        let taken: HashSet<String> = ["report.pdf", "report (2).pdf", "notes"]
+            .into_iter()
+            .map(String::from)
+            .collect();
 
-    // Also emit a transfer-complete with error for frontend to properly handle it
-    app.emit(
-        "transfer-complete",
-        serde_json::json!({
-            "id": id,
-            "name": name,
-            "status": "failed",
-            "message": error
-        }),
-    )
-    .map_err(|e| format!("Failed to emit completion error: {}", e))?;
+        assert_eq!(unique_name("photo.jpg", &taken), "photo.jpg");
+        assert_eq!(unique_name("report.pdf", &taken), "report (3).pdf");
+        assert_eq!(unique_name("notes", &taken), "notes (2)");
+    }
 
-    Ok(())
-}
+    #[tokio::test]
+    async fn empty_leaf_directories_each_get_a_folder_id() {
+        let root = std::env::temp_dir().join(format!("cirrussync-{}", generate_id()));
+        for leaf in ["a/empty", "a/b/empty", "c", "d/e/f"] {
+            std::fs::create_dir_all(root.join(leaf)).unwrap();
+        }
+        std::fs::write(root.join("a/file.txt"), b"data").unwrap();
 
-// Add a handler function for thumbnail completion
-#[command]
-pub fn handle_thumbnail_complete(payload: Option<&str>) -> Result<(), String> {
-    if let Some(payload_str) = payload {
-        if let Ok(payload_json) = serde_json::from_str::<serde_json::Value>(payload_str) {
-            // Extract the thumbnail info
-            let thumbnail_id = payload_json["thumbnail_id"].as_str().unwrap_or("");
-            let hash = payload_json["hash"].as_str().unwrap_or("");
-            let size = payload_json["size"].as_u64().unwrap_or(0);
+        let mut queue = TransferQueue::new();
+        queue
+            .items
+            .push_back(test_item(&generate_id(), &root.to_string_lossy(), "folder"));
+
+        // Drive the queue the way process_next_item does, standing in for the
+        // server by handing out a new folder ID for every create-folder request
+        let mut created = 0;
+        while let Some(item) = queue.take_next_item() {
+            // Everything found inside goes to the share the root was queued for
+            assert_eq!(item.share_id, "share-1");
+            if item.item_type == "folder" {
+                created += 1;
+                let folder_id = format!("folder-{}", created);
+                queue.record_created_folder(&item, &folder_id, &item.parent_id);
+
+                let (subfolders, files) = scan_folder(Path::new(&item.path)).await.unwrap();
+                queue.insert_folder_contents(
+                    &folder_id,
+                    &item.share_id,
+                    item.priority,
+                    subfolders,
+                    files,
+                );
+                queue.finish_folder(&item);
+            } else {
+                // A file is never taken before its folder exists
+                assert!(queue.folder_id_map.values().any(|id| *id == item.parent_id));
+                queue.completed.insert(item.id);
+            }
+        }
 
-            println!(
-                "Thumbnail completed: id={}, hash={}, size={}",
-                thumbnail_id, hash, size
-            );
+        assert!(queue.items.is_empty());
+        assert!(queue.pending_folders.is_empty());
+        assert_eq!(created, 9);
 
-            // The frontend API will handle updating the server with this information
-            return Ok(());
+        for dir in ["a", "a/empty", "a/b", "a/b/empty", "c", "d", "d/e", "d/e/f"] {
+            let path = root.join(dir).to_string_lossy().to_string();
+            assert!(
+                queue.folder_id_map.contains_key(&path),
+                "no folder id for {}",
+                dir
+            );
         }
+
+        std::fs::remove_dir_all(&root).unwrap();
     }
 
-    Err("Invalid thumbnail completion payload".to_string())
-}
+    // Block size the mock frontend hands out, so small files span several blocks
+    const MOCK_BLOCK_SIZE: u64 = 4;
+
+    /// Stands in for the frontend and server: records every event and answers
+    /// create-folder, init-file-upload and finalize-transfer the way the
+    /// frontend would
+    struct MockFrontend {
+        this: std::sync::Weak<MockFrontend>,
+        session: TransferSession,
+        answer_twice: bool,
+        events: std::sync::Mutex<Vec<(String, serde_json::Value)>>,
+        repeat_deliveries: std::sync::Mutex<Vec<Delivery>>,
+    }
 
-/// Handler for URL response from frontend
-#[command]
-pub async fn upload_urls_response(
-    payload: UploadUrlsResponsePayload,
-    app: AppHandle,
-) -> Result<(), String> {
-    println!(
-        "Received upload URLs response for transfer ID: {}",
-        payload.transfer_id
-    );
+    impl MockFrontend {
+        fn new(session: &TransferSession, answer_twice: bool) -> Arc<Self> {
+            Arc::new_cyclic(|this| MockFrontend {
+                this: this.clone(),
+                session: session.clone(),
+                answer_twice,
+                events: std::sync::Mutex::new(Vec::new()),
+                repeat_deliveries: std::sync::Mutex::new(Vec::new()),
+            })
+        }
 
-    // Check if we need to handle this response
-    let mut channels = RESPONSE_CHANNELS.lock().await;
-    if let Some(sender) = channels.remove(&payload.transfer_id) {
-        if let Err(_) = sender.send(Ok(payload.response)) {
-            println!("Failed to send response through channel - receiver dropped");
-        } else {
-            println!("Successfully sent response through channel");
+        fn events(&self, name: &str) -> Vec<serde_json::Value> {
+            let events = self.events.lock().unwrap();
+            events
+                .iter()
+                .filter(|(event, _)| event == name)
+                .map(|(_, payload)| payload.clone())
+                .collect()
         }
-    } else {
-        println!(
-            "No waiting receiver found for transfer ID: {}",
-            payload.transfer_id
-        );
 
-        // If no receiver was found, we should clear any state related to this ID
-        let state = app.state::<TransferManagerState>();
-        let mut queue = state.0.lock().await;
-        queue.received_url_responses.remove(&payload.transfer_id);
-        queue.request_timestamps.remove(&payload.transfer_id);
+        /// Hands a response to the waiting request, and again if asked to, the
+        /// way a frontend retrying a slow call would
+        fn answer<T: Clone + Send + 'static>(
+            &self,
+            channels: &'static ResponseChannels<T>,
+            id: String,
+            response: T,
+        ) {
+            let this = self.this.upgrade().unwrap();
+            tokio::spawn(async move {
+                deliver_response(channels, &id, Ok(response.clone())).await;
+                if this.answer_twice {
+                    let repeat = deliver_response(channels, &id, Ok(response)).await;
+                    this.repeat_deliveries.lock().unwrap().push(repeat);
+                }
+            });
+        }
     }
 
-    Ok(())
-}
+    impl EventSink for MockFrontend {
+        fn send_event(
+            &self,
+            _label: &str,
+            event: &str,
+            payload: serde_json::Value,
+        ) -> Result<(), String> {
+            self.events
+                .lock()
+                .unwrap()
+                .push((event.to_string(), payload.clone()));
+            let id = payload["id"].as_str().unwrap_or_default().to_string();
+
+            match event {
+                "create-folder" => {
+                    let folder_id = format!("folder:{}", payload["path"].as_str().unwrap());
+                    self.answer(&FOLDER_RESPONSE_CHANNELS, id, FolderResponse { folder_id });
+                }
+                "init-file-upload" => {
+                    let name = payload["name"].as_str().unwrap();
+                    let blocks = payload["size"].as_u64().unwrap().div_ceil(MOCK_BLOCK_SIZE);
+                    let upload_urls: Vec<serde_json::Value> = (0..blocks)
+                        .map(|index| {
+                            serde_json::json!({
+                                "url": format!("mock://{}/{}", name, index),
+                                "block_id": format!("{}-{}", name, index),
+                                "index": index,
+                                "expires_in": 3600,
+                            })
+                        })
+                        .collect();
+                    let response: UploadUrlsResponse = serde_json::from_value(serde_json::json!({
+                        "file_id": format!("file:{}", name),
+                        "revision_id": "revision-1",
+                        "total_blocks": blocks,
+                        "block_size": MOCK_BLOCK_SIZE,
+                        "upload_urls": upload_urls,
+                        "content_key": general_purpose::STANDARD.encode([7u8; 32]),
+                        "thumbnail": null,
+                    }))
+                    .unwrap();
+                    self.answer(&RESPONSE_CHANNELS, id, response);
+                }
+                "finalize-transfer" => {
+                    let events: Events = self.this.upgrade().unwrap();
+                    let session = self.session.clone();
+                    let file_id = payload["file_id"].as_str().unwrap().to_string();
+                    let parent_id = payload["parent_id"].as_str().unwrap().to_string();
+                    tokio::spawn(async move {
+                        complete_transfer(events, session, id, file_id, parent_id, true, None)
+                            .await
+                            .unwrap();
+                    });
+                }
+                _ => {}
+            }
+            Ok(())
+        }
 
-/// Handler for error response from frontend
-#[command]
-pub async fn upload_error_response(
-    payload: ErrorResponsePayload,
-    app: AppHandle,
-) -> Result<(), String> {
-    println!(
-        "Received error response for transfer ID: {}: {}",
-        payload.transfer_id, payload.error
-    );
+        fn notify(&self, _title: &str, _body: &str) -> Result<(), String> {
+            Ok(())
+        }
+    }
 
-    let mut channels = RESPONSE_CHANNELS.lock().await;
-    if let Some(sender) = channels.remove(&payload.transfer_id) {
-        if let Err(_) = sender.send(Err(payload.error.clone())) {
-            println!("Failed to send error through channel - receiver dropped");
-        } else {
-            println!("Successfully sent error through channel");
+    /// Accepts every block except those whose URL contains `reject`
+    #[derive(Default)]
+    struct MockUploader {
+        reject: Option<&'static str>,
+        uploaded: std::sync::Mutex<Vec<String>>,
+    }
+
+    impl BlockUploader for MockUploader {
+        fn upload_block<'a>(
+            &'a self,
+            _index: usize,
+            _data: Vec<u8>,
+            metadata: &'a BlockMetadata<'a>,
+        ) -> BoxFuture<'a, Result<BlockReceipt, BlockUploadError>> {
+            Box::pin(async move {
+                let url = metadata.url.url.clone();
+                if self.reject.is_some_and(|reject| url.contains(reject)) {
+                    return Err(BlockUploadError::Fatal(TransferError::Server(
+                        "Block rejected".to_string(),
+                    )));
+                }
+                self.uploaded.lock().unwrap().push(url);
+                Ok(BlockReceipt::default())
+            })
         }
-    } else {
-        println!(
-            "No waiting receiver found for transfer ID: {}",
-            payload.transfer_id
-        );
 
-        // If no receiver was found, we should clear any state related to this ID
-        let state = app.state::<TransferManagerState>();
-        let mut queue = state.0.lock().await;
-        queue.received_url_responses.remove(&payload.transfer_id);
-        queue.request_timestamps.remove(&payload.transfer_id);
+        fn finalize<'a>(
+            &'a self,
+            _target: &'a UploadTarget<'a>,
+            _parts: &'a BTreeMap<usize, String>,
+        ) -> BoxFuture<'a, Result<Vec<usize>, String>> {
+            Box::pin(async { Ok(Vec::new()) })
+        }
     }
 
-    // Get the item name for the error
-    let item_name = {
-        let state = app.state::<TransferManagerState>();
-        let queue = state.0.lock().await;
-        queue
-            .items
-            .iter()
-            .find(|item| item.id == payload.transfer_id)
-            .map(|item| item.name.clone())
-            .unwrap_or_else(|| "Unknown".to_string())
-    };
+    /// Writes `files` under a fresh temp folder, creating their folders
+    fn synthetic_tree(files: &[(&str, &str)]) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("cirrussync-{}", generate_id()));
+        for (path, contents) in files {
+            let path = root.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, contents).unwrap();
+        }
+        root
+    }
 
-    // Update transfer state to failed
-    handle_file_error(
-        &app,
-        &app.state::<TransferManagerState>(),
-        &payload.transfer_id,
-        &item_name,
-        &None,
-        &payload.error,
-    )
-    .await?;
+    /// Queues `root` through the same path as select_folders and waits for
+    /// the queue to drain
+    async fn upload_tree(
+        root: &Path,
+        uploader: Arc<MockUploader>,
+        answer_twice: bool,
+    ) -> (TransferSession, Arc<MockFrontend>) {
+        let session = TransferSession::new("test", &TransferSettings::default());
+        session.0.lock().await.custom_uploader = Some(uploader);
+        let frontend = MockFrontend::new(&session, answer_twice);
+
+        enqueue_folders(
+            frontend.clone(),
+            session.clone(),
+            vec![root.to_string_lossy().to_string()],
+            "share-1".to_string(),
+            "share-root".to_string(),
+            Priority::default(),
+            false,
+        )
+        .await
+        .unwrap();
 
-    Ok(())
-}
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while frontend.events("queue-idle").is_empty() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("queue never went idle");
 
-/// Handler for folder creation response from frontend
-#[command]
-pub async fn folder_created_response(
-    transfer_id: String,
-    response: FolderResponse,
-    app: AppHandle,
-) -> Result<(), String> {
-    println!(
-        "Received folder creation response for transfer ID: {}",
-        transfer_id
-    );
+        (session, frontend)
+    }
 
-    let mut channels = FOLDER_RESPONSE_CHANNELS.lock().await;
-    if let Some(sender) = channels.remove(&transfer_id) {
-        if let Err(_) = sender.send(Ok(response)) {
-            println!("Failed to send folder response through channel - receiver dropped");
-        } else {
-            println!("Successfully sent folder response through channel");
+    #[tokio::test]
+    async fn synthetic_tree_uploads_each_file_after_its_folder() {
+        let root = synthetic_tree(&[
+            ("top.txt", "hello world"),
+            ("sub/middle.txt", "some more text"),
+            ("sub/deeper/bottom.txt", "the end"),
+        ]);
+        let uploader = Arc::new(MockUploader::default());
+        let (session, frontend) = upload_tree(&root, uploader.clone(), false).await;
+
+        // Each file is initialized only once the folder it goes into exists
+        let mut created = HashSet::new();
+        for (event, payload) in frontend.events.lock().unwrap().iter() {
+            match event.as_str() {
+                "create-folder" => {
+                    let parent_id = payload["parent_id"].as_str().unwrap();
+                    assert!(parent_id == "share-root" || created.contains(parent_id));
+                    created.insert(format!("folder:{}", payload["path"].as_str().unwrap()));
+                }
+                "init-file-upload" => {
+                    assert!(created.contains(payload["parent_id"].as_str().unwrap()));
+                }
+                _ => {}
+            }
         }
-    } else {
-        println!(
-            "No waiting receiver found for folder transfer ID: {}",
-            transfer_id
-        );
+        assert_eq!(created.len(), 3);
 
-        // If no receiver was found, we should clear any state related to this ID
-        let state = app.state::<TransferManagerState>();
-        let mut queue = state.0.lock().await;
-        queue.received_folder_responses.remove(&transfer_id);
-        queue.request_timestamps.remove(&transfer_id);
+        // Init and finalize of the same file carry the same idempotency key,
+        // and no two files share one
+        let keys = |event: &str| -> HashMap<String, String> {
+            frontend
+                .events(event)
+                .iter()
+                .map(|payload| {
+                    let key = payload["idempotency_key"].as_str().unwrap().to_string();
+                    (payload["id"].as_str().unwrap().to_string(), key)
+                })
+                .collect()
+        };
+        let init_keys = keys("init-file-upload");
+        assert_eq!(init_keys, keys("finalize-transfer"));
+        assert_eq!(init_keys.values().collect::<HashSet<_>>().len(), 3);
+
+        let queue = session.0.lock().await;
+        assert!(queue.items.is_empty());
+        assert!(queue.processing.is_none());
+        assert!(queue.pending_folders.is_empty());
+        assert!(queue.failed.is_empty());
+        assert_eq!(queue.completed.len(), 6);
+        // 11, 14 and 7 bytes in 4-byte blocks
+        assert_eq!(uploader.uploaded.lock().unwrap().len(), 3 + 4 + 2);
+
+        std::fs::remove_dir_all(&root).unwrap();
     }
 
-    Ok(())
-}
-
-/// Handler for folder error response from frontend
-#[command]
-pub async fn folder_error_response(
-    transfer_id: String,
-    error: String,
-    app: AppHandle,
-) -> Result<(), String> {
-    println!(
-        "Received folder error response for transfer ID: {}: {}",
-        transfer_id, error
-    );
+    #[tokio::test]
+    async fn repeated_frontend_responses_are_ignored() {
+        let root = synthetic_tree(&[("a.txt", "first file"), ("nested/b.txt", "second")]);
+        let (session, frontend) = upload_tree(&root, Arc::new(MockUploader::default()), true).await;
 
-    let mut channels = FOLDER_RESPONSE_CHANNELS.lock().await;
-    if let Some(sender) = channels.remove(&transfer_id) {
-        if let Err(_) = sender.send(Err(error.clone())) {
-            println!("Failed to send folder error through channel - receiver dropped");
-        } else {
-            println!("Successfully sent folder error through channel");
-        }
-    } else {
-        println!(
-            "No waiting receiver found for folder transfer ID: {}",
-            transfer_id
+        // Every repeat found its request already answered
+        let repeats = frontend.repeat_deliveries.lock().unwrap();
+        assert_eq!(repeats.len(), 4);
+        assert!(
+            repeats
+                .iter()
+                .all(|delivery| *delivery == Delivery::NoReceiver)
         );
 
-        // If no receiver was found, we should clear any state related to this ID
-        let state = app.state::<TransferManagerState>();
-        let mut queue = state.0.lock().await;
-        queue.received_folder_responses.remove(&transfer_id);
-        queue.request_timestamps.remove(&transfer_id);
+        // ...so each folder was created, and each file finalized, exactly once
+        assert_eq!(frontend.events("create-folder").len(), 2);
+        assert_eq!(frontend.events("finalize-transfer").len(), 2);
+        let queue = session.0.lock().await;
+        assert_eq!(queue.completed.len(), 4);
+        assert!(queue.failed.is_empty());
+
+        std::fs::remove_dir_all(&root).unwrap();
     }
 
-    // Get the item name for the error
-    let item_name = {
-        let state = app.state::<TransferManagerState>();
-        let queue = state.0.lock().await;
-        queue
-            .items
-            .iter()
-            .find(|item| item.id == transfer_id)
-            .map(|item| item.name.clone())
-            .unwrap_or_else(|| "Unknown".to_string())
-    };
+    #[tokio::test]
+    async fn a_rejected_block_fails_only_its_file() {
+        let root = synthetic_tree(&[
+            ("good.txt", "uploads fine"),
+            ("bad.txt", "never makes it"),
+            ("later/also-good.txt", "still uploads"),
+        ]);
+        let uploader = Arc::new(MockUploader {
+            reject: Some("bad.txt"),
+            ..MockUploader::default()
+        });
+        let (session, frontend) = upload_tree(&root, uploader, false).await;
+
+        let failures: Vec<serde_json::Value> = frontend
+            .events("transfer-complete")
+            .into_iter()
+            .filter(|payload| payload["status"] == "failed")
+            .collect();
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0]["name"], "bad.txt");
+        assert_eq!(failures[0]["code"], "server");
+
+        // The queue moved on past the failure and finished everything else
+        let queue = session.0.lock().await;
+        assert!(queue.items.is_empty());
+        assert!(queue.processing.is_none());
+        assert_eq!(queue.failed.len(), 1);
+        assert_eq!(queue.completed.len(), 4);
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
 
-    // Update transfer state to failed
-    handle_folder_error(
-        &app,
-        &app.state::<TransferManagerState>(),
-        &transfer_id,
-        &item_name,
-        &error,
-    )
-    .await?;
+    #[test]
+    fn upload_checks_report_the_first_reason_that_applies() {
+        // An empty file is reported as empty even when its name is also filtered
+        assert_eq!(
+            file_skip_reason("notes.tmp", None, 0, true),
+            Some(SkipReason::Empty)
+        );
+        assert_eq!(
+            file_skip_reason("notes.tmp", None, 10, true),
+            Some(SkipReason::TempPattern)
+        );
+        assert_eq!(file_skip_reason("notes.tmp", None, 10, false), None);
+        assert_eq!(
+            file_skip_reason("~$report.docx", None, 10, true),
+            Some(SkipReason::Locked)
+        );
 
-    Ok(())
-}
+        let to_json = |check| serde_json::to_value(check).unwrap();
+        assert_eq!(to_json(UploadCheck::Accepted), "accepted");
+        assert_eq!(to_json(UploadCheck::NotFound), "not_found");
+        assert_eq!(
+            to_json(UploadCheck::Skipped(SkipReason::TempPattern)),
+            "temp_pattern"
+        );
+    }
 
-/// Cleans up any stuck or hanging transfers
-#[command]
-pub async fn cleanup_stuck_transfers(
-    app: AppHandle,
-    state: State<'_, TransferManagerState>,
-) -> Result<serde_json::Value, String> {
-    let cleaned_count;
-    let mut cleaned_ids: Vec<String> = Vec::new();
+    #[test]
+    fn staging_directory_must_be_a_writable_folder() {
+        let dir = std::env::temp_dir().join(format!("cirrussync-{}", generate_id()));
+        assert!(check_staging_directory(&dir).is_err());
 
-    {
-        let mut queue = state.0.lock().await;
-        let current_time = Instant::now();
-        let mut hanging_ids: Vec<String> = Vec::new();
+        std::fs::create_dir_all(&dir).unwrap();
+        assert!(check_staging_directory(&dir).is_ok());
+        // The check leaves nothing behind
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
 
-        // Find all hanging requests (older than 35 seconds)
-        let mut hanging_ids = Vec::new();
-        for (id, timestamp) in &queue.request_timestamps {
-            if current_time.duration_since(*timestamp) > Duration::from_secs(35) {
-                hanging_ids.push(id.clone());
-                println!("Found hanging request for ID: {}, will clean up", id);
-            }
-        }
+        let file = dir.join("not-a-folder");
+        std::fs::write(&file, b"data").unwrap();
+        assert!(check_staging_directory(&file).is_err());
 
-        cleaned_count = hanging_ids.len();
-        cleaned_ids = hanging_ids.clone();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 
-        // Clean up each hanging request
-        for id in &hanging_ids {
-            queue.request_timestamps.remove(id);
-            queue.received_url_responses.remove(id);
-            queue.received_folder_responses.remove(id);
+    #[test]
+    fn higher_priority_items_go_first_once_their_folder_exists() {
+        let item = |item_type: &str, path: &str, priority: Priority| QueueItem {
+            priority,
+            ..test_item(path, path, item_type)
+        };
 
-            // If this was the current processing item, clear it
-            if let Some(processing_id) = &queue.processing {
-                if processing_id == id {
-                    queue.processing = None;
-                }
-            }
+        let mut queue = TransferQueue::new();
+        queue.pending_folders.insert("/sync".to_string());
+        queue.items.extend([
+            item("file", "/sync/a.txt", Priority::High),
+            item("file", "/b.txt", Priority::Normal),
+            item("folder", "/photos", Priority::Low),
+            item("file", "/urgent.txt", Priority::High),
+            item("file", "/c.txt", Priority::Normal),
+        ]);
+
+        // The high priority file inside a pending folder still has to wait
+        let order: Vec<String> = std::iter::from_fn(|| queue.take_next_item())
+            .map(|item| item.id)
+            .take(3)
+            .collect();
+        assert_eq!(order, ["/urgent.txt", "/b.txt", "/c.txt"]);
 
+        // Raising a folder raises what is queued inside it
+        queue
+            .items
+            .push_back(item("file", "/photos/d.jpg", Priority::Low));
+        assert_eq!(queue.set_priority("/photos", Priority::High), Some(2));
+        assert!(
             queue
-                .failed
-                .insert(id.clone(), "Request timed out".to_string());
-        }
-
-        // Handle channels outside the main lock to avoid deadlocks
-        for id in &hanging_ids {
-            let item_name = queue
                 .items
                 .iter()
-                .find(|item| &item.id == id)
-                .map(|item| item.name.clone())
-                .unwrap_or_else(|| "Unknown".to_string());
-
-            // Also notify frontend of failure
-            app.emit(
-                "transfer-complete",
-                serde_json::json!({
-                    "id": id,
-                    "name": item_name,
-                    "status": "failed",
-                    "message": "Request timed out"
-                }),
-            )
-            .ok();
-        }
+                .all(|item| item.priority == Priority::High)
+        );
+        assert_eq!(queue.set_priority("/missing", Priority::High), None);
     }
 
-    // Handle channels
-    let mut channels_to_clean = Vec::new();
-    {
-        let queue = state.0.lock().await;
-        for (id, timestamp) in &queue.request_timestamps {
-            if Instant::now().duration_since(*timestamp) > Duration::from_secs(35) {
-                channels_to_clean.push(id.clone());
+    #[test]
+    fn low_priority_items_still_start_under_steady_high_load() {
+        let mut queue = TransferQueue::new();
+        let mut started: BTreeMap<Priority, usize> = BTreeMap::new();
+        let mut next_id = 0;
+
+        // Every class always has work waiting, like a big background sync
+        // while the user keeps adding files
+        for _ in 0..20 {
+            for priority in Priority::ALL {
+                if !queue.items.iter().any(|item| item.priority == priority) {
+                    next_id += 1;
+                    let mut item: QueueItem = serde_json::from_value(serde_json::json!({
+                        "type": "file",
+                        "id": format!("transfer-{}", next_id),
+                        "path": format!("/file-{}.txt", next_id),
+                        "name": format!("file-{}.txt", next_id),
+                        "parent_id": "share-root",
+                        "depth": 0,
+                    }))
+                    .unwrap();
+                    item.priority = priority;
+                    queue.items.push_back(item);
+                }
             }
+            let item = queue.take_next_item().unwrap();
+            *started.entry(item.priority).or_default() += 1;
         }
+
+        // Started in proportion to the class weights
+        assert_eq!(started[&Priority::High], 12);
+        assert_eq!(started[&Priority::Normal], 6);
+        assert_eq!(started[&Priority::Low], 2);
     }
 
-    for id in &channels_to_clean {
-        let mut channels = RESPONSE_CHANNELS.lock().await;
-        if let Some(sender) = channels.remove(id) {
-            let _ = sender.send(Err("Request timed out".to_string()));
-        }
+    #[test]
+    fn thumbnails_never_reuse_a_nonce() {
+        let cipher = content_cipher(&general_purpose::STANDARD.encode([7u8; 32])).unwrap();
+        let thumbnail = b"not really a jpeg";
+
+        let (first, first_nonce) = encrypt_thumbnail(&cipher, thumbnail).unwrap();
+        let (second, second_nonce) = encrypt_thumbnail(&cipher, thumbnail).unwrap();
+        assert_ne!(first_nonce, second_nonce);
+        assert_ne!(first, second);
+        assert_ne!(first_nonce, block_nonce(0));
+
+        // The nonce leads the body, so the thumbnail can be decrypted from it
+        let (nonce, ciphertext) = first.split_at(12);
+        assert_eq!(nonce, first_nonce);
+        let decrypted = cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .unwrap();
+        assert_eq!(decrypted, thumbnail);
+    }
+
+    #[test]
+    fn download_blocks_decrypt_only_with_their_own_key_and_index() {
+        let key = general_purpose::STANDARD.encode([7u8; 32]);
+        let cipher = content_cipher(&key).unwrap();
+        let encrypt = |index: usize, data: &[u8]| {
+            cipher
+                .encrypt(Nonce::from_slice(&block_nonce(index)), data)
+                .unwrap()
+        };
 
-        let mut folder_channels = FOLDER_RESPONSE_CHANNELS.lock().await;
-        if let Some(sender) = folder_channels.remove(id) {
-            let _ = sender.send(Err("Request timed out".to_string()));
+        let full = encrypt(0, b"abcd");
+        let tail = encrypt(1, b"ef");
+        assert_eq!(decrypt_block(&cipher, 0, &full, 4, false).unwrap(), b"abcd");
+        assert_eq!(decrypt_block(&cipher, 1, &tail, 4, true).unwrap(), b"ef");
+
+        // Wrong index, wrong key, damaged data, and a short block mid-file
+        assert!(decrypt_block(&cipher, 1, &full, 4, false).is_err());
+        let other = content_cipher(&general_purpose::STANDARD.encode([8u8; 32])).unwrap();
+        assert!(decrypt_block(&other, 0, &full, 4, false).is_err());
+        let mut damaged = full.clone();
+        damaged[0] ^= 1;
+        let error = decrypt_block(&cipher, 0, &damaged, 4, false).unwrap_err();
+        assert_eq!(error.code(), "encryption_failed");
+        assert!(decrypt_block(&cipher, 1, &tail, 4, false).is_err());
+    }
+
+    #[tokio::test]
+    async fn download_blocks_over_the_block_size_are_rejected() {
+        // Serves one response per connection; without a Content-Length the
+        // body runs until the connection closes
+        async fn serve(head: &'static str, body: Vec<u8>) -> String {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = format!("http://{}/block", listener.local_addr().unwrap());
+            tokio::spawn(async move {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut request = [0u8; 1024];
+                let _ = socket.read(&mut request).await;
+                let _ = socket.write_all(head.as_bytes()).await;
+                let _ = socket.write_all(&body).await;
+            });
+            url
+        }
+        let client = reqwest::Client::new();
+        let with_length = "HTTP/1.1 200 OK\r\nContent-Length: 24\r\nConnection: close\r\n\r\n";
+        let without_length = "HTTP/1.1 200 OK\r\nConnection: close\r\n\r\n";
+
+        let url = serve(with_length, vec![0; 24]).await;
+        assert_eq!(fetch_block(&client, &url, 0, 24).await.unwrap().len(), 24);
+        for head in [with_length, without_length] {
+            let url = serve(head, vec![0; 24]).await;
+            let error = fetch_block(&client, &url, 0, 20).await.unwrap_err();
+            assert_eq!(error.code(), "block_too_large");
         }
     }
 
-    // Recheck pending folders and clean up any that are stuck
-    {
-        let mut queue = state.0.lock().await;
-        for id in &channels_to_clean {
-            // Find any queue items that matched this ID and get their paths
-            let path_to_remove = queue
-                .items
-                .iter()
-                .find(|item| &item.id == id)
-                .map(|item| item.path.clone());
+    #[tokio::test]
+    async fn an_interrupted_upload_resumes_unless_the_file_changed() {
+        let contents = "0123456789abcdefghij"; // 5 blocks of MOCK_BLOCK_SIZE
+        let root = synthetic_tree(&[("resume.txt", contents)]);
+        let path = root.join("resume.txt");
+        let checkpoint_dir = root.join(CHECKPOINT_SUBDIR);
+        let modified = std::fs::metadata(&path)
+            .unwrap()
+            .modified()
+            .unwrap()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
+
+        let checkpoint_path = UploadCheckpoint::path(&checkpoint_dir, &path).unwrap();
+
+        // Blocks 0 and 1 went up before the restart; the second time around
+        // the file was modified since
+        for (saved_modified, expected_uploads) in [(modified, 3), (modified - 1000, 5)] {
+            let saved = UploadCheckpoint {
+                source: std::fs::canonicalize(&path)
+                    .unwrap()
+                    .to_string_lossy()
+                    .to_string(),
+                file_id: "file:resume.txt".to_string(),
+                revision_id: "revision-1".to_string(),
+                block_size: MOCK_BLOCK_SIZE,
+                file_size: contents.len() as u64,
+                modified: Some(saved_modified),
+                blocks: BTreeMap::from([(0, None), (1, None)]),
+            };
+            saved.save(&checkpoint_path).await.unwrap();
 
-            if let Some(path) = path_to_remove {
-                queue.pending_folders.remove(&path);
+            let session = TransferSession::new("test", &TransferSettings::default());
+            let uploader = Arc::new(MockUploader::default());
+            {
+                let mut queue = session.0.lock().await;
+                queue.custom_uploader = Some(uploader.clone());
+                queue.staging_directory = Some(root.clone());
+                // Queued afresh, as after a restart
+                queue
+                    .items
+                    .push_back(test_item(&generate_id(), &path.to_string_lossy(), "file"));
             }
+            let frontend = MockFrontend::new(&session, false);
+            process_next_item(frontend.clone(), session.clone())
+                .await
+                .unwrap();
+            tokio::time::timeout(Duration::from_secs(10), async {
+                while frontend.events("transfer-complete").is_empty() {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+            })
+            .await
+            .expect("upload never completed");
+
+            assert_eq!(uploader.uploaded.lock().unwrap().len(), expected_uploads);
+
+            // The content hash still covers the whole file
+            let mut expected_hash = ContentHasher::new(HashAlgorithm::Sha256);
+            expected_hash.update(contents.as_bytes());
+            let finalized = frontend.events("finalize-transfer");
+            assert_eq!(finalized[0]["content_hash"], expected_hash.finalize_hex());
+
+            // Nothing is left to resume once the upload is finalized
+            assert!(!checkpoint_path.exists());
         }
+
+        std::fs::remove_dir_all(&root).unwrap();
     }
 
-    // If we cleaned up any items, try to process the next one
-    if !channels_to_clean.is_empty() {
-        if let Some(share_id) = {
-            let queue = state.0.lock().await;
-            queue.original_share_id.clone()
-        } {
-            process_next_item(app.clone(), state.clone(), share_id).await?;
-        }
+    #[tokio::test]
+    async fn intermediate_files_go_to_the_staging_directory() {
+        let root = synthetic_tree(&[("staging/partial.download", "done")]);
+        let mut queue = TransferQueue::new();
+        assert_eq!(queue.staging_dir(), std::env::temp_dir());
+
+        queue.apply_settings(&TransferSettings {
+            staging_directory: Some(root.join("staging")),
+            ..TransferSettings::default()
+        });
+        assert_eq!(
+            queue.checkpoint_dir(),
+            root.join("staging").join(CHECKPOINT_SUBDIR)
+        );
+
+        // Finished downloads leave the staging directory for their destination
+        let partial = root.join("staging/partial.download");
+        let dest = root.join("file.txt");
+        move_into_place(&partial, &dest).await.unwrap();
+        assert_eq!(std::fs::read_to_string(&dest).unwrap(), "done");
+        assert!(!partial.exists());
+
+        std::fs::remove_dir_all(&root).unwrap();
     }
 
-    // Return the number of cleaned up transfers
-    Ok(serde_json::json!({
-        "cleaned_count": cleaned_count,
-        "cleaned_ids": channels_to_clean
-    }))
-}
+    #[tokio::test]
+    async fn only_checkpoints_of_queued_files_outlive_startup() {
+        let root = synthetic_tree(&[("queued.txt", "0123"), ("cancelled.txt", "4567")]);
+        let checkpoint_dir = root.join(CHECKPOINT_SUBDIR);
+        let checkpoint = |name: &str| {
+            let path = root.join(name);
+            let saved = UploadCheckpoint {
+                source: path.to_string_lossy().to_string(),
+                file_id: format!("file:{}", name),
+                revision_id: "revision-1".to_string(),
+                block_size: MOCK_BLOCK_SIZE,
+                file_size: 4,
+                modified: None,
+                blocks: BTreeMap::from([(0, None)]),
+            };
+            (
+                saved,
+                UploadCheckpoint::path(&checkpoint_dir, &path).unwrap(),
+            )
+        };
+        let (queued, queued_path) = checkpoint("queued.txt");
+        queued.save(&queued_path).await.unwrap();
+        let (cancelled, cancelled_path) = checkpoint("cancelled.txt");
+        cancelled.save(&cancelled_path).await.unwrap();
+        std::fs::write(checkpoint_dir.join("leftover.json.tmp"), "{").unwrap();
 
-/// Health check to verify frontend-backend communication
-#[command]
-pub async fn check_transfer_health() -> Result<serde_json::Value, String> {
-    Ok(serde_json::json!({
-        "status": "healthy",
-        "timestamp": chrono::Utc::now().timestamp(),
-        "version": env!("CARGO_PKG_VERSION")
-    }))
-}
+        let state = TransferManagerState::with_settings(TransferSettings::default());
+        {
+            let session = state.session(None);
+            let mut queue = session.0.lock().await;
+            queue.staging_directory = Some(root.clone());
+            queue.items.push_back(
+                serde_json::from_value(serde_json::json!({
+                    "type": "file",
+                    "id": "transfer-1",
+                    "path": root.join("queued.txt"),
+                    "name": "queued.txt",
+                    "parent_id": "share-root",
+                    "depth": 0,
+                }))
+                .unwrap(),
+            );
+        }
+        remove_stale_checkpoints(&state).await;
 
-/// Checks and repairs pending folder state
-#[command]
-pub async fn repair_pending_folders(
-    app: AppHandle,
-    state: State<'_, TransferManagerState>,
-) -> Result<serde_json::Value, String> {
-    let mut repaired_count = 0;
-    let original_share_id;
+        let left: Vec<PathBuf> = std::fs::read_dir(&checkpoint_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        assert_eq!(left, [queued_path]);
 
-    {
-        let mut queue = state.0.lock().await;
-        original_share_id = queue.original_share_id.clone();
+        std::fs::remove_dir_all(&root).unwrap();
+    }
 
-        // Check if any pending folders don't have a matching folder in the queue or processing
-        let mut stale_pending_folders = Vec::new();
+    #[tokio::test]
+    async fn hash_file_matches_hashing_the_whole_content() {
+        let contents = "0123456789abcdef".repeat(HASH_READ_SIZE / 8);
+        let root = synthetic_tree(&[("large.bin", contents.as_str())]);
+        let path = root.join("large.bin");
+        let session = TransferSession::new("test", &TransferSettings::default());
+        let frontend = MockFrontend::new(&session, false);
+        let not_cancelled = AtomicBool::new(false);
+
+        for algorithm in [HashAlgorithm::Sha256, HashAlgorithm::Blake3] {
+            let mut expected = ContentHasher::new(algorithm);
+            expected.update(contents.as_bytes());
+            let digest = hash_local_file(
+                &frontend,
+                &session,
+                &path,
+                algorithm,
+                "hash-1",
+                &not_cancelled,
+            )
+            .await
+            .unwrap();
+            assert_eq!(digest, expected.finalize_hex());
+        }
 
-        for pending_path in queue.pending_folders.iter() {
-            // Check if this folder is currently being processed
-            let is_processing = if let Some(processing_id) = &queue.processing {
-                queue.items.iter().any(|item| {
-                    &item.id == processing_id
-                        && item.item_type == "folder"
-                        && &item.path == pending_path
-                })
-            } else {
-                false
-            };
+        let cancelled = AtomicBool::new(true);
+        let result = hash_local_file(
+            &frontend,
+            &session,
+            &path,
+            HashAlgorithm::Sha256,
+            "hash-2",
+            &cancelled,
+        )
+        .await;
+        assert!(matches!(result, Err(TransferError::Cancelled)));
+
+        let missing = root.join("missing.bin");
+        let result = hash_local_file(
+            &frontend,
+            &session,
+            &missing,
+            HashAlgorithm::Sha256,
+            "hash-3",
+            &not_cancelled,
+        )
+        .await;
+        assert!(matches!(result, Err(TransferError::FileNotFound(_))));
 
-            // Check if this folder is waiting in the queue
-            let in_queue = queue
-                .items
-                .iter()
-                .any(|item| item.item_type == "folder" && &item.path == pending_path);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
 
-            // If neither processing nor in queue, it's stale
-            if !is_processing && !in_queue {
-                stale_pending_folders.push(pending_path.clone());
+    /// Cancels the scan of `folder_id` from the first `scan-progress` event
+    /// whose `done` matches, the way cancel_scan would from the frontend
+    struct CancelOnScanProgress {
+        session: TransferSession,
+        on_done: bool,
+    }
+
+    impl EventSink for CancelOnScanProgress {
+        fn send_event(
+            &self,
+            _label: &str,
+            event: &str,
+            payload: serde_json::Value,
+        ) -> Result<(), String> {
+            if event == "scan-progress" && payload["done"] == self.on_done {
+                let queue = self.session.0.try_lock().unwrap();
+                queue.active_scans["folder-1"].store(true, Ordering::Relaxed);
             }
+            Ok(())
         }
 
-        // Remove stale pending folders
-        for stale_path in stale_pending_folders {
-            queue.pending_folders.remove(&stale_path);
-            repaired_count += 1;
+        fn notify(&self, _title: &str, _body: &str) -> Result<(), String> {
+            Ok(())
         }
     }
 
-    // If we repaired any items and processing is not active, try to process the next one
-    if repaired_count > 0 {
-        if let Some(share_id) = original_share_id {
-            let is_processing = {
-                let queue = state.0.lock().await;
-                queue.processing.is_some() || queue.paused
+    #[tokio::test]
+    async fn a_cancelled_scan_queues_nothing() {
+        let files: Vec<(String, &str)> = (0..20)
+            .map(|i| (format!("file-{}.txt", i), "contents"))
+            .collect();
+        let files: Vec<(&str, &str)> = files.iter().map(|(p, c)| (p.as_str(), *c)).collect();
+        let root = synthetic_tree(&files);
+
+        // Cancelled mid-listing, and after the listing but before the enqueue
+        for on_done in [false, true] {
+            let session = TransferSession::new("test", &TransferSettings::default());
+            let app = CancelOnScanProgress {
+                session: session.clone(),
+                on_done,
             };
 
-            if !is_processing {
-                process_next_item(app.clone(), state.clone(), share_id).await?;
-            }
+            let result = scan_and_enqueue(
+                &app,
+                &session,
+                &root,
+                "folder-1",
+                "share-1",
+                Priority::Normal,
+            )
+            .await;
+            assert!(matches!(result, Err(TransferError::Cancelled)));
+
+            let queue = session.0.lock().await;
+            assert!(queue.items.is_empty());
+            assert!(queue.active_scans.is_empty());
+            assert!(queue.scan_progress.is_none());
         }
+
+        std::fs::remove_dir_all(&root).unwrap();
     }
 
-    Ok(serde_json::json!({
-        "repaired_count": repaired_count
-    }))
-}
+    #[test]
+    fn idempotency_keys_survive_a_saved_queue() {
+        let saved = serde_json::json!({
+            "type": "file",
+            "id": "transfer-1",
+            "path": "/notes.txt",
+            "name": "notes.txt",
+            "parent_id": "share-root",
+            "depth": 0,
+        });
 
-/// Returns detailed queue status for debugging
-#[command]
-pub async fn get_detailed_queue_status(
-    state: State<'_, TransferManagerState>,
-) -> Result<serde_json::Value, String> {
-    let queue = state.0.lock().await;
+        // Items saved before keys existed get one when loaded...
+        let item: QueueItem = serde_json::from_value(saved).unwrap();
+        assert_eq!(item.idempotency_key.len(), 36);
 
-    // Format queue items for display
-    let queue_items: Vec<serde_json::Value> = queue
-        .items
-        .iter()
-        .map(|item| {
-            serde_json::json!({
-                "id": item.id,
-                "type": item.item_type,
-                "name": item.name,
-                "depth": item.depth,
-                "parent_id": item.parent_id
-            })
+        // ...and keep it from then on
+        let reloaded: QueueItem =
+            serde_json::from_value(serde_json::to_value(&item).unwrap()).unwrap();
+        assert_eq!(reloaded.idempotency_key, item.idempotency_key);
+        assert_ne!(generate_idempotency_key(), item.idempotency_key);
+    }
+
+    #[tokio::test]
+    async fn an_upload_restored_after_a_restart_keeps_its_idempotency_key() {
+        let root = synthetic_tree(&[("notes.txt", "hello world")]);
+        let store = root.join("store");
+        let item = test_item(
+            "transfer-1",
+            &root.join("notes.txt").to_string_lossy(),
+            "file",
+        );
+        let key = item.idempotency_key.clone();
+
+        // Saved at exit as shutdown does it...
+        let mut before = TransferQueue::new();
+        before.items.push_back(item);
+        save_queue_snapshot(&store, DEFAULT_SESSION, &before.snapshot(DEFAULT_SESSION)).unwrap();
+
+        // ...then restored and resumed on the next launch
+        let state = TransferManagerState::with_settings(TransferSettings::default());
+        restore_queue_snapshots(&store, &state).await;
+        let session = state.session(None);
+        let uploader = Arc::new(MockUploader::default());
+        {
+            let mut queue = session.0.lock().await;
+            queue.custom_uploader = Some(uploader);
+            queue.paused = false;
+        }
+        let frontend = MockFrontend::new(&session, false);
+        process_next_item(frontend.clone(), session.clone())
+            .await
+            .unwrap();
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while frontend.events("transfer-complete").is_empty() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
         })
-        .collect();
+        .await
+        .expect("upload never completed");
 
-    // Format pending folders for display
-    let pending_folders: Vec<String> = queue.pending_folders.iter().cloned().collect();
+        // The server sees the same key it may have seen before the restart
+        assert_eq!(
+            frontend.events("init-file-upload")[0]["idempotency_key"],
+            key
+        );
+        assert_eq!(
+            frontend.events("finalize-transfer")[0]["idempotency_key"],
+            key
+        );
 
-    // Format folder ID mappings
-    let folder_mappings: HashMap<String, String> = queue.folder_id_map.clone();
+        std::fs::remove_dir_all(&root).unwrap();
+    }
 
-    let result = serde_json::json!({
-        "queue_size": queue.items.len(),
-        "processing": queue.processing,
-        "completed_count": queue.completed.len(),
-        "failed_count": queue.failed.len(),
-        "paused": queue.paused,
-        "elapsed_time": queue.start_time.elapsed().as_secs(),
-        "pending_folders_count": queue.pending_folders.len(),
-        "queue_items": queue_items,
-        "pending_folders": pending_folders,
-        "folder_mappings": folder_mappings,
-        "initialized_files_count": queue.initialized_files.len(),
-        "initialized_folders_count": queue.initialized_folders.len(),
-        "block_completion_sent_count": queue.block_completion_sent.len()
-    });
+    #[tokio::test]
+    async fn a_setting_changed_on_its_own_is_saved_and_applied() {
+        let store = synthetic_tree(&[]);
+        let state = TransferManagerState::with_settings(TransferSettings::default());
+        let session = state.session(None);
+
+        update_transfer_settings(&store, &state, |settings| settings.connection_limit = 3)
+            .await
+            .unwrap();
+        assert_eq!(load_transfer_settings(&store).await.connection_limit, 3);
+        assert_eq!(session.0.lock().await.connection_limit, 3);
+
+        // An invalid value is neither saved nor applied
+        assert!(
+            update_transfer_settings(&store, &state, |settings| settings.connection_limit = 0)
+                .await
+                .is_err()
+        );
+        assert_eq!(load_transfer_settings(&store).await.connection_limit, 3);
+        assert_eq!(session.0.lock().await.connection_limit, 3);
 
-    Ok(result)
-}
+        std::fs::remove_dir_all(&store).unwrap();
+    }
 
-/// Registers all the file transfer commands with Tauri
-pub fn register_file_transfer_commands() -> Result<(), Box<dyn std::error::Error>> {
-    println!("File transfer commands registered");
-    Ok(())
+    #[tokio::test]
+    async fn saved_queues_come_back_paused_in_their_sessions() {
+        let store = synthetic_tree(&[]);
+        let item = |id: &str| {
+            serde_json::from_value::<QueueItem>(serde_json::json!({
+                "type": "file",
+                "id": id,
+                "path": format!("/{}.txt", id),
+                "name": format!("{}.txt", id),
+                "parent_id": "share-root",
+                "depth": 0,
+            }))
+            .unwrap()
+        };
+        let snapshot = |session_id: &str, items| QueueSnapshot {
+            session_id: session_id.to_string(),
+            items,
+            failed: HashMap::from([("old".to_string(), "Timed out".to_string())]),
+            folder_id_map: HashMap::from([("/photos".to_string(), "folder-1".to_string())]),
+        };
+        // "interrupted" was processing at shutdown, so the snapshot lists it first
+        let main = snapshot(DEFAULT_SESSION, vec![item("interrupted"), item("queued")]);
+        save_queue_snapshot(&store, DEFAULT_SESSION, &main).unwrap();
+        let other = snapshot("settings-window", vec![item("other")]);
+        save_queue_snapshot(&store, "settings-window", &other).unwrap();
+
+        let state = TransferManagerState::with_settings(TransferSettings::default());
+        restore_queue_snapshots(&store, &state).await;
+
+        let session = state.session(None);
+        let queue = session.0.lock().await;
+        let ids: Vec<&str> = queue.items.iter().map(|item| item.id.as_str()).collect();
+        assert_eq!(ids, ["interrupted", "queued"]);
+        assert!(queue.paused && queue.processing.is_none());
+        assert_eq!(queue.folder_id_map["/photos"], "folder-1");
+        assert_eq!(queue.failed["old"], "Timed out");
+        drop(queue);
+
+        let session = state.session(Some("settings-window"));
+        assert_eq!(session.0.lock().await.items[0].id, "other");
+
+        // Snapshots are consumed, so nothing is restored twice
+        assert_eq!(std::fs::read_dir(&store).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn watchdog_only_looks_at_busy_queues() {
+        let mut queue = TransferQueue::new();
+        assert!(!queue.has_in_flight());
+
+        queue
+            .request_timestamps
+            .insert("file-1".to_string(), Instant::now());
+        assert!(queue.has_in_flight());
+
+        queue.request_timestamps.clear();
+        queue.pending_folders.insert("/photos".to_string());
+        assert!(queue.has_in_flight());
+    }
 }
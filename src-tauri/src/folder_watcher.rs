@@ -0,0 +1,577 @@
+// src/folder_watcher.rs
+
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use serde::Serialize;
+use tauri::{AppHandle, Emitter, Manager, State, command};
+use tokio::sync::{Mutex, mpsc};
+
+use crate::file_transfer::{self, TransferManagerState};
+
+// How long a path must stay quiet before it is uploaded, so a file that is
+// still being written or saved in several steps is only uploaded once
+const DEBOUNCE_DELAY: Duration = Duration::from_secs(2);
+
+// How often pending changes are flushed into the transfer queue
+const FLUSH_INTERVAL: Duration = Duration::from_millis(500);
+
+// How often to check whether an unmounted folder has come back
+const REMOUNT_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+// Temporary files written by editors, browsers and our own transfers
+const IGNORED_SUFFIXES: [&str; 6] = [".tmp", ".part", ".crdownload", ".download", ".swp", "~"];
+
+/// Active folder watches, keyed by the watched path
+#[derive(Default)]
+pub struct FolderWatcherState(pub Mutex<HashMap<String, WatchHandle>>);
+
+/// A running watch task and the info it keeps up to date
+pub struct WatchHandle {
+    task: tauri::async_runtime::JoinHandle<()>,
+    info: Arc<std::sync::Mutex<WatcherInfo>>,
+}
+
+/// One watched folder, as returned by get_watchers
+#[derive(Debug, Clone, Serialize)]
+pub struct WatcherInfo {
+    path: String,
+    share_id: String,
+    parent_id: String,
+    active: bool,           // A watcher is attached to the folder right now
+    status: String,         // "starting", "watching", "unavailable" or "errored"
+    error: Option<String>,  // Last watch error, kept while the status is "errored"
+    pending_changes: usize, // Changed paths waiting out the debounce delay
+}
+
+impl WatchHandle {
+    fn info(&self) -> WatcherInfo {
+        self.info
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone()
+    }
+}
+
+/// Start uploading new and modified files in a folder automatically. Changes
+/// are queued in the transfer session of the window that started the watch.
+#[command]
+pub async fn watch_folder(
+    app: AppHandle,
+    path: String,
+    share_id: String,
+    parent_id: String,
+    state: State<'_, FolderWatcherState>,
+    session_id: Option<String>,
+) -> Result<(), String> {
+    let (share_id, parent_id) =
+        file_transfer::validate_target(&share_id, &parent_id).map_err(|e| e.to_string())?;
+    let root = PathBuf::from(&path);
+    if !root.is_dir() {
+        return Err(format!("Invalid folder path: {}", path));
+    }
+
+    let mut watches = state.0.lock().await;
+    if watches.contains_key(&path) {
+        return Err(format!("Folder is already being watched: {}", path));
+    }
+
+    println!("Watching folder for changes: {}", path);
+
+    let info = Arc::new(std::sync::Mutex::new(WatcherInfo {
+        path: path.clone(),
+        share_id: share_id.clone(),
+        parent_id: parent_id.clone(),
+        active: false,
+        status: "starting".to_string(),
+        error: None,
+        pending_changes: 0,
+    }));
+    let task = tauri::async_runtime::spawn(run_watch(
+        app,
+        root,
+        share_id,
+        parent_id,
+        session_id,
+        info.clone(),
+    ));
+    watches.insert(path, WatchHandle { task, info });
+
+    Ok(())
+}
+
+/// Stop watching a folder previously passed to watch_folder
+#[command]
+pub async fn unwatch_folder(
+    path: String,
+    state: State<'_, FolderWatcherState>,
+) -> Result<(), String> {
+    let mut watches = state.0.lock().await;
+
+    // Aborting the task drops the watcher along with it
+    match watches.remove(&path) {
+        Some(watch) => {
+            watch.task.abort();
+            println!("Stopped watching folder: {}", path);
+            Ok(())
+        }
+        None => Err(format!("Folder is not being watched: {}", path)),
+    }
+}
+
+/// Lists every watched folder with its target, state and pending change count
+#[command]
+pub async fn get_watchers(
+    state: State<'_, FolderWatcherState>,
+) -> Result<Vec<WatcherInfo>, String> {
+    let watches = state.0.lock().await;
+    let mut watchers: Vec<WatcherInfo> = watches.values().map(WatchHandle::info).collect();
+    watchers.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(watchers)
+}
+
+/// Start a recursive watcher that forwards its events into the channel
+fn start_watcher(
+    root: &Path,
+    tx: mpsc::UnboundedSender<notify::Result<Event>>,
+) -> Result<RecommendedWatcher, String> {
+    let mut watcher = notify::recommended_watcher(move |result| {
+        let _ = tx.send(result);
+    })
+    .map_err(|e| format!("Failed to create watcher: {}", e))?;
+
+    watcher
+        .watch(root, RecursiveMode::Recursive)
+        .map_err(|e| format!("Failed to watch folder: {}", e))?;
+
+    Ok(watcher)
+}
+
+/// Watches one folder until the task is aborted, re-attaching after unmounts
+async fn run_watch(
+    app: AppHandle,
+    root: PathBuf,
+    share_id: String,
+    parent_id: String,
+    session_id: Option<String>,
+    info: Arc<std::sync::Mutex<WatcherInfo>>,
+) {
+    let update_info = |update: &dyn Fn(&mut WatcherInfo)| {
+        update(&mut info.lock().unwrap_or_else(|poisoned| poisoned.into_inner()));
+    };
+
+    let (tx, mut rx) = mpsc::unbounded_channel::<notify::Result<Event>>();
+    let mut watcher: Option<RecommendedWatcher> = None;
+    // Changed path -> (last change, whether it was newly created)
+    let mut pending: HashMap<PathBuf, (Instant, bool)> = HashMap::new();
+    let mut flush_timer = tokio::time::interval(FLUSH_INTERVAL);
+    let mut last_mount_check: Option<Instant> = None;
+
+    loop {
+        tokio::select! {
+            Some(result) = rx.recv() => match result {
+                Ok(event) => {
+                    if matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) {
+                        let created = matches!(event.kind, EventKind::Create(_));
+                        for path in event.paths {
+                            let entry = pending.entry(path).or_insert((Instant::now(), false));
+                            entry.0 = Instant::now();
+                            entry.1 |= created;
+                        }
+                        update_info(&|info| info.pending_changes = pending.len());
+                    }
+                }
+                Err(e) => {
+                    // Usually the folder went away; the mount check re-attaches later
+                    println!("Watch error for {}: {}", root.display(), e);
+                    watcher = None;
+                    update_info(&|info| {
+                        info.active = false;
+                        info.status = "errored".to_string();
+                        info.error = Some(e.to_string());
+                    });
+                    emit_watch_status(&app, &root, "errored");
+                }
+            },
+            _ = flush_timer.tick() => {
+                let check_mount = last_mount_check
+                    .is_none_or(|checked| checked.elapsed() >= REMOUNT_CHECK_INTERVAL);
+
+                if check_mount {
+                    last_mount_check = Some(Instant::now());
+
+                    match (root.is_dir(), watcher.is_some()) {
+                        (true, false) => match start_watcher(&root, tx.clone()) {
+                            Ok(new_watcher) => {
+                                watcher = Some(new_watcher);
+                                update_info(&|info| {
+                                    info.active = true;
+                                    info.status = "watching".to_string();
+                                    info.error = None;
+                                });
+                                emit_watch_status(&app, &root, "watching");
+                            }
+                            Err(e) => {
+                                println!("{}", e);
+                                update_info(&|info| {
+                                    info.status = "errored".to_string();
+                                    info.error = Some(e.clone());
+                                });
+                            }
+                        },
+                        (false, true) => {
+                            watcher = None;
+                            pending.clear();
+                            update_info(&|info| {
+                                info.active = false;
+                                info.status = "unavailable".to_string();
+                                info.pending_changes = 0;
+                            });
+                            emit_watch_status(&app, &root, "unavailable");
+                        }
+                        _ => {}
+                    }
+                }
+
+                let ready = take_settled(&mut pending, Instant::now());
+                if !ready.is_empty() {
+                    update_info(&|info| info.pending_changes = pending.len());
+                    enqueue_changes(&app, &root, &share_id, &parent_id, session_id.as_deref(), ready)
+                        .await;
+                }
+            }
+        }
+    }
+}
+
+fn emit_watch_status(app: &AppHandle, root: &Path, status: &str) {
+    let _ = app.emit(
+        "watch-folder-status",
+        serde_json::json!({
+            "path": root.to_string_lossy(),
+            "status": status,
+        }),
+    );
+}
+
+/// Removes and returns the changes that have been quiet for DEBOUNCE_DELAY,
+/// with whether each path was newly created
+fn take_settled(
+    pending: &mut HashMap<PathBuf, (Instant, bool)>,
+    now: Instant,
+) -> Vec<(PathBuf, bool)> {
+    let ready: Vec<(PathBuf, bool)> = pending
+        .iter()
+        .filter(|(_, (changed, _))| now.saturating_duration_since(*changed) >= DEBOUNCE_DELAY)
+        .map(|(path, (_, created))| (path.clone(), *created))
+        .collect();
+
+    for (path, _) in &ready {
+        pending.remove(path);
+    }
+    ready
+}
+
+fn is_ignored(path: &Path) -> bool {
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or_default();
+
+    name.starts_with('.') || IGNORED_SUFFIXES.iter().any(|suffix| name.ends_with(suffix))
+}
+
+/// Settled changes sorted by where they go. Files and folders are keyed by
+/// destination folder ID; paths in a folder this queue has no ID for are
+/// keyed by that folder.
+#[derive(Debug, Default)]
+struct RoutedChanges {
+    files: HashMap<String, Vec<String>>,
+    folders: HashMap<String, Vec<String>>,
+    unresolved: HashMap<PathBuf, Vec<String>>,
+}
+
+/// A settled path that still exists, with what it is on disk
+#[derive(Debug)]
+struct SettledChange {
+    path: PathBuf,
+    created: bool,
+    is_dir: bool,
+}
+
+/// Looks settled paths up on disk, dropping the watched folder itself,
+/// ignored names and paths that are gone again. Done before the queue is
+/// locked, so disk access never holds it.
+fn stat_changes(root: &Path, paths: Vec<(PathBuf, bool)>) -> Vec<SettledChange> {
+    paths
+        .into_iter()
+        .filter(|(path, _)| path != root && !is_ignored(path))
+        .filter_map(|(path, created)| {
+            let metadata = std::fs::metadata(&path).ok()?;
+            Some(SettledChange {
+                path,
+                created,
+                is_dir: metadata.is_dir(),
+            })
+        })
+        .collect()
+}
+
+/// Decides what to upload from settled changes and into which folder.
+/// `is_queued` and `folder_id` look paths up in the transfer queue; the disk
+/// isn't touched.
+fn route_changes(
+    root: &Path,
+    parent_id: &str,
+    changes: Vec<SettledChange>,
+    is_queued: impl Fn(&str) -> bool,
+    folder_id: impl Fn(&str) -> Option<String>,
+) -> RoutedChanges {
+    // A new folder is uploaded whole, so drop anything inside one. Existing
+    // folders only report metadata changes and are left alone.
+    let new_folders: HashSet<PathBuf> = changes
+        .iter()
+        .filter(|change| change.created && change.is_dir)
+        .map(|change| change.path.clone())
+        .collect();
+
+    let mut routed = RoutedChanges::default();
+    for SettledChange { path, is_dir, .. } in changes {
+        if is_dir && !new_folders.contains(&path) {
+            continue;
+        }
+        if path
+            .ancestors()
+            .skip(1)
+            .any(|ancestor| new_folders.contains(ancestor))
+        {
+            continue;
+        }
+
+        let path_str = path.to_string_lossy().to_string();
+
+        // Skip anything the queue is already handling
+        if is_queued(&path_str) {
+            continue;
+        }
+
+        // Files directly in the watched folder go to its parent_id; deeper
+        // ones go to their folder if this queue uploaded it
+        let parent_dir = path.parent().unwrap_or(root);
+        let destination = if parent_dir == root {
+            Some(parent_id.to_string())
+        } else {
+            folder_id(&parent_dir.to_string_lossy())
+        };
+
+        let Some(destination) = destination else {
+            routed
+                .unresolved
+                .entry(parent_dir.to_path_buf())
+                .or_default()
+                .push(path_str);
+            continue;
+        };
+
+        let by_parent = if is_dir {
+            &mut routed.folders
+        } else {
+            &mut routed.files
+        };
+        by_parent.entry(destination).or_default().push(path_str);
+    }
+
+    routed
+}
+
+/// Feed settled paths into the transfer queue through select_files/select_folders.
+/// Changes in folders that existed before the watch started have no known
+/// folder ID, so they go to the frontend as `watch-folder-unresolved` with
+/// the folder's path relative to the watched one; it can find or create that
+/// folder and queue the paths itself.
+async fn enqueue_changes(
+    app: &AppHandle,
+    root: &Path,
+    share_id: &str,
+    parent_id: &str,
+    session_id: Option<&str>,
+    paths: Vec<(PathBuf, bool)>,
+) {
+    let changes = stat_changes(root, paths);
+    let routed = {
+        let state = app.state::<TransferManagerState>().session(session_id);
+        let queue = state.0.lock().await;
+        route_changes(
+            root,
+            parent_id,
+            changes,
+            |path| queue.is_path_queued(path),
+            |path| queue.folder_id_for_path(path),
+        )
+    };
+
+    for (folder, paths) in routed.unresolved {
+        println!(
+            "No folder ID for {}, leaving {} changes to the frontend",
+            folder.display(),
+            paths.len()
+        );
+        let relative = folder.strip_prefix(root).unwrap_or(&folder);
+        let _ = app.emit(
+            "watch-folder-unresolved",
+            serde_json::json!({
+                "root": root.to_string_lossy(),
+                "folder": folder.to_string_lossy(),
+                "relative_folder": relative.to_string_lossy(),
+                "share_id": share_id,
+                "parent_id": parent_id,
+                "paths": paths,
+            }),
+        );
+    }
+
+    for (destination, folders) in routed.folders {
+        if let Err(e) = file_transfer::select_folders(
+            app.clone(),
+            folders,
+            share_id.to_string(),
+            destination,
+            None,
+            None,
+            app.state::<TransferManagerState>(),
+            session_id.map(str::to_string),
+        )
+        .await
+        {
+            println!("Failed to queue watched folders: {}", e);
+        }
+    }
+
+    for (destination, files) in routed.files {
+        if let Err(e) = file_transfer::select_files(
+            app.clone(),
+            files,
+            share_id.to_string(),
+            destination,
+            None,
+            None,
+            None,
+            None,
+            app.state::<TransferManagerState>(),
+            session_id.map(str::to_string),
+        )
+        .await
+        {
+            println!("Failed to queue watched files: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::Rng;
+
+    #[test]
+    fn temporary_and_hidden_files_are_ignored() {
+        for name in [
+            ".DS_Store",
+            "report.docx.tmp",
+            "movie.mkv.part",
+            "setup.exe.crdownload",
+            "photo.jpg.download",
+            ".notes.txt.swp",
+            "notes.txt~",
+        ] {
+            assert!(is_ignored(&Path::new("/watched").join(name)), "{}", name);
+        }
+        for name in ["notes.txt", "archive.tar.gz", "tmp", "download"] {
+            assert!(!is_ignored(&Path::new("/watched").join(name)), "{}", name);
+        }
+    }
+
+    #[test]
+    fn changes_wait_out_the_debounce_delay() {
+        let start = Instant::now();
+        let mut pending = HashMap::from([
+            (PathBuf::from("/watched/old.txt"), (start, false)),
+            (
+                PathBuf::from("/watched/new.txt"),
+                (start + DEBOUNCE_DELAY, true),
+            ),
+        ]);
+
+        assert!(take_settled(&mut pending, start).is_empty());
+
+        let settled = take_settled(&mut pending, start + DEBOUNCE_DELAY);
+        assert_eq!(settled, [(PathBuf::from("/watched/old.txt"), false)]);
+        assert_eq!(pending.len(), 1);
+
+        let settled = take_settled(&mut pending, start + DEBOUNCE_DELAY * 2);
+        assert_eq!(settled, [(PathBuf::from("/watched/new.txt"), true)]);
+        assert!(pending.is_empty());
+    }
+
+    #[test]
+    fn new_folders_go_up_whole_and_unknown_folders_are_reported() {
+        let root =
+            std::env::temp_dir().join(format!("cirrussync-watch-{}", rand::rng().random::<u64>()));
+        for dir in ["existing", "uploaded", "new/inner"] {
+            std::fs::create_dir_all(root.join(dir)).unwrap();
+        }
+        for file in [
+            "top.txt",
+            "top.txt.part",
+            "existing/old.txt",
+            "uploaded/photo.jpg",
+            "new/a.txt",
+            "new/inner/b.txt",
+            "queued.txt",
+        ] {
+            std::fs::write(root.join(file), "data").unwrap();
+        }
+
+        let changed = |path: &str, created: bool| (root.join(path), created);
+        let changes = stat_changes(
+            &root,
+            vec![
+                changed("top.txt", false),
+                changed("top.txt.part", true),
+                changed("existing", false), // Metadata change on an existing folder
+                changed("existing/old.txt", false),
+                changed("uploaded/photo.jpg", true),
+                changed("new", true),
+                changed("new/a.txt", true),
+                changed("new/inner/b.txt", true),
+                changed("queued.txt", true),
+                changed("deleted.txt", false),
+            ],
+        );
+
+        // Routing goes by the stats alone, so the tree can be gone by then
+        std::fs::remove_dir_all(&root).unwrap();
+        let routed = route_changes(
+            &root,
+            "parent-1",
+            changes,
+            |path| path.ends_with("queued.txt"),
+            |path| path.ends_with("uploaded").then(|| "folder-9".to_string()),
+        );
+
+        let path = |path: &str| root.join(path).to_string_lossy().to_string();
+        let mut top_level = routed.files["parent-1"].clone();
+        top_level.sort();
+        assert_eq!(top_level, [path("top.txt")]);
+        assert_eq!(routed.files["folder-9"], [path("uploaded/photo.jpg")]);
+        assert_eq!(routed.folders["parent-1"], [path("new")]);
+        assert_eq!(routed.files.len() + routed.folders.len(), 3);
+        assert_eq!(
+            routed.unresolved[&root.join("existing")],
+            [path("existing/old.txt")]
+        );
+        assert_eq!(routed.unresolved.len(), 1);
+    }
+}
@@ -1,121 +1,653 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fs;
 use std::path::PathBuf;
 use std::sync::Mutex;
 use tauri::State;
 use tauri::{Emitter, Manager, Theme};
+use tauri_plugin_window_state::StateFlags;
+use zeroize::Zeroizing;
 mod file_transfer;
+mod folder_watcher;
+mod proxy;
 mod system_identity;
 use file_transfer::TransferManagerState;
-use std::sync::Arc;
-use system_identity::generate_system_identifier;
-use tokio::sync::Mutex as AsyncMutex;
+use password_store::{PasswordBackend, PasswordStorage};
+use system_identity::{generate_system_identifier, get_system_details, refresh_system_identifier};
+mod password_store;
 mod recovery_key;
+mod store_archive;
+mod trash_manager;
+#[cfg(desktop)]
+mod tray;
 
 // Store state for basic key-value storage
 #[derive(Default)]
 struct AppState {
     auth_store_path: Mutex<Option<PathBuf>>,
+    theme_mode: Mutex<ThemeMode>,
+    session_layout: Mutex<bool>,
+    // Handles to the app menu items by id, so they can be toggled after build_menu
+    menu_items: Mutex<HashMap<String, tauri::menu::MenuItem>>,
+    // Last fullscreen state reported to the frontend
+    fullscreen: Mutex<bool>,
+    // Logical size to restore when leaving fullscreen
+    pre_fullscreen_size: Mutex<Option<tauri::LogicalSize<f64>>>,
+}
+
+// What the user currently has selected, as reported by the frontend
+#[derive(Debug, Clone, Default, Deserialize)]
+struct MenuSelection {
+    // Whether the trash view is open rather than the drive
+    #[serde(default)]
+    in_trash: bool,
+    #[serde(default)]
+    file_count: usize,
+    #[serde(default)]
+    folder_count: usize,
+}
+
+impl MenuSelection {
+    // Enabled state for each selection-dependent menu item
+    fn menu_item_states(&self) -> Vec<(&'static str, bool)> {
+        let in_drive = !self.in_trash;
+        let files_only = self.file_count > 0 && self.folder_count == 0;
+        let single_file = self.file_count == 1 && self.folder_count == 0;
+        let single_folder = self.folder_count == 1 && self.file_count == 0;
+        let any_selected = self.file_count + self.folder_count > 0;
+
+        vec![
+            // File menu
+            ("upload_file", in_drive),
+            ("preview_file", in_drive && single_file),
+            ("rename_file", in_drive && single_file),
+            ("move_file", in_drive && files_only),
+            ("file_details", in_drive && single_file),
+            ("download_file", in_drive && files_only),
+            ("move_to_trash", in_drive && files_only),
+            // Folder menu
+            ("new_folder", in_drive),
+            ("upload_folder", in_drive),
+            ("rename_folder", in_drive && single_folder),
+            ("folder_details", in_drive && single_folder),
+            ("move_folder_to_trash", in_drive && self.folder_count > 0),
+            // Edit menu
+            ("select_all", in_drive),
+            // Trash menu
+            ("select_all_trash", self.in_trash),
+            ("recover_all", self.in_trash),
+            ("recover_selected", self.in_trash && any_selected),
+            ("delete_selected", self.in_trash && any_selected),
+            ("empty_trash", self.in_trash),
+        ]
+    }
+}
+
+// File in the app config directory holding the last session window
+// geometry. It is kept out of the store so it isn't read, exported or
+// integrity checked as a store value.
+const WINDOW_GEOMETRY_FILE: &str = "window_geometry.json";
+
+// Default and minimum size of the session window
+const SESSION_WIDTH: f64 = 800.0;
+const SESSION_HEIGHT: f64 = 600.0;
+
+// Fixed size of the login window
+const LOGIN_WIDTH: f64 = 500.0;
+const LOGIN_HEIGHT: f64 = 600.0;
+
+// Last size and position of the session window, in logical pixels
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct WindowGeometry {
+    width: f64,
+    height: f64,
+    x: f64,
+    y: f64,
+}
+
+// Theme preference applied to every window
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum ThemeMode {
+    Light,
+    Dark,
+    // Follow the OS appearance and track its changes
+    #[default]
+    System,
+}
+
+impl ThemeMode {
+    fn parse(mode: &str) -> Result<Self, String> {
+        match mode.to_lowercase().as_str() {
+            "light" => Ok(ThemeMode::Light),
+            "dark" => Ok(ThemeMode::Dark),
+            "system" => Ok(ThemeMode::System),
+            other => Err(format!(
+                "Invalid theme mode '{}', expected light, dark or system",
+                other
+            )),
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            ThemeMode::Light => "light",
+            ThemeMode::Dark => "dark",
+            ThemeMode::System => "system",
+        }
+    }
+
+    // Explicit theme to force on windows, None lets the OS decide
+    fn window_theme(&self) -> Option<Theme> {
+        match self {
+            ThemeMode::Light => Some(Theme::Light),
+            ThemeMode::Dark => Some(Theme::Dark),
+            ThemeMode::System => None,
+        }
+    }
+}
+
+fn theme_name(theme: Theme) -> &'static str {
+    match theme {
+        Theme::Dark => "dark",
+        _ => "light",
+    }
 }
 
 // Initialize the app and set up storage directories
 #[tauri::command]
 async fn initialize_app(app: tauri::AppHandle, state: State<'_, AppState>) -> Result<(), String> {
-    let app_dir = app
-        .path()
-        .app_data_dir()
-        .expect("Failed to get app data directory");
-
     // Set up store directory
-    let store_path = app_dir.join("store");
+    let store_path = app_data_dir(&app)?.join("store");
 
     println!("Store path: {:?}", store_path);
 
     // Make sure the directory exists
-    fs::create_dir_all(&store_path)
+    tokio::fs::create_dir_all(&store_path)
+        .await
         .map_err(|e| format!("Failed to create store directory: {}", e))?;
 
+    // Window geometry used to be saved as a store value
+    let legacy_geometry = store_path.join(WINDOW_GEOMETRY_FILE);
+    if legacy_geometry.exists() {
+        let _ = tokio::fs::remove_file(legacy_geometry).await;
+    }
+
     // Store the path for later use
     *state.auth_store_path.lock().unwrap() = Some(store_path);
 
     Ok(())
 }
 
-// Store a value in JSON file
+// Where the app keeps its data on disk
+#[derive(Debug, Serialize)]
+struct AppPaths {
+    app_data_dir: String,
+    store_path: String,
+    secure_dir: String,
+}
+
+fn app_data_dir(app: &tauri::AppHandle) -> Result<PathBuf, String> {
+    app.path()
+        .app_data_dir()
+        .map_err(|e| format!("Failed to get app data directory: {}", e))
+}
+
+// Data, store and password directories. Works before initialize_app too, by
+// working the store path out the same way it does.
+#[tauri::command]
+fn get_app_paths(app: tauri::AppHandle, state: State<'_, AppState>) -> Result<AppPaths, String> {
+    let app_dir = app_data_dir(&app)?;
+    let store_path = store_dir(&state).unwrap_or_else(|_| app_dir.join("store"));
+
+    Ok(AppPaths {
+        app_data_dir: app_dir.to_string_lossy().to_string(),
+        secure_dir: store_path.join("secure").to_string_lossy().to_string(),
+        store_path: store_path.to_string_lossy().to_string(),
+    })
+}
+
+// Open the app data directory in the system file manager
+#[tauri::command]
+async fn open_data_directory(app: tauri::AppHandle) -> Result<(), String> {
+    use tauri_plugin_opener::OpenerExt;
+
+    // It may not exist yet on a fresh install
+    let app_dir = app_data_dir(&app)?;
+    tokio::fs::create_dir_all(&app_dir)
+        .await
+        .map_err(|e| format!("Failed to create app data directory: {}", e))?;
+
+    app.opener()
+        .open_path(app_dir.to_string_lossy(), None::<&str>)
+        .map_err(|e| format!("Failed to open app data directory: {}", e))
+}
+
+// Values larger than this are split into chunk files instead of one JSON file
+const LARGE_VALUE_THRESHOLD: usize = 1024 * 1024;
+const STORE_CHUNK_SIZE: usize = 1024 * 1024;
+
+// Manifest listing the chunk files of a large value
+#[derive(Debug, Serialize, Deserialize)]
+struct ChunkManifest {
+    chunks: usize,
+    size: usize,
+}
+
+// Store directory, copied out so the lock isn't held across file I/O. The
+// lock is a std Mutex, so its guard must never live across an .await: that
+// blocks a runtime thread and makes the command future non-Send.
+fn store_dir(state: &State<'_, AppState>) -> Result<PathBuf, String> {
+    state
+        .auth_store_path
+        .lock()
+        .unwrap()
+        .clone()
+        .ok_or_else(|| "Store not initialized".to_string())
+}
+
+// Directory holding a large value's manifest and chunks. Keys map to .json
+// files, so this can't collide with another key.
+fn chunk_dir(store_path: &std::path::Path, key: &str) -> PathBuf {
+    store_path.join(format!("{}.chunks", key))
+}
+
+// Where a large value's chunks are written before being swapped into place
+fn pending_chunk_dir(store_path: &std::path::Path, key: &str) -> PathBuf {
+    store_path.join(format!("{}.chunks.tmp", key))
+}
+
+// Where a large value's old chunks are moved while the new ones go in
+fn previous_chunk_dir(store_path: &std::path::Path, key: &str) -> PathBuf {
+    store_path.join(format!("{}.chunks.old", key))
+}
+
+// Whether a chunk directory was written to the end. The manifest goes last.
+fn chunks_complete(dir: &std::path::Path) -> bool {
+    dir.join("manifest.json").exists()
+}
+
+// Finish or undo large value writes that were cut short, leaving each key
+// with either its old value or its new one. Runs at startup before anything
+// reads the store, so reads never move files around under a writer. Returns
+// how many values were moved back into place.
+pub(crate) async fn recover_store_chunks(store_path: &std::path::Path) -> Result<usize, String> {
+    if !store_path.exists() {
+        return Ok(0);
+    }
+
+    let mut keys = std::collections::BTreeSet::new();
+    let mut entries = tokio::fs::read_dir(store_path)
+        .await
+        .map_err(|e| format!("Failed to read store directory: {}", e))?;
+    while let Some(entry) = entries
+        .next_entry()
+        .await
+        .map_err(|e| format!("Failed to read store directory: {}", e))?
+    {
+        let file_name = entry.file_name().to_string_lossy().to_string();
+        if let Some(key) = file_name
+            .strip_suffix(".chunks.tmp")
+            .or_else(|| file_name.strip_suffix(".chunks.old"))
+        {
+            keys.insert(key.to_string());
+        }
+    }
+
+    let mut recovered = 0;
+    for key in keys {
+        let chunks = chunk_dir(store_path, &key);
+        let pending = pending_chunk_dir(store_path, &key);
+        let previous = previous_chunk_dir(store_path, &key);
+
+        // The write removes a small value before swapping chunks, so a value
+        // still in place in either form is the one to keep. Otherwise the
+        // swap stopped halfway: the new chunks win if they are complete.
+        let in_place =
+            store_path.join(format!("{}.json", key)).exists() || chunks_complete(&chunks);
+        if !in_place {
+            if let Some(source) = [&pending, &previous]
+                .into_iter()
+                .find(|dir| chunks_complete(dir))
+            {
+                if chunks.exists() {
+                    tokio::fs::remove_dir_all(&chunks)
+                        .await
+                        .map_err(|e| format!("Failed to clear {}: {}", chunks.display(), e))?;
+                }
+                tokio::fs::rename(source, &chunks)
+                    .await
+                    .map_err(|e| format!("Failed to move chunks into place: {}", e))?;
+                recovered += 1;
+            }
+        }
+
+        for dir in [&pending, &previous] {
+            if dir.exists() {
+                tokio::fs::remove_dir_all(dir)
+                    .await
+                    .map_err(|e| format!("Failed to clear {}: {}", dir.display(), e))?;
+            }
+        }
+    }
+
+    Ok(recovered)
+}
+
+// Remove whichever form a value is stored in
+async fn remove_stored_value(store_path: &std::path::Path, key: &str) -> Result<(), String> {
+    let file_path = store_path.join(format!("{}.json", key));
+    if file_path.exists() {
+        tokio::fs::remove_file(file_path)
+            .await
+            .map_err(|e| format!("Failed to delete file: {}", e))?;
+    }
+
+    // Including chunks a cut short write left behind, which recovery at the
+    // next launch would otherwise move into place
+    for chunks in [
+        chunk_dir(store_path, key),
+        pending_chunk_dir(store_path, key),
+        previous_chunk_dir(store_path, key),
+    ] {
+        if chunks.exists() {
+            tokio::fs::remove_dir_all(chunks)
+                .await
+                .map_err(|e| format!("Failed to delete chunks: {}", e))?;
+        }
+    }
+
+    Ok(())
+}
+
+// Store a value in JSON file, or in chunk files when it is large
 #[tauri::command]
 async fn set_store_value(
     state: State<'_, AppState>,
     key: String,
     value: String,
 ) -> Result<(), String> {
-    let state_guard = state.auth_store_path.lock().unwrap();
-    let store_path = state_guard.as_ref().ok_or("Store not initialized")?;
+    let store_path = store_dir(&state)?;
+    write_store_value(&store_path, &key, &value).await
+}
 
+// Write a value the way set_store_value does. Small values go to a temporary
+// file that is renamed over the old one, so an interrupted write leaves the
+// previous value intact.
+pub(crate) async fn write_store_value(
+    store_path: &std::path::Path,
+    key: &str,
+    value: &str,
+) -> Result<(), String> {
+    if value.len() <= LARGE_VALUE_THRESHOLD {
+        let file_path = store_path.join(format!("{}.json", key));
+        let temp_path = store_path.join(format!("{}.json.tmp", key));
+        tokio::fs::write(&temp_path, value)
+            .await
+            .map_err(|e| format!("Failed to write file: {}", e))?;
+        tokio::fs::rename(&temp_path, &file_path)
+            .await
+            .map_err(|e| format!("Failed to replace file: {}", e))?;
+
+        // The previous value may have been large enough to be chunked
+        for chunks in [
+            chunk_dir(store_path, key),
+            pending_chunk_dir(store_path, key),
+            previous_chunk_dir(store_path, key),
+        ] {
+            if chunks.exists() {
+                tokio::fs::remove_dir_all(chunks)
+                    .await
+                    .map_err(|e| format!("Failed to delete chunks: {}", e))?;
+            }
+        }
+        return Ok(());
+    }
+
+    // Large values are written to a sibling directory and swapped in once
+    // complete, so an interrupted write leaves either the old value or the new
+    let chunks = chunk_dir(store_path, key);
+    let pending = pending_chunk_dir(store_path, key);
+    let previous = previous_chunk_dir(store_path, key);
+    for dir in [&pending, &previous] {
+        if dir.exists() {
+            tokio::fs::remove_dir_all(dir)
+                .await
+                .map_err(|e| format!("Failed to clear {}: {}", dir.display(), e))?;
+        }
+    }
+    tokio::fs::create_dir_all(&pending)
+        .await
+        .map_err(|e| format!("Failed to create chunk directory: {}", e))?;
+
+    let bytes = value.as_bytes();
+    let mut count = 0;
+    for chunk in bytes.chunks(STORE_CHUNK_SIZE) {
+        tokio::fs::write(pending.join(count.to_string()), chunk)
+            .await
+            .map_err(|e| format!("Failed to write chunk: {}", e))?;
+        count += 1;
+    }
+
+    // Written last, so the pending directory is only complete once it has one
+    let manifest = serde_json::to_vec(&ChunkManifest {
+        chunks: count,
+        size: bytes.len(),
+    })
+    .map_err(|e| format!("Failed to serialize manifest: {}", e))?;
+    tokio::fs::write(pending.join("manifest.json"), manifest)
+        .await
+        .map_err(|e| format!("Failed to write manifest: {}", e))?;
+
+    // A small previous value would be read before the chunks. From here on
+    // recover_store_chunks finishes the swap at the next launch if it is cut
+    // short.
     let file_path = store_path.join(format!("{}.json", key));
-    fs::write(&file_path, value).map_err(|e| format!("Failed to write file: {}", e))?;
+    if file_path.exists() {
+        tokio::fs::remove_file(file_path)
+            .await
+            .map_err(|e| format!("Failed to delete file: {}", e))?;
+    }
+    if chunks.exists() {
+        tokio::fs::rename(&chunks, &previous)
+            .await
+            .map_err(|e| format!("Failed to move old chunks aside: {}", e))?;
+    }
+    tokio::fs::rename(&pending, &chunks)
+        .await
+        .map_err(|e| format!("Failed to move chunks into place: {}", e))?;
+    let _ = tokio::fs::remove_dir_all(previous).await;
 
     Ok(())
 }
 
-// Get a value from JSON file
+// Get a value from JSON file, reassembling it from chunks when it is large
 #[tauri::command]
 async fn get_store_value(
     state: State<'_, AppState>,
     key: String,
 ) -> Result<Option<String>, String> {
-    let state_guard = state.auth_store_path.lock().unwrap();
-    let store_path = state_guard.as_ref().ok_or("Store not initialized")?;
+    let store_path = store_dir(&state)?;
+    read_store_value(&store_path, &key).await
+}
 
+// Read a value in whichever form it is stored
+pub(crate) async fn read_store_value(
+    store_path: &std::path::Path,
+    key: &str,
+) -> Result<Option<String>, String> {
     let file_path = store_path.join(format!("{}.json", key));
+    if file_path.exists() {
+        let content = tokio::fs::read_to_string(file_path)
+            .await
+            .map_err(|e| format!("Failed to read file: {}", e))?;
+        return Ok(Some(content));
+    }
+
+    read_chunked_value(store_path, key).await
+}
 
-    if !file_path.exists() {
+// Reassemble a large value from its chunk files, if it is stored that way
+async fn read_chunked_value(
+    store_path: &std::path::Path,
+    key: &str,
+) -> Result<Option<String>, String> {
+    let chunks = chunk_dir(store_path, key);
+    let manifest_path = chunks.join("manifest.json");
+    if !manifest_path.exists() {
         return Ok(None);
     }
 
-    let content =
-        fs::read_to_string(file_path).map_err(|e| format!("Failed to read file: {}", e))?;
-    Ok(Some(content))
+    let manifest: ChunkManifest = serde_json::from_slice(
+        &tokio::fs::read(manifest_path)
+            .await
+            .map_err(|e| format!("Failed to read manifest: {}", e))?,
+    )
+    .map_err(|e| format!("Invalid chunk manifest: {}", e))?;
+
+    let mut bytes = Vec::with_capacity(manifest.size);
+    for index in 0..manifest.chunks {
+        let chunk = tokio::fs::read(chunks.join(index.to_string()))
+            .await
+            .map_err(|e| format!("Failed to read chunk {}: {}", index, e))?;
+        bytes.extend_from_slice(&chunk);
+    }
+
+    if bytes.len() != manifest.size {
+        return Err(format!(
+            "Stored value for {} is {} bytes, expected {}",
+            key,
+            bytes.len(),
+            manifest.size
+        ));
+    }
+
+    String::from_utf8(bytes)
+        .map(Some)
+        .map_err(|e| format!("Stored value is not valid UTF-8: {}", e))
 }
 
-// Delete a value from JSON file
+// Delete a value from JSON file or chunk files
 #[tauri::command]
 async fn delete_store_value(state: State<'_, AppState>, key: String) -> Result<(), String> {
-    let state_guard = state.auth_store_path.lock().unwrap();
-    let store_path = state_guard.as_ref().ok_or("Store not initialized")?;
+    let store_path = store_dir(&state)?;
+    remove_stored_value(&store_path, &key).await
+}
 
-    let file_path = store_path.join(format!("{}.json", key));
+// Subdirectory that unparseable store values are moved into
+const CORRUPT_DIR: &str = "corrupt";
 
-    if file_path.exists() {
-        fs::remove_file(file_path).map_err(|e| format!("Failed to delete file: {}", e))?;
+// Check that every stored value parses as JSON and return the keys that
+// don't. With quarantine, their files are moved into corrupt/ so the next
+// read sees no value instead of a broken one. Passwords in secure/ aren't JSON
+// and are left alone.
+#[tauri::command]
+async fn verify_store_integrity(
+    state: State<'_, AppState>,
+    quarantine: bool,
+) -> Result<Vec<String>, String> {
+    let store_path = store_dir(&state)?;
+    find_corrupt_values(&store_path, quarantine).await
+}
+
+// The check verify_store_integrity runs on a store directory
+async fn find_corrupt_values(
+    store_path: &std::path::Path,
+    quarantine: bool,
+) -> Result<Vec<String>, String> {
+    let mut corrupt = Vec::new();
+
+    if !store_path.exists() {
+        return Ok(corrupt);
     }
 
-    Ok(())
+    let mut entries = tokio::fs::read_dir(store_path)
+        .await
+        .map_err(|e| format!("Failed to read store directory: {}", e))?;
+
+    while let Some(entry) = entries
+        .next_entry()
+        .await
+        .map_err(|e| format!("Failed to read store directory: {}", e))?
+    {
+        let file_name = entry.file_name().to_string_lossy().to_string();
+
+        // Values are either key.json or a key.chunks directory
+        let (key, content) = if let Some(key) = file_name.strip_suffix(".json") {
+            let content = tokio::fs::read(entry.path())
+                .await
+                .map_err(|e| format!("Failed to read {}: {}", file_name, e))?;
+            (key.to_string(), Ok(content))
+        } else if let Some(key) = file_name.strip_suffix(".chunks") {
+            let content = read_chunked_value(store_path, key)
+                .await
+                .map(|value| value.unwrap_or_default().into_bytes());
+            (key.to_string(), content)
+        } else {
+            continue;
+        };
+
+        let valid = match content {
+            Ok(content) => serde_json::from_slice::<serde_json::Value>(&content).is_ok(),
+            Err(e) => {
+                println!("Failed to reassemble stored value {}: {}", key, e);
+                false
+            }
+        };
+        if valid {
+            continue;
+        }
+
+        println!("Store value {} is not valid JSON", key);
+        if quarantine {
+            let corrupt_dir = store_path.join(CORRUPT_DIR);
+            tokio::fs::create_dir_all(&corrupt_dir)
+                .await
+                .map_err(|e| format!("Failed to create corrupt directory: {}", e))?;
+
+            // Replace anything quarantined earlier under the same key
+            let target = corrupt_dir.join(&file_name);
+            if target.is_dir() {
+                let _ = tokio::fs::remove_dir_all(&target).await;
+            }
+            tokio::fs::rename(entry.path(), &target)
+                .await
+                .map_err(|e| format!("Failed to quarantine {}: {}", key, e))?;
+        }
+        corrupt.push(key);
+    }
+
+    corrupt.sort();
+    Ok(corrupt)
+}
+
+// Password backend for a call: the storage it asks for, else the one picked
+// with set_password_storage
+async fn password_backend(
+    state: &State<'_, AppState>,
+    storage: Option<PasswordStorage>,
+) -> Result<Box<dyn PasswordBackend>, String> {
+    let store_path = store_dir(state)?;
+    let storage = match storage {
+        Some(storage) => storage,
+        None => password_store::saved_storage(&store_path).await,
+    };
+    Ok(password_store::backend(storage, store_path.join("secure")))
 }
 
-// Functions for secure password storage (simulating a keychain)
+// Functions for secure password storage, in the OS keychain or in files
+// encrypted at rest
 #[tauri::command]
 async fn set_password(
     state: State<'_, AppState>,
     service: String,
     username: String,
-    password: String,
+    password: Zeroizing<String>,
+    storage: Option<PasswordStorage>,
 ) -> Result<(), String> {
-    let state_guard = state.auth_store_path.lock().unwrap();
-    let store_path = state_guard.as_ref().ok_or("Store not initialized")?;
-
-    // Create a secure directory for storing passwords
-    let secure_dir = store_path.join("secure");
-    fs::create_dir_all(&secure_dir)
-        .map_err(|e| format!("Failed to create secure directory: {}", e))?;
-
-    // Create a file name from service and username
-    let file_name = format!("{}_{}.secure", service, username);
-    let file_path = secure_dir.join(file_name);
-
-    // Write password to file
-    // Note: In production, you should encrypt this data
-    fs::write(&file_path, password).map_err(|e| format!("Failed to write password file: {}", e))?;
-
-    Ok(())
+    password_backend(&state, storage)
+        .await?
+        .set_password(&service, &username, &password)
+        .await
 }
 
 #[tauri::command]
@@ -123,23 +655,14 @@ async fn get_password(
     state: State<'_, AppState>,
     service: String,
     username: String,
-) -> Result<String, String> {
-    let state_guard = state.auth_store_path.lock().unwrap();
-    let store_path = state_guard.as_ref().ok_or("Store not initialized")?;
-
-    let secure_dir = store_path.join("secure");
-    let file_name = format!("{}_{}.secure", service, username);
-    let file_path = secure_dir.join(file_name);
-
-    if !file_path.exists() {
-        return Err(format!("No password found for {}/{}", service, username));
-    }
-
-    // Read password from file
-    let password = fs::read_to_string(file_path)
-        .map_err(|e| format!("Failed to read password file: {}", e))?;
-
-    Ok(password)
+    storage: Option<PasswordStorage>,
+) -> Result<Zeroizing<String>, String> {
+    // Our copy is wiped once it has been sent back
+    password_backend(&state, storage)
+        .await?
+        .get_password(&service, &username)
+        .await?
+        .ok_or_else(|| format!("No password found for {}/{}", service, username))
 }
 
 #[tauri::command]
@@ -147,23 +670,38 @@ async fn delete_password(
     state: State<'_, AppState>,
     service: String,
     username: String,
+    storage: Option<PasswordStorage>,
 ) -> Result<(), String> {
-    let state_guard = state.auth_store_path.lock().unwrap();
-    let store_path = state_guard.as_ref().ok_or("Store not initialized")?;
-
-    let secure_dir = store_path.join("secure");
-    let file_name = format!("{}_{}.secure", service, username);
-    let file_path = secure_dir.join(file_name);
+    password_backend(&state, storage)
+        .await?
+        .delete_password(&service, &username)
+        .await
+}
 
-    if file_path.exists() {
-        fs::remove_file(file_path).map_err(|e| format!("Failed to delete password file: {}", e))?;
-    }
+// Where passwords go when a password command doesn't pass a storage
+#[tauri::command]
+async fn get_password_storage(state: State<'_, AppState>) -> Result<PasswordStorage, String> {
+    let store_path = store_dir(&state)?;
+    Ok(password_store::saved_storage(&store_path).await)
+}
 
-    Ok(())
+// Saved passwords aren't moved; with the keychain picked, ones still in
+// files are found there until they are saved again
+#[tauri::command]
+async fn set_password_storage(
+    state: State<'_, AppState>,
+    storage: PasswordStorage,
+) -> Result<(), String> {
+    let store_path = store_dir(&state)?;
+    password_store::save_storage(&store_path, storage).await
 }
 
 #[tauri::command]
-fn set_window_theme(window: tauri::Window, is_dark: bool) -> Result<(), String> {
+fn set_window_theme(
+    window: tauri::Window,
+    state: State<'_, AppState>,
+    is_dark: bool,
+) -> Result<(), String> {
     // Use a dedicated error handler for better error reporting
     fn handle_theme_error(e: impl std::fmt::Display) -> String {
         format!("Failed to set window theme: {}", e)
@@ -180,6 +718,13 @@ fn set_window_theme(window: tauri::Window, is_dark: bool) -> Result<(), String>
         .set_theme(Some(if is_dark { Theme::Dark } else { Theme::Light }))
         .map_err(handle_theme_error)?;
 
+    // An explicit choice stops the window from following the OS theme
+    *state.theme_mode.lock().unwrap() = if is_dark {
+        ThemeMode::Dark
+    } else {
+        ThemeMode::Light
+    };
+
     // Log successful theme change
     println!(
         "Window theme set successfully to {}",
@@ -189,6 +734,99 @@ fn set_window_theme(window: tauri::Window, is_dark: bool) -> Result<(), String>
     Ok(())
 }
 
+// Apply a theme mode (light, dark or system) to all open windows
+#[tauri::command]
+fn set_theme_mode(
+    app: tauri::AppHandle,
+    state: State<'_, AppState>,
+    mode: String,
+) -> Result<(), String> {
+    let theme_mode = ThemeMode::parse(&mode)?;
+    *state.theme_mode.lock().unwrap() = theme_mode;
+
+    println!("Setting theme mode: {}", theme_mode.as_str());
+
+    for (label, window) in app.webview_windows() {
+        window
+            .set_theme(theme_mode.window_theme())
+            .map_err(|e| format!("Failed to set theme for window {}: {}", label, e))?;
+
+        // Report the effective theme so the web UI can restyle in sync
+        let theme = match theme_mode.window_theme() {
+            Some(theme) => theme,
+            None => window.theme().map_err(|e| e.to_string())?,
+        };
+
+        emit_theme_changed(&window, theme_mode, theme);
+    }
+
+    Ok(())
+}
+
+// Tell the frontend a window gained or lost focus, e.g. to pause animations
+// or refresh data when the user comes back
+fn emit_focus_change(window: &tauri::Window, focused: bool) {
+    let event = if focused {
+        "window-focus"
+    } else {
+        "window-blur"
+    };
+    if let Err(e) = window.emit(event, serde_json::json!({ "label": window.label() })) {
+        println!("Failed to emit {}: {}", event, e);
+    }
+}
+
+// Re-apply the OS theme when it changes while in system mode
+fn handle_system_theme_change(window: &tauri::Window, theme: Theme) {
+    let theme_mode = *window.state::<AppState>().theme_mode.lock().unwrap();
+
+    if theme_mode != ThemeMode::System {
+        return;
+    }
+
+    println!(
+        "System theme changed to {} for window {}",
+        theme_name(theme),
+        window.label()
+    );
+
+    if let Err(e) = window.set_theme(None) {
+        println!("Failed to re-apply system theme: {}", e);
+    }
+
+    emit_theme_changed(window, theme_mode, theme);
+}
+
+fn emit_theme_changed<E: Emitter>(emitter: &E, theme_mode: ThemeMode, theme: Theme) {
+    let _ = emitter.emit(
+        "theme-changed",
+        serde_json::json!({
+            "mode": theme_mode.as_str(),
+            "theme": theme_name(theme)
+        }),
+    );
+}
+
+// Enable or disable menu items to match the current selection
+#[tauri::command]
+fn update_menu_state(state: State<'_, AppState>, selection: MenuSelection) -> Result<(), String> {
+    let menu_items = state.menu_items.lock().unwrap();
+
+    // No menu is built on platforms other than macOS
+    if menu_items.is_empty() {
+        return Ok(());
+    }
+
+    for (id, enabled) in selection.menu_item_states() {
+        if let Some(item) = menu_items.get(id) {
+            item.set_enabled(enabled)
+                .map_err(|e| format!("Failed to update menu item {}: {}", id, e))?;
+        }
+    }
+
+    Ok(())
+}
+
 #[tauri::command]
 async fn check_if_directory(path: String) -> Result<bool, String> {
     let path = std::path::Path::new(&path);
@@ -206,32 +844,60 @@ fn frontend_ready(window: tauri::Window) -> Result<(), String> {
 }
 
 #[tauri::command]
-fn resize_window(window: tauri::Window, session: bool) -> Result<(), String> {
+fn resize_window(
+    window: tauri::Window,
+    state: State<'_, AppState>,
+    session: bool,
+) -> Result<(), String> {
+    let was_session = std::mem::replace(&mut *state.session_layout.lock().unwrap(), session);
+
     if session {
         // Clear any existing size constraints first
         window
-            .set_min_size(Some(tauri::LogicalSize::new(800.0, 600.0)))
+            .set_min_size(Some(tauri::LogicalSize::new(SESSION_WIDTH, SESSION_HEIGHT)))
             .map_err(|e| e.to_string())?;
 
         window
             .set_max_size::<tauri::LogicalSize<f64>>(None)
             .map_err(|e| e.to_string())?;
 
-        // Set the initial size
-        window
-            .set_size(tauri::LogicalSize::new(800.0, 600.0))
-            .map_err(|e| e.to_string())?;
-
         // Make the window resizable
         window.set_resizable(true).map_err(|e| e.to_string())?;
+
+        // Keep the current geometry if the session layout is already active
+        if was_session {
+            return Ok(());
+        }
+
+        // Restore the last session geometry, or fall back to the default size
+        match load_window_geometry(&window) {
+            Some(geometry) => restore_window_geometry(&window, geometry)?,
+            None => {
+                window
+                    .set_size(tauri::LogicalSize::new(SESSION_WIDTH, SESSION_HEIGHT))
+                    .map_err(|e| e.to_string())?;
+            }
+        }
     } else {
+        // Remember the session geometry before shrinking to the login layout
+        if was_session {
+            save_window_geometry(&window);
+        }
+
+        // The login layout can't be fullscreen
+        if window.is_fullscreen().unwrap_or(false) {
+            window.set_fullscreen(false).map_err(|e| e.to_string())?;
+            state.pre_fullscreen_size.lock().unwrap().take();
+            sync_fullscreen_state(&window);
+        }
+
         // For login window - fixed size
         window
-            .set_size(tauri::LogicalSize::new(500.0, 600.0))
+            .set_size(tauri::LogicalSize::new(LOGIN_WIDTH, LOGIN_HEIGHT))
             .map_err(|e| e.to_string())?;
 
         window
-            .set_max_size(Some(tauri::LogicalSize::new(500.0, 600.0)))
+            .set_max_size(Some(tauri::LogicalSize::new(LOGIN_WIDTH, LOGIN_HEIGHT)))
             .map_err(|e| e.to_string())?;
 
         // Make the window non-resizable
@@ -241,22 +907,253 @@ fn resize_window(window: tauri::Window, session: bool) -> Result<(), String> {
     Ok(())
 }
 
+// Enter or leave fullscreen; only allowed in the session layout
+#[tauri::command]
+fn set_fullscreen(
+    window: tauri::Window,
+    state: State<'_, AppState>,
+    enabled: bool,
+) -> Result<(), String> {
+    if enabled && !*state.session_layout.lock().unwrap() {
+        return Err("Fullscreen is not available on the login window".to_string());
+    }
+
+    if enabled && !window.is_fullscreen().map_err(|e| e.to_string())? {
+        let scale_factor = window.scale_factor().map_err(|e| e.to_string())?;
+        let size = window
+            .inner_size()
+            .map_err(|e| e.to_string())?
+            .to_logical::<f64>(scale_factor);
+        *state.pre_fullscreen_size.lock().unwrap() = Some(size);
+    }
+
+    window.set_fullscreen(enabled).map_err(|e| e.to_string())?;
+    sync_fullscreen_state(&window);
+
+    Ok(())
+}
+
+// Emit fullscreen-changed if the window's fullscreen state differs from the
+// last one reported, restoring the previous size on exit. Also runs on resize
+// so OS-level toggles are picked up.
+fn sync_fullscreen_state(window: &tauri::Window) {
+    let fullscreen = match window.is_fullscreen() {
+        Ok(fullscreen) => fullscreen,
+        Err(e) => {
+            println!("Failed to read fullscreen state: {}", e);
+            return;
+        }
+    };
+
+    let state = window.state::<AppState>();
+    if std::mem::replace(&mut *state.fullscreen.lock().unwrap(), fullscreen) == fullscreen {
+        return;
+    }
+
+    if !fullscreen {
+        let previous_size = state.pre_fullscreen_size.lock().unwrap().take();
+        if let Some(size) = previous_size {
+            if let Err(e) = window.set_size(size) {
+                println!("Failed to restore window size: {}", e);
+            }
+        }
+    }
+
+    if let Err(e) = window.emit(
+        "fullscreen-changed",
+        serde_json::json!({ "label": window.label(), "fullscreen": fullscreen }),
+    ) {
+        println!("Failed to emit fullscreen-changed: {}", e);
+    }
+}
+
+// Clear the saved session geometry and return to the default size
+#[tauri::command]
+fn reset_window_size(window: tauri::Window, state: State<'_, AppState>) -> Result<(), String> {
+    let geometry_path = window_geometry_path(&window)?;
+    if geometry_path.exists() {
+        fs::remove_file(geometry_path)
+            .map_err(|e| format!("Failed to delete window geometry: {}", e))?;
+    }
+
+    if *state.session_layout.lock().unwrap() {
+        window
+            .set_size(tauri::LogicalSize::new(SESSION_WIDTH, SESSION_HEIGHT))
+            .map_err(|e| e.to_string())?;
+        window.center().map_err(|e| e.to_string())?;
+    }
+
+    Ok(())
+}
+
+fn window_geometry_path(window: &tauri::Window) -> Result<PathBuf, String> {
+    window
+        .path()
+        .app_config_dir()
+        .map(|dir| dir.join(WINDOW_GEOMETRY_FILE))
+        .map_err(|e| format!("Failed to get app config directory: {}", e))
+}
+
+// Read the saved session geometry, if any
+fn load_window_geometry(window: &tauri::Window) -> Option<WindowGeometry> {
+    let content = fs::read_to_string(window_geometry_path(window).ok()?).ok()?;
+    serde_json::from_str(&content).ok()
+}
+
+// Persist the current session geometry
+fn save_window_geometry(window: &tauri::Window) {
+    let file_path = match window_geometry_path(window) {
+        Ok(file_path) => file_path,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
+
+    let geometry = match current_window_geometry(window) {
+        Ok(geometry) => geometry,
+        Err(e) => {
+            println!("Failed to read window geometry: {}", e);
+            return;
+        }
+    };
+
+    match serde_json::to_string(&geometry) {
+        Ok(content) => {
+            let written = file_path
+                .parent()
+                .map_or(Ok(()), fs::create_dir_all)
+                .and_then(|_| fs::write(&file_path, content));
+            if let Err(e) = written {
+                println!("Failed to save window geometry: {}", e);
+            }
+        }
+        Err(e) => println!("Failed to serialize window geometry: {}", e),
+    }
+}
+
+fn current_window_geometry(window: &tauri::Window) -> Result<WindowGeometry, String> {
+    let scale_factor = window.scale_factor().map_err(|e| e.to_string())?;
+    let size: tauri::LogicalSize<f64> = window
+        .inner_size()
+        .map_err(|e| e.to_string())?
+        .to_logical(scale_factor);
+    let position: tauri::LogicalPosition<f64> = window
+        .outer_position()
+        .map_err(|e| e.to_string())?
+        .to_logical(scale_factor);
+
+    Ok(WindowGeometry {
+        width: size.width,
+        height: size.height,
+        x: position.x,
+        y: position.y,
+    })
+}
+
+// Apply a saved geometry, re-centering if it no longer lands on a visible display
+fn restore_window_geometry(window: &tauri::Window, geometry: WindowGeometry) -> Result<(), String> {
+    window
+        .set_size(tauri::LogicalSize::new(
+            geometry.width.max(SESSION_WIDTH),
+            geometry.height.max(SESSION_HEIGHT),
+        ))
+        .map_err(|e| e.to_string())?;
+
+    let monitors = window.available_monitors().map_err(|e| e.to_string())?;
+    if is_position_visible(&monitors, &geometry) {
+        window
+            .set_position(tauri::LogicalPosition::new(geometry.x, geometry.y))
+            .map_err(|e| e.to_string())?;
+    } else {
+        // The display it was on has been disconnected
+        println!("Saved window position is off-screen, centering window");
+        window.center().map_err(|e| e.to_string())?;
+    }
+
+    Ok(())
+}
+
+// Check that the window title bar area falls within one of the monitors
+fn is_position_visible(monitors: &[tauri::Monitor], geometry: &WindowGeometry) -> bool {
+    // Require enough of the window to be on screen to grab and move it
+    const VISIBLE_MARGIN: f64 = 50.0;
+
+    monitors.iter().any(|monitor| {
+        let scale_factor = monitor.scale_factor();
+        let position: tauri::LogicalPosition<f64> = monitor.position().to_logical(scale_factor);
+        let size: tauri::LogicalSize<f64> = monitor.size().to_logical(scale_factor);
+
+        geometry.x + VISIBLE_MARGIN >= position.x
+            && geometry.x + VISIBLE_MARGIN <= position.x + size.width
+            && geometry.y >= position.y
+            && geometry.y + VISIBLE_MARGIN <= position.y + size.height
+    })
+}
+
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
     tauri::Builder::default()
-        .plugin(tauri_plugin_window_state::Builder::new().build())
+        .plugin(
+            // Size and position are managed by resize_window per layout
+            tauri_plugin_window_state::Builder::new()
+                .with_state_flags(StateFlags::all() & !(StateFlags::SIZE | StateFlags::POSITION))
+                .build(),
+        )
         .plugin(tauri_plugin_fs::init())
         .plugin(tauri_plugin_opener::init())
         .plugin(tauri_plugin_dialog::init())
+        .plugin(tauri_plugin_notification::init())
         .manage(AppState::default())
+        .manage(folder_watcher::FolderWatcherState::default())
+        .on_window_event(|window, event| match event {
+            tauri::WindowEvent::ThemeChanged(theme) => {
+                handle_system_theme_change(window, *theme);
+            }
+            tauri::WindowEvent::Focused(focused) => {
+                emit_focus_change(window, *focused);
+            }
+            tauri::WindowEvent::Resized(_) => {
+                sync_fullscreen_state(window);
+            }
+            tauri::WindowEvent::CloseRequested { .. } => {
+                let state = window.state::<AppState>();
+                // Don't persist the fullscreen geometry as the session size
+                if *state.session_layout.lock().unwrap() && !*state.fullscreen.lock().unwrap() {
+                    save_window_geometry(window);
+                }
+            }
+            _ => {}
+        })
         .setup(|app| {
-            let transfer_manager = Arc::new(AsyncMutex::new(file_transfer::TransferQueue::new()));
-            app.manage(TransferManagerState(transfer_manager));
+            // Sessions start with the transfer settings saved last time
+            let store_path = app.path().app_data_dir()?.join("store");
+            // Large store writes cut short at the last exit are settled
+            // before anything reads the store
+            match tauri::async_runtime::block_on(recover_store_chunks(&store_path)) {
+                Ok(0) => {}
+                Ok(count) => println!("Recovered {} interrupted store writes", count),
+                Err(e) => println!("Failed to recover interrupted store writes: {}", e),
+            }
+            let settings =
+                tauri::async_runtime::block_on(file_transfer::load_transfer_settings(&store_path));
+            app.manage(TransferManagerState::with_settings(settings));
+            // Transfers left in the queue at the last exit come back paused
+            tauri::async_runtime::block_on(async {
+                let state = app.state::<TransferManagerState>();
+                file_transfer::restore_queue_snapshots(&store_path, &state).await;
+                file_transfer::remove_stale_checkpoints(&state).await;
+            });
+            file_transfer::start_watchdog(app.handle().clone());
 
             let window = app.get_webview_window("main").unwrap();
 
             window.hide().unwrap();
 
+            // The window starts hidden, so the tray is how users reach the app
+            #[cfg(desktop)]
+            tray::setup_tray(app)?;
+
             // window.eval("document.addEventListener('contextmenu', event => event.preventDefault(), false);").unwrap();
 
             #[cfg(debug_assertions)]
@@ -269,7 +1166,11 @@ pub fn run() {
             {
                 // Create and set the initial application menu (no file selected)
                 let menu = menu_builder::build_menu(app)?;
-                app.set_menu(menu)?;
+                app.set_menu(menu.clone())?;
+
+                // Keep handles so update_menu_state can toggle items later
+                *app.state::<AppState>().menu_items.lock().unwrap() =
+                    menu_builder::collect_menu_items(&menu)?;
 
                 // Set up event handlers for menu items
                 setup_menu_event_handlers(app);
@@ -280,50 +1181,128 @@ pub fn run() {
         .invoke_handler(tauri::generate_handler![
             frontend_ready,
             resize_window,
+            reset_window_size,
+            set_fullscreen,
+            update_menu_state,
             set_window_theme,
+            set_theme_mode,
             initialize_app,
+            get_app_paths,
+            open_data_directory,
             set_store_value,
             get_store_value,
             delete_store_value,
+            verify_store_integrity,
             set_password,
             get_password,
             delete_password,
+            get_password_storage,
+            set_password_storage,
             file_transfer::select_files,
             file_transfer::select_folders,
+            file_transfer::create_folder,
+            file_transfer::upload_bytes,
+            file_transfer::generate_local_thumbnail,
             file_transfer::cancel_transfer,
             file_transfer::cancel_all_transfers,
+            file_transfer::cancel_queued_transfers,
+            file_transfer::set_transfer_priority,
+            file_transfer::cancel_scan,
             file_transfer::pause_transfers,
+            file_transfer::pause_after_current,
             file_transfer::resume_transfers,
             file_transfer::get_queue_status,
+            file_transfer::get_transfer_status,
+            file_transfer::resync_transfer_state,
+            file_transfer::set_notifications_enabled,
+            file_transfer::set_max_queue_size,
+            file_transfer::set_per_file_timeout,
+            file_transfer::set_folder_prescan,
+            file_transfer::set_skip_temp_files,
+            file_transfer::would_upload,
+            file_transfer::set_perceptual_hashing,
+            file_transfer::set_connection_limit,
+            file_transfer::set_block_size_limit,
+            file_transfer::estimate_upload,
+            file_transfer::cancel_estimate,
+            file_transfer::hash_file,
+            file_transfer::cancel_hash,
+            file_transfer::restore_xattrs,
+            file_transfer::check_free_space,
+            file_transfer::set_max_history,
+            file_transfer::set_progress_smoothing,
+            file_transfer::clear_transfer_history,
+            file_transfer::get_transfer_metrics,
+            file_transfer::reset_transfer_metrics,
+            file_transfer::invalidate_folder_mapping,
+            file_transfer::retry_thumbnail,
+            file_transfer::thumbnail_url_response,
+            file_transfer::reupload_block,
+            file_transfer::download_file,
             file_transfer::handle_thumbnail_complete,
             file_transfer::upload_urls_response,
+            file_transfer::complete_multipart_response,
             file_transfer::folder_created_response,
             file_transfer::upload_error_response,
             file_transfer::folder_error_response,
             file_transfer::finalize_transfer_complete,
             file_transfer::check_transfer_health,
+            file_transfer::reset_processing_state,
             file_transfer::cleanup_stuck_transfers,
             file_transfer::repair_pending_folders,
+            file_transfer::set_watchdog,
             file_transfer::get_detailed_queue_status,
+            file_transfer::set_upload_backend,
+            file_transfer::get_transfer_settings,
+            file_transfer::set_transfer_settings,
+            file_transfer::set_staging_directory,
+            file_transfer::rekey_pending_transfers,
+            file_transfer::export_diagnostics,
+            folder_watcher::watch_folder,
+            folder_watcher::unwatch_folder,
+            folder_watcher::get_watchers,
+            proxy::set_proxy,
+            proxy::clear_proxy,
+            proxy::set_pinned_certificates,
+            proxy::clear_pinned_certificates,
+            trash_manager::move_to_trash,
+            trash_manager::trash_items_response,
+            trash_manager::trash_error_response,
             check_if_directory,
             generate_system_identifier,
+            refresh_system_identifier,
+            get_system_details,
             recovery_key::generate_recovery_phrase,
             recovery_key::derive_seed_from_password,
             recovery_key::generate_recovery_phrase,
             recovery_key::verify_recovery_phrase,
+            recovery_key::seed_from_recovery_phrase,
+            recovery_key::suggest_recovery_words,
+            recovery_key::split_seed_into_shares,
+            recovery_key::combine_seed_shares,
+            store_archive::export_store,
+            store_archive::import_store,
+            recovery_key::derive_keypair_from_seed,
         ])
-        .run(tauri::generate_context!())
-        .expect("error while running tauri application");
+        .build(tauri::generate_context!())
+        .expect("error while building tauri application")
+        .run(|app, event| {
+            // Stop transfers cleanly and save the queue before the process exits
+            if let tauri::RunEvent::ExitRequested { .. } = event {
+                tauri::async_runtime::block_on(file_transfer::shutdown(app));
+            }
+        });
 }
 
 // Enhanced cloud operations module
 mod menu_builder {
     use chrono::Datelike;
+    use std::collections::HashMap;
     use tauri::{
         Manager, Runtime,
         menu::{
-            AboutMetadata, Menu, MenuBuilder, MenuItemBuilder, PredefinedMenuItem, Submenu,
-            SubmenuBuilder,
+            AboutMetadata, Menu, MenuBuilder, MenuItem, MenuItemBuilder, MenuItemKind,
+            PredefinedMenuItem, Submenu, SubmenuBuilder,
         },
     };
 
@@ -369,6 +1348,25 @@ mod menu_builder {
         Ok(menu)
     }
 
+    // Collect the regular menu items of every submenu, keyed by id
+    pub fn collect_menu_items<R: Runtime>(
+        menu: &Menu<R>,
+    ) -> Result<HashMap<String, MenuItem<R>>, Box<dyn std::error::Error>> {
+        let mut items = HashMap::new();
+
+        for entry in menu.items()? {
+            if let MenuItemKind::Submenu(submenu) = entry {
+                for item in submenu.items()? {
+                    if let MenuItemKind::MenuItem(item) = item {
+                        items.insert(item.id().0.clone(), item);
+                    }
+                }
+            }
+        }
+
+        Ok(items)
+    }
+
     // App menu
     pub fn build_app_submenu<R: Runtime, M: Manager<R>>(
         manager: &M,
@@ -687,6 +1685,7 @@ fn setup_menu_event_handlers(app: &tauri::App) {
             "file_details" => {
                 let _ = app.emit("file-event", "details");
             }
+            // The frontend owns the selection and answers with move_to_trash
             "move_to_trash" => {
                 let _ = app.emit("file-event", "trash");
             }
@@ -698,6 +1697,7 @@ fn setup_menu_event_handlers(app: &tauri::App) {
             }
 
             // Folder menu
+            // The frontend asks for a name and answers with create_folder
             "new_folder" => {
                 let _ = app.emit("folder-event", "new-folder");
             }
@@ -707,6 +1707,7 @@ fn setup_menu_event_handlers(app: &tauri::App) {
             "rename_folder" => {
                 let _ = app.emit("folder-event", "rename-folder");
             }
+            // Handled the same way as move_to_trash above
             "move_folder_to_trash" => {
                 let _ = app.emit("folder-event", "trash-folder");
             }
@@ -763,3 +1764,207 @@ fn setup_menu_event_handlers(app: &tauri::App) {
         }
     });
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::Rng;
+
+    fn test_store() -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("cirrussync-store-{}", rand::rng().random::<u64>()));
+        fs::create_dir_all(&path).unwrap();
+        path
+    }
+
+    // A JSON string spanning a few chunks
+    fn large_value(fill: char) -> String {
+        format!(
+            "\"{}\"",
+            fill.to_string().repeat(LARGE_VALUE_THRESHOLD * 2 + 17)
+        )
+    }
+
+    #[tokio::test]
+    async fn large_values_round_trip_through_chunks() {
+        let store = test_store();
+        let value = large_value('a');
+        write_store_value(&store, "big", &value).await.unwrap();
+
+        assert!(chunks_complete(&chunk_dir(&store, "big")));
+        assert!(!store.join("big.json").exists());
+        assert_eq!(read_store_value(&store, "big").await.unwrap(), Some(value));
+
+        fs::remove_dir_all(&store).unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_small_value_replaces_a_chunked_one() {
+        let store = test_store();
+        write_store_value(&store, "big", &large_value('a'))
+            .await
+            .unwrap();
+        write_store_value(&store, "big", "\"small\"").await.unwrap();
+
+        assert!(!chunk_dir(&store, "big").exists());
+        assert_eq!(
+            read_store_value(&store, "big").await.unwrap().as_deref(),
+            Some("\"small\"")
+        );
+
+        fs::remove_dir_all(&store).unwrap();
+    }
+
+    #[tokio::test]
+    async fn interrupted_large_writes_recover_to_a_whole_value() {
+        let store = test_store();
+        let old = large_value('o');
+        let new = large_value('n');
+        // Writes `value` under `key`, then moves its chunks to `dir`
+        let chunks_at =
+            |key: &'static str, value: String, dir: fn(&std::path::Path, &str) -> PathBuf| {
+                let store = store.clone();
+                async move {
+                    write_store_value(&store, key, &value).await.unwrap();
+                    fs::rename(chunk_dir(&store, key), dir(&store, key)).unwrap();
+                }
+            };
+
+        // Stopped after moving the old chunks aside: the new ones go in
+        chunks_at("moved_aside", old.clone(), previous_chunk_dir).await;
+        chunks_at("moved_aside", new.clone(), pending_chunk_dir).await;
+
+        // Stopped before removing the old chunks: the new ones stay
+        write_store_value(&store, "swapped", &new).await.unwrap();
+        chunks_at("swapped_old", old.clone(), previous_chunk_dir).await;
+        fs::rename(
+            previous_chunk_dir(&store, "swapped_old"),
+            previous_chunk_dir(&store, "swapped"),
+        )
+        .unwrap();
+
+        // Stopped while writing new chunks over a small value: it stays
+        write_store_value(&store, "small", "\"small\"")
+            .await
+            .unwrap();
+        chunks_at("small_pending", new.clone(), pending_chunk_dir).await;
+        fs::remove_file(pending_chunk_dir(&store, "small_pending").join("manifest.json")).unwrap();
+        fs::rename(
+            pending_chunk_dir(&store, "small_pending"),
+            pending_chunk_dir(&store, "small"),
+        )
+        .unwrap();
+
+        // Only the old chunks survived, aside: they go back
+        chunks_at("only_old", old.clone(), previous_chunk_dir).await;
+
+        // Reads leave leftovers alone until recovery runs
+        assert_eq!(read_store_value(&store, "moved_aside").await.unwrap(), None);
+        assert!(pending_chunk_dir(&store, "moved_aside").exists());
+
+        assert_eq!(recover_store_chunks(&store).await.unwrap(), 2);
+        assert_eq!(
+            read_store_value(&store, "moved_aside").await.unwrap(),
+            Some(new.clone())
+        );
+        assert_eq!(
+            read_store_value(&store, "swapped").await.unwrap(),
+            Some(new)
+        );
+        assert_eq!(
+            read_store_value(&store, "small").await.unwrap().as_deref(),
+            Some("\"small\"")
+        );
+        assert_eq!(
+            read_store_value(&store, "only_old").await.unwrap(),
+            Some(old)
+        );
+
+        let leftovers: Vec<_> = fs::read_dir(&store)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .filter(|name| name.ends_with(".tmp") || name.ends_with(".old"))
+            .collect();
+        assert!(leftovers.is_empty(), "left behind: {:?}", leftovers);
+
+        fs::remove_dir_all(&store).unwrap();
+    }
+
+    #[tokio::test]
+    async fn an_intact_store_has_no_corrupt_values() {
+        let store = test_store();
+        write_store_value(&store, "small", "{\"theme\":\"dark\"}")
+            .await
+            .unwrap();
+        write_store_value(&store, "big", &large_value('a'))
+            .await
+            .unwrap();
+        fs::create_dir_all(store.join("secure")).unwrap();
+        fs::write(store.join("secure").join("account.secure"), b"not json").unwrap();
+
+        assert!(find_corrupt_values(&store, true).await.unwrap().is_empty());
+        assert!(!store.join(CORRUPT_DIR).exists());
+
+        fs::remove_dir_all(&store).unwrap();
+    }
+
+    #[tokio::test]
+    async fn corrupt_and_truncated_values_are_quarantined() {
+        let store = test_store();
+        write_store_value(&store, "intact", "[1, 2]").await.unwrap();
+        write_store_value(&store, "broken", "{\"theme\":")
+            .await
+            .unwrap();
+        write_store_value(&store, "truncated", &large_value('t'))
+            .await
+            .unwrap();
+        let last_chunk = chunk_dir(&store, "truncated").join("2");
+        let length = fs::metadata(&last_chunk).unwrap().len();
+        fs::OpenOptions::new()
+            .write(true)
+            .open(&last_chunk)
+            .unwrap()
+            .set_len(length - 1)
+            .unwrap();
+
+        // Reported but left in place without quarantine
+        assert_eq!(
+            find_corrupt_values(&store, false).await.unwrap(),
+            vec!["broken", "truncated"]
+        );
+        assert!(store.join("broken.json").exists());
+
+        assert_eq!(
+            find_corrupt_values(&store, true).await.unwrap(),
+            vec!["broken", "truncated"]
+        );
+        assert!(store.join(CORRUPT_DIR).join("broken.json").exists());
+        assert!(store.join(CORRUPT_DIR).join("truncated.chunks").is_dir());
+        assert_eq!(read_store_value(&store, "broken").await.unwrap(), None);
+        assert_eq!(read_store_value(&store, "truncated").await.unwrap(), None);
+        assert_eq!(
+            read_store_value(&store, "intact").await.unwrap().as_deref(),
+            Some("[1, 2]")
+        );
+
+        fs::remove_dir_all(&store).unwrap();
+    }
+
+    #[tokio::test]
+    async fn values_missing_a_chunk_are_corrupt() {
+        let store = test_store();
+        write_store_value(&store, "big", &large_value('a'))
+            .await
+            .unwrap();
+        fs::remove_file(chunk_dir(&store, "big").join("1")).unwrap();
+
+        assert!(read_store_value(&store, "big").await.is_err());
+        assert_eq!(
+            find_corrupt_values(&store, true).await.unwrap(),
+            vec!["big"]
+        );
+        assert_eq!(read_store_value(&store, "big").await.unwrap(), None);
+
+        fs::remove_dir_all(&store).unwrap();
+    }
+}
@@ -0,0 +1,577 @@
+// src/password_store.rs
+
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+
+use aes_gcm::aead::Aead;
+use aes_gcm::{Aes256Gcm, Key, KeyInit, Nonce};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use zeroize::Zeroizing;
+
+// File in the secure directory holding the key passwords are encrypted with.
+// It lives beside them, so an encrypted store export carries it along.
+const KEY_FILE: &str = "passwords.key";
+
+// Leads every encrypted password file. Plaintext files written by older
+// versions never start with a NUL byte.
+const ENCRYPTED_MAGIC: &[u8] = b"\0cspw1";
+
+// Store value holding the PasswordStorage picked with set_password_storage
+const PASSWORD_STORAGE_KEY: &str = "password_storage";
+
+type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Where passwords are kept when a command doesn't say
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PasswordStorage {
+    #[default]
+    File, // Encrypted files in the store's secure directory
+    Keychain, // The OS keychain, falling back to files when it can't be used
+}
+
+/// A place passwords are saved, keyed by service and username. Failures
+/// come back as display strings whichever store they came from.
+pub trait PasswordBackend: Send + Sync {
+    fn set_password<'a>(
+        &'a self,
+        service: &'a str,
+        username: &'a str,
+        password: &'a str,
+    ) -> BoxFuture<'a, Result<(), String>>;
+
+    /// None when nothing is saved for the service and username
+    fn get_password<'a>(
+        &'a self,
+        service: &'a str,
+        username: &'a str,
+    ) -> BoxFuture<'a, Result<Option<Zeroizing<String>>, String>>;
+
+    /// Nothing to delete is not an error
+    fn delete_password<'a>(
+        &'a self,
+        service: &'a str,
+        username: &'a str,
+    ) -> BoxFuture<'a, Result<(), String>>;
+}
+
+/// The backend for a storage choice, keeping its files in `secure_dir`
+pub fn backend(storage: PasswordStorage, secure_dir: PathBuf) -> Box<dyn PasswordBackend> {
+    let file = FileBackend { secure_dir };
+    match storage {
+        PasswordStorage::File => Box::new(file),
+        PasswordStorage::Keychain => Box::new(Fallback {
+            primary: Box::new(KeychainBackend),
+            file,
+        }),
+    }
+}
+
+/// The storage picked with set_password_storage, or the default
+pub async fn saved_storage(store_path: &Path) -> PasswordStorage {
+    match crate::read_store_value(store_path, PASSWORD_STORAGE_KEY).await {
+        Ok(Some(saved)) => serde_json::from_str(&saved).unwrap_or_else(|e| {
+            println!("Ignoring saved password storage: {}", e);
+            PasswordStorage::default()
+        }),
+        Ok(None) => PasswordStorage::default(),
+        Err(e) => {
+            println!("Failed to read password storage: {}", e);
+            PasswordStorage::default()
+        }
+    }
+}
+
+pub async fn save_storage(store_path: &Path, storage: PasswordStorage) -> Result<(), String> {
+    let value = serde_json::to_string(&storage).map_err(|e| e.to_string())?;
+    crate::write_store_value(store_path, PASSWORD_STORAGE_KEY, &value).await
+}
+
+/// Encrypted password files, one per service and username
+pub struct FileBackend {
+    secure_dir: PathBuf,
+}
+
+impl PasswordBackend for FileBackend {
+    fn set_password<'a>(
+        &'a self,
+        service: &'a str,
+        username: &'a str,
+        password: &'a str,
+    ) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(write_password(
+            &self.secure_dir,
+            service,
+            username,
+            password,
+        ))
+    }
+
+    fn get_password<'a>(
+        &'a self,
+        service: &'a str,
+        username: &'a str,
+    ) -> BoxFuture<'a, Result<Option<Zeroizing<String>>, String>> {
+        Box::pin(read_password(&self.secure_dir, service, username))
+    }
+
+    fn delete_password<'a>(
+        &'a self,
+        service: &'a str,
+        username: &'a str,
+    ) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(async move {
+            let path = password_path(&self.secure_dir, service, username);
+            if path.exists() {
+                tokio::fs::remove_file(path)
+                    .await
+                    .map_err(|e| format!("Failed to delete password file: {}", e))?;
+            }
+            Ok(())
+        })
+    }
+}
+
+/// The macOS Keychain, Windows Credential Manager or the Secret Service on
+/// Linux. Their calls block, so they run off the async runtime.
+pub struct KeychainBackend;
+
+impl KeychainBackend {
+    async fn with_entry<T: Send + 'static>(
+        service: &str,
+        username: &str,
+        call: impl FnOnce(&keyring::Entry) -> keyring::Result<T> + Send + 'static,
+    ) -> Result<T, String> {
+        let (service, username) = (service.to_string(), username.to_string());
+        tokio::task::spawn_blocking(move || call(&keyring::Entry::new(&service, &username)?))
+            .await
+            .map_err(|e| format!("Task failed: {:?}", e))?
+            .map_err(|e| format!("Keychain error: {}", e))
+    }
+}
+
+impl PasswordBackend for KeychainBackend {
+    fn set_password<'a>(
+        &'a self,
+        service: &'a str,
+        username: &'a str,
+        password: &'a str,
+    ) -> BoxFuture<'a, Result<(), String>> {
+        let password = Zeroizing::new(password.to_string());
+        Box::pin(Self::with_entry(service, username, move |entry| {
+            entry.set_password(&password)
+        }))
+    }
+
+    fn get_password<'a>(
+        &'a self,
+        service: &'a str,
+        username: &'a str,
+    ) -> BoxFuture<'a, Result<Option<Zeroizing<String>>, String>> {
+        Box::pin(Self::with_entry(service, username, |entry| {
+            match entry.get_password() {
+                Ok(password) => Ok(Some(Zeroizing::new(password))),
+                Err(keyring::Error::NoEntry) => Ok(None),
+                Err(e) => Err(e),
+            }
+        }))
+    }
+
+    fn delete_password<'a>(
+        &'a self,
+        service: &'a str,
+        username: &'a str,
+    ) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(Self::with_entry(service, username, |entry| {
+            match entry.delete_credential() {
+                Err(keyring::Error::NoEntry) => Ok(()),
+                result => result,
+            }
+        }))
+    }
+}
+
+/// A backend that falls back to the password files whenever it fails, and
+/// still finds passwords saved there before it was picked
+struct Fallback {
+    primary: Box<dyn PasswordBackend>,
+    file: FileBackend,
+}
+
+impl PasswordBackend for Fallback {
+    fn set_password<'a>(
+        &'a self,
+        service: &'a str,
+        username: &'a str,
+        password: &'a str,
+    ) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(async move {
+            match self.primary.set_password(service, username, password).await {
+                // Don't leave an older copy behind in a file
+                Ok(()) => self.file.delete_password(service, username).await,
+                Err(e) => {
+                    println!("{}; saving the password to a file instead", e);
+                    self.file.set_password(service, username, password).await
+                }
+            }
+        })
+    }
+
+    fn get_password<'a>(
+        &'a self,
+        service: &'a str,
+        username: &'a str,
+    ) -> BoxFuture<'a, Result<Option<Zeroizing<String>>, String>> {
+        Box::pin(async move {
+            match self.primary.get_password(service, username).await {
+                Ok(Some(password)) => return Ok(Some(password)),
+                Ok(None) => {}
+                Err(e) => println!("{}; reading the password from a file instead", e),
+            }
+            self.file.get_password(service, username).await
+        })
+    }
+
+    fn delete_password<'a>(
+        &'a self,
+        service: &'a str,
+        username: &'a str,
+    ) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(async move {
+            if let Err(e) = self.primary.delete_password(service, username).await {
+                println!("{}", e);
+            }
+            self.file.delete_password(service, username).await
+        })
+    }
+}
+
+/// File a password for `service`/`username` is kept in
+fn password_path(secure_dir: &Path, service: &str, username: &str) -> PathBuf {
+    secure_dir.join(format!("{}_{}.secure", service, username))
+}
+
+/// The cipher passwords are encrypted with, creating its random key the
+/// first time one is needed
+async fn password_cipher(secure_dir: &Path) -> Result<Aes256Gcm, String> {
+    let key_path = secure_dir.join(KEY_FILE);
+
+    let key = match tokio::fs::read(&key_path).await {
+        Ok(key) => Zeroizing::new(key),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let mut key = Zeroizing::new(vec![0u8; 32]);
+            rand::rng().fill(key.as_mut_slice());
+
+            // Written whole to a file of its own, then linked into place, so
+            // the key file never exists empty or half written. The link fails
+            // if it already exists, so two first writes can't each keep a
+            // different key.
+            let temp_path =
+                key_path.with_extension(format!("{:x}.tmp", rand::rng().random::<u64>()));
+            let linked = async {
+                write_private_file(&temp_path, &key).await?;
+                tokio::fs::hard_link(&temp_path, &key_path).await
+            }
+            .await;
+            let _ = tokio::fs::remove_file(&temp_path).await;
+
+            match linked {
+                Ok(()) => key,
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => Zeroizing::new(
+                    tokio::fs::read(&key_path)
+                        .await
+                        .map_err(|e| format!("Failed to read password key: {}", e))?,
+                ),
+                Err(e) => return Err(format!("Failed to create password key: {}", e)),
+            }
+        }
+        Err(e) => return Err(format!("Failed to read password key: {}", e)),
+    };
+
+    if key.len() != 32 {
+        return Err("Password key is damaged".to_string());
+    }
+    Ok(Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key)))
+}
+
+/// Writes a new file readable by the owner alone, failing if it exists
+async fn write_private_file(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let mut options = tokio::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+
+    let mut file = options.open(path).await?;
+    file.write_all(contents).await?;
+    file.sync_all().await
+}
+
+/// Encrypts a password under a random nonce, stored right after the magic
+fn seal(cipher: &Aes256Gcm, password: &str) -> Result<Vec<u8>, String> {
+    let mut nonce = [0u8; 12];
+    rand::rng().fill(&mut nonce);
+    let ciphertext = cipher
+        .encrypt(Nonce::from_slice(&nonce), password.as_bytes())
+        .map_err(|e| format!("Failed to encrypt password: {}", e))?;
+
+    let mut contents = ENCRYPTED_MAGIC.to_vec();
+    contents.extend_from_slice(&nonce);
+    contents.extend_from_slice(&ciphertext);
+    Ok(contents)
+}
+
+/// Decrypts what seal wrote
+fn open(cipher: &Aes256Gcm, sealed: &[u8]) -> Result<Zeroizing<String>, String> {
+    if sealed.len() < 12 {
+        return Err("Password file is damaged".to_string());
+    }
+    let (nonce, ciphertext) = sealed.split_at(12);
+    let password = Zeroizing::new(
+        cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| "Password file is damaged or was saved under another key".to_string())?,
+    );
+
+    String::from_utf8(password.to_vec())
+        .map(Zeroizing::new)
+        .map_err(|_| "Password file is damaged".to_string())
+}
+
+/// Writes a file through a temporary copy, so a crash can't leave half of it.
+/// Each write gets its own copy, so concurrent writes to one file can't
+/// interleave; the last rename wins.
+async fn write_atomically(path: &Path, contents: &[u8]) -> Result<(), String> {
+    let temp_path = path.with_extension(format!("secure.{:x}.tmp", rand::rng().random::<u64>()));
+    let written = async {
+        write_private_file(&temp_path, contents).await?;
+        tokio::fs::rename(&temp_path, path).await
+    }
+    .await;
+    if written.is_err() {
+        let _ = tokio::fs::remove_file(&temp_path).await;
+    }
+    written.map_err(|e| format!("Failed to write password file: {}", e))
+}
+
+/// Encrypts and saves a password, replacing any saved before
+async fn write_password(
+    secure_dir: &Path,
+    service: &str,
+    username: &str,
+    password: &str,
+) -> Result<(), String> {
+    tokio::fs::create_dir_all(secure_dir)
+        .await
+        .map_err(|e| format!("Failed to create secure directory: {}", e))?;
+
+    let cipher = password_cipher(secure_dir).await?;
+    let contents = seal(&cipher, password)?;
+    write_atomically(&password_path(secure_dir, service, username), &contents).await
+}
+
+/// Reads a saved password, or None when there is none. Plaintext files from
+/// before passwords were encrypted are still read, and encrypted in place on
+/// the way; reading them can go once a release has migrated them.
+async fn read_password(
+    secure_dir: &Path,
+    service: &str,
+    username: &str,
+) -> Result<Option<Zeroizing<String>>, String> {
+    let path = password_path(secure_dir, service, username);
+    let contents = match tokio::fs::read(&path).await {
+        Ok(contents) => Zeroizing::new(contents),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(format!("Failed to read password file: {}", e)),
+    };
+
+    let cipher = password_cipher(secure_dir).await?;
+    if let Some(sealed) = contents.strip_prefix(ENCRYPTED_MAGIC) {
+        return open(&cipher, sealed).map(Some);
+    }
+
+    let password = String::from_utf8(contents.to_vec())
+        .map(Zeroizing::new)
+        .map_err(|_| "Password file is damaged".to_string())?;
+
+    // The password is still returned if the file can't be rewritten
+    match seal(&cipher, &password) {
+        Ok(sealed) => match write_atomically(&path, &sealed).await {
+            Ok(()) => println!(
+                "Encrypted plaintext password file for {}/{}",
+                service, username
+            ),
+            Err(e) => println!("{}", e),
+        },
+        Err(e) => println!("{}", e),
+    }
+
+    Ok(Some(password))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn passwords_are_encrypted_and_plaintext_ones_migrated() {
+        let secure_dir =
+            std::env::temp_dir().join(format!("cirrussync-secure-{}", rand::rng().random::<u64>()));
+
+        write_password(&secure_dir, "app", "alice", "hunter2")
+            .await
+            .unwrap();
+        let written = std::fs::read(password_path(&secure_dir, "app", "alice")).unwrap();
+        assert!(written.starts_with(ENCRYPTED_MAGIC));
+        assert!(!written.windows(7).any(|window| window == b"hunter2"));
+        let password = read_password(&secure_dir, "app", "alice").await.unwrap();
+        assert_eq!(password.unwrap().as_str(), "hunter2");
+
+        // A file from before encryption is read, then rewritten encrypted
+        let legacy_path = password_path(&secure_dir, "app", "bob");
+        std::fs::write(&legacy_path, "letmein").unwrap();
+        let password = read_password(&secure_dir, "app", "bob").await.unwrap();
+        assert_eq!(password.unwrap().as_str(), "letmein");
+        assert!(
+            std::fs::read(&legacy_path)
+                .unwrap()
+                .starts_with(ENCRYPTED_MAGIC)
+        );
+        let password = read_password(&secure_dir, "app", "bob").await.unwrap();
+        assert_eq!(password.unwrap().as_str(), "letmein");
+
+        assert!(
+            read_password(&secure_dir, "app", "carol")
+                .await
+                .unwrap()
+                .is_none()
+        );
+
+        std::fs::remove_dir_all(&secure_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn concurrent_writes_to_one_password_leave_a_whole_one() {
+        let secure_dir =
+            std::env::temp_dir().join(format!("cirrussync-secure-{}", rand::rng().random::<u64>()));
+        write_password(&secure_dir, "app", "alice", "initial")
+            .await
+            .unwrap();
+
+        let passwords: Vec<String> = (0..8).map(|n| format!("password-{}", n)).collect();
+        let writes = passwords
+            .iter()
+            .map(|password| write_password(&secure_dir, "app", "alice", password));
+        for written in futures_util::future::join_all(writes).await {
+            written.unwrap();
+        }
+
+        let password = read_password(&secure_dir, "app", "alice").await.unwrap();
+        assert!(passwords.contains(&password.unwrap()));
+        let leftovers: Vec<_> = std::fs::read_dir(&secure_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .filter(|name| name.ends_with(".tmp"))
+            .collect();
+        assert!(leftovers.is_empty(), "left behind: {:?}", leftovers);
+
+        std::fs::remove_dir_all(&secure_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn racing_first_writes_agree_on_one_key() {
+        let secure_dir =
+            std::env::temp_dir().join(format!("cirrussync-secure-{}", rand::rng().random::<u64>()));
+        std::fs::create_dir_all(&secure_dir).unwrap();
+
+        let ciphers =
+            futures_util::future::join_all((0..8).map(|_| password_cipher(&secure_dir))).await;
+        let sealed = seal(ciphers[0].as_ref().unwrap(), "hunter2").unwrap();
+        let sealed = sealed.strip_prefix(ENCRYPTED_MAGIC).unwrap();
+        for cipher in &ciphers {
+            let password = open(cipher.as_ref().unwrap(), sealed).unwrap();
+            assert_eq!(password.as_str(), "hunter2");
+        }
+
+        // Only the finished key is left, and only its owner can read it
+        let names: Vec<_> = std::fs::read_dir(&secure_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(names, [KEY_FILE]);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(secure_dir.join(KEY_FILE))
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        std::fs::remove_dir_all(&secure_dir).unwrap();
+    }
+
+    /// A keychain that can't be reached, like one without a Secret Service
+    struct Unavailable;
+
+    impl PasswordBackend for Unavailable {
+        fn set_password<'a>(
+            &'a self,
+            _service: &'a str,
+            _username: &'a str,
+            _password: &'a str,
+        ) -> BoxFuture<'a, Result<(), String>> {
+            Box::pin(async { Err("Keychain error: unavailable".to_string()) })
+        }
+
+        fn get_password<'a>(
+            &'a self,
+            _service: &'a str,
+            _username: &'a str,
+        ) -> BoxFuture<'a, Result<Option<Zeroizing<String>>, String>> {
+            Box::pin(async { Err("Keychain error: unavailable".to_string()) })
+        }
+
+        fn delete_password<'a>(
+            &'a self,
+            _service: &'a str,
+            _username: &'a str,
+        ) -> BoxFuture<'a, Result<(), String>> {
+            Box::pin(async { Err("Keychain error: unavailable".to_string()) })
+        }
+    }
+
+    #[tokio::test]
+    async fn an_unavailable_keychain_falls_back_to_files() {
+        let secure_dir =
+            std::env::temp_dir().join(format!("cirrussync-secure-{}", rand::rng().random::<u64>()));
+        let backend = Fallback {
+            primary: Box::new(Unavailable),
+            file: FileBackend {
+                secure_dir: secure_dir.clone(),
+            },
+        };
+
+        backend
+            .set_password("app", "alice", "hunter2")
+            .await
+            .unwrap();
+        assert!(password_path(&secure_dir, "app", "alice").exists());
+        let password = backend.get_password("app", "alice").await.unwrap();
+        assert_eq!(password.unwrap().as_str(), "hunter2");
+
+        backend.delete_password("app", "alice").await.unwrap();
+        assert!(
+            backend
+                .get_password("app", "alice")
+                .await
+                .unwrap()
+                .is_none()
+        );
+
+        std::fs::remove_dir_all(&secure_dir).unwrap();
+    }
+}
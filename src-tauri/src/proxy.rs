@@ -0,0 +1,212 @@
+// src/proxy.rs
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use lazy_static::lazy_static;
+use tauri::command;
+use tokio::sync::Mutex;
+
+/// Proxy set explicitly by the user
+#[derive(Clone)]
+struct ProxySettings {
+    url: String,
+    username: Option<String>,
+    password: Option<String>,
+}
+
+/// Certificates the storage endpoint must chain to
+#[derive(Clone)]
+struct PinnedCertificates {
+    pems: Vec<String>,
+    strict: bool, // Trust only these, not the system or built-in roots
+}
+
+lazy_static! {
+    static ref PROXY_SETTINGS: Mutex<Option<ProxySettings>> = Mutex::new(None);
+    static ref PINNED_CERTIFICATES: Mutex<Option<PinnedCertificates>> = Mutex::new(None);
+    static ref UPLOAD_CLIENT: Mutex<Option<reqwest::Client>> = Mutex::new(None);
+}
+
+// Set while certificates are pinned, so failed requests can be told apart
+// without taking the lock
+static PINNING_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Build an upload client using the given proxy and pinned certificates.
+/// Without a proxy, reqwest falls back to the HTTP_PROXY/HTTPS_PROXY/ALL_PROXY
+/// environment variables.
+fn build_client(
+    settings: Option<&ProxySettings>,
+    pins: Option<&PinnedCertificates>,
+) -> Result<reqwest::Client, String> {
+    let mut builder = reqwest::Client::builder();
+
+    if let Some(pins) = pins {
+        let certificates = pins
+            .pems
+            .iter()
+            .map(|pem| reqwest::Certificate::from_pem(pem.as_bytes()))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("Invalid pinned certificate: {}", e))?;
+
+        builder = if pins.strict {
+            builder.tls_certs_only(certificates)
+        } else {
+            builder.tls_certs_merge(certificates)
+        };
+    }
+
+    if let Some(settings) = settings {
+        let mut proxy =
+            reqwest::Proxy::all(&settings.url).map_err(|e| format!("Invalid proxy URL: {}", e))?;
+
+        if let Some(username) = &settings.username {
+            proxy = proxy.basic_auth(username, settings.password.as_deref().unwrap_or_default());
+        }
+
+        builder = builder.proxy(proxy);
+    }
+
+    builder
+        .build()
+        .map_err(|e| format!("Failed to build upload client: {}", e))
+}
+
+/// The client shared by block and thumbnail uploads. Timeouts are set per
+/// request since blocks and thumbnails need different limits.
+pub async fn upload_client() -> reqwest::Client {
+    let mut client = UPLOAD_CLIENT.lock().await;
+    if let Some(client) = client.as_ref() {
+        return client.clone();
+    }
+
+    let settings = PROXY_SETTINGS.lock().await.clone();
+    let pins = PINNED_CERTIFICATES.lock().await.clone();
+    // Pinned certificates were checked when they were set, so only the proxy
+    // can fail here; never fall back to a client without the pins
+    let new_client = build_client(settings.as_ref(), pins.as_ref())
+        .or_else(|e| {
+            println!("{}, uploading without a proxy", e);
+            build_client(None, pins.as_ref())
+        })
+        .unwrap_or_else(|e| {
+            println!("{}", e);
+            reqwest::Client::new()
+        });
+
+    *client = Some(new_client.clone());
+    new_client
+}
+
+/// Route uploads through an HTTP(S) or SOCKS5 proxy, e.g.
+/// `http://proxy.example.com:8080` or `socks5://127.0.0.1:1080`
+#[command]
+pub async fn set_proxy(
+    url: String,
+    username: Option<String>,
+    password: Option<String>,
+) -> Result<(), String> {
+    let url = url.trim().to_string();
+    let scheme = url.split("://").next().unwrap_or_default().to_lowercase();
+    if !["http", "https", "socks5", "socks5h"].contains(&scheme.as_str()) {
+        return Err(format!(
+            "Unsupported proxy scheme: {} (expected http, https or socks5)",
+            scheme
+        ));
+    }
+
+    let settings = ProxySettings {
+        url,
+        username: username.filter(|username| !username.is_empty()),
+        password,
+    };
+
+    // Build the client first so a bad URL leaves the current settings alone
+    let pins = PINNED_CERTIFICATES.lock().await.clone();
+    let client = build_client(Some(&settings), pins.as_ref())?;
+
+    println!("Using upload proxy: {}", settings.url);
+
+    *PROXY_SETTINGS.lock().await = Some(settings);
+    *UPLOAD_CLIENT.lock().await = Some(client);
+
+    Ok(())
+}
+
+/// Stop using the proxy from set_proxy and go back to the environment defaults
+#[command]
+pub async fn clear_proxy() -> Result<(), String> {
+    *PROXY_SETTINGS.lock().await = None;
+
+    // Rebuilt on the next upload
+    *UPLOAD_CLIENT.lock().await = None;
+
+    println!("Upload proxy cleared");
+
+    Ok(())
+}
+
+/// Pin uploads to the given PEM certificates, each a CA or the endpoint's own
+/// certificate. They are trusted alongside the system roots, or instead of
+/// them when `strict` is set.
+#[command]
+pub async fn set_pinned_certificates(
+    pem_list: Vec<String>,
+    strict: Option<bool>,
+) -> Result<(), String> {
+    if pem_list.is_empty() {
+        return Err("At least one certificate is required".to_string());
+    }
+
+    let pins = PinnedCertificates {
+        pems: pem_list,
+        strict: strict.unwrap_or(false),
+    };
+
+    // Build the client first so a bad certificate leaves the current pins alone
+    let settings = PROXY_SETTINGS.lock().await.clone();
+    let client = build_client(settings.as_ref(), Some(&pins))?;
+
+    println!(
+        "Pinned {} upload certificates{}",
+        pins.pems.len(),
+        if pins.strict { " (strict)" } else { "" }
+    );
+
+    *PINNED_CERTIFICATES.lock().await = Some(pins);
+    *UPLOAD_CLIENT.lock().await = Some(client);
+    PINNING_ENABLED.store(true, Ordering::Relaxed);
+
+    Ok(())
+}
+
+/// Stop pinning certificates and go back to the system trust store
+#[command]
+pub async fn clear_pinned_certificates() -> Result<(), String> {
+    *PINNED_CERTIFICATES.lock().await = None;
+    PINNING_ENABLED.store(false, Ordering::Relaxed);
+
+    // Rebuilt on the next upload
+    *UPLOAD_CLIENT.lock().await = None;
+
+    println!("Pinned upload certificates cleared");
+
+    Ok(())
+}
+
+/// Whether a request failed certificate verification while certificates are
+/// pinned, as opposed to an ordinary network error
+pub fn is_pin_failure(error: &reqwest::Error) -> bool {
+    if !PINNING_ENABLED.load(Ordering::Relaxed) || !error.is_connect() {
+        return false;
+    }
+
+    let mut source = std::error::Error::source(error);
+    while let Some(inner) = source {
+        if inner.to_string().to_lowercase().contains("certificate") {
+            return true;
+        }
+        source = inner.source();
+    }
+
+    false
+}
@@ -1,26 +1,351 @@
 use argon2::{self, Config, Variant, Version};
 use bip39::{Language, Mnemonic};
+use ed25519_dalek::SigningKey;
 use hex;
+use hkdf::Hkdf;
 use rand::Rng;
 use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use sharks::{Share, Sharks};
+use std::borrow::Cow;
+use std::collections::HashSet;
 use tokio::task;
+use x25519_dalek::{PublicKey, StaticSecret};
+use zeroize::Zeroizing;
 
-// Simplified result structs - only return what's needed
+// Simplified result structs - only return what's needed. Secrets are held in
+// Zeroizing so they are wiped from memory once the result is dropped.
 #[derive(Serialize, Deserialize, Debug)]
 pub struct SeedResult {
-    seed: String,
+    seed: Zeroizing<String>,
+    // Salt used, whether supplied or generated. Together with the password and
+    // params it reproduces the seed, so callers must keep it.
+    salt_hex: String,
+    // Cost parameters used, so the seed can be re-derived identically later
+    params: Argon2Params,
+}
+
+// Argon2id cost parameters for password-based seed derivation
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Argon2Params {
+    mem_cost: u32,  // Memory in KiB
+    time_cost: u32, // Iterations
+    lanes: u32,     // Parallelism factor
+}
+
+impl Default for Argon2Params {
+    fn default() -> Self {
+        Self {
+            mem_cost: 32768, // 32 MB memory
+            time_cost: 3,    // 3 iterations (reduced from 10)
+            lanes: 2,        // Parallelism factor (reduced from 4)
+        }
+    }
+}
+
+// Bounds that keep derivation from being trivially weak or exhausting the device
+const MIN_MEM_COST: u32 = 8192; // 8 MB
+const MAX_MEM_COST: u32 = 1_048_576; // 1 GB
+const MAX_TIME_COST: u32 = 20;
+const MAX_LANES: u32 = 16;
+
+impl Argon2Params {
+    /// Build parameters from optional overrides, falling back to the defaults
+    fn from_options(
+        mem_cost: Option<u32>,
+        time_cost: Option<u32>,
+        lanes: Option<u32>,
+    ) -> Result<Self, String> {
+        let defaults = Self::default();
+        let params = Self {
+            mem_cost: mem_cost.unwrap_or(defaults.mem_cost),
+            time_cost: time_cost.unwrap_or(defaults.time_cost),
+            lanes: lanes.unwrap_or(defaults.lanes),
+        };
+        params.validate()?;
+        Ok(params)
+    }
+
+    pub(crate) fn validate(&self) -> Result<(), String> {
+        if !(MIN_MEM_COST..=MAX_MEM_COST).contains(&self.mem_cost) {
+            return Err(format!(
+                "mem_cost must be between {} and {} KiB",
+                MIN_MEM_COST, MAX_MEM_COST
+            ));
+        }
+        if !(1..=MAX_TIME_COST).contains(&self.time_cost) {
+            return Err(format!("time_cost must be between 1 and {}", MAX_TIME_COST));
+        }
+        if !(1..=MAX_LANES).contains(&self.lanes) {
+            return Err(format!("lanes must be between 1 and {}", MAX_LANES));
+        }
+        // Argon2 needs at least 8 KiB of memory per lane
+        if self.mem_cost < 8 * self.lanes {
+            return Err("mem_cost must be at least 8 KiB per lane".to_string());
+        }
+        Ok(())
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct RecoveryPhraseResult {
-    recovery_phrase: String,
-    seed: String,
+    recovery_phrase: Zeroizing<String>,
+    seed: Zeroizing<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct VerifyResult {
+    // Whether the phrase itself is a well-formed BIP39 mnemonic, i.e. the
+    // length, words and checksum below are all valid. This never depends on
+    // the passphrase, which BIP39 has no way to check.
     is_valid: bool,
-    seed: Option<String>,
+    // The phrase has one of the supported word counts
+    length_valid: bool,
+    // Every word is in the wordlist
+    words_valid: bool,
+    // The words parse as a mnemonic with a matching checksum. Only checked
+    // once the length and words are valid, so false otherwise. A bad checksum
+    // with valid words usually means two words are swapped or a wrong-but-real
+    // word was typed.
+    checksum_valid: bool,
+    // Seed derived from the phrase and passphrase. The passphrase changes the
+    // seed, so a wrong passphrase yields a valid-but-different seed rather than
+    // an error - callers must compare it against a known value to detect that.
+    seed: Option<Zeroizing<String>>,
+    // Whether a non-empty passphrase was mixed into the seed
+    passphrase_used: bool,
+    // Wordlist language the phrase was checked against, either the one
+    // requested or the one detected from the words
+    language: Option<String>,
+    // Words (zero-based position, word as typed) not found in the wordlist.
+    // Empty for a valid phrase.
+    invalid_words: Vec<(usize, String)>,
+}
+
+// Keys derived from a seed for one label. Private keys are only included on request.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct KeypairResult {
+    label: String,
+    x25519_public_key: Option<String>,
+    x25519_private_key: Option<Zeroizing<String>>,
+    ed25519_public_key: Option<String>,
+    ed25519_private_key: Option<Zeroizing<String>>,
+}
+
+// Domain separation for HKDF so these keys never collide with other seed uses
+const KEYPAIR_HKDF_SALT: &[u8] = b"cirrussync-keypair-v1";
+
+// Layout of an encoded seed share: version, split id, threshold, then the
+// raw Shamir share (x coordinate followed by one byte per seed byte)
+const SHARE_FORMAT_VERSION: u8 = 1;
+const SHARE_SPLIT_ID_LEN: usize = 4;
+const SHARE_HEADER_LEN: usize = 1 + SHARE_SPLIT_ID_LEN + 1;
+
+// BIP39 phrase lengths we accept
+const SUPPORTED_WORD_COUNTS: [usize; 5] = [12, 15, 18, 21, 24];
+const DEFAULT_WORD_COUNT: usize = 12;
+
+// Most autocomplete suggestions returned for one prefix
+const MAX_WORD_SUGGESTIONS: usize = 10;
+
+// Letters that uniquely identify a word in the Latin-script wordlists
+const UNIQUE_PREFIX_LEN: usize = 4;
+
+/// Map a BIP39 word count to its entropy size in bytes
+fn entropy_bytes_for_word_count(word_count: usize) -> Result<usize, String> {
+    if !SUPPORTED_WORD_COUNTS.contains(&word_count) {
+        return Err(format!(
+            "Unsupported word count {}, expected one of {:?}",
+            word_count, SUPPORTED_WORD_COUNTS
+        ));
+    }
+
+    // Every 3 words encode 32 bits of entropy plus a 1-bit checksum
+    Ok(word_count / 3 * 4)
+}
+
+/// Map a language name from the frontend to a BIP39 wordlist
+fn parse_language(name: &str) -> Result<Language, String> {
+    match name.to_lowercase().replace(['-', ' '], "_").as_str() {
+        "english" | "en" => Ok(Language::English),
+        "japanese" | "ja" => Ok(Language::Japanese),
+        "korean" | "ko" => Ok(Language::Korean),
+        "spanish" | "es" => Ok(Language::Spanish),
+        "french" | "fr" => Ok(Language::French),
+        "italian" | "it" => Ok(Language::Italian),
+        "czech" | "cs" => Ok(Language::Czech),
+        "portuguese" | "pt" => Ok(Language::Portuguese),
+        "chinese" | "chinese_simplified" | "zh" | "zh_hans" => Ok(Language::SimplifiedChinese),
+        "chinese_traditional" | "zh_hant" => Ok(Language::TraditionalChinese),
+        _ => Err(format!("Unsupported recovery phrase language: {}", name)),
+    }
+}
+
+/// Name of a BIP39 wordlist as reported back to the frontend
+fn language_name(language: Language) -> &'static str {
+    match language {
+        Language::English => "english",
+        Language::Japanese => "japanese",
+        Language::Korean => "korean",
+        Language::Spanish => "spanish",
+        Language::French => "french",
+        Language::Italian => "italian",
+        Language::Czech => "czech",
+        Language::Portuguese => "portuguese",
+        Language::SimplifiedChinese => "chinese_simplified",
+        Language::TraditionalChinese => "chinese_traditional",
+    }
+}
+
+/// Parse a recovery phrase in the given language, or detect it from the words
+fn parse_phrase(phrase: &str, language: Option<Language>) -> Result<Mnemonic, String> {
+    let result = match language {
+        Some(language) => Mnemonic::parse_in(language, phrase),
+        None => Mnemonic::parse(phrase),
+    };
+
+    result.map_err(|e| format!("Invalid recovery phrase: {}", e))
+}
+
+/// Pick the wordlist to check a phrase against and collect the words missing from it
+fn find_invalid_words(
+    phrase: &str,
+    language: Option<Language>,
+) -> (Language, Vec<(usize, String)>) {
+    // Normalize the same way parsing does so accented words match the wordlist
+    let mut normalized = Cow::Borrowed(phrase);
+    Mnemonic::normalize_utf8_cow(&mut normalized);
+    let words: Vec<&str> = normalized.split_whitespace().collect();
+
+    // Without an explicit language, assume the one that recognizes the most
+    // words, falling back to English when none stands out
+    let language = language.unwrap_or_else(|| {
+        let known_words = |language: &Language| {
+            words
+                .iter()
+                .filter(|word| language.find_word(word).is_some())
+                .count()
+        };
+        Language::ALL
+            .iter()
+            .copied()
+            .fold(Language::English, |best, language| {
+                if known_words(&language) > known_words(&best) {
+                    language
+                } else {
+                    best
+                }
+            })
+    });
+
+    let invalid_words = words
+        .iter()
+        .enumerate()
+        .filter(|(_, word)| language.find_word(word).is_none())
+        .map(|(index, word)| (index, word.to_string()))
+        .collect();
+
+    (language, invalid_words)
+}
+
+/// Derive the hex seed for a mnemonic, mixing in the optional BIP39 passphrase
+fn seed_from_mnemonic(mnemonic: &Mnemonic, passphrase: &str) -> Zeroizing<String> {
+    let seed = Zeroizing::new(mnemonic.to_seed(passphrase));
+    Zeroizing::new(hex::encode(&seed[..]))
+}
+
+/// Expand a seed into 32 bytes of key material bound to the given info string
+fn derive_key_material(seed: &[u8], info: &str) -> Result<Zeroizing<[u8; 32]>, String> {
+    let hkdf = Hkdf::<Sha256>::new(Some(KEYPAIR_HKDF_SALT), seed);
+    let mut key_material = Zeroizing::new([0u8; 32]);
+    hkdf.expand(info.as_bytes(), key_material.as_mut())
+        .map_err(|_| "Key derivation failed".to_string())?;
+    Ok(key_material)
+}
+
+/// Derive a deterministic X25519 and/or Ed25519 keypair from a seed and label
+#[tauri::command]
+pub async fn derive_keypair_from_seed(
+    seed_hex: String,
+    label: String,
+    key_type: Option<String>,
+    include_private: Option<bool>,
+) -> Result<KeypairResult, String> {
+    let seed_hex = Zeroizing::new(seed_hex);
+    let seed = Zeroizing::new(hex::decode(&*seed_hex).map_err(|_| "Invalid seed hex".to_string())?);
+    if seed.len() < 32 {
+        return Err("Seed must be at least 32 bytes".to_string());
+    }
+    if label.trim().is_empty() {
+        return Err("Derivation label must not be empty".to_string());
+    }
+
+    let (want_x25519, want_ed25519) = match key_type.as_deref().unwrap_or("both") {
+        "x25519" => (true, false),
+        "ed25519" => (false, true),
+        "both" => (true, true),
+        other => return Err(format!("Unsupported key type: {}", other)),
+    };
+    let include_private = include_private.unwrap_or(false);
+
+    task::spawn_blocking(move || {
+        let mut result = KeypairResult {
+            label: label.clone(),
+            x25519_public_key: None,
+            x25519_private_key: None,
+            ed25519_public_key: None,
+            ed25519_private_key: None,
+        };
+
+        // Each key type gets its own HKDF info, so labels and types never share keys
+        if want_x25519 {
+            let key_material = derive_key_material(&seed, &format!("x25519:{}", label))?;
+            let secret = StaticSecret::from(*key_material);
+            result.x25519_public_key = Some(hex::encode(PublicKey::from(&secret).as_bytes()));
+            if include_private {
+                let private_key = Zeroizing::new(secret.to_bytes());
+                result.x25519_private_key = Some(Zeroizing::new(hex::encode(&private_key[..])));
+            }
+        }
+
+        if want_ed25519 {
+            let key_material = derive_key_material(&seed, &format!("ed25519:{}", label))?;
+            let signing_key = SigningKey::from_bytes(&key_material);
+            result.ed25519_public_key = Some(hex::encode(signing_key.verifying_key().as_bytes()));
+            if include_private {
+                let private_key = Zeroizing::new(signing_key.to_bytes());
+                result.ed25519_private_key = Some(Zeroizing::new(hex::encode(&private_key[..])));
+            }
+        }
+
+        Ok(result)
+    })
+    .await
+    .map_err(|e| format!("Task failed: {:?}", e))?
+}
+
+/// 32-byte Argon2id key for a password and salt. Slow by design, so call it
+/// from a blocking task.
+pub(crate) fn argon2_key(
+    password: &[u8],
+    salt: &[u8],
+    params: &Argon2Params,
+) -> Result<Zeroizing<Vec<u8>>, String> {
+    // Argon2 Config with the requested (or default) cost parameters
+    let config = Config {
+        variant: Variant::Argon2id,
+        version: Version::Version13,
+        mem_cost: params.mem_cost,
+        time_cost: params.time_cost,
+        lanes: params.lanes,
+        secret: &[],
+        ad: &[],
+        hash_length: 32, // 32-byte output (256 bits)
+    };
+
+    argon2::hash_raw(password, salt, &config)
+        .map(Zeroizing::new)
+        .map_err(|e| format!("Seed generation failed: {:?}", e))
 }
 
 /// Derive a seed from a password using Argon2 without storing the hash
@@ -28,7 +353,13 @@ pub struct VerifyResult {
 pub async fn derive_seed_from_password(
     password: String,
     salt_hex: Option<String>,
+    mem_cost: Option<u32>,
+    time_cost: Option<u32>,
+    lanes: Option<u32>,
 ) -> Result<SeedResult, String> {
+    let params = Argon2Params::from_options(mem_cost, time_cost, lanes)?;
+    let password = Zeroizing::new(password);
+
     task::spawn_blocking(move || {
         let salt = match salt_hex {
             Some(hex_str) => hex::decode(&hex_str).map_err(|_| "Invalid salt hex".to_string())?,
@@ -39,76 +370,686 @@ pub async fn derive_seed_from_password(
             }
         };
 
-        // Argon2 Config with reduced cost parameters
-        let config = Config {
-            variant: Variant::Argon2id,
-            version: Version::Version13,
-            mem_cost: 32768, // 32 MB memory
-            time_cost: 3,    // 3 iterations (reduced from 10)
-            lanes: 2,        // Parallelism factor (reduced from 4)
-            secret: &[],
-            ad: &[],
-            hash_length: 32, // 32-byte output (256 bits)
-        };
-
         // Generate seed directly without storing hash
-        let seed_bytes = argon2::hash_raw(password.as_bytes(), &salt, &config)
-            .map_err(|e| format!("Seed generation failed: {:?}", e))?;
+        let seed_bytes = argon2_key(password.as_bytes(), &salt, &params)?;
 
         Ok(SeedResult {
-            seed: hex::encode(&seed_bytes),
+            seed: Zeroizing::new(hex::encode(&*seed_bytes)),
+            salt_hex: hex::encode(&salt),
+            params,
         })
     })
     .await
     .map_err(|e| format!("Task failed: {:?}", e))?
 }
 
-/// Generate a BIP39 12-word recovery phrase and derive the same seed
+/// Generate a BIP39 recovery phrase (12 words by default) and derive the same seed
 #[tauri::command]
-pub async fn generate_recovery_phrase() -> Result<RecoveryPhraseResult, String> {
+pub async fn generate_recovery_phrase(
+    word_count: Option<usize>,
+    passphrase: Option<String>,
+    language: Option<String>,
+) -> Result<RecoveryPhraseResult, String> {
+    let entropy_size = entropy_bytes_for_word_count(word_count.unwrap_or(DEFAULT_WORD_COUNT))?;
+    let language = match language {
+        Some(name) => parse_language(&name)?,
+        None => Language::English,
+    };
+
+    let passphrase = passphrase.map(Zeroizing::new);
+
     task::spawn_blocking(move || {
         // Generate random entropy for mnemonic
-        let mut entropy = [0u8; 16];
-        rand::rng().fill(&mut entropy);
+        let mut entropy = Zeroizing::new(vec![0u8; entropy_size]);
+        rand::rng().fill(&mut entropy[..]);
 
         // Create mnemonic from entropy
-        let mnemonic = Mnemonic::from_entropy(&entropy)
+        let mnemonic = Mnemonic::from_entropy_in(language, &entropy)
             .map_err(|_| "Failed to generate mnemonic".to_string())?;
-        let recovery_phrase = mnemonic.to_string();
+        let recovery_phrase = Zeroizing::new(mnemonic.to_string());
 
-        // Generate seed directly from the mnemonic with empty password
-        // This makes the seed derivation dependent only on the recovery phrase
-        let seed_bytes = mnemonic.to_seed("");
+        // Generate seed directly from the mnemonic and optional passphrase
+        // Without a passphrase the seed depends only on the recovery phrase
+        let seed = seed_from_mnemonic(
+            &mnemonic,
+            passphrase.as_deref().map(String::as_str).unwrap_or(""),
+        );
 
         Ok(RecoveryPhraseResult {
             recovery_phrase,
-            seed: hex::encode(&seed_bytes),
+            seed,
+        })
+    })
+    .await
+    .map_err(|e| format!("Task failed: {:?}", e))?
+}
+
+/// Split a seed into `total` hex shares, any `threshold` of which recover it
+#[tauri::command]
+pub fn split_seed_into_shares(
+    seed_hex: String,
+    threshold: u8,
+    total: u8,
+) -> Result<Vec<String>, String> {
+    let seed_hex = Zeroizing::new(seed_hex);
+    let seed = Zeroizing::new(hex::decode(&*seed_hex).map_err(|_| "Invalid seed hex".to_string())?);
+    if seed.is_empty() {
+        return Err("Seed must not be empty".to_string());
+    }
+    if threshold < 2 {
+        return Err("Threshold must be at least 2".to_string());
+    }
+    if total < threshold {
+        return Err(format!(
+            "Total shares ({}) must be at least the threshold ({})",
+            total, threshold
+        ));
+    }
+
+    // A random split id lets us refuse to combine shares from different splits,
+    // which would otherwise silently produce a wrong seed
+    let mut split_id = [0u8; SHARE_SPLIT_ID_LEN];
+    rand::rng().fill(&mut split_id);
+
+    let shares = Sharks(threshold)
+        .dealer(&seed)
+        .take(total as usize)
+        .map(|share| {
+            let mut bytes = Vec::with_capacity(SHARE_HEADER_LEN + 1 + seed.len());
+            bytes.push(SHARE_FORMAT_VERSION);
+            bytes.extend_from_slice(&split_id);
+            bytes.push(threshold);
+            bytes.extend(Vec::from(&share));
+            hex::encode(bytes)
+        })
+        .collect();
+
+    Ok(shares)
+}
+
+/// Recover a seed from shares produced by `split_seed_into_shares`
+#[tauri::command]
+pub fn combine_seed_shares(shares: Vec<String>) -> Result<Zeroizing<String>, String> {
+    if shares.is_empty() {
+        return Err("No shares provided".to_string());
+    }
+
+    let mut header: Option<Vec<u8>> = None;
+    let mut share_len: Option<usize> = None;
+    let mut seen_indices = HashSet::new();
+    let mut parsed = Vec::with_capacity(shares.len());
+
+    for (index, share_hex) in shares.iter().enumerate() {
+        let bytes = hex::decode(share_hex.trim())
+            .map_err(|_| format!("Share {} is not valid hex", index + 1))?;
+
+        if bytes.len() < SHARE_HEADER_LEN + 2 || bytes[0] != SHARE_FORMAT_VERSION {
+            return Err(format!("Share {} is malformed", index + 1));
+        }
+
+        // All shares must come from the same split and cover the same seed length
+        let share_header = &bytes[..SHARE_HEADER_LEN];
+        match &header {
+            None => header = Some(share_header.to_vec()),
+            Some(expected) if expected.as_slice() != share_header => {
+                return Err(format!("Share {} belongs to a different split", index + 1));
+            }
+            _ => {}
+        }
+        match share_len {
+            None => share_len = Some(bytes.len()),
+            Some(expected) if expected != bytes.len() => {
+                return Err(format!("Share {} has a different length", index + 1));
+            }
+            _ => {}
+        }
+
+        // The x coordinate is never zero, since f(0) is the secret itself
+        let share_bytes = &bytes[SHARE_HEADER_LEN..];
+        if share_bytes[0] == 0 {
+            return Err(format!("Share {} is malformed", index + 1));
+        }
+        if !seen_indices.insert(share_bytes[0]) {
+            return Err(format!("Share {} is a duplicate", index + 1));
+        }
+
+        parsed.push(Share::try_from(share_bytes).map_err(|e| e.to_string())?);
+    }
+
+    let threshold = header
+        .map(|header| header[SHARE_HEADER_LEN - 1])
+        .unwrap_or(0);
+    if parsed.len() < threshold as usize {
+        return Err(format!(
+            "Need at least {} shares to recover the seed, got {}",
+            threshold,
+            parsed.len()
+        ));
+    }
+
+    let seed = Zeroizing::new(
+        Sharks(threshold)
+            .recover(&parsed)
+            .map_err(|e| format!("Failed to combine shares: {}", e))?,
+    );
+
+    Ok(Zeroizing::new(hex::encode(&*seed)))
+}
+
+/// Suggest wordlist entries starting with a prefix, for autocompleting recovery words
+#[tauri::command]
+pub fn suggest_recovery_words(
+    prefix: String,
+    language: Option<String>,
+) -> Result<Vec<String>, String> {
+    let language = match language {
+        Some(name) => parse_language(&name)?,
+        None => Language::English,
+    };
+
+    let mut prefix = Cow::Owned(prefix.trim().to_lowercase());
+    Mnemonic::normalize_utf8_cow(&mut prefix);
+    if prefix.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    // In the Latin-script lists the first four letters pin down a word, so
+    // anything typed past them can only narrow to that word or be a typo
+    let unique_prefix = match language {
+        Language::English
+        | Language::Spanish
+        | Language::French
+        | Language::Italian
+        | Language::Czech
+        | Language::Portuguese => prefix
+            .char_indices()
+            .nth(UNIQUE_PREFIX_LEN)
+            .map(|(end, _)| &prefix[..end]),
+        _ => None,
+    };
+
+    let mut matches: Vec<String> = language
+        .word_list()
+        .iter()
+        .filter(|word| word.starts_with(prefix.as_ref()))
+        .take(MAX_WORD_SUGGESTIONS)
+        .map(|word| word.to_string())
+        .collect();
+
+    // Still offer the word the first four letters point to when later letters are mistyped
+    if matches.is_empty() {
+        if let Some(unique_prefix) = unique_prefix {
+            matches = language
+                .word_list()
+                .iter()
+                .filter(|word| word.starts_with(unique_prefix))
+                .take(MAX_WORD_SUGGESTIONS)
+                .map(|word| word.to_string())
+                .collect();
+        }
+    }
+
+    Ok(matches)
+}
+
+#[tauri::command]
+pub async fn verify_recovery_phrase(
+    phrase: String,
+    passphrase: Option<String>,
+    language: Option<String>,
+) -> Result<VerifyResult, String> {
+    // Without an explicit language, detect it from the words
+    let language = language.map(|name| parse_language(&name)).transpose()?;
+
+    let phrase = Zeroizing::new(phrase);
+
+    task::spawn_blocking(move || {
+        let passphrase = Zeroizing::new(passphrase.unwrap_or_default());
+
+        // Check the length and each word first so the user can be pointed at
+        // the actual problem rather than a generic parse failure
+        let length_valid = SUPPORTED_WORD_COUNTS.contains(&phrase.split_whitespace().count());
+        let (language, invalid_words) = find_invalid_words(&phrase, language);
+        let words_valid = invalid_words.is_empty();
+
+        // Derive the seed the same way it was generated originally. With the
+        // length and words valid, a parse failure means the checksum is wrong.
+        let mnemonic = (length_valid && words_valid)
+            .then(|| parse_phrase(&phrase, Some(language)).ok())
+            .flatten();
+        let seed = mnemonic
+            .as_ref()
+            .map(|mnemonic| seed_from_mnemonic(mnemonic, &passphrase));
+
+        Ok(VerifyResult {
+            is_valid: seed.is_some(),
+            length_valid,
+            words_valid,
+            checksum_valid: seed.is_some(),
+            seed,
+            passphrase_used: !passphrase.is_empty(),
+            language: Some(language_name(language).to_string()),
+            invalid_words,
         })
     })
     .await
     .map_err(|e| format!("Task failed: {:?}", e))?
 }
 
+/// Derive the hex seed for an existing recovery phrase, mixing in the
+/// optional BIP39 passphrase the same way generate_recovery_phrase does
 #[tauri::command]
-pub async fn verify_recovery_phrase(phrase: String) -> Result<VerifyResult, String> {
+pub async fn seed_from_recovery_phrase(
+    phrase: String,
+    passphrase: Option<String>,
+    language: Option<String>,
+) -> Result<Zeroizing<String>, String> {
+    // Without an explicit language, detect it from the words
+    let language = language.map(|name| parse_language(&name)).transpose()?;
+
+    let phrase = Zeroizing::new(phrase);
+
     task::spawn_blocking(move || {
-        // Check if the recovery phrase is valid
-        let mnemonic_result = Mnemonic::parse_in_normalized(Language::English, &phrase);
-
-        let is_valid = mnemonic_result.is_ok();
-        let seed = if is_valid {
-            // Generate seed directly from mnemonic with empty password
-            // to match how we generated it originally
-            let mnemonic = mnemonic_result.unwrap();
-            let seed_bytes = mnemonic.to_seed("");
-
-            Some(hex::encode(&seed_bytes))
-        } else {
-            None
-        };
+        let passphrase = Zeroizing::new(passphrase.unwrap_or_default());
+
+        let word_count = phrase.split_whitespace().count();
+        if !SUPPORTED_WORD_COUNTS.contains(&word_count) {
+            return Err(format!(
+                "Unsupported word count {}, expected one of {:?}",
+                word_count, SUPPORTED_WORD_COUNTS
+            ));
+        }
+
+        let (language, invalid_words) = find_invalid_words(&phrase, language);
+        if let Some((index, word)) = invalid_words.first() {
+            return Err(format!(
+                "Unknown word \"{}\" at position {}",
+                word,
+                index + 1
+            ));
+        }
+
+        // All words are known at this point, so a parse failure means the
+        // checksum is wrong
+        let mnemonic = parse_phrase(&phrase, Some(language))
+            .map_err(|_| "Invalid recovery phrase checksum".to_string())?;
 
-        Ok(VerifyResult { is_valid, seed })
+        Ok(seed_from_mnemonic(&mnemonic, &passphrase))
     })
     .await
     .map_err(|e| format!("Task failed: {:?}", e))?
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_word_counts_to_entropy_sizes() {
+        assert_eq!(entropy_bytes_for_word_count(12), Ok(16));
+        assert_eq!(entropy_bytes_for_word_count(15), Ok(20));
+        assert_eq!(entropy_bytes_for_word_count(18), Ok(24));
+        assert_eq!(entropy_bytes_for_word_count(21), Ok(28));
+        assert_eq!(entropy_bytes_for_word_count(24), Ok(32));
+        assert!(entropy_bytes_for_word_count(13).is_err());
+    }
+
+    #[tokio::test]
+    async fn generated_24_word_phrase_round_trips() {
+        let generated = generate_recovery_phrase(Some(24), None, None)
+            .await
+            .unwrap();
+        assert_eq!(generated.recovery_phrase.split_whitespace().count(), 24);
+
+        let verified = verify_recovery_phrase(generated.recovery_phrase.to_string(), None, None)
+            .await
+            .unwrap();
+        assert!(verified.is_valid);
+        assert_eq!(verified.seed, Some(generated.seed));
+    }
+
+    #[tokio::test]
+    async fn rejects_unsupported_word_counts() {
+        assert!(
+            generate_recovery_phrase(Some(11), None, None)
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn passphrase_changes_seed_but_not_validity() {
+        let passphrase = Some("hidden".to_string());
+        let generated = generate_recovery_phrase(None, passphrase.clone(), None)
+            .await
+            .unwrap();
+
+        let with_passphrase =
+            verify_recovery_phrase(generated.recovery_phrase.to_string(), passphrase, None)
+                .await
+                .unwrap();
+        assert!(with_passphrase.passphrase_used);
+        assert_eq!(with_passphrase.seed, Some(generated.seed.clone()));
+
+        let without_passphrase =
+            verify_recovery_phrase(generated.recovery_phrase.to_string(), None, None)
+                .await
+                .unwrap();
+        assert!(without_passphrase.is_valid);
+        assert_ne!(without_passphrase.seed, Some(generated.seed));
+    }
+
+    #[tokio::test]
+    async fn detects_language_when_not_specified() {
+        let generated = generate_recovery_phrase(None, None, Some("spanish".to_string()))
+            .await
+            .unwrap();
+
+        let verified = verify_recovery_phrase(generated.recovery_phrase.to_string(), None, None)
+            .await
+            .unwrap();
+        assert!(verified.is_valid);
+        assert_eq!(verified.language.as_deref(), Some("spanish"));
+        assert_eq!(verified.seed, Some(generated.seed));
+    }
+
+    // The BIP39 test vector for all-zero 128-bit entropy
+    const VALID_PHRASE: &str = "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about";
+
+    #[tokio::test]
+    async fn valid_phrase_passes_every_check() {
+        let verified = verify_recovery_phrase(VALID_PHRASE.to_string(), None, None)
+            .await
+            .unwrap();
+        assert!(verified.is_valid);
+        assert!(verified.length_valid);
+        assert!(verified.words_valid);
+        assert!(verified.checksum_valid);
+        assert!(verified.invalid_words.is_empty());
+        assert!(verified.seed.is_some());
+    }
+
+    #[tokio::test]
+    async fn reports_unsupported_length() {
+        // Real words, but one short of a supported length
+        let short = VALID_PHRASE.split_whitespace().skip(1).collect::<Vec<_>>();
+        let verified = verify_recovery_phrase(short.join(" "), None, None)
+            .await
+            .unwrap();
+        assert!(!verified.is_valid);
+        assert!(!verified.length_valid);
+        assert!(verified.words_valid);
+        assert!(!verified.checksum_valid);
+        assert!(verified.seed.is_none());
+    }
+
+    #[tokio::test]
+    async fn reports_unknown_words() {
+        let mut words: Vec<&str> = VALID_PHRASE.split_whitespace().collect();
+        words[3] = "notaword";
+        let typo = verify_recovery_phrase(words.join(" "), None, None)
+            .await
+            .unwrap();
+        assert!(!typo.is_valid);
+        assert!(typo.length_valid);
+        assert!(!typo.words_valid);
+        assert!(!typo.checksum_valid);
+        assert_eq!(typo.invalid_words, vec![(3, "notaword".to_string())]);
+        assert!(typo.seed.is_none());
+    }
+
+    #[tokio::test]
+    async fn reports_checksum_failures() {
+        // Swapping the last word to the front keeps every word real but
+        // breaks the checksum
+        let mut words: Vec<&str> = VALID_PHRASE.split_whitespace().collect();
+        words.swap(0, 11);
+        let swapped = verify_recovery_phrase(words.join(" "), None, None)
+            .await
+            .unwrap();
+        assert!(!swapped.is_valid);
+        assert!(swapped.length_valid);
+        assert!(swapped.words_valid);
+        assert!(!swapped.checksum_valid);
+        assert!(swapped.invalid_words.is_empty());
+        assert!(swapped.seed.is_none());
+    }
+
+    #[tokio::test]
+    async fn derives_the_generated_seed_from_its_phrase() {
+        let passphrase = Some("hidden".to_string());
+        let generated = generate_recovery_phrase(None, passphrase.clone(), None)
+            .await
+            .unwrap();
+
+        let seed =
+            seed_from_recovery_phrase(generated.recovery_phrase.to_string(), passphrase, None)
+                .await
+                .unwrap();
+        assert_eq!(seed, generated.seed);
+
+        let mut words: Vec<&str> = VALID_PHRASE.split_whitespace().collect();
+        assert!(
+            seed_from_recovery_phrase(words[1..].join(" "), None, None)
+                .await
+                .is_err()
+        );
+        words.swap(0, 11);
+        assert!(
+            seed_from_recovery_phrase(words.join(" "), None, None)
+                .await
+                .is_err()
+        );
+        words[3] = "notaword";
+        assert!(
+            seed_from_recovery_phrase(words.join(" "), None, None)
+                .await
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn suggests_words_by_prefix() {
+        assert_eq!(
+            suggest_recovery_words("ABO".to_string(), None).unwrap(),
+            vec!["about".to_string(), "above".to_string()]
+        );
+        assert_eq!(
+            suggest_recovery_words("abanxx".to_string(), None).unwrap(),
+            vec!["abandon".to_string()]
+        );
+        assert!(
+            suggest_recovery_words("a".to_string(), None).unwrap().len() <= MAX_WORD_SUGGESTIONS
+        );
+        assert!(
+            suggest_recovery_words("zzz".to_string(), None)
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn seed_shares_round_trip() {
+        let seed_hex = hex::encode((0..64).collect::<Vec<u8>>());
+
+        for (threshold, total) in [(2, 2), (2, 3), (3, 5), (5, 5), (4, 10)] {
+            let shares = split_seed_into_shares(seed_hex.clone(), threshold, total).unwrap();
+            assert_eq!(shares.len(), total as usize);
+
+            // Any `threshold` shares are enough, whichever ones they are
+            let first = shares[..threshold as usize].to_vec();
+            let last = shares[shares.len() - threshold as usize..].to_vec();
+            assert_eq!(*combine_seed_shares(first).unwrap(), seed_hex);
+            assert_eq!(*combine_seed_shares(last).unwrap(), seed_hex);
+            assert_eq!(*combine_seed_shares(shares.clone()).unwrap(), seed_hex);
+
+            let too_few = shares[..threshold as usize - 1].to_vec();
+            assert!(combine_seed_shares(too_few).is_err());
+        }
+    }
+
+    #[test]
+    fn rejects_bad_seed_shares() {
+        let seed_hex = hex::encode([7u8; 32]);
+        assert!(split_seed_into_shares(seed_hex.clone(), 1, 3).is_err());
+        assert!(split_seed_into_shares(seed_hex.clone(), 4, 3).is_err());
+
+        let shares = split_seed_into_shares(seed_hex.clone(), 2, 3).unwrap();
+        let other = split_seed_into_shares(seed_hex, 2, 3).unwrap();
+
+        // Shares from different splits of the same seed must not be mixed
+        assert!(combine_seed_shares(vec![shares[0].clone(), other[1].clone()]).is_err());
+        assert!(combine_seed_shares(vec![shares[0].clone(), shares[0].clone()]).is_err());
+        assert!(combine_seed_shares(vec![shares[0].clone(), "zz".to_string()]).is_err());
+        assert!(combine_seed_shares(vec![shares[0].clone(), shares[1][..20].to_string()]).is_err());
+        assert!(combine_seed_shares(Vec::new()).is_err());
+    }
+
+    #[tokio::test]
+    async fn secrets_are_wiped_on_drop() {
+        // These only compile while the secrets are wrapped in Zeroizing, so
+        // switching any of them back to a plain String or array fails here
+        let material: Zeroizing<[u8; 32]> = derive_key_material(&[1u8; 32], "x25519:test").unwrap();
+        assert_ne!(*material, [0u8; 32]);
+
+        let generated = generate_recovery_phrase(None, None, None).await.unwrap();
+        let _: &Zeroizing<String> = &generated.recovery_phrase;
+        let _: &Zeroizing<String> = &generated.seed;
+
+        let derived = derive_seed_from_password("hunter2".to_string(), None, None, None, None)
+            .await
+            .unwrap();
+        let _: &Zeroizing<String> = &derived.seed;
+
+        let keys = derive_keypair_from_seed(
+            generated.seed.to_string(),
+            "test".to_string(),
+            None,
+            Some(true),
+        )
+        .await
+        .unwrap();
+        let _: &Option<Zeroizing<String>> = &keys.x25519_private_key;
+        let _: &Option<Zeroizing<String>> = &keys.ed25519_private_key;
+
+        let shares = split_seed_into_shares(generated.seed.to_string(), 2, 2).unwrap();
+        let _: Zeroizing<String> = combine_seed_shares(shares).unwrap();
+    }
+
+    #[tokio::test]
+    async fn returned_salt_reproduces_the_seed() {
+        let first = derive_seed_from_password("hunter2".to_string(), None, None, None, None)
+            .await
+            .unwrap();
+        assert_eq!(first.salt_hex.len(), 32);
+
+        let again = derive_seed_from_password(
+            "hunter2".to_string(),
+            Some(first.salt_hex.clone()),
+            Some(first.params.mem_cost),
+            Some(first.params.time_cost),
+            Some(first.params.lanes),
+        )
+        .await
+        .unwrap();
+        assert_eq!(again.seed, first.seed);
+        assert_eq!(again.salt_hex, first.salt_hex);
+    }
+
+    // Seed of bytes 0..32, derived independently with HKDF-SHA256 and RFC 7748
+    // / RFC 8032 public key derivation
+    const TEST_SEED: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+    async fn test_keypair(label: &str, key_type: &str) -> KeypairResult {
+        derive_keypair_from_seed(
+            TEST_SEED.to_string(),
+            label.to_string(),
+            Some(key_type.to_string()),
+            Some(true),
+        )
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn keypairs_match_known_answers() {
+        let keys = test_keypair("vault", "both").await;
+        assert_eq!(
+            keys.x25519_public_key.as_deref(),
+            Some("54282fa55e316b026d47eb11e2e54d61b574dc3ce1a46130bacfe408a8eab85c")
+        );
+        assert_eq!(
+            keys.ed25519_public_key.as_deref(),
+            Some("27961d25cf4c485939edb48e078b5991a14762900dcf1b125d2066aeb9f1595d")
+        );
+    }
+
+    #[tokio::test]
+    async fn keypairs_are_deterministic_per_label() {
+        let first = test_keypair("vault", "both").await;
+        let again = test_keypair("vault", "both").await;
+        assert_eq!(first.x25519_public_key, again.x25519_public_key);
+        assert_eq!(first.ed25519_public_key, again.ed25519_public_key);
+        assert_eq!(
+            first.x25519_private_key.as_deref().map(|key| key.as_str()),
+            again.x25519_private_key.as_deref().map(|key| key.as_str())
+        );
+
+        let other = test_keypair("sharing", "both").await;
+        assert_ne!(first.x25519_public_key, other.x25519_public_key);
+        assert_ne!(first.ed25519_public_key, other.ed25519_public_key);
+    }
+
+    #[tokio::test]
+    async fn each_key_type_gives_only_its_own_valid_keys() {
+        let x25519 = test_keypair("vault", "x25519").await;
+        assert!(x25519.ed25519_public_key.is_none() && x25519.ed25519_private_key.is_none());
+        let private: [u8; 32] = hex::decode(x25519.x25519_private_key.unwrap().as_str())
+            .unwrap()
+            .try_into()
+            .unwrap();
+        let public = hex::decode(x25519.x25519_public_key.unwrap()).unwrap();
+        assert_eq!(
+            PublicKey::from(&StaticSecret::from(private)).as_bytes()[..],
+            public[..]
+        );
+
+        let ed25519 = test_keypair("vault", "ed25519").await;
+        assert!(ed25519.x25519_public_key.is_none() && ed25519.x25519_private_key.is_none());
+        let private: [u8; 32] = hex::decode(ed25519.ed25519_private_key.unwrap().as_str())
+            .unwrap()
+            .try_into()
+            .unwrap();
+        let public = hex::decode(ed25519.ed25519_public_key.unwrap()).unwrap();
+        assert_eq!(
+            SigningKey::from_bytes(&private).verifying_key().as_bytes()[..],
+            public[..]
+        );
+
+        // Keys of one type match the same type from "both"
+        let both = test_keypair("vault", "both").await;
+        assert_eq!(both.ed25519_public_key, Some(hex::encode(public)));
+
+        assert!(
+            derive_keypair_from_seed(
+                TEST_SEED.to_string(),
+                "vault".to_string(),
+                Some("rsa".to_string()),
+                None
+            )
+            .await
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn rejects_out_of_range_argon2_params() {
+        assert_eq!(
+            Argon2Params::from_options(None, None, None),
+            Ok(Argon2Params::default())
+        );
+        assert!(Argon2Params::from_options(Some(1024), None, None).is_err());
+        assert!(Argon2Params::from_options(None, Some(0), None).is_err());
+        assert!(Argon2Params::from_options(None, None, Some(64)).is_err());
+    }
+}
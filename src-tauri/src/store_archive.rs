@@ -0,0 +1,375 @@
+// src/store_archive.rs
+
+use std::path::{Component, Path, PathBuf};
+
+use aes_gcm::aead::Aead;
+use aes_gcm::{Aes256Gcm, Key, KeyInit, Nonce};
+use base64::{Engine as _, engine::general_purpose};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tauri::{State, command};
+use tokio::task;
+use zeroize::Zeroizing;
+
+use crate::recovery_key::{self, Argon2Params};
+use crate::{AppState, CORRUPT_DIR, store_dir};
+
+// Bumped whenever the archive layout changes
+const ARCHIVE_VERSION: u32 = 1;
+
+// Store subdirectory holding the saved passwords
+const SECURE_DIR: &str = "secure";
+
+/// One file of the store, by its path relative to the store directory
+#[derive(Serialize, Deserialize)]
+struct ArchivedFile {
+    path: String,
+    data: String, // Base64
+}
+
+/// The whole store as written by export_store
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum StoreArchive {
+    Plain {
+        version: u32,
+        files: Vec<ArchivedFile>,
+    },
+    // The files, serialized as JSON and sealed with AES-256-GCM under an
+    // Argon2id key derived from the passphrase
+    Encrypted {
+        version: u32,
+        params: Argon2Params,
+        salt: String,  // Hex
+        nonce: String, // Hex
+        data: String,  // Base64 ciphertext
+    },
+}
+
+/// Paths of every file under `dir`, relative to it. Password files are only
+/// included when asked for, and quarantined values never are.
+async fn list_store_files(dir: &Path, include_secure: bool) -> Result<Vec<PathBuf>, String> {
+    let mut files = Vec::new();
+    let mut pending = vec![PathBuf::new()];
+
+    while let Some(relative) = pending.pop() {
+        let mut entries = tokio::fs::read_dir(dir.join(&relative))
+            .await
+            .map_err(|e| format!("Failed to read store directory: {}", e))?;
+
+        while let Some(entry) = entries
+            .next_entry()
+            .await
+            .map_err(|e| format!("Failed to read store directory: {}", e))?
+        {
+            let path = relative.join(entry.file_name());
+            let file_type = entry
+                .file_type()
+                .await
+                .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+
+            if file_type.is_dir() {
+                let top_level = relative.as_os_str().is_empty();
+                if top_level && entry.file_name() == CORRUPT_DIR {
+                    continue;
+                }
+                if top_level && entry.file_name() == SECURE_DIR && !include_secure {
+                    continue;
+                }
+                pending.push(path);
+            } else if file_type.is_file() {
+                files.push(path);
+            }
+        }
+    }
+
+    files.sort();
+    Ok(files)
+}
+
+/// Rejects archive paths that would land outside the store directory
+fn check_archive_path(path: &str) -> Result<PathBuf, String> {
+    let path = PathBuf::from(path);
+    let safe = !path.as_os_str().is_empty()
+        && path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)));
+    if !safe {
+        return Err(format!(
+            "Archive contains an unsafe path: {}",
+            path.display()
+        ));
+    }
+    Ok(path)
+}
+
+/// AES-256-GCM cipher keyed from a passphrase, off the async runtime since
+/// Argon2 is slow by design
+async fn passphrase_cipher(
+    passphrase: Zeroizing<String>,
+    salt: Vec<u8>,
+    params: Argon2Params,
+) -> Result<Aes256Gcm, String> {
+    params.validate()?;
+    task::spawn_blocking(move || {
+        let key = recovery_key::argon2_key(passphrase.as_bytes(), &salt, &params)?;
+        Ok(Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key)))
+    })
+    .await
+    .map_err(|e| format!("Task failed: {:?}", e))?
+}
+
+/// Writes every store value into a single archive file at `dest_path`. With a
+/// passphrase the archive is encrypted and also carries the saved passwords;
+/// without one they are left out. Returns how many files were archived.
+#[command]
+pub async fn export_store(
+    state: State<'_, AppState>,
+    dest_path: String,
+    passphrase: Option<String>,
+) -> Result<usize, String> {
+    let store_path = store_dir(&state)?;
+    let passphrase = passphrase.map(Zeroizing::new);
+
+    let mut files = Vec::new();
+    if store_path.exists() {
+        for relative in list_store_files(&store_path, passphrase.is_some()).await? {
+            let data = tokio::fs::read(store_path.join(&relative))
+                .await
+                .map_err(|e| format!("Failed to read {}: {}", relative.display(), e))?;
+            files.push(ArchivedFile {
+                // Forward slashes, so archives move between platforms
+                path: relative.to_string_lossy().replace('\\', "/"),
+                data: general_purpose::STANDARD.encode(&data),
+            });
+        }
+    }
+    let count = files.len();
+
+    let archive = match passphrase {
+        Some(passphrase) => {
+            let mut salt = [0u8; 16];
+            let mut nonce = [0u8; 12];
+            rand::rng().fill(&mut salt);
+            rand::rng().fill(&mut nonce);
+
+            let params = Argon2Params::default();
+            let cipher = passphrase_cipher(passphrase, salt.to_vec(), params).await?;
+            let plaintext = Zeroizing::new(
+                serde_json::to_vec(&files)
+                    .map_err(|e| format!("Failed to serialize store: {}", e))?,
+            );
+            let ciphertext = cipher
+                .encrypt(Nonce::from_slice(&nonce), plaintext.as_ref())
+                .map_err(|e| format!("Failed to encrypt store: {}", e))?;
+
+            StoreArchive::Encrypted {
+                version: ARCHIVE_VERSION,
+                params,
+                salt: hex::encode(salt),
+                nonce: hex::encode(nonce),
+                data: general_purpose::STANDARD.encode(ciphertext),
+            }
+        }
+        None => StoreArchive::Plain {
+            version: ARCHIVE_VERSION,
+            files,
+        },
+    };
+
+    let contents = serde_json::to_vec(&archive)
+        .map_err(|e| format!("Failed to serialize store archive: {}", e))?;
+    tokio::fs::write(&dest_path, contents)
+        .await
+        .map_err(|e| format!("Failed to write store archive: {}", e))?;
+
+    println!("Exported {} store files to {}", count, dest_path);
+    Ok(count)
+}
+
+/// Replaces the store with the contents of an archive from export_store. The
+/// whole archive is decrypted and checked before anything is written, and a
+/// store that already has values is only replaced with `force`. A plain
+/// archive carries no passwords, so the saved ones are kept. Returns how many
+/// files were restored.
+#[command]
+pub async fn import_store(
+    state: State<'_, AppState>,
+    src_path: String,
+    passphrase: Option<String>,
+    force: Option<bool>,
+) -> Result<usize, String> {
+    let store_path = store_dir(&state)?;
+
+    let contents = tokio::fs::read(&src_path)
+        .await
+        .map_err(|e| format!("Failed to read store archive: {}", e))?;
+    let archive: StoreArchive =
+        serde_json::from_slice(&contents).map_err(|e| format!("Not a store archive: {}", e))?;
+
+    let archived_files: Vec<ArchivedFile> = match archive {
+        StoreArchive::Plain { version, files } => {
+            if version != ARCHIVE_VERSION {
+                return Err(format!("Unsupported store archive version {}", version));
+            }
+            files
+        }
+        StoreArchive::Encrypted {
+            version,
+            params,
+            salt,
+            nonce,
+            data,
+        } => {
+            if version != ARCHIVE_VERSION {
+                return Err(format!("Unsupported store archive version {}", version));
+            }
+            let passphrase = passphrase
+                .map(Zeroizing::new)
+                .ok_or("This store archive is encrypted and needs a passphrase")?;
+            let salt = hex::decode(salt).map_err(|_| "Invalid archive salt".to_string())?;
+            let nonce = hex::decode(nonce).map_err(|_| "Invalid archive nonce".to_string())?;
+            if nonce.len() != 12 {
+                return Err("Invalid archive nonce".to_string());
+            }
+            let ciphertext = general_purpose::STANDARD
+                .decode(data)
+                .map_err(|e| format!("Invalid archive data: {}", e))?;
+
+            let cipher = passphrase_cipher(passphrase, salt, params).await?;
+            let plaintext = Zeroizing::new(
+                cipher
+                    .decrypt(Nonce::from_slice(&nonce), ciphertext.as_ref())
+                    .map_err(|_| "Wrong passphrase or damaged store archive".to_string())?,
+            );
+            serde_json::from_slice(&plaintext)
+                .map_err(|e| format!("Invalid store archive contents: {}", e))?
+        }
+    };
+
+    // Decode everything up front, so a bad entry can't leave a half-restored store
+    let mut files = Vec::with_capacity(archived_files.len());
+    for file in archived_files {
+        let path = check_archive_path(&file.path)?;
+        let data = general_purpose::STANDARD
+            .decode(&file.data)
+            .map_err(|e| format!("Invalid data for {}: {}", file.path, e))?;
+        files.push((path, Zeroizing::new(data)));
+    }
+
+    let has_values = store_path.exists() && !list_store_files(&store_path, true).await?.is_empty();
+    if has_values && !force.unwrap_or(false) {
+        return Err("The store already has values; pass force to replace them".to_string());
+    }
+
+    replace_store(&store_path, &files).await?;
+
+    println!("Imported {} store files from {}", files.len(), src_path);
+    Ok(files.len())
+}
+
+/// Swaps the store for `files`. Without password files among them, the
+/// current secure directory is carried over, so importing a plain archive
+/// doesn't throw away the saved passwords and their key.
+async fn replace_store(
+    store_path: &Path,
+    files: &[(PathBuf, Zeroizing<Vec<u8>>)],
+) -> Result<(), String> {
+    // Write the new store beside the old one and swap them, so a failed write
+    // leaves the existing store as it was
+    let staging_path = store_path.with_extension("importing");
+    let previous_path = store_path.with_extension("previous");
+    for path in [&staging_path, &previous_path] {
+        if path.exists() {
+            tokio::fs::remove_dir_all(path)
+                .await
+                .map_err(|e| format!("Failed to clear {}: {}", path.display(), e))?;
+        }
+    }
+
+    let mut secure_files = Vec::new();
+    let secure_path = store_path.join(SECURE_DIR);
+    let archive_has_secure = files
+        .iter()
+        .any(|(relative, _)| relative.starts_with(SECURE_DIR));
+    if !archive_has_secure && secure_path.exists() {
+        for relative in list_store_files(&secure_path, true).await? {
+            let data = tokio::fs::read(secure_path.join(&relative))
+                .await
+                .map_err(|e| format!("Failed to read {}: {}", relative.display(), e))?;
+            secure_files.push((Path::new(SECURE_DIR).join(relative), Zeroizing::new(data)));
+        }
+    }
+
+    for (relative, data) in files.iter().chain(&secure_files) {
+        let file_path = staging_path.join(relative);
+        if let Some(parent) = file_path.parent() {
+            tokio::fs::create_dir_all(parent)
+                .await
+                .map_err(|e| format!("Failed to create store directory: {}", e))?;
+        }
+        tokio::fs::write(&file_path, data.as_slice())
+            .await
+            .map_err(|e| format!("Failed to write {}: {}", relative.display(), e))?;
+    }
+    tokio::fs::create_dir_all(&staging_path)
+        .await
+        .map_err(|e| format!("Failed to create store directory: {}", e))?;
+
+    if store_path.exists() {
+        tokio::fs::rename(&store_path, &previous_path)
+            .await
+            .map_err(|e| format!("Failed to move the old store aside: {}", e))?;
+    }
+    tokio::fs::rename(&staging_path, &store_path)
+        .await
+        .map_err(|e| format!("Failed to move the imported store into place: {}", e))?;
+    if previous_path.exists() {
+        let _ = tokio::fs::remove_dir_all(&previous_path).await;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn archive_paths_stay_inside_the_store() {
+        assert!(check_archive_path("settings.json").is_ok());
+        assert!(check_archive_path("secure/app_user.secure").is_ok());
+
+        assert!(check_archive_path("").is_err());
+        assert!(check_archive_path("../outside.json").is_err());
+        assert!(check_archive_path("secure/../../outside.json").is_err());
+        assert!(check_archive_path("/etc/passwd").is_err());
+    }
+
+    #[tokio::test]
+    async fn a_plain_import_keeps_the_saved_passwords() {
+        let store_path =
+            std::env::temp_dir().join(format!("cirrussync-store-{}", rand::rng().random::<u64>()));
+        std::fs::create_dir_all(store_path.join(SECURE_DIR)).unwrap();
+        std::fs::write(store_path.join("settings.json"), "old").unwrap();
+        std::fs::write(store_path.join("secure/passwords.key"), "key").unwrap();
+        let file = |path: &str, data: &str| (PathBuf::from(path), Zeroizing::new(data.into()));
+
+        replace_store(&store_path, &[file("settings.json", "new")])
+            .await
+            .unwrap();
+        let read = |path: &str| std::fs::read_to_string(store_path.join(path)).unwrap();
+        assert_eq!(read("settings.json"), "new");
+        assert_eq!(read("secure/passwords.key"), "key");
+
+        // An archive with passwords brings its own
+        let archive = [
+            file("settings.json", "newer"),
+            file("secure/passwords.key", "other key"),
+        ];
+        replace_store(&store_path, &archive).await.unwrap();
+        assert_eq!(read("secure/passwords.key"), "other key");
+
+        std::fs::remove_dir_all(&store_path).unwrap();
+    }
+}
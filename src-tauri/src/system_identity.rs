@@ -1,28 +1,320 @@
 use base64::{Engine as _, engine::general_purpose::STANDARD as BASE64};
 use serde::Serialize;
 use sha2::{Digest, Sha256};
-use sysinfo::System;
+use std::sync::Mutex;
+#[cfg(test)]
+use std::sync::atomic::{AtomicUsize, Ordering};
+use sysinfo::{Networks, System};
 
-#[derive(Serialize)]
+// None of the identifier's inputs change while the app runs, and collecting
+// them enumerates every process, so compute it once per process
+static CACHED_IDENTIFIER: Mutex<Option<SystemIdentifier>> = Mutex::new(None);
+
+// Number of full sysinfo refreshes, so tests can check the cache is used
+#[cfg(test)]
+static REFRESH_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Serialize, Clone)]
 pub struct SystemIdentifier {
+    // Hash of hostname, OS name/version, kernel version, distribution, core
+    // count, total memory and app version. Changes after OS updates, RAM
+    // upgrades or app releases, so it is useful for detecting changes.
     hash: String,
+    // Hash of durable hardware traits only: the machine UUID and board serial
+    // where the OS exposes them to unprivileged users, falling back to the
+    // primary MAC address when neither is available. Survives upgrades, so it
+    // suits device binding.
+    stable_hash: String,
     os_long_version: String,
     os_name: String,
 }
 
-#[tauri::command]
-pub fn generate_system_identifier() -> SystemIdentifier {
+// Raw inputs behind the identifier hashes, for support and debugging.
+// Unlike the hashes this contains identifying information such as the
+// hostname, machine UUID, board serial and MAC address, so it should only
+// be shown to the user or sent with their explicit consent.
+#[derive(Serialize, Clone)]
+pub struct SystemDetails {
+    hostname: String,
+    os_name: String,
+    os_version: String,
+    kernel_version: String,
+    os_long_version: String,
+    distribution_id: String,
+    core_count: usize,
+    total_memory: u64,
+    app_version: String,
+    machine_uuid: Option<String>,
+    board_serial: Option<String>,
+    mac_address: Option<String>,
+}
+
+// Values firmware vendors leave in serial and UUID fields that were never
+// filled in. Shared by many machines, so they can't identify one.
+const PLACEHOLDER_SERIALS: [&str; 10] = [
+    "to be filled by o.e.m.",
+    "default string",
+    "system serial number",
+    "base board serial number",
+    "not specified",
+    "not applicable",
+    "none",
+    "n/a",
+    "oem",
+    "0123456789",
+];
+
+/// The value, trimmed, unless it is empty or a placeholder: a known filler
+/// string, or all zeros or all Fs once separators are left out
+fn real_serial(value: &str) -> Option<String> {
+    let value = value.trim();
+    let digits: Vec<char> = value
+        .chars()
+        .filter(|c| !matches!(c, '-' | ':' | ' ' | '.'))
+        .collect();
+    let placeholder = digits.is_empty()
+        || digits.iter().all(|c| *c == '0')
+        || digits.iter().all(|c| c.eq_ignore_ascii_case(&'f'))
+        || PLACEHOLDER_SERIALS.contains(&value.to_lowercase().as_str());
+
+    (!placeholder).then(|| value.to_string())
+}
+
+/// A command that doesn't open a console window. The release build is a GUI
+/// app, so Windows would otherwise flash one up for every call.
+#[cfg(target_os = "windows")]
+fn hidden_command(program: &str) -> std::process::Command {
+    use std::os::windows::process::CommandExt;
+
+    const CREATE_NO_WINDOW: u32 = 0x0800_0000;
+    let mut command = std::process::Command::new(program);
+    command.creation_flags(CREATE_NO_WINDOW);
+    command
+}
+
+/// Read the platform machine UUID, if the OS exposes one to unprivileged users
+fn machine_uuid() -> Option<String> {
+    #[cfg(target_os = "linux")]
+    {
+        // /sys/class/dmi/id/product_uuid is left out: only root can read it,
+        // so the identifier would differ between root and user runs
+        ["/etc/machine-id", "/var/lib/dbus/machine-id"]
+            .iter()
+            .find_map(|path| read_trimmed(path))
+            .and_then(|uuid| real_serial(&uuid))
+    }
+
+    #[cfg(target_os = "macos")]
+    {
+        let output = std::process::Command::new("ioreg")
+            .args(["-rd1", "-c", "IOPlatformExpertDevice"])
+            .output()
+            .ok()?;
+        String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .find(|line| line.contains("IOPlatformUUID"))
+            .and_then(|line| line.split('"').nth(3))
+            .and_then(real_serial)
+    }
+
+    #[cfg(target_os = "windows")]
+    {
+        let output = hidden_command("reg")
+            .args([
+                "query",
+                "HKLM\\SOFTWARE\\Microsoft\\Cryptography",
+                "/v",
+                "MachineGuid",
+            ])
+            .output()
+            .ok()?;
+        String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .find(|line| line.contains("MachineGuid"))
+            .and_then(|line| line.split_whitespace().last())
+            .and_then(real_serial)
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "windows")))]
+    {
+        None
+    }
+}
+
+/// Read the motherboard serial number where an unprivileged user can
+fn board_serial() -> Option<String> {
+    #[cfg(target_os = "linux")]
+    {
+        // /sys/class/dmi/id/board_serial is only readable by root, and an
+        // input that comes and goes with privileges isn't stable
+        None
+    }
+
+    #[cfg(target_os = "macos")]
+    {
+        let output = std::process::Command::new("ioreg")
+            .args(["-rd1", "-c", "IOPlatformExpertDevice"])
+            .output()
+            .ok()?;
+        String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .find(|line| line.contains("IOPlatformSerialNumber"))
+            .and_then(|line| line.split('"').nth(3))
+            .and_then(real_serial)
+    }
+
+    #[cfg(target_os = "windows")]
+    {
+        // wmic is deprecated and gone from current Windows 11, so PowerShell's
+        // CIM cmdlets are asked when it is missing
+        let wmic = || {
+            let output = hidden_command("wmic")
+                .args(["baseboard", "get", "serialnumber"])
+                .output()
+                .ok()?;
+            String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .skip(1) // Column header
+                .find_map(real_serial)
+        };
+        let powershell = || {
+            let output = hidden_command("powershell")
+                .args([
+                    "-NoProfile",
+                    "-NonInteractive",
+                    "-Command",
+                    "(Get-CimInstance -ClassName Win32_BaseBoard).SerialNumber",
+                ])
+                .output()
+                .ok()?;
+            String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .find_map(real_serial)
+        };
+        wmic().or_else(powershell)
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "windows")))]
+    {
+        None
+    }
+}
+
+/// Pick a MAC address that does not depend on interface enumeration order
+fn primary_mac_address() -> Option<String> {
+    let networks = Networks::new_with_refreshed_list();
+    networks
+        .iter()
+        .filter(|(name, _)| {
+            // Skip loopback and common virtual interfaces, which come and go
+            let name = name.to_lowercase();
+            ![
+                "lo", "docker", "veth", "br-", "virbr", "vmnet", "utun", "tun", "tap",
+            ]
+            .iter()
+            .any(|prefix| name.starts_with(prefix))
+        })
+        .map(|(_, data)| data.mac_address())
+        .filter(|mac| !mac.is_unspecified())
+        .map(|mac| mac.to_string())
+        .min()
+}
+
+#[cfg(target_os = "linux")]
+fn read_trimmed(path: &str) -> Option<String> {
+    std::fs::read_to_string(path)
+        .ok()
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+}
+
+/// Hash the durable hardware traits into an identifier that survives upgrades
+fn generate_stable_hash(details: &SystemDetails) -> String {
+    // The MAC address is only a fallback, since adapters can be swapped
+    let mac = if details.machine_uuid.is_none() && details.board_serial.is_none() {
+        details.mac_address.as_deref()
+    } else {
+        None
+    };
+
+    let stable_info = format!(
+        "uuid:{}|serial:{}|mac:{}",
+        details.machine_uuid.as_deref().unwrap_or_default(),
+        details.board_serial.as_deref().unwrap_or_default(),
+        mac.unwrap_or_default()
+    );
+
+    let mut hasher = Sha256::new();
+    hasher.update(stable_info.as_bytes());
+    BASE64.encode(hasher.finalize())
+}
+
+/// Collect every raw input that feeds the identifier hashes
+fn collect_system_details() -> SystemDetails {
+    #[cfg(test)]
+    REFRESH_COUNT.fetch_add(1, Ordering::SeqCst);
+
     let mut system = System::new_all();
     system.refresh_all();
 
-    let hostname = System::host_name().unwrap_or_else(|| "unknown".to_string());
-    let os_name = System::name().unwrap_or_else(|| "unknown".to_string());
-    let os_version = System::os_version().unwrap_or_else(|| "<unknown>".to_owned());
-    let kernel_version = System::kernel_version().unwrap_or_else(|| "unknown".to_string());
-    let os_long_version = System::long_os_version().unwrap_or_else(|| "unknown".to_string());
-    let distribution_id = System::distribution_id();
-    let core_count = system.physical_core_count().unwrap_or(0);
-    let total_memory = system.total_memory();
+    SystemDetails {
+        hostname: System::host_name().unwrap_or_else(|| "unknown".to_string()),
+        os_name: System::name().unwrap_or_else(|| "unknown".to_string()),
+        os_version: System::os_version().unwrap_or_else(|| "<unknown>".to_owned()),
+        kernel_version: System::kernel_version().unwrap_or_else(|| "unknown".to_string()),
+        os_long_version: System::long_os_version().unwrap_or_else(|| "unknown".to_string()),
+        distribution_id: System::distribution_id(),
+        core_count: system.physical_core_count().unwrap_or(0),
+        total_memory: system.total_memory(),
+        // Include app version if available (you'll need to replace this with your actual app version)
+        app_version: env!("CARGO_PKG_VERSION").to_string(),
+        machine_uuid: machine_uuid(),
+        board_serial: board_serial(),
+        mac_address: primary_mac_address(),
+    }
+}
+
+/// Return the raw fields behind the system identifier. The result contains
+/// potentially identifying information such as the hostname and hardware
+/// serials; use `generate_system_identifier` for anything sent routinely.
+#[tauri::command]
+pub fn get_system_details() -> SystemDetails {
+    collect_system_details()
+}
+
+#[tauri::command]
+pub fn generate_system_identifier() -> SystemIdentifier {
+    let mut cached = CACHED_IDENTIFIER
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    cached.get_or_insert_with(compute_system_identifier).clone()
+}
+
+/// Recompute the system identifier, replacing the cached value
+#[tauri::command]
+pub fn refresh_system_identifier() -> SystemIdentifier {
+    let identifier = compute_system_identifier();
+    let mut cached = CACHED_IDENTIFIER
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    *cached = Some(identifier.clone());
+    identifier
+}
+
+fn compute_system_identifier() -> SystemIdentifier {
+    let details = collect_system_details();
+    let SystemDetails {
+        hostname,
+        os_name,
+        os_version,
+        kernel_version,
+        os_long_version,
+        distribution_id,
+        core_count,
+        total_memory,
+        app_version,
+        ..
+    } = details.clone();
 
     // Format OS name in a clean way (e.g., "macOS" instead of "darwin")
     let formatted_os_name = match os_name.to_lowercase().as_str() {
@@ -44,9 +336,6 @@ pub fn generate_system_identifier() -> SystemIdentifier {
         _ => os_name.clone(), // Clone instead of moving
     };
 
-    // Include app version if available (you'll need to replace this with your actual app version)
-    let app_version = env!("CARGO_PKG_VERSION");
-
     // Construct system identifier string with all values to ensure uniqueness
     let system_info = format!(
         "{}|{}|{}|{}|{}|{}|cores:{}|total_memory:{}|app_version:{}",
@@ -72,7 +361,60 @@ pub fn generate_system_identifier() -> SystemIdentifier {
     // Return system identifier struct with formatted OS name
     SystemIdentifier {
         hash: hash_encoded,
+        stable_hash: generate_stable_hash(&details),
         os_long_version,
-        os_name: formatted_os_name + "-" + app_version,
+        os_name: formatted_os_name + "-" + &app_version,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn caches_identifier_between_calls() {
+        let first = generate_system_identifier();
+        let refreshes = REFRESH_COUNT.load(Ordering::SeqCst);
+
+        let second = generate_system_identifier();
+        assert_eq!(REFRESH_COUNT.load(Ordering::SeqCst), refreshes);
+        assert_eq!(first.hash, second.hash);
+
+        let refreshed = refresh_system_identifier();
+        assert_eq!(REFRESH_COUNT.load(Ordering::SeqCst), refreshes + 1);
+        assert_eq!(refreshed.stable_hash, first.stable_hash);
+    }
+
+    #[test]
+    fn placeholder_serials_are_ignored() {
+        for placeholder in [
+            "",
+            "   ",
+            "To be filled by O.E.M.",
+            "Default string",
+            "0",
+            "00000000-0000-0000-0000-000000000000",
+            "FFFFFFFF-FFFF-FFFF-FFFF-FFFFFFFFFFFF",
+            "None",
+        ] {
+            assert_eq!(real_serial(placeholder), None, "{:?}", placeholder);
+        }
+        assert_eq!(real_serial(" PF2XK0QB \n").as_deref(), Some("PF2XK0QB"));
+        assert_eq!(
+            real_serial("4c4c4544-0042-3510-8052-b4c04f4e3732").as_deref(),
+            Some("4c4c4544-0042-3510-8052-b4c04f4e3732")
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn linux_identity_only_uses_files_any_user_can_read() {
+        // Root-only DMI files would make root and user runs disagree
+        assert_eq!(board_serial(), None);
+        let expected = ["/etc/machine-id", "/var/lib/dbus/machine-id"]
+            .iter()
+            .find_map(|path| read_trimmed(path))
+            .and_then(|uuid| real_serial(&uuid));
+        assert_eq!(machine_uuid(), expected);
     }
 }
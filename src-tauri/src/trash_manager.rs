@@ -0,0 +1,192 @@
+// src/trash_manager.rs
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use lazy_static::lazy_static;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tauri::{AppHandle, Emitter, command};
+use tokio::sync::Mutex;
+
+/// Items the frontend reports as trashed on the server
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrashResponse {
+    trashed_ids: Vec<String>,
+    failed_ids: Vec<String>,
+}
+
+/// Payload for a successful trash response from the frontend
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrashResponsePayload {
+    request_id: String,
+    response: TrashResponse,
+}
+
+/// Payload for a failed trash request from the frontend
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrashErrorPayload {
+    request_id: String,
+    error: String,
+}
+
+/// Outcome of a move_to_trash call, also sent with `trash-complete`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrashResult {
+    share_id: String,
+    parent_id: String,
+    trashed_ids: Vec<String>,
+    failed_ids: Vec<String>,
+    local_paths_trashed: usize,
+}
+
+lazy_static! {
+    static ref TRASH_RESPONSE_CHANNELS: Mutex<HashMap<String, tokio::sync::oneshot::Sender<Result<TrashResponse, String>>>> =
+        Mutex::new(HashMap::new());
+}
+
+/// Generates a unique ID for trash requests
+fn generate_request_id() -> String {
+    let random_part: u64 = rand::rng().random();
+    format!(
+        "trash-{}-{:x}",
+        chrono::Utc::now().timestamp_millis(),
+        random_part
+    )
+}
+
+/// Move items to the trash. Items on the server are trashed through the
+/// frontend, which owns the API session; locally staged files that were
+/// never uploaded are moved straight into the OS trash.
+#[command]
+pub async fn move_to_trash(
+    app: AppHandle,
+    share_id: String,
+    item_ids: Vec<String>,
+    parent_id: String,
+    local_paths: Option<Vec<String>>,
+) -> Result<TrashResult, String> {
+    let local_paths: Vec<PathBuf> = local_paths
+        .unwrap_or_default()
+        .into_iter()
+        .map(PathBuf::from)
+        .collect();
+
+    if item_ids.is_empty() && local_paths.is_empty() {
+        return Err("No items to move to trash".to_string());
+    }
+
+    let local_paths_trashed = local_paths.len();
+    if !local_paths.is_empty() {
+        tokio::task::spawn_blocking(move || trash::delete_all(&local_paths))
+            .await
+            .map_err(|e| format!("Task failed: {:?}", e))?
+            .map_err(|e| format!("Failed to move local files to trash: {}", e))?;
+    }
+
+    let response = if item_ids.is_empty() {
+        TrashResponse {
+            trashed_ids: Vec::new(),
+            failed_ids: Vec::new(),
+        }
+    } else {
+        let request_id = generate_request_id();
+
+        // Insert the channel BEFORE emitting the event so a fast response isn't lost
+        let (tx, rx) = tokio::sync::oneshot::channel::<Result<TrashResponse, String>>();
+        {
+            let mut channels = TRASH_RESPONSE_CHANNELS.lock().await;
+            channels.insert(request_id.clone(), tx);
+        }
+
+        println!(
+            "Requesting trash of {} items in share {}",
+            item_ids.len(),
+            share_id
+        );
+
+        if let Err(e) = app.emit(
+            "trash-items",
+            serde_json::json!({
+                "request_id": request_id,
+                "share_id": share_id,
+                "parent_id": parent_id,
+                "item_ids": item_ids,
+            }),
+        ) {
+            TRASH_RESPONSE_CHANNELS.lock().await.remove(&request_id);
+            return Err(format!("Failed to request trash operation: {}", e));
+        }
+
+        match tokio::time::timeout(Duration::from_secs(30), rx).await {
+            Ok(Ok(Ok(response))) => response,
+            Ok(Ok(Err(e))) => return Err(format!("Failed to move items to trash: {}", e)),
+            Ok(Err(_)) => return Err("Trash response channel closed".to_string()),
+            Err(_) => {
+                TRASH_RESPONSE_CHANNELS.lock().await.remove(&request_id);
+                return Err("Timed out waiting for trash response".to_string());
+            }
+        }
+    };
+
+    let result = TrashResult {
+        share_id,
+        parent_id,
+        trashed_ids: response.trashed_ids,
+        failed_ids: response.failed_ids,
+        local_paths_trashed,
+    };
+
+    let _ = app.emit("trash-complete", &result);
+
+    Ok(result)
+}
+
+/// Handler for trash results from frontend
+#[command]
+pub async fn trash_items_response(payload: TrashResponsePayload) -> Result<(), String> {
+    println!(
+        "Received trash response for request ID: {}",
+        payload.request_id
+    );
+
+    let mut channels = TRASH_RESPONSE_CHANNELS.lock().await;
+    match channels.remove(&payload.request_id) {
+        Some(sender) => {
+            if sender.send(Ok(payload.response)).is_err() {
+                println!("Failed to send trash response through channel - receiver dropped");
+            }
+        }
+        None => println!(
+            "No waiting receiver found for trash request ID: {}",
+            payload.request_id
+        ),
+    }
+
+    Ok(())
+}
+
+/// Handler for trash errors from frontend
+#[command]
+pub async fn trash_error_response(payload: TrashErrorPayload) -> Result<(), String> {
+    println!(
+        "Received trash error for request ID: {}: {}",
+        payload.request_id, payload.error
+    );
+
+    let mut channels = TRASH_RESPONSE_CHANNELS.lock().await;
+    match channels.remove(&payload.request_id) {
+        Some(sender) => {
+            if sender.send(Err(payload.error)).is_err() {
+                println!("Failed to send trash error through channel - receiver dropped");
+            }
+        }
+        None => println!(
+            "No waiting receiver found for trash request ID: {}",
+            payload.request_id
+        ),
+    }
+
+    Ok(())
+}
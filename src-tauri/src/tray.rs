@@ -0,0 +1,157 @@
+// src/tray.rs
+
+use std::time::Duration;
+
+use tauri::menu::{Menu, MenuEvent, MenuItem, PredefinedMenuItem};
+use tauri::tray::{MouseButton, MouseButtonState, TrayIconBuilder, TrayIconEvent};
+use tauri::{AppHandle, Manager};
+
+use crate::file_transfer::{self, TransferManagerState};
+
+const TRAY_ID: &str = "main";
+
+// How often the tooltip and pause/resume label follow the transfer queue
+const STATUS_REFRESH_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Build the tray icon and start keeping its status in sync with the queue
+pub fn setup_tray(app: &tauri::App) -> Result<(), Box<dyn std::error::Error>> {
+    let toggle_window =
+        MenuItem::with_id(app, "tray_toggle_window", "Show/Hide", true, None::<&str>)?;
+    let toggle_transfers = MenuItem::with_id(
+        app,
+        "tray_toggle_transfers",
+        "Pause All Transfers",
+        true,
+        None::<&str>,
+    )?;
+    let quit = MenuItem::with_id(app, "tray_quit", "Quit CirrusSync", true, None::<&str>)?;
+
+    let menu = Menu::with_items(
+        app,
+        &[
+            &toggle_window,
+            &PredefinedMenuItem::separator(app)?,
+            &toggle_transfers,
+            &PredefinedMenuItem::separator(app)?,
+            &quit,
+        ],
+    )?;
+
+    let mut builder = TrayIconBuilder::with_id(TRAY_ID)
+        .tooltip("CirrusSync")
+        .menu(&menu)
+        .show_menu_on_left_click(false)
+        .on_menu_event(handle_tray_menu_event)
+        .on_tray_icon_event(|tray, event| {
+            // Left click toggles the window, right click opens the menu
+            if let TrayIconEvent::Click {
+                button: MouseButton::Left,
+                button_state: MouseButtonState::Up,
+                ..
+            } = event
+            {
+                toggle_main_window(tray.app_handle());
+            }
+        });
+
+    if let Some(icon) = app.default_window_icon() {
+        builder = builder.icon(icon.clone());
+    }
+
+    builder.build(app)?;
+
+    let app_handle = app.handle().clone();
+    tauri::async_runtime::spawn(async move {
+        loop {
+            tokio::time::sleep(STATUS_REFRESH_INTERVAL).await;
+            update_tray_status(&app_handle, &toggle_transfers).await;
+        }
+    });
+
+    Ok(())
+}
+
+fn handle_tray_menu_event(app: &AppHandle, event: MenuEvent) {
+    match event.id().0.as_str() {
+        "tray_toggle_window" => toggle_main_window(app),
+        "tray_toggle_transfers" => {
+            let app = app.clone();
+            tauri::async_runtime::spawn(async move {
+                if let Err(e) = toggle_transfers(app).await {
+                    println!("Failed to toggle transfers from tray: {}", e);
+                }
+            });
+        }
+        "tray_quit" => app.exit(0),
+        _ => {}
+    }
+}
+
+fn toggle_main_window(app: &AppHandle) {
+    let Some(window) = app.get_webview_window("main") else {
+        return;
+    };
+
+    if window.is_visible().unwrap_or(false) {
+        let _ = window.hide();
+    } else {
+        let _ = window.show();
+        let _ = window.unminimize();
+        let _ = window.set_focus();
+    }
+}
+
+/// Pending transfers across every window's session, and whether all of the
+/// sessions are paused
+async fn transfer_status(app: &AppHandle) -> (usize, bool) {
+    let state = app.state::<TransferManagerState>();
+    state.session(None); // The main window's session counts even before it is used
+
+    let mut pending = 0;
+    let mut paused = true;
+    for (_, session) in state.sessions() {
+        let queue = session.0.lock().await;
+        pending += queue.pending_count();
+        paused &= queue.is_paused();
+    }
+    (pending, paused)
+}
+
+/// Pause or resume every window's queue through the regular transfer
+/// commands. Anything still running means pause; otherwise resume all.
+async fn toggle_transfers(app: AppHandle) -> Result<(), String> {
+    let (_, paused) = transfer_status(&app).await;
+    let state = app.state::<TransferManagerState>();
+
+    for (session_id, _) in state.sessions() {
+        if paused {
+            file_transfer::resume_transfers(app.clone(), app.state(), Some(session_id)).await?;
+        } else {
+            file_transfer::pause_transfers(app.state(), Some(session_id)).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Reflect the queue size and pause state in the tray tooltip and menu
+async fn update_tray_status(app: &AppHandle, toggle_transfers: &MenuItem) {
+    let (pending, paused) = transfer_status(app).await;
+
+    let tooltip = match (pending, paused) {
+        (0, _) => "CirrusSync - Up to date".to_string(),
+        (1, false) => "CirrusSync - 1 transfer in progress".to_string(),
+        (count, false) => format!("CirrusSync - {} transfers in progress", count),
+        (count, true) => format!("CirrusSync - {} transfers paused", count),
+    };
+
+    if let Some(tray) = app.tray_by_id(TRAY_ID) {
+        let _ = tray.set_tooltip(Some(tooltip));
+    }
+
+    let label = if paused {
+        "Resume All Transfers"
+    } else {
+        "Pause All Transfers"
+    };
+    let _ = toggle_transfers.set_text(label);
+}